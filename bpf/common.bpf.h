@@ -38,6 +38,15 @@ const u32 zero = 0;
     return BUG_ERROR_CODE;                                                     \
   }
 
+// Timer flushing a query's windows on schedule (as a GLOBAL_VAR). Tracing
+// programs can't use timers, so the timer is armed (and its callback owned) by a
+// separate tc program, run from user space.
+struct window_timer {
+  struct bpf_timer timer;
+};
+// Clock of BPF timers (vmlinux.h doesn't carry macros)
+#define CLOCK_MONOTONIC 1
+
 // Compute array size
 #define ARRAY_SIZE(a) (sizeof(a) / sizeof(a[0]))
 
@@ -100,9 +109,11 @@ const volatile u8 LOG_LVL = L_DEBUG;
     tgid = (u32)pid_tgid;                                                      \
   } while (0)
 
+// Each program defines KTIME_NS() as the helper reading its configured clock
+// (e.g. bpf_ktime_get_ns or bpf_ktime_get_boot_ns).
 #define TIME(var) \
   do {            \
-    var = bpf_ktime_get_ns(); \
+    var = KTIME_NS(); \
 } while (0)
 
 #define CPU(var) \
@@ -115,6 +126,97 @@ const volatile u8 LOG_LVL = L_DEBUG;
     var = bpf_get_current_cgroup_id(); \
   } while (0)
 
+// Maximum number of frames of collected stacks (i.e. PERF_MAX_STACK_DEPTH)
+#define MAX_STACK_DEPTH 127
+typedef u64 stack_trace_t[MAX_STACK_DEPTH];
+
+// Returned by bpf_get_stackid() for stacks whose hash collides with another
+// stack's, and once the stack trace map is full
+#ifndef ENOMEM
+#define ENOMEM 12
+#endif
+#ifndef EEXIST
+#define EEXIST 17
+#endif
+
+// Programs reading stacks define STACK_TRACES_0 and STACK_TRACES_1 as their
+// stack trace maps, which windows collect stacks into in turn (by the parity of
+// stack_epoch, flipped as windows are flushed): user space clears the map of
+// each window once it has resolved its stacks. Stack ids carry their map in
+// bit 31. Colliding stacks aren't replaced (i.e. there's no
+// BPF_F_REUSE_STACKID), since the window's rows may still refer to them;
+// they're lost, and counted as dropped events (see agg.bpf.h).
+//
+// Stacks that can't be collected (e.g. the kernel stack of a sample taken in
+// user space) read as -1. User stacks also carry their process (in the upper 32
+// bits), since their addresses are only meaningful within it.
+#define __STACKID(id, epoch, flags) \
+  do {                              \
+    epoch = stack_epoch & 1;        \
+    id = epoch ? bpf_get_stackid(ctx, &STACK_TRACES_1, flags) \
+               : bpf_get_stackid(ctx, &STACK_TRACES_0, flags); \
+    if (id == -EEXIST || id == -ENOMEM) overflow_lose_stack(); \
+  } while (0)
+
+#define KSTACK(var) \
+  do {              \
+    long __kstack_id; \
+    u64 __kstack_epoch; \
+    __STACKID(__kstack_id, __kstack_epoch, 0); \
+    var = __kstack_id < 0 ? (u64)-1 : (__kstack_epoch << 31) | (u32)__kstack_id; \
+  } while (0)
+
+#define USTACK(var) \
+  do {              \
+    long __ustack_id; \
+    u64 __ustack_epoch; \
+    __STACKID(__ustack_id, __ustack_epoch, BPF_F_USER_STACK); \
+    var = __ustack_id < 0 ? (u64)-1 \
+        : (bpf_get_current_pid_tgid() & 0xffffffff00000000ULL) | (__ustack_epoch << 31) \
+          | (u32)__ustack_id; \
+  } while (0)
+
+// Maximum number of bytes compared in string predicates
+#define STR_MAX_LEN 256
+
+// Returns whether the first n bytes of a string match the literal. n is a
+// constant after inlining, so the loop is bounded for the verifier.
+static __always_inline bool str_match(const char *s, const char *lit,
+                                      const u32 n) {
+  for (u32 i = 0; i < n && i < STR_MAX_LEN; i++) {
+    if (s[i] != lit[i])
+      return false;
+  }
+  return true;
+}
+
+// String equality (comparing the null terminator) and prefix matching against
+// string literals.
+#define STR_EQ(s, lit) str_match(s, lit, sizeof(lit))
+#define STR_PREFIX(s, lit) str_match(s, lit, sizeof(lit) - 1)
+
+// Hashes a value into 64 uniformly distributed bits (the splitmix64 finalizer);
+// sketches derive independent hashes by varying the seed.
+static __always_inline u64 sketch_hash(u64 x, u64 seed) {
+  x += seed * 0x9e3779b97f4a7c15ULL + 0x9e3779b97f4a7c15ULL;
+  x = (x ^ (x >> 30)) * 0xbf58476d1ce4e5b9ULL;
+  x = (x ^ (x >> 27)) * 0x94d049bb133111ebULL;
+  return x ^ (x >> 31);
+}
+
+// Gets the number of bits needed to represent a value (0 for 0), by halves.
+static __always_inline u32 bit_length(u64 x) {
+  if (!x) return 0;
+  u32 n = 1;
+  if (x >> 32) { n += 32; x >>= 32; }
+  if (x >> 16) { n += 16; x >>= 16; }
+  if (x >> 8) { n += 8; x >>= 8; }
+  if (x >> 4) { n += 4; x >>= 4; }
+  if (x >> 2) { n += 2; x >>= 2; }
+  if (x >> 1) { n += 1; }
+  return n;
+}
+
 // Compute the average of two ints (s32s) without overflow.
 static int average_without_overflow(s32 a, s32 b) {
   return (a & b) + ((a ^ b) >> 1);
@@ -1,7 +1,21 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 use ebql::{
     exec::executor::Executor,
-    query::{bpf_ops::compiler::QueryCompiler, parser::parse_query, physical_plan::PhysicalPlan},
+    query::{
+        bpf_ops::{
+            agg::{AggMaps, OverflowPolicy, DEFAULT_MAX_GROUPS},
+            window::WindowFlush,
+        },
+        explain::explain,
+    },
+    schema::{
+        data_types::{Clock, DataType},
+        record::DataValue,
+        record_batch::RecordBatch,
+        stack::{StackKind, UNKNOWN_FRAME},
+    },
 };
 
 #[derive(Parser, Debug, Clone)]
@@ -9,6 +23,36 @@ use ebql::{
 pub struct Args {
     #[arg(short, long)]
     query: String,
+    /// Clock to read the time of events from (monotonic, boot, or utc)
+    #[arg(long, default_value = "monotonic")]
+    clock: Clock,
+    /// How time windows are flushed (event, timer, or tick)
+    #[arg(long, default_value = "timer")]
+    window_flush: WindowFlush,
+    /// Emit rows with zero counts for groups without events in a window
+    #[arg(long)]
+    emit_empty_windows: bool,
+    /// Maps to keep aggregations in (auto, shared, or percpu); auto uses
+    /// per-CPU maps for frequent events
+    #[arg(long, default_value = "auto")]
+    agg_maps: AggMaps,
+    /// What happens to new groups once --max-groups are kept (drop, lru,
+    /// other, or error); select dropped_events, dropped_groups, and other to
+    /// see what overflowed
+    #[arg(long, default_value = "drop")]
+    group_overflow: OverflowPolicy,
+    /// Maximum number of groups kept by aggregations
+    #[arg(long, default_value_t = DEFAULT_MAX_GROUPS)]
+    max_groups: u64,
+    /// Print rows with stacks as folded stacks (the row's other columns, then
+    /// its user and kernel frames from the root, then its last integer
+    /// column as the count), e.g. for flamegraph.pl
+    #[arg(long)]
+    folded: bool,
+    /// Directory to compile queries in (by default, under the system's
+    /// temporary directory)
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
 }
 
 fn main() {
@@ -19,19 +63,111 @@ fn main() {
 
     let args = Args::parse();
 
-    let s = parse_query(args.query).unwrap();
-    let physical_plan = PhysicalPlan::from_select(s).unwrap();
-    let bpf_plan = &physical_plan.event_plans[0];
+    // EXPLAIN statements only print what the query would do
+    if let Some(explanation) = explain(&args.query).unwrap() {
+        println!("{explanation}");
+        return;
+    }
+
+    let mut exec = Executor::new()
+        .with_clock(args.clock)
+        .with_window_flush(args.window_flush)
+        .with_empty_windows(args.emit_empty_windows)
+        .with_agg_maps(args.agg_maps)
+        .with_overflow(args.group_overflow, args.max_groups);
+    if let Some(work_dir) = args.work_dir {
+        exec = exec.with_work_root(work_dir);
+    }
+    let handle = exec.execute_query(args.query).unwrap();
 
-    log::info!("Schema: {}", bpf_plan.schema);
+    log::info!("Schema: {}", handle.schema());
 
-    let mut qc = QueryCompiler {};
-    let obj = qc.compile_bpf_ops(bpf_plan).unwrap();
+    let mut failed = false;
+    for rb in handle.stream() {
+        if let Some(error) = &rb.error {
+            eprintln!("Query failed: {error}");
+            failed = true;
+            break;
+        }
+        if args.folded {
+            print_folded(&rb);
+        } else {
+            print_batch(&rb);
+        }
+    }
+    // Stop the query before exiting
+    drop(handle);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Prints a batch; histogram columns are rendered as bar charts (like
+/// bpftrace's `hist()`), each labeled with the row's other columns.
+fn print_batch(rb: &RecordBatch) {
+    let is_hist = rb
+        .schema
+        .fields
+        .iter()
+        .map(|f| matches!(f.data_type, DataType::Histogram(_)))
+        .collect::<Vec<_>>();
+    if !is_hist.contains(&true) {
+        println!("{rb}");
+        return;
+    }
+    let partial = if rb.partial { " [partial]" } else { "" };
+    for record in rb {
+        let label = rb
+            .schema
+            .fields
+            .iter()
+            .zip(record)
+            .zip(&is_hist)
+            .filter(|(_, is_hist)| !**is_hist)
+            .map(|((f, v), _)| format!("{}: {v}", f.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        for (f, v) in rb.schema.fields.iter().zip(record) {
+            if let DataValue::Histogram(h) = v {
+                println!("@{}[{label}]{partial}:\n{}", f.name, h.render());
+            }
+        }
+    }
+}
 
-    let exec = Executor::new(obj).unwrap();
-    let rx = exec.prog_streams.get(&bpf_plan.schema.name).unwrap();
-    for rb in rx {
-        println!("{rb}")
+/// Prints a batch as folded stacks, one line per row, e.g.
+/// `bash;main;read;entry_SYSCALL_64;ksys_read 42`. Timestamps (e.g. window
+/// bounds) are left out, so that the rows of all windows fold together.
+fn print_folded(rb: &RecordBatch) {
+    let count_col = rb
+        .schema
+        .fields
+        .iter()
+        .rposition(|f| f.data_type.is_integer());
+    for record in rb {
+        let mut frames = Vec::new();
+        let mut stacks = Vec::new();
+        for (i, (f, v)) in rb.schema.fields.iter().zip(record).enumerate() {
+            match (v, &f.data_type) {
+                (DataValue::Stack(stack), _) => stacks.push(stack),
+                (_, DataType::Timestamp(_)) => (),
+                _ if Some(i) == count_col => (),
+                _ => frames.push(v.to_string()),
+            }
+        }
+        // User frames are below kernel frames
+        stacks.sort_by_key(|stack| stack.kind != StackKind::User);
+        frames.extend(
+            stacks
+                .iter()
+                .flat_map(|stack| stack.root_first())
+                .map(String::from),
+        );
+        if frames.is_empty() {
+            frames.push(UNKNOWN_FRAME.to_string());
+        }
+        let count = count_col.map_or(DataValue::UInt64(1), |i| record.get(i));
+        println!("{} {count}", frames.join(";"));
     }
 }
 
@@ -1,13 +1,34 @@
-use std::{str, sync::Arc};
+use std::{str, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use super::{Field, Type};
 use crate::{
+    data_types::{Clock, DataType},
+    error::EbqlError,
+    histogram::{Buckets, Histogram},
     record::{DataValue, Record},
-    schema::schema::Schema,
+    schema::{field::Statistic, schema::Schema},
 };
 
+/// Number of readings taken to calibrate the offset of UTC from the boot clock
+const CALIBRATION_ROUNDS: usize = 8;
+
+/// How the raw value of a field is decoded into a data value.
+#[derive(Clone, Copy, Debug)]
+enum Decode {
+    /// As the field's type
+    Raw,
+    /// As a float, from a fixed-point integer with the scale
+    FixedPoint(u64),
+    /// As a timestamp, from nanoseconds of the struct's clock
+    Timestamp,
+    /// As a float, the statistic computed from the moments of a column
+    Moments(Statistic),
+    /// As a histogram, from the counts of its buckets
+    Histogram(Buckets),
+}
+
 /// Representation of a struct in BPF (C).
 #[derive(Clone, Debug)]
 pub struct Struct {
@@ -19,6 +40,12 @@ pub struct Struct {
     pub schema: Arc<Schema>,
     /// Map sorted offsets to original offsets
     mapping: Vec<usize>,
+    /// How each field is decoded
+    decodes: Vec<Decode>,
+    /// Clock that timestamp fields are read from
+    clock: Clock,
+    /// Offset added to timestamps (i.e. from the boot clock to UTC)
+    clock_offset: Duration,
 }
 
 impl Struct {
@@ -32,15 +59,50 @@ impl Struct {
             sz: 0,
             schema,
             mapping: vec![0; fields.len()],
+            decodes: Vec::new(),
+            clock: Clock::default(),
+            clock_offset: Duration::ZERO,
         };
         if optimize {
             s.optimize_padding();
         }
+        s.decodes = s
+            .mapping
+            .iter()
+            .map(|i| {
+                match s.schema.fields.get(*i) {
+                    Some(f) => {
+                        match f.data_type {
+                            DataType::Timestamp(_) => Decode::Timestamp,
+                            DataType::Histogram(buckets) => Decode::Histogram(buckets),
+                            _ => {
+                                f.moments()
+                                    .map(Decode::Moments)
+                                    .or_else(|| f.fixed_point_scale().map(Decode::FixedPoint))
+                                    .unwrap_or(Decode::Raw)
+                            }
+                        }
+                    }
+                    None => Decode::Raw,
+                }
+            })
+            .collect();
         // Populate size and offsets of s
         s.sz = s.populate_offsets(optimize);
         s
     }
 
+    /// Sets the clock that the struct's timestamps are read from. For UTC,
+    /// calibrates the offset of UTC from the boot clock.
+    pub fn with_clock(mut self, clock: Clock) -> Result<Self> {
+        self.clock = clock;
+        self.clock_offset = match clock {
+            Clock::Utc => utc_offset()?,
+            Clock::Monotonic | Clock::Boot => Duration::ZERO,
+        };
+        Ok(self)
+    }
+
     /// Returns the fields, together with their byte offsets.
     pub fn field_offsets(&self) -> Vec<(Field, usize)> {
         self.fields
@@ -114,6 +176,22 @@ impl Struct {
             // log::info!("Reading buf[{}..{}] for type {}", start, end, f._type);
 
             let f_buf = &buf[start..end];
+            // Moments and histograms are carried as raw bytes
+            match self.decodes[i] {
+                Decode::Moments(stat) => {
+                    dvs[self.mapping[i]] = DataValue::Float64(moments_statistic(f_buf, stat));
+                    continue;
+                }
+                Decode::Histogram(buckets) => {
+                    let counts = f_buf
+                        .chunks_exact(8)
+                        .map(|c| u64::from_ne_bytes(c.try_into().unwrap()))
+                        .collect();
+                    dvs[self.mapping[i]] = DataValue::Histogram(Histogram { buckets, counts });
+                    continue;
+                }
+                _ => (),
+            }
             // Based on the field's data type, transmute it to the appropriate type
             let dv = match f._type {
                 Type::Bool => {
@@ -131,7 +209,8 @@ impl Struct {
                         unsafe { std::mem::transmute::<[u8; 4], u32>(f_buf.try_into().unwrap()) };
                     DataValue::UInt32(val)
                 }
-                Type::U64 => {
+                // Pointers are output as their addresses
+                Type::U64 | Type::Pointer(_) => {
                     let val =
                         unsafe { std::mem::transmute::<[u8; 8], u64>(f_buf.try_into().unwrap()) };
                     DataValue::UInt64(val)
@@ -156,8 +235,25 @@ impl Struct {
                     let s = str::from_utf8(f_buf)?;
                     DataValue::String(s.to_string(), len)
                 }
-                Type::Pointer(_) => unimplemented!("dunno how to handle this"),
-                Type::Struct(_, _) => unimplemented!("dunno how to handle this"),
+                Type::Struct(ref name, _) => {
+                    bail!(EbqlError::unsupported(
+                        name,
+                        "struct fields cannot be decoded"
+                    ))
+                }
+            };
+            // Decode fixed-point integers and timestamps
+            let dv = match (self.decodes[i], dv.as_i128()) {
+                (Decode::FixedPoint(scale), Some(val)) => {
+                    DataValue::Float64(val as f64 / scale as f64)
+                }
+                (Decode::Timestamp, Some(val)) => {
+                    DataValue::Timestamp(
+                        Duration::from_nanos(val as u64) + self.clock_offset,
+                        self.clock,
+                    )
+                }
+                _ => dv,
             };
 
             // Assign mapping from optimized representation order to schema order
@@ -167,3 +263,117 @@ impl Struct {
         Ok(Record::from(dvs))
     }
 }
+
+/// Computes the statistic from the moments of a column, as laid out by
+/// `moments_t` (see `agg.bpf.h.tmpl`): the count, the shift, then the low and
+/// high halves of the signed sum and unsigned sum of squares of the values
+/// minus the shift. The statistic of fewer than two values is 0.
+fn moments_statistic(buf: &[u8], stat: Statistic) -> f64 {
+    let word = |i: usize| u64::from_ne_bytes(buf[i * 8..(i + 1) * 8].try_into().unwrap());
+    let n = word(0);
+    if n < 2 {
+        return 0.0;
+    }
+    let sum = ((word(3) as u128) << 64 | word(2) as u128) as i128 as f64;
+    let sq = ((word(5) as u128) << 64 | word(4) as u128) as f64;
+    let n = n as f64;
+    // Variance is invariant to the shift, which keeps the sums small
+    let variance = ((sq - sum * sum / n) / (n - 1.0)).max(0.0);
+    match stat {
+        Statistic::Variance => variance,
+        Statistic::Stddev => variance.sqrt(),
+    }
+}
+
+/// Calibrates the offset of UTC from the boot clock, using the reading of UTC
+/// that is most tightly bracketed by two readings of the boot clock.
+fn utc_offset() -> Result<Duration> {
+    let mut best: Option<(Duration, Duration)> = None;
+    for _ in 0..CALIBRATION_ROUNDS {
+        let before = read_clock(libc::CLOCK_BOOTTIME)?;
+        let utc = read_clock(libc::CLOCK_REALTIME)?;
+        let after = read_clock(libc::CLOCK_BOOTTIME)?;
+        let gap = after.saturating_sub(before);
+        if best.map_or(true, |(best_gap, _)| gap < best_gap) {
+            best = Some((gap, utc.saturating_sub(before + gap / 2)));
+        }
+    }
+    Ok(best.map_or(Duration::ZERO, |(_, offset)| offset))
+}
+
+fn read_clock(clock: libc::clockid_t) -> Result<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut ts) } != 0 {
+        bail!(
+            "failed to read clock {clock}: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lays out moments as the kernel does: the count, the shift, then the sum
+    /// and the sum of squares of the shifted values as (low, high) halves.
+    fn moments(count: u64, sum: i128, sq: u128) -> Vec<u8> {
+        let sum = sum as u128;
+        [
+            count,
+            0,
+            sum as u64,
+            (sum >> 64) as u64,
+            sq as u64,
+            (sq >> 64) as u64,
+        ]
+        .iter()
+        .flat_map(|w| w.to_ne_bytes())
+        .collect()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() <= b.abs() * 1e-12, "{a} != {b}");
+    }
+
+    #[test]
+    fn computes_sample_statistics() {
+        // 2, 4, 4, 4, 5, 5, 7, 9, shifted by 2
+        let buf = moments(8, 24, 104);
+        assert_close(moments_statistic(&buf, Statistic::Variance), 32.0 / 7.0);
+        assert_close(
+            moments_statistic(&buf, Statistic::Stddev),
+            (32.0f64 / 7.0).sqrt(),
+        );
+    }
+
+    #[test]
+    fn computes_statistics_of_negative_shifted_sums() {
+        // 10, 4, 6, shifted by 10
+        let buf = moments(3, -10, 52);
+        assert_close(moments_statistic(&buf, Statistic::Variance), 28.0 / 3.0);
+    }
+
+    #[test]
+    fn computes_statistics_of_sums_past_64_bits() {
+        // 0, 2^33, 2^33: the sum of squares is 2^67
+        let buf = moments(3, 1 << 34, 1 << 67);
+        assert_close(
+            moments_statistic(&buf, Statistic::Variance),
+            (1u128 << 67) as f64 / 6.0,
+        );
+    }
+
+    #[test]
+    fn has_no_spread_below_two_values() {
+        for n in [0, 1] {
+            let buf = moments(n, 0, 0);
+            assert_eq!(moments_statistic(&buf, Statistic::Variance), 0.0);
+            assert_eq!(moments_statistic(&buf, Statistic::Stddev), 0.0);
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Resolution of cgroup ids (e.g. the `cgroup` system variable) into the paths
+//! of their cgroups in the cgroup v2 hierarchy, and into the containers and
+//! Kubernetes pods they belong to, for the `cgroup_path`, `container_id` and
+//! `pod_uid` functions. Filters over these (e.g. `cgroup_path LIKE
+//! '/kubepods/%'`) are run in the kernel as lookups in sets of cgroup ids.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use crossbeam::channel::{bounded, RecvTimeoutError, Sender};
+use libbpf_rs::{MapFlags, MapHandle};
+
+use super::MapDef;
+use crate::{
+    data_types::{CgroupKind, DataType},
+    record::{DataValue, Record},
+    schema::schema::Schema,
+};
+
+/// Functions resolving cgroup ids in user space; without arguments, they
+/// resolve the cgroup of the current task (i.e. `cgroup_path` is
+/// `cgroup_path(cgroup)`).
+pub const CGROUP_PATH: &str = "cgroup_path";
+pub const CONTAINER_ID: &str = "container_id";
+pub const POD_UID: &str = "pod_uid";
+
+/// Mount point of the cgroup v2 hierarchy, if it isn't found in the mount
+/// table.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Minimum interval between two walks of the hierarchy on lookups of a cgroup
+/// it's missing, and interval at which cgroup sets are synced.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of cgroups in a cgroup set.
+pub const CGROUP_SET_ENTRIES: u64 = 1 << 14;
+/// Length of container ids (in hex).
+pub const CONTAINER_ID_LEN: usize = 64;
+
+impl CgroupKind {
+    /// Gets the function resolving cgroups into this kind.
+    pub fn func(&self) -> &str {
+        match self {
+            CgroupKind::Path => CGROUP_PATH,
+            CgroupKind::ContainerId => CONTAINER_ID,
+            CgroupKind::PodUid => POD_UID,
+        }
+    }
+
+    /// Gets the kind a function resolves cgroups into, if any.
+    pub fn from_func(name: &str) -> Option<CgroupKind> {
+        match name {
+            CGROUP_PATH => Some(CgroupKind::Path),
+            CONTAINER_ID => Some(CgroupKind::ContainerId),
+            POD_UID => Some(CgroupKind::PodUid),
+            _ => None,
+        }
+    }
+
+    /// Resolves the path of a cgroup into this kind. Cgroups outside of
+    /// containers (or pods) have empty ids.
+    fn resolve(&self, cgroup_path: &str) -> String {
+        match self {
+            CgroupKind::Path => cgroup_path.to_string(),
+            CgroupKind::ContainerId => container_id(cgroup_path).unwrap_or_default(),
+            CgroupKind::PodUid => pod_uid(cgroup_path).unwrap_or_default(),
+        }
+    }
+}
+
+/// Gets the cgroup columns of a schema, by index.
+pub fn cgroup_columns(schema: &Schema) -> Vec<(usize, CgroupKind)> {
+    schema
+        .fields
+        .iter()
+        .enumerate()
+        .filter_map(|(i, f)| {
+            match f.data_type {
+                DataType::Cgroup(kind) => Some((i, kind)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Resolves the cgroup columns of a program's output (see
+/// [`DataType::Cgroup`]). Cgroups that can't be found (e.g. that have been
+/// removed) are output by their ids, e.g. `cgroup 1234`.
+pub struct CgroupResolver {
+    columns: Vec<(usize, CgroupKind)>,
+    cgroups: Cgroups,
+}
+
+impl CgroupResolver {
+    pub fn new(schema: &Schema) -> Self {
+        Self {
+            columns: cgroup_columns(schema),
+            cgroups: Cgroups::default(),
+        }
+    }
+
+    /// Replaces the cgroup ids of the records by their paths (or containers
+    /// or pods).
+    pub fn resolve(&mut self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .map(|record| {
+                let mut values = record.to_vec();
+                for (i, kind) in &self.columns {
+                    let Some(id) = values[*i].as_i128() else {
+                        continue;
+                    };
+                    let id = id as u64;
+                    let s = match (self.cgroups.path(id), kind) {
+                        (Some(path), _) => kind.resolve(path),
+                        (None, CgroupKind::Path) => format!("cgroup {id}"),
+                        (None, _) => String::new(),
+                    };
+                    values[*i] = DataValue::String(s.clone(), s.len());
+                }
+                Record::from(values)
+            })
+            .collect()
+    }
+}
+
+/// Paths of the cgroups of the cgroup v2 hierarchy, by id (i.e. the inode of
+/// their directory in cgroupfs). Paths are relative to the hierarchy's root,
+/// e.g. `/system.slice/sshd.service`.
+#[derive(Default)]
+struct Cgroups {
+    paths: HashMap<u64, String>,
+    /// When the hierarchy was last walked
+    walked: Option<Instant>,
+}
+
+impl Cgroups {
+    /// Gets the path of the cgroup with the id, walking the hierarchy again
+    /// if it's missing (e.g. if the cgroup is new).
+    fn path(&mut self, id: u64) -> Option<&str> {
+        let stale = self
+            .walked
+            .map_or(true, |at| at.elapsed() >= REFRESH_INTERVAL);
+        if !self.paths.contains_key(&id) && stale {
+            self.walk();
+        }
+        self.paths.get(&id).map(String::as_str)
+    }
+
+    fn walk(&mut self) {
+        self.walked = Some(Instant::now());
+        self.paths.clear();
+        let root = cgroup_root();
+        let mut dirs = vec![root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(meta) = fs::metadata(&dir) else {
+                continue;
+            };
+            let path = match dir.strip_prefix(&root) {
+                Ok(rel) => format!("/{}", rel.to_string_lossy()),
+                Err(_) => continue,
+            };
+            self.paths.insert(meta.ino(), path);
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if entry.file_type().map_or(false, |t| t.is_dir()) {
+                    dirs.push(entry.path());
+                }
+            }
+        }
+    }
+}
+
+/// Gets the mount point of the cgroup v2 hierarchy from the mount table (e.g.
+/// `/sys/fs/cgroup/unified` on hybrid systems).
+fn cgroup_root() -> PathBuf {
+    let mounts = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    cgroup2_mount(&mounts).unwrap_or_else(|| Path::new(CGROUP_ROOT).to_path_buf())
+}
+
+/// Finds the first mount point of the cgroup v2 hierarchy in a mount table, as
+/// listed by `/proc/<pid>/mountinfo`.
+fn cgroup2_mount(mountinfo: &str) -> Option<PathBuf> {
+    mountinfo.lines().find_map(|line| {
+        // Optional fields are followed by a separator, then the fs type
+        let (mount, fs) = line.split_once(" - ")?;
+        let point = mount.split(' ').nth(4)?;
+        (fs.split(' ').next() == Some("cgroup2")).then(|| PathBuf::from(point))
+    })
+}
+
+/// Gets the id of the container of a cgroup, i.e. the 64 hex digits naming its
+/// innermost container cgroup, as laid out by container runtimes (e.g.
+/// `docker-<id>.scope`, `cri-containerd-<id>.scope`, `crio-<id>.scope`, or
+/// `/kubepods/<qos>/pod<uid>/<id>`).
+pub fn container_id(cgroup_path: &str) -> Option<String> {
+    cgroup_path.rsplit('/').find_map(|name| {
+        name.split(|c: char| !c.is_ascii_hexdigit())
+            .find(|s| s.len() == CONTAINER_ID_LEN)
+            .map(str::to_string)
+    })
+}
+
+/// Gets the uid of the Kubernetes pod of a cgroup, as laid out by the kubelet:
+/// `pod<uid>` with the cgroupfs driver, and `kubepods-<qos>-pod<uid>.slice`
+/// (with underscores for dashes) with the systemd driver.
+pub fn pod_uid(cgroup_path: &str) -> Option<String> {
+    cgroup_path.rsplit('/').find_map(|name| {
+        let name = name.strip_suffix(".slice").unwrap_or(name);
+        let (_, uid) = name.rsplit_once("pod")?;
+        let uid = uid.replace('_', "-");
+        let is_uid = uid.split('-').map(str::len).eq([8, 4, 4, 4, 12])
+            && uid.chars().all(|c| c == '-' || c.is_ascii_hexdigit());
+        is_uid.then_some(uid)
+    })
+}
+
+/// Set of the ids of the cgroups matching a filter (e.g. `cgroup_path LIKE
+/// '/kubepods/%'`), which programs look their cgroups up in.
+#[derive(Clone, Debug)]
+pub struct CgroupSet {
+    pub map: MapDef,
+    /// What cgroups are matched by
+    pub kind: CgroupKind,
+    /// LIKE patterns, any of which cgroups must match; exact values have
+    /// their wildcards escaped
+    pub patterns: Vec<String>,
+}
+
+impl CgroupSet {
+    /// Populates the set's map with the matching cgroups, then keeps it in
+    /// sync with the hierarchy (e.g. as pods are created) from a thread,
+    /// which stops once the returned sender is dropped.
+    pub fn watch(&self, map: MapHandle) -> Result<Sender<()>> {
+        let mut cgroups = Cgroups::default();
+        let mut ids = HashSet::new();
+        self.sync(&map, &mut cgroups, &mut ids)
+            .with_context(|| format!("failed to populate cgroup set {}", self.map.name))?;
+
+        let (stop_tx, stop_rx) = bounded::<()>(0);
+        let set = self.clone();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(REFRESH_INTERVAL) {
+                if let Err(e) = set.sync(&map, &mut cgroups, &mut ids) {
+                    log::warn!("Failed to sync cgroup set {}: {e}", set.map.name);
+                }
+            }
+        });
+        Ok(stop_tx)
+    }
+
+    /// Walks the hierarchy, then updates the map with the cgroups that have
+    /// started or stopped matching since the last sync.
+    fn sync(&self, map: &MapHandle, cgroups: &mut Cgroups, ids: &mut HashSet<u64>) -> Result<()> {
+        cgroups.walk();
+        let matching = cgroups
+            .paths
+            .iter()
+            .filter(|(_, path)| {
+                let s = self.kind.resolve(path);
+                self.patterns.iter().any(|p| like(p, &s))
+            })
+            .map(|(id, _)| *id)
+            .collect::<HashSet<_>>();
+        for id in ids.difference(&matching) {
+            // Removed cgroups may have been evicted already
+            let _ = map.delete(&id.to_ne_bytes());
+        }
+        for id in matching.difference(ids) {
+            map.update(&id.to_ne_bytes(), &[1], MapFlags::ANY)?;
+        }
+        *ids = matching;
+        Ok(())
+    }
+}
+
+/// Escapes the wildcards of a string, so that it matches itself as a LIKE
+/// pattern.
+pub fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Matches a string against a LIKE pattern, where `%` matches any substring,
+/// `_` any character, and `\` escapes the next character.
+fn like(pattern: &str, s: &str) -> bool {
+    fn matches(p: &[char], s: &[char]) -> bool {
+        match p {
+            [] => s.is_empty(),
+            ['%', rest @ ..] => (0..=s.len()).any(|i| matches(rest, &s[i..])),
+            ['_', rest @ ..] => !s.is_empty() && matches(rest, &s[1..]),
+            ['\\', c, rest @ ..] | [c, rest @ ..] => s.first() == Some(c) && matches(rest, &s[1..]),
+        }
+    }
+    let p = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    matches(&p, &s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4b8b3a2e7b6c5d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1a0b9c8d7e";
+    const POD: &str = "0f2d5a1c-3b4e-4f6a-8b9c-1d2e3f4a5b6c";
+
+    #[test]
+    fn finds_container_ids() {
+        for path in [
+            format!("/system.slice/docker-{ID}.scope"),
+            format!("/docker/{ID}"),
+            format!("/system.slice/crio-{ID}.scope"),
+            format!("/kubepods/besteffort/pod{POD}/{ID}"),
+            format!(
+                "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice/\
+                 cri-containerd-{ID}.scope",
+                POD.replace('-', "_")
+            ),
+        ] {
+            assert_eq!(container_id(&path).as_deref(), Some(ID), "{path}");
+        }
+        for path in [
+            "/",
+            "/user.slice/user-1000.slice/session-2.scope",
+            &format!("/kubepods/besteffort/pod{POD}"),
+            // Too short to be a container id
+            &format!("/docker/{}", &ID[..12]),
+        ] {
+            assert_eq!(container_id(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn finds_pod_uids() {
+        for path in [
+            format!("/kubepods/besteffort/pod{POD}/{ID}"),
+            format!("/kubepods/pod{POD}"),
+            format!(
+                "/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod{}.slice",
+                POD.replace('-', "_")
+            ),
+        ] {
+            assert_eq!(pod_uid(&path).as_deref(), Some(POD), "{path}");
+        }
+        for path in [
+            "/",
+            "/kubepods/besteffort",
+            &format!("/system.slice/docker-{ID}.scope"),
+            "/kubepods/podnot-a-uid",
+        ] {
+            assert_eq!(pod_uid(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn resolves_cgroup_kinds() {
+        let path = format!("/kubepods/besteffort/pod{POD}/{ID}");
+        assert_eq!(CgroupKind::Path.resolve(&path), path);
+        assert_eq!(CgroupKind::ContainerId.resolve(&path), ID);
+        assert_eq!(CgroupKind::PodUid.resolve(&path), POD);
+        assert_eq!(CgroupKind::PodUid.resolve("/init.scope"), "");
+        for kind in [
+            CgroupKind::Path,
+            CgroupKind::ContainerId,
+            CgroupKind::PodUid,
+        ] {
+            assert_eq!(CgroupKind::from_func(kind.func()), Some(kind));
+        }
+        assert_eq!(CgroupKind::from_func("cgroup"), None);
+    }
+
+    #[test]
+    fn matches_like_patterns() {
+        assert!(like("/kubepods/%", "/kubepods/besteffort"));
+        assert!(like("%", ""));
+        assert!(like("/docker/_bc", "/docker/abc"));
+        assert!(!like("/docker/_bc", "/docker/bc"));
+        assert!(!like("/kubepods/%", "/system.slice"));
+        // Escaped wildcards only match themselves
+        let exact = escape_like("/a_b%c\\d");
+        assert!(like(&exact, "/a_b%c\\d"));
+        assert!(!like(&exact, "/axb%c\\d"));
+        assert!(!like(&exact, "/a_bxyzc\\d"));
+    }
+
+    #[test]
+    fn finds_cgroup2_mount() {
+        let hybrid = "\
+25 30 0:23 / /sys ro,nosuid shared:7 - sysfs sysfs rw
+33 25 0:28 / /sys/fs/cgroup ro,nosuid shared:9 - tmpfs tmpfs ro,mode=755
+34 33 0:29 / /sys/fs/cgroup/unified rw,nosuid shared:10 - cgroup2 cgroup2 rw
+35 33 0:30 / /sys/fs/cgroup/systemd rw,nosuid shared:11 - cgroup cgroup rw,name=systemd
+";
+        assert_eq!(
+            cgroup2_mount(hybrid),
+            Some(PathBuf::from("/sys/fs/cgroup/unified"))
+        );
+        // Without optional fields
+        let unified = "29 23 0:26 / /sys/fs/cgroup rw,nosuid - cgroup2 cgroup2 rw\n";
+        assert_eq!(
+            cgroup2_mount(unified),
+            Some(PathBuf::from("/sys/fs/cgroup"))
+        );
+        assert_eq!(cgroup2_mount(&hybrid.replace("cgroup2", "cgroup")), None);
+    }
+}
@@ -2,9 +2,10 @@
 
 use std::{str::FromStr, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use super::Field;
+use crate::error::EbqlError;
 
 /// Available program types.
 pub mod program_types;
@@ -15,11 +16,24 @@ pub mod tracepoints;
 /// System information.
 pub mod system;
 
+/// Sampling of event rates.
+pub mod rate;
+
+/// Perf events representation.
+pub mod perf;
+
+use perf::*;
 use program_types::*;
 use tracepoints::*;
 
-/// Event trait.
-pub trait Event {
+/// Separates the name of an event from its argument in table names, e.g.
+/// `perf/cpu_clock__99` for `perf/cpu_clock(99)`, since the SQL parser doesn't
+/// accept parentheses in them (see [`crate::parser`]).
+pub const EVENT_ARG_SEP: &str = "__";
+
+/// Event trait. Events are shared with the threads that execute queries over
+/// them.
+pub trait Event: Send + Sync {
     /// Gets the event program type.
     fn program_type(&self) -> ProgramType;
 
@@ -40,6 +54,11 @@ pub trait Event {
 
     /// Gets the context name at the event.
     fn ctx(&self) -> String;
+
+    /// Gets the ELF section of programs attached to the event.
+    fn section(&self) -> String {
+        format!("{}/{}", self.program_type().section_name(), self.name())
+    }
 }
 
 /// Gets the event associated with a name.
@@ -48,6 +67,9 @@ pub fn get_event<S: AsRef<str>>(event: S) -> Option<Arc<dyn Event>> {
     if let Ok(tp) = TracepointEvent::from_str(event.as_ref()) {
         return Some(Arc::new(tp));
     }
+    if let Ok(pe) = PerfEvent::from_str(event.as_ref()) {
+        return Some(Arc::new(pe));
+    }
 
     // TODO: implement other event types; will probably need an as_any trait
     // impl to allow additional contexts e.g. from kprobes
@@ -55,11 +77,11 @@ pub fn get_event<S: AsRef<str>>(event: S) -> Option<Arc<dyn Event>> {
 }
 
 /// Gets the field associated with an event + name, if it exists.
-pub fn get_event_field<S: AsRef<str>>(e: &Arc<dyn Event>, field: S) -> Option<Field> {
+pub fn get_event_field<S: AsRef<str>>(e: &Arc<dyn Event>, field: S) -> Result<Option<Field>> {
     let t = e.program_type();
-    match t {
+    let f = match t {
         ProgramType::Tracepoint => {
-            let te = TracepointEvent::from_str(&e.name()).unwrap();
+            let te = TracepointEvent::from_str(&e.name())?;
             let e = TP_ARGS.get(&te);
             match e {
                 Some(fields) => {
@@ -73,15 +95,14 @@ pub fn get_event_field<S: AsRef<str>>(e: &Arc<dyn Event>, field: S) -> Option<Fi
                 None => None,
             }
         }
-        ProgramType::RawTracepoint => todo!(),
-        ProgramType::Usdt => todo!(),
-        ProgramType::Kprobe => todo!(),
-        ProgramType::Kretprobe => todo!(),
-        ProgramType::Uprobe => todo!(),
-        ProgramType::Uretprobe => todo!(),
-        ProgramType::Iter => todo!(),
-        ProgramType::Xdp => todo!(),
-        ProgramType::Tc => todo!(),
-        ProgramType::Lsm => todo!(),
-    }
+        // Perf events only have system variables
+        ProgramType::PerfEvent => None,
+        _ => {
+            bail!(EbqlError::unsupported(
+                e.name(),
+                format!("{} events are not supported", t.section_name())
+            ))
+        }
+    };
+    Ok(f)
 }
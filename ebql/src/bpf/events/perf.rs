@@ -0,0 +1,167 @@
+//! Perf event representation, i.e. software events sampled at a frequency on
+//! every CPU (e.g. `perf/cpu_clock(99)`).
+
+use std::{fmt::Display, mem, os::fd::OwnedFd, str::FromStr};
+
+use anyhow::{bail, Result};
+use strum::IntoEnumIterator;
+
+use super::{
+    super::Field,
+    rate::{open_counter, PerfEventAttr},
+    system::SystemVar,
+    Event, ProgramType, EVENT_ARG_SEP,
+};
+use crate::error::EbqlError;
+
+/// Sampling frequency (in Hz) of perf events named without one; off the round
+/// numbers, so that samples don't line up with periodic work.
+pub const DEFAULT_SAMPLE_FREQ: u64 = 99;
+
+/// perf_event_open(2) event type of software events, whose config is the
+/// counter.
+const PERF_TYPE_SOFTWARE: u32 = 1;
+/// Flag of `struct perf_event_attr` making its sample period a frequency.
+const PERF_ATTR_FLAG_FREQ: u64 = 1 << 10;
+
+/// Software counters that can be sampled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PerfCounter {
+    /// Wall time on each CPU, whether or not it's idle
+    CpuClock,
+    /// Time spent running tasks on each CPU
+    TaskClock,
+}
+
+impl PerfCounter {
+    fn name(&self) -> &str {
+        match self {
+            PerfCounter::CpuClock => "cpu_clock",
+            PerfCounter::TaskClock => "task_clock",
+        }
+    }
+
+    /// Gets the counter's config (i.e. PERF_COUNT_SW_*).
+    fn config(&self) -> u64 {
+        match self {
+            PerfCounter::CpuClock => 0,
+            PerfCounter::TaskClock => 1,
+        }
+    }
+}
+
+/// A software counter, sampled at a frequency on every CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PerfEvent {
+    pub counter: PerfCounter,
+    /// Samples per second on each CPU
+    pub freq: u64,
+}
+
+impl PerfEvent {
+    /// Opens the event on every online CPU, which starts sampling once a
+    /// program is attached to it.
+    pub fn open(&self) -> Result<Vec<OwnedFd>> {
+        let attr = PerfEventAttr {
+            type_: PERF_TYPE_SOFTWARE,
+            size: mem::size_of::<PerfEventAttr>() as u32,
+            config: self.counter.config(),
+            sample_period: self.freq,
+            flags: PERF_ATTR_FLAG_FREQ,
+            ..Default::default()
+        };
+        // Offline CPUs can't be opened, and are skipped
+        let n_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as i32;
+        let mut err = None;
+        let fds = (0..n_cpus)
+            .filter_map(|cpu| open_counter(&attr, cpu).map_err(|e| err = Some(e)).ok())
+            .collect::<Vec<_>>();
+        if fds.is_empty() {
+            let err = err.map_or(String::from("no CPUs"), |e| e.to_string());
+            bail!("failed to open perf event {self}: {err}");
+        }
+        Ok(fds)
+    }
+}
+
+impl Event for PerfEvent {
+    fn program_type(&self) -> ProgramType {
+        ProgramType::PerfEvent
+    }
+
+    fn name(&self) -> String {
+        self.to_string()
+    }
+
+    fn id(&self) -> u64 {
+        self.counter.config()
+    }
+
+    fn get_all_args(&self) -> Result<Vec<Field>> {
+        Ok(SystemVar::iter()
+            .filter(|sv| !sv.is_stack())
+            .map(|sv| sv.to_field())
+            .collect())
+    }
+
+    fn get_arg(&self, arg: &str) -> Result<Field> {
+        SystemVar::get_field(arg)
+    }
+
+    fn get_args(&self, args: &[&str]) -> Result<Vec<Field>> {
+        args.iter().map(|arg| SystemVar::get_field(arg)).collect()
+    }
+
+    fn ctx(&self) -> String {
+        "struct bpf_perf_event_data".into()
+    }
+
+    /// Perf event programs aren't attached by section, but to the events
+    /// opened by user space.
+    fn section(&self) -> String {
+        ProgramType::PerfEvent.section_name().into()
+    }
+}
+
+impl Display for PerfEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "perf/{}({})", self.counter.name(), self.freq)
+    }
+}
+
+impl FromStr for PerfEvent {
+    type Err = anyhow::Error;
+
+    /// Parses `perf/<counter>[(<freq>)]`, also accepting the frequency after
+    /// [`EVENT_ARG_SEP`].
+    fn from_str(s: &str) -> Result<PerfEvent> {
+        let Some(name) = s.strip_prefix("perf/") else {
+            bail!("Perf event {s} not found");
+        };
+        let (name, freq) = match name.split_once(EVENT_ARG_SEP) {
+            Some((name, freq)) => (name, Some(freq)),
+            None => {
+                match name.strip_suffix(')').and_then(|n| n.split_once('(')) {
+                    Some((name, freq)) => (name, Some(freq)),
+                    None => (name, None),
+                }
+            }
+        };
+        let counter = match name {
+            "cpu_clock" => PerfCounter::CpuClock,
+            "task_clock" => PerfCounter::TaskClock,
+            _ => bail!("Perf event {s} not found"),
+        };
+        let freq = match freq.map(|f| f.trim().parse::<u64>()) {
+            None => DEFAULT_SAMPLE_FREQ,
+            Some(Ok(freq)) if freq > 0 => freq,
+            Some(_) => {
+                bail!(EbqlError::bind(
+                    s,
+                    "perf events are sampled at a positive frequency (in Hz)"
+                ))
+            }
+        };
+        Ok(PerfEvent { counter, freq })
+    }
+}
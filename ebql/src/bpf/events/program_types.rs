@@ -12,6 +12,7 @@ pub enum ProgramType {
     Xdp,
     Tc,
     Lsm,
+    PerfEvent,
 }
 
 impl ProgramType {
@@ -30,6 +31,7 @@ impl ProgramType {
             ProgramType::Xdp => "xdp",
             ProgramType::Tc => "tc",
             ProgramType::Lsm => "lsm",
+            ProgramType::PerfEvent => "perf_event",
         }
     }
 }
@@ -0,0 +1,162 @@
+use std::{
+    fs, io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::{program_types::ProgramType, Event};
+use crate::error::EbqlError;
+
+/// perf_event_open(2) event type of tracepoints, whose config is the
+/// tracepoint's id.
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+
+/// Directories that tracefs events may be mounted under, in order of
+/// preference.
+const TRACEFS_EVENTS: [&str; 2] = [
+    "/sys/kernel/tracing/events",
+    "/sys/kernel/debug/tracing/events",
+];
+
+/// First published layout of `struct perf_event_attr` (PERF_ATTR_SIZE_VER0),
+/// which is all counting requires; the kernel treats later fields as zeroed.
+#[repr(C)]
+#[derive(Default)]
+pub(super) struct PerfEventAttr {
+    pub type_: u32,
+    pub size: u32,
+    pub config: u64,
+    /// Or, with the freq flag, the sampling frequency
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+    pub wakeup_events: u32,
+    pub bp_type: u32,
+    pub config1: u64,
+}
+
+/// Samples the rate (per second, across all CPUs) at which an event fires, by
+/// counting its occurrences over the period. Only tracepoints can be sampled.
+pub fn sample_rate(e: &dyn Event, period: Duration) -> Result<f64> {
+    if !matches!(e.program_type(), ProgramType::Tracepoint) {
+        bail!(EbqlError::unsupported(
+            e.name(),
+            "only tracepoint rates can be sampled"
+        ));
+    }
+    let attr = PerfEventAttr {
+        type_: PERF_TYPE_TRACEPOINT,
+        size: mem::size_of::<PerfEventAttr>() as u32,
+        config: tracepoint_id(&TRACEFS_EVENTS, &e.name())?,
+        ..Default::default()
+    };
+
+    // Tracepoints are counted per CPU; offline CPUs can't be opened, and are
+    // skipped
+    let n_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as i32;
+    let mut err = None;
+    let counters = (0..n_cpus)
+        .filter_map(|cpu| open_counter(&attr, cpu).map_err(|e| err = Some(e)).ok())
+        .collect::<Vec<_>>();
+    if counters.is_empty() {
+        let err = err.map_or(String::from("no CPUs"), |e| e.to_string());
+        bail!("failed to count events of {}: {err}", e.name());
+    }
+
+    thread::sleep(period);
+    let mut total = 0;
+    for fd in &counters {
+        total += read_counter(fd)?;
+    }
+    Ok(total as f64 / period.as_secs_f64())
+}
+
+/// Reads the id of a tracepoint (named `<category>/<name>`) from the first of
+/// the tracefs event directories that has it, since ids differ across kernels.
+fn tracepoint_id<P: AsRef<Path>>(roots: &[P], name: &str) -> Result<u64> {
+    let Some(path) = roots
+        .iter()
+        .map(|root| root.as_ref().join(name).join("id"))
+        .find(|path| path.exists())
+    else {
+        bail!(
+            "failed to find the id of tracepoint {name} in tracefs (is it mounted under {}?)",
+            TRACEFS_EVENTS[0]
+        );
+    };
+    let id =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    id.trim()
+        .parse()
+        .with_context(|| format!("invalid tracepoint id in {}: {id:?}", path.display()))
+}
+
+/// Opens a counter of the event on the CPU, which starts counting immediately.
+pub(super) fn open_counter(attr: &PerfEventAttr, cpu: i32) -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            -1 as libc::pid_t,
+            cpu,
+            -1 as libc::c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Reads the current value of a counter.
+fn read_counter(fd: &OwnedFd) -> Result<u64> {
+    let mut count = 0u64;
+    let n = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            &mut count as *mut u64 as *mut libc::c_void,
+            mem::size_of::<u64>(),
+        )
+    };
+    if n != mem::size_of::<u64>() as isize {
+        bail!(
+            "failed to read event counter: {}",
+            io::Error::last_os_error()
+        );
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn reads_tracepoint_ids_from_first_root_with_them() {
+        let root = env::temp_dir().join(format!("ebql_rate_test_{}", std::process::id()));
+        let (tracing, debug) = (root.join("tracing"), root.join("debug"));
+        fs::create_dir_all(debug.join("syscalls/sys_enter_pread64")).unwrap();
+        fs::write(debug.join("syscalls/sys_enter_pread64/id"), "697\n").unwrap();
+        fs::create_dir_all(debug.join("syscalls/sys_exit_pread64")).unwrap();
+        fs::write(debug.join("syscalls/sys_exit_pread64/id"), "garbage\n").unwrap();
+
+        let roots = [&tracing, &debug];
+        let id = tracepoint_id(&roots, "syscalls/sys_enter_pread64");
+        let bad = tracepoint_id(&roots, "syscalls/sys_exit_pread64");
+        let missing = tracepoint_id(&roots, "syscalls/sys_enter_read");
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(id.unwrap(), 697);
+        assert!(bad.is_err());
+        assert!(missing.is_err());
+    }
+}
@@ -3,7 +3,10 @@ use std::{fmt::Display, str::FromStr};
 use anyhow::{bail, Result};
 use strum::EnumIter;
 
-use crate::bpf::{Field, Type};
+use crate::{
+    bpf::{Field, Type},
+    error::EbqlError,
+};
 
 const TASK_COMM_LEN: usize = 16;
 
@@ -16,6 +19,9 @@ pub enum SystemVar {
     CPU,
     COMM,
     CGROUP,
+    /// Kernel and user stacks, by their ids in the program's stack trace map
+    KSTACK,
+    USTACK,
 }
 
 impl SystemVar {
@@ -69,6 +75,22 @@ impl SystemVar {
                     _off: None,
                 }
             }
+            SystemVar::KSTACK => {
+                Field {
+                    _name: String::from("kstack"),
+                    _type: Type::U64,
+                    _arr: None,
+                    _off: None,
+                }
+            }
+            SystemVar::USTACK => {
+                Field {
+                    _name: String::from("ustack"),
+                    _type: Type::U64,
+                    _arr: None,
+                    _off: None,
+                }
+            }
         }
     }
 
@@ -80,10 +102,18 @@ impl SystemVar {
             "cpu" => Ok(SystemVar::CPU.to_field()),
             "comm" => Ok(SystemVar::COMM.to_field()),
             "cgroup" => Ok(SystemVar::CGROUP.to_field()),
-            _ => bail!("System var {sv} does not exist"),
+            "kstack" => Ok(SystemVar::KSTACK.to_field()),
+            "ustack" => Ok(SystemVar::USTACK.to_field()),
+            _ => bail!(EbqlError::bind(sv, "no such field or system variable")),
         }
     }
 
+    /// Returns whether the variable is a stack, which programs read from
+    /// their stack trace map.
+    pub fn is_stack(&self) -> bool {
+        matches!(self, SystemVar::KSTACK | SystemVar::USTACK)
+    }
+
     pub fn get_helper(&self) -> &str {
         match self {
             SystemVar::TIME => "TIME",
@@ -92,6 +122,8 @@ impl SystemVar {
             SystemVar::CPU => "CPU",
             SystemVar::COMM => "COMM",
             SystemVar::CGROUP => "CGROUP",
+            SystemVar::KSTACK => "KSTACK",
+            SystemVar::USTACK => "USTACK",
         }
     }
 }
@@ -108,6 +140,8 @@ impl FromStr for SystemVar {
             "cpu" => Ok(SystemVar::CPU),
             "comm" => Ok(SystemVar::COMM),
             "cgroup" => Ok(SystemVar::CGROUP),
+            "kstack" => Ok(SystemVar::KSTACK),
+            "ustack" => Ok(SystemVar::USTACK),
             _ => bail!("System var {input} does not exist"),
         }
     }
@@ -122,6 +156,8 @@ impl Display for SystemVar {
             SystemVar::CPU => write!(f, "cpu"),
             SystemVar::COMM => write!(f, "comm"),
             SystemVar::CGROUP => write!(f, "cgroup"),
+            SystemVar::KSTACK => write!(f, "kstack"),
+            SystemVar::USTACK => write!(f, "ustack"),
         }
     }
 }
@@ -61,8 +61,12 @@ impl Event for TracepointEvent {
             )));
         }
         let mut all_args = tp_args.unwrap().values().cloned().collect::<Vec<_>>();
-        // Add all system variables
-        all_args.extend(SystemVar::iter().map(|sv| sv.to_field()));
+        // Add all system variables; stacks are only collected when selected
+        all_args.extend(
+            SystemVar::iter()
+                .filter(|sv| !sv.is_stack())
+                .map(|sv| sv.to_field()),
+        );
         Ok(all_args)
     }
 
@@ -2,7 +2,7 @@
 
 use std::{fmt::Display, path::PathBuf};
 
-use super::{Struct, Type};
+use super::{percpu::PercpuAggs, Struct, Type};
 
 /// Ring buffer definition.
 #[derive(Clone, Debug)]
@@ -10,6 +10,9 @@ pub struct RingBuf {
     pub name: String,
     pub s_repr: Struct,
     pub max_entries: u64,
+    /// If set, records only mark the end of windows, whose rows are merged
+    /// from per-CPU aggregation maps
+    pub percpu: Option<PercpuAggs>,
     // TODO: add pinning, flags
 }
 
@@ -36,6 +39,7 @@ pub struct MapDef {
 pub enum MapType {
     Hash = 1,
     Array = 2,
+    StackTrace = 7,
     RingBuffer = 27,
 }
 
@@ -45,6 +49,7 @@ impl MapType {
             MapType::RingBuffer => libbpf_rs::MapType::RingBuf,
             MapType::Hash => libbpf_rs::MapType::Hash,
             MapType::Array => libbpf_rs::MapType::Array,
+            MapType::StackTrace => libbpf_rs::MapType::StackTrace,
         }
     }
 }
@@ -54,6 +59,7 @@ impl Display for MapType {
             MapType::RingBuffer => write!(f, "BPF_MAP_TYPE_RINGBUF"),
             MapType::Hash => write!(f, "BPF_MAP_TYPE_HASH"),
             MapType::Array => write!(f, "BPF_MAP_TYPE_ARRAY"),
+            MapType::StackTrace => write!(f, "BPF_MAP_TYPE_STACK_TRACE"),
         }
     }
 }
@@ -2,6 +2,8 @@
 
 /// BPF struct representation.
 pub mod bpf_struct;
+/// Resolution of cgroup ids into paths, containers and pods.
+pub mod cgroups;
 /// Kernel eBPF events.
 pub mod events;
 /// Representation of BPF maps, to provide easier interfacing.
@@ -13,10 +15,20 @@ pub mod object;
 /// Generic eBPF program builder. Contains helper methods for cleaner eBPF
 /// program synthesis.
 pub mod prog_builder;
+/// Per-CPU aggregation maps, merged in user space.
+pub mod percpu;
+/// Resolution of file descriptors and inodes into paths.
+pub mod paths;
 /// Representation of BPF program.
 pub mod program;
+/// Stack trace maps, and the resolution of their stacks.
+pub mod stack_map;
+/// Symbolization of kernel and user addresses.
+pub mod symbols;
 /// BPF data types and field representations.
 pub mod types;
+/// Per-compilation work directories.
+pub mod work_dir;
 
 use bpf_struct::*;
 use map::*;
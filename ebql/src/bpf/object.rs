@@ -1,13 +1,53 @@
 //! BPF Program representation.
 
-use std::{collections::HashMap, path::PathBuf, process::Command, thread, time::Duration};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    mem,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, OwnedFd},
+    path::PathBuf,
+    process::Command,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-use anyhow::{bail, Context, Result};
-use crossbeam::channel::{unbounded, Receiver};
-use libbpf_rs::{ObjectBuilder, RingBufferBuilder};
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use libbpf_rs::{Link, Map, MapFlags, MapHandle, ObjectBuilder, RingBufferBuilder};
 
-use super::MapDef;
-use crate::{prog_builder::BuildResult, program::Program, record_batch::RecordBatch};
+use super::{MapDef, Struct};
+use crate::{
+    cgroups::{cgroup_columns, CgroupResolver, CgroupSet},
+    error::EbqlError,
+    paths::{path_columns, PathResolver},
+    percpu::PercpuMerger,
+    prog_builder::BuildResult,
+    program::{FlushProgram, Poller, Program},
+    record::DataValue,
+    record_batch::RecordBatch,
+    stack_map::{stack_columns, stack_map_name, StackResolver, STACKS_SECTION},
+    symbols::{symbol_columns, SymbolResolver},
+    work_dir::WorkDir,
+};
+
+/// Data section holding query parameters. libbpf exposes custom data sections
+/// as mmapable array maps named after the section.
+pub const PARAMS_SECTION: &str = ".data.params";
+/// Name of the struct (and the global variable) holding query parameters.
+pub const PARAMS_STRUCT: &str = "params_t";
+pub const PARAMS_VAR: &str = "params";
+
+/// Data section holding the counts of events and groups that overflowed a
+/// program's aggregations (see `struct agg_overflow` in `agg.bpf.h.tmpl`).
+pub const OVERFLOW_SECTION: &str = ".data.overflow";
+
+/// Interval at which ring buffer polling threads check whether to stop.
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
 
 /// Handle over a BPF Object (which can itself contain multiple BPF programs).
 /// TODO: later, if necessary, expose interface for pinning maps.
@@ -20,6 +60,65 @@ pub struct Object {
     pub maps: HashMap<String, MapDef>,
     /// Libbpf object of loaded BPF object (with all info across all programs)
     obj: libbpf_rs::Object,
+    /// Mapping of the parameters section, once a parameter has been set
+    params: Option<ParamsMmap>,
+    /// Threads keeping the object's cgroup sets in sync, which stop once
+    /// their senders are dropped with the object
+    cgroup_watchers: Vec<Sender<()>>,
+    /// Directory the object was compiled in, removed along with the object
+    work_dir: Option<WorkDir>,
+}
+
+/// Counts of the events and groups that didn't get their own group in a
+/// program's aggregations, since its group maps were full.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OverflowStats {
+    /// Counts in the open window
+    pub dropped_events: u64,
+    pub dropped_groups: u64,
+    /// Counts over the program's lifetime
+    pub total_dropped_events: u64,
+    pub total_dropped_groups: u64,
+}
+
+impl OverflowStats {
+    /// Reads the counts from the overflow section.
+    fn read(section: &MapHandle) -> Result<Self> {
+        let buf = section
+            .lookup(&0u32.to_ne_bytes(), MapFlags::ANY)?
+            .ok_or_else(|| anyhow!("overflow section is empty"))?;
+        let field = |i: usize| {
+            buf.get(i * 8..(i + 1) * 8)
+                .and_then(|b| b.try_into().ok())
+                .map_or(0, u64::from_ne_bytes)
+        };
+        // Fields of struct agg_overflow: window, dropped_events, dropped_groups,
+        // groups, total_dropped_events, total_dropped_groups
+        Ok(Self {
+            dropped_events: field(1),
+            dropped_groups: field(2),
+            total_dropped_events: field(4),
+            total_dropped_groups: field(5),
+        })
+    }
+}
+
+/// Writable mapping of a program's parameters section.
+struct ParamsMmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only written through &mut Object.
+unsafe impl Send for ParamsMmap {}
+unsafe impl Sync for ParamsMmap {}
+
+impl Drop for ParamsMmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
 }
 
 impl Object {
@@ -35,7 +134,10 @@ impl Object {
             bail!("Must call program with at least one object");
         }
         // Get final object path
-        let dir = objs[0].obj_path.parent().unwrap();
+        let dir = objs[0]
+            .obj_path
+            .parent()
+            .context("object file has no parent directory")?;
         let mut dst_path = PathBuf::from(dir);
         dst_path.push(format!("{}.bpf.o", name.as_ref()));
 
@@ -44,13 +146,13 @@ impl Object {
             // Link together into one bpf object
             let bpftool = get_bpftool_path()?;
             // Create command arguments
-            let args = vec!["gen", "object"]
+            let args = vec![OsStr::new("gen"), OsStr::new("object")]
                 .into_iter()
                 // Add final target
-                .chain(std::iter::once(dst_path.to_str().unwrap()))
+                .chain(std::iter::once(dst_path.as_os_str()))
                 .chain(
                     // Convert objects into their file names
-                    objs.iter().map(|br| br.obj_path.to_str().unwrap()),
+                    objs.iter().map(|br| br.obj_path.as_os_str()),
                 )
                 .collect::<Vec<_>>();
             // Link into one file
@@ -79,8 +181,10 @@ impl Object {
             }
         }
 
-        // Load into system
-        let obj = open_obj.load().unwrap();
+        // Load into system; this is where the verifier checks the programs
+        let obj = open_obj
+            .load()
+            .map_err(|e| EbqlError::verifier(name.as_ref(), e.to_string()))?;
 
         // Construct programs for each individual bpf program
         let progs = objs
@@ -89,28 +193,38 @@ impl Object {
                 // Get program associated with this build result
                 (
                     br.name.clone(),
-                    Program::new(br.structs.clone(), br.globals.clone(), br.ringbuf.clone()),
+                    Program::new(
+                        br.structs.clone(),
+                        br.globals.clone(),
+                        br.ringbuf.clone(),
+                        br.flush.clone(),
+                        br.stop.clone(),
+                        br.fail_on_overflow,
+                        br.perf,
+                    ),
                 )
             })
             .collect();
         // Consolidate maps across programs
-        let maps = objs
-            .iter()
-            .map(|br| br.maps.clone())
-            .reduce(|mut acc_maps, maps| {
-                acc_maps.extend(maps);
-                acc_maps
-            })
-            .unwrap();
+        let maps = objs.iter().flat_map(|br| br.maps.clone()).collect();
 
         Ok(Self {
             obj,
             path: dst_path,
             progs,
             maps,
+            params: None,
+            cgroup_watchers: Vec::new(),
+            work_dir: None,
         })
     }
 
+    /// Ties the directory the object was compiled in to the object's lifetime.
+    pub fn with_work_dir(mut self, work_dir: WorkDir) -> Self {
+        self.work_dir = Some(work_dir);
+        self
+    }
+
     /// Attaches all programs to the kernel.
     pub fn attach_progs(&mut self) -> Result<()> {
         let progs = self.progs.keys().cloned().into_iter().collect::<Vec<_>>();
@@ -122,25 +236,80 @@ impl Object {
 
     /// Attaches program with specified name to the kernel.
     pub fn attach_prog(&mut self, name: String) -> Result<()> {
-        let prog = self.obj.prog_mut(&name).unwrap();
-
-        // TODO: add extra metadata somewhere along the line for
-        // uprobes/kprobes/perf events, and handle here
-        use libbpf_rs::ProgramType::*;
-        let link = match prog.prog_type() {
-            Tracepoint | RawTracepoint => prog.attach()?,
-            _ => unimplemented!("logic for other events"),
-        };
+        let links = self.attach_links(&name)?;
 
         // Get program handle for this program
-        let prog = self.progs.get_mut(&name).unwrap();
+        let prog = match self.progs.get_mut(&name) {
+            Some(prog) => prog,
+            None => bail!(EbqlError::attach(&name, "program has no build information")),
+        };
         // After attaching, build channel and ring buffer handler
         let (tx, rx) = unbounded();
         let mut rb = RingBufferBuilder::new();
         let rb_repr = prog.ring_buffer.clone();
+        let prog_name = name.clone();
+        let (stop, partial) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let cb_partial = partial.clone();
+        // Output ends with an error once the program drops events, if it fails
+        // on overflow
+        let overflow = match prog.fail_on_overflow {
+            true => Some(MapHandle::try_from(overflow_section(&self.obj)?)?),
+            false => None,
+        };
+        let mut tx = Some(tx);
+        // Stacks are read from the program's stack trace maps
+        let mut stacks = match stack_columns(&rb_repr.s_repr.schema).is_empty() {
+            true => None,
+            false => {
+                let map = |epoch| -> Result<MapHandle> {
+                    let map = self
+                        .obj
+                        .map(stack_map_name(&name, epoch))
+                        .with_context(|| {
+                            format!("stack trace map {epoch} of {name} does not exist")
+                        })?;
+                    Ok(MapHandle::try_from(map)?)
+                };
+                Some(StackResolver::new(
+                    [map(0)?, map(1)?],
+                    MapHandle::try_from(stacks_section(&self.obj)?)?,
+                    &rb_repr.s_repr.schema,
+                ))
+            }
+        };
+        // Addresses are symbolized, and fds, inodes and cgroups resolved into
+        // paths, in user space
+        let mut symbols = match symbol_columns(&rb_repr.s_repr.schema).is_empty() {
+            true => None,
+            false => Some(SymbolResolver::new(&rb_repr.s_repr.schema)),
+        };
+        let mut paths = match path_columns(&rb_repr.s_repr.schema).is_empty() {
+            true => None,
+            false => Some(PathResolver::new(&rb_repr.s_repr.schema)),
+        };
+        let mut cgroups = match cgroup_columns(&rb_repr.s_repr.schema).is_empty() {
+            true => None,
+            false => Some(CgroupResolver::new(&rb_repr.s_repr.schema)),
+        };
+        // Per-CPU aggregations are merged from their maps at the end of each window
+        let mut merger = match &rb_repr.percpu {
+            Some(aggs) => {
+                Some(PercpuMerger::new(
+                    aggs.clone(),
+                    rb_repr.s_repr.clone(),
+                    &self.obj,
+                )?)
+            }
+            None => None,
+        };
         rb.add(
             // TODO: migrate this into RingBuf struct
-            self.obj.map(&prog.ring_buffer.name).unwrap(),
+            self.obj
+                .map(&prog.ring_buffer.name)
+                .with_context(|| format!("ring buffer {} does not exist", prog.ring_buffer.name))?,
             move |buf: &[u8]| -> i32 {
                 // Error if buffer is not some multiple of struct size
                 if buf.len() % rb_repr.s_repr.sz != 0 {
@@ -152,52 +321,467 @@ impl Object {
                     return 0;
                 }
 
-                // Iterate over each byte chunk, converting into a record
-                let records = buf
-                    .chunks(rb_repr.s_repr.sz)
-                    .into_iter()
-                    .map(|buf| rb_repr.s_repr.produce_record(buf))
-                    .collect::<Result<Vec<_>>>();
-                if !records.is_ok() {
-                    log::error!(
-                        "Failed to parse bytes into record batch of struct {}",
-                        rb_repr.s_repr.name
-                    );
+                // Iterate over each byte chunk, converting into a record (or,
+                // for window markers, into the window's merged rows)
+                let records = match merger.as_mut() {
+                    Some(merger) => {
+                        buf.chunks(rb_repr.s_repr.sz)
+                            .map(|marker| merger.merge(marker))
+                            .collect::<Result<Vec<_>>>()
+                            .map(|rows| rows.into_iter().flatten().collect())
+                    }
+                    None => {
+                        buf.chunks(rb_repr.s_repr.sz)
+                            .into_iter()
+                            .map(|buf| rb_repr.s_repr.produce_record(buf))
+                            .collect::<Result<Vec<_>>>()
+                    }
+                };
+                let records = match (records, stacks.as_mut()) {
+                    (Ok(records), Some(stacks)) => stacks.resolve(records),
+                    (Ok(records), None) => records,
+                    (Err(_), _) => {
+                        log::error!(
+                            "Failed to parse bytes into record batch of struct {}",
+                            rb_repr.s_repr.name
+                        );
+                        return 0;
+                    }
+                };
+                let records = match symbols.as_mut() {
+                    Some(symbols) => symbols.resolve(records),
+                    None => records,
+                };
+                let records = match paths.as_mut() {
+                    Some(paths) => paths.resolve(records),
+                    None => records,
+                };
+                let records = match cgroups.as_mut() {
+                    Some(cgroups) => cgroups.resolve(records),
+                    None => records,
+                };
+
+                let Some(sender) = &tx else {
                     return 0;
+                };
+                if let Some(section) = &overflow {
+                    let dropped =
+                        OverflowStats::read(section).map_or(0, |s| s.total_dropped_events);
+                    if dropped > 0 {
+                        let error = format!(
+                            "program {prog_name} dropped {dropped} events on overflowing groups or \
+                             stacks"
+                        );
+                        log::error!("{error}; stopping its output");
+                        let rb = RecordBatch::new(rb_repr.s_repr.schema.clone(), vec![])
+                            .with_error(error);
+                        if let Err(err) = sender.send(rb) {
+                            log::warn!("Failed to send to program {prog_name}'s channel: {err}");
+                        }
+                        tx = None;
+                        return 0;
+                    }
                 }
 
-                let rb = RecordBatch::new(rb_repr.s_repr.schema.clone(), records.unwrap());
+                let rb = RecordBatch::new(rb_repr.s_repr.schema.clone(), records)
+                    .with_partial(cb_partial.load(Ordering::Acquire));
 
-                if let Err(err) = tx.send(rb) {
-                    log::warn!("Failed to send to program {}'s channel: {}", name, err);
+                if let Err(err) = sender.send(rb) {
+                    log::warn!("Failed to send to program {}'s channel: {}", prog_name, err);
                 }
                 return 0;
             },
         )?;
         let rb = rb.build()?;
-        // Indefinitely poll ringbuffer
-        thread::spawn({
-            let rb = rb;
-            move || while rb.poll(Duration::MAX).is_ok() {}
+        // Poll ringbuffer until the program is stopped
+        let handle = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Acquire) && rb.poll(POLL_TIMEOUT).is_ok() {}
+                rb
+            }
         });
 
         // Add to program info
-        prog.add_attach_info(link, rx);
+        let poller = Poller {
+            stop,
+            partial,
+            handle,
+        };
+        prog.add_attach_info(links, rx, poller);
+
+        // Start flushing windows on schedule
+        if let Some(flush) = prog.flush.clone() {
+            let ticker = self.start_flush(&flush)?;
+            if let Some(prog) = self.progs.get_mut(&name) {
+                prog.ticker = ticker;
+            }
+        }
+        Ok(())
+    }
+
+    /// Attaches the program with the specified name to its event, returning
+    /// its links.
+    fn attach_links(&mut self, name: &str) -> Result<Vec<Link>> {
+        let prog = match self.obj.prog_mut(name) {
+            Some(prog) => prog,
+            None => bail!(EbqlError::attach(name, "program does not exist in object")),
+        };
+        // TODO: add extra metadata somewhere along the line for
+        // uprobes/kprobes, and handle here
+        use libbpf_rs::ProgramType::*;
+        let links = match prog.prog_type() {
+            Tracepoint | RawTracepoint => {
+                vec![prog
+                    .attach()
+                    .map_err(|e| EbqlError::attach(name, e.to_string()))?]
+            }
+            // Perf events are sampled on every CPU, each with its own link
+            PerfEvent => {
+                let perf = self
+                    .progs
+                    .get(name)
+                    .and_then(|p| p.perf)
+                    .ok_or_else(|| EbqlError::attach(name, "program has no perf event"))?;
+                let fds = perf
+                    .open()
+                    .map_err(|e| EbqlError::attach(name, e.to_string()))?;
+                let mut links = Vec::with_capacity(fds.len());
+                for fd in fds {
+                    let link = prog
+                        .attach_perf_event(fd.as_raw_fd())
+                        .map_err(|e| EbqlError::attach(name, e.to_string()))?;
+                    // The link closes the event once destroyed
+                    let _ = fd.into_raw_fd();
+                    links.push(link);
+                }
+                links
+            }
+            _ => {
+                bail!(EbqlError::unsupported(
+                    name,
+                    "only tracepoint and perf event programs can be attached"
+                ))
+            }
+        };
+        Ok(links)
+    }
+
+    /// Pauses the program with the specified name by detaching it. Its maps
+    /// (and output stream) are kept, so that it resumes from its state once
+    /// attached again; its windows keep being flushed on schedule (and are
+    /// buffered for paused readers by the executor).
+    pub fn pause_prog<S: AsRef<str>>(&mut self, name: S) -> Result<()> {
+        let name = name.as_ref();
+        let prog = self
+            .progs
+            .get_mut(name)
+            .with_context(|| format!("program {name} does not exist"))?;
+        if prog.poller.is_none() {
+            bail!("program {name} is not attached");
+        }
+        prog.links.clear();
+        Ok(())
+    }
+
+    /// Resumes a paused program, by attaching it to its event again.
+    pub fn resume_prog<S: AsRef<str>>(&mut self, name: S) -> Result<()> {
+        let name = name.as_ref();
+        let prog = self
+            .progs
+            .get(name)
+            .with_context(|| format!("program {name} does not exist"))?;
+        if prog.poller.is_none() {
+            bail!("program {name} is not attached");
+        }
+        if !prog.links.is_empty() {
+            return Ok(());
+        }
+        let links = self.attach_links(name)?;
+        if let Some(prog) = self.progs.get_mut(name) {
+            prog.links = links;
+        }
         Ok(())
     }
 
+    /// Stops the program with the specified name: detaches it and stops
+    /// flushing its windows on schedule. Its open window is flushed, and its
+    /// output channel closed, by [`StoppingProg::finish`], which waits on the
+    /// program's threads and so is best called without holding locks.
+    pub fn stop_prog<S: AsRef<str>>(&mut self, name: S) -> Result<StoppingProg> {
+        let name = name.as_ref();
+        let prog = self
+            .progs
+            .get_mut(name)
+            .with_context(|| format!("program {name} does not exist"))?;
+
+        // Detach, so that no more events are processed
+        prog.links.clear();
+        let ticker = prog.ticker.take();
+        let Some(poller) = prog.poller.take() else {
+            bail!("program {name} is not attached");
+        };
+        prog.out_rx = None;
+        let stop = match &prog.stop {
+            Some(stop) => {
+                let fd = self
+                    .obj
+                    .prog(stop)
+                    .with_context(|| format!("stop program {stop} does not exist"))
+                    .and_then(|prog| Ok(prog.as_fd().try_clone_to_owned()?));
+                Some(fd)
+            }
+            None => None,
+        };
+        Ok(StoppingProg {
+            name: name.to_string(),
+            ticker,
+            poller,
+            stop,
+        })
+    }
+
+    /// Starts the program flushing a program's windows on schedule: timers are
+    /// armed once, while tick programs are run every interval from a thread,
+    /// which stops once the returned sender is dropped.
+    fn start_flush(&self, flush: &FlushProgram) -> Result<Option<(Sender<()>, JoinHandle<()>)>> {
+        let prog = self
+            .obj
+            .prog(flush.name())
+            .ok_or_else(|| EbqlError::attach(flush.name(), "program does not exist in object"))?;
+        let fd = prog
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|e| EbqlError::attach(flush.name(), e.to_string()))?;
+
+        match flush {
+            FlushProgram::Timer(flush_name) => {
+                run_prog(fd.as_fd()).map_err(|e| EbqlError::attach(flush_name, e.to_string()))?;
+                Ok(None)
+            }
+            FlushProgram::Tick(flush_name, interval) => {
+                let (stop_tx, stop_rx) = bounded::<()>(0);
+                let (flush_name, interval) = (flush_name.clone(), *interval);
+                let handle = thread::spawn(move || {
+                    // The first run opens the window
+                    loop {
+                        if let Err(e) = run_prog(fd.as_fd()) {
+                            log::warn!("Failed to run flush program {flush_name}: {e}");
+                        }
+                        match stop_rx.recv_timeout(interval) {
+                            Err(RecvTimeoutError::Timeout) => (),
+                            _ => break,
+                        }
+                    }
+                });
+                Ok(Some((stop_tx, handle)))
+            }
+        }
+    }
+
     /// Gets the receiving channel for events for the program. Returns None if
     /// attach_prog is not called beforehand.
     pub fn prog_rx<S: AsRef<str>>(&self, name: S) -> Option<Receiver<RecordBatch>> {
-        self.progs.get(name.as_ref()).unwrap().out_rx.clone()
+        self.progs.get(name.as_ref())?.out_rx.clone()
+    }
+
+    /// Populates a cgroup set of the object, and keeps it in sync with the
+    /// cgroup hierarchy for as long as the object is loaded.
+    pub fn watch_cgroups(&mut self, set: &CgroupSet) -> Result<()> {
+        let map = self
+            .obj
+            .map(&set.map.name)
+            .with_context(|| format!("map {} does not exist", set.map.name))?;
+        let watcher = set.watch(MapHandle::try_from(map)?)?;
+        self.cgroup_watchers.push(watcher);
+        Ok(())
+    }
+
+    /// Inserts (or updates) an entry of the map with the specified name.
+    pub fn update_map<S: AsRef<str>>(&self, name: S, key: &[u8], value: &[u8]) -> Result<()> {
+        let map = self
+            .obj
+            .map(name.as_ref())
+            .with_context(|| format!("map {} does not exist", name.as_ref()))?;
+        map.update(key, value, MapFlags::ANY)?;
+        Ok(())
     }
 
-    // TODO: find out how to set global variables
+    /// Inserts (or updates) an entry of the per-CPU map with the specified
+    /// name, with a value for each possible CPU.
+    pub fn update_percpu_map<S: AsRef<str>>(
+        &self,
+        name: S,
+        key: &[u8],
+        values: &[Vec<u8>],
+    ) -> Result<()> {
+        let map = self
+            .obj
+            .map(name.as_ref())
+            .with_context(|| format!("map {} does not exist", name.as_ref()))?;
+        map.update_percpu(key, values, MapFlags::ANY)?;
+        Ok(())
+    }
+
+    /// Gets the counts of events and groups that overflowed the object's
+    /// aggregations, if it has any.
+    pub fn overflow_stats(&self) -> Result<Option<OverflowStats>> {
+        let Ok(section) = overflow_section(&self.obj) else {
+            return Ok(None);
+        };
+        Ok(Some(OverflowStats::read(&MapHandle::try_from(section)?)?))
+    }
+
+    /// Sets a query parameter. The parameter is written directly into the
+    /// program's (mmapped) parameters section, so the change takes effect on
+    /// the running program.
+    pub fn set_param<S: AsRef<str>>(&mut self, name: S, value: &DataValue) -> Result<()> {
+        let name = name.as_ref();
+        // Get parameter layout
+        let (f, off) = self
+            .params_struct()
+            .and_then(|s| s.field_offsets().into_iter().find(|(f, _)| f._name == name))
+            .ok_or_else(|| anyhow!("query has no parameter ${name}"))?;
+        let bytes = f._type.encode(value)?;
+
+        if self.params.is_none() {
+            self.params = Some(self.mmap_params()?);
+        }
+        let params = self.params.as_ref().unwrap();
+        if off + bytes.len() > params.len {
+            bail!("parameter ${name} lies outside of the parameters section");
+        }
+        unsafe {
+            for (i, b) in bytes.iter().enumerate() {
+                ptr::write_volatile(params.ptr.add(off + i), *b);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the struct definition of the parameters section, if any.
+    fn params_struct(&self) -> Option<&Struct> {
+        self.progs
+            .values()
+            .find_map(|prog| prog.structs.get(PARAMS_STRUCT))
+    }
+
+    /// Maps the parameters section into memory.
+    fn mmap_params(&self) -> Result<ParamsMmap> {
+        let map = self
+            .obj
+            .maps_iter()
+            .find(|m| m.name().ends_with(PARAMS_SECTION))
+            .ok_or_else(|| anyhow!("parameters section {PARAMS_SECTION} not found"))?;
+        let len = map.value_size() as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                map.as_fd().as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            bail!(
+                "failed to mmap parameters section: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(ParamsMmap {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+}
+
+/// Program detached by [`Object::stop_prog`], whose open window is yet to be
+/// flushed.
+pub struct StoppingProg {
+    name: String,
+    /// Thread running the program's tick flush program, if any
+    ticker: Option<(Sender<()>, JoinHandle<()>)>,
+    poller: Poller,
+    /// Program flushing the open window, if any
+    stop: Option<Result<OwnedFd>>,
+}
+
+impl StoppingProg {
+    /// Stops flushing on schedule, flushes the open window (as a partial
+    /// batch), and closes the output channel once all of the program's output
+    /// has been delivered. Fails if the open window can't be flushed, though
+    /// the output channel is closed regardless.
+    pub fn finish(self) -> Result<()> {
+        let name = self.name;
+        // Stop flushing on schedule
+        if let Some((tick_stop, handle)) = self.ticker {
+            drop(tick_stop);
+            let _ = handle.join();
+        }
+
+        // Take the ring buffer back from its polling thread, so that the final
+        // flush can be read synchronously
+        self.poller.stop.store(true, Ordering::Release);
+        let rb = self
+            .poller
+            .handle
+            .join()
+            .map_err(|_| anyhow!("ring buffer polling thread of {name} panicked"))?;
+
+        // Read the windows flushed before stopping, which are complete
+        rb.consume()?;
+
+        // Flush the open window, marking its batch as partial
+        self.poller.partial.store(true, Ordering::Release);
+        let flushed = match self.stop {
+            Some(fd) => fd.and_then(|fd| run_prog(fd.as_fd())),
+            None => Ok(()),
+        };
+        rb.consume()?;
+
+        // Dropping the ring buffer drops its callback, closing the channel
+        drop(rb);
+        flushed.with_context(|| format!("failed to flush the open window of {name}"))
+    }
+}
+
+/// Gets the map of an object's overflow section.
+fn overflow_section(obj: &libbpf_rs::Object) -> Result<&Map> {
+    obj.maps_iter()
+        .find(|m| m.name().ends_with(OVERFLOW_SECTION))
+        .ok_or_else(|| anyhow!("overflow section {OVERFLOW_SECTION} not found"))
+}
+
+/// Gets the map of an object's stacks section.
+fn stacks_section(obj: &libbpf_rs::Object) -> Result<&Map> {
+    obj.maps_iter()
+        .find(|m| m.name().ends_with(STACKS_SECTION))
+        .ok_or_else(|| anyhow!("stacks section {STACKS_SECTION} not found"))
+}
+
+/// Runs a (tc) program once from user space with BPF_PROG_TEST_RUN, failing if
+/// it returns non-zero. The program is run on an empty packet.
+fn run_prog(fd: BorrowedFd) -> Result<()> {
+    let pkt = [0u8; 64];
+    let mut opts = libbpf_sys::bpf_test_run_opts {
+        sz: mem::size_of::<libbpf_sys::bpf_test_run_opts>() as _,
+        data_in: pkt.as_ptr() as *const _,
+        data_size_in: pkt.len() as u32,
+        ..Default::default()
+    };
+    let ret = unsafe { libbpf_sys::bpf_prog_test_run_opts(fd.as_raw_fd(), &mut opts) };
+    if ret != 0 {
+        bail!("failed to run program: {}", std::io::Error::last_os_error());
+    }
+    if opts.retval != 0 {
+        bail!("program returned {}", opts.retval);
+    }
+    Ok(())
 }
 
 fn get_bpftool_path() -> Result<PathBuf> {
     // TODO: find how to automatically build submodule
-    // let root = get_project_root()?;
-    // root.join(PathBuf::from("bpftool_build/bpftool"))
     which::which("bpftool").with_context(|| format!("failed to find bpftool"))
 }
@@ -0,0 +1,377 @@
+//! Resolution of file descriptors (through `/proc/<pid>/fd`) and of inodes
+//! (through an index of the mounted filesystems) into file paths, for the
+//! `fd_path` and `inode_path` functions.
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::{DirEntryExt, MetadataExt},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{bounded, Receiver, TryRecvError};
+
+use crate::{
+    data_types::{DataType, PathKind},
+    record::{DataValue, Record},
+    schema::schema::Schema,
+};
+
+/// Functions resolving columns into file paths in user space:
+/// `fd_path(pid, fd)` for the file descriptors of a process, and
+/// `inode_path(s_dev, i_ino)` for the inodes of a device.
+pub const FD_PATH: &str = "fd_path";
+pub const INODE_PATH: &str = "inode_path";
+/// In-kernel path resolution (with the `bpf_d_path` helper), which only
+/// tracing programs attached to a few kernel functions can call.
+pub const D_PATH: &str = "d_path";
+
+/// Minimum interval between two reads of the mount table, or two indexings of
+/// a filesystem, on lookups of a device or inode they're missing.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Maximum number of inodes indexed on a filesystem; the rest of the
+/// filesystem is left unresolved.
+const MAX_INDEXED_INODES: usize = 1 << 20;
+
+/// Gets the path columns of a schema by index, with the index of the column
+/// each is resolved within. Columns whose key isn't output are skipped.
+pub fn path_columns(schema: &Schema) -> Vec<(usize, usize, PathKind)> {
+    schema
+        .fields
+        .iter()
+        .enumerate()
+        .filter_map(|(i, f)| {
+            let DataType::Path(kind) = f.data_type else {
+                return None;
+            };
+            let key = f.path_key()?;
+            let k = schema.fields.iter().position(|f| f.name == key)?;
+            Some((i, k, kind))
+        })
+        .collect()
+}
+
+/// Resolves the path columns of a program's output (see [`DataType::Path`])
+/// into file paths. Files that can't be resolved (e.g. of processes that
+/// have exited, of inodes that have been deleted, or of devices that are
+/// still being indexed) are output by their numbers, e.g. `fd 3 of 1234`.
+pub struct PathResolver {
+    columns: Vec<(usize, usize, PathKind)>,
+    inodes: InodePaths,
+}
+
+impl PathResolver {
+    pub fn new(schema: &Schema) -> Self {
+        Self {
+            columns: path_columns(schema),
+            inodes: InodePaths::default(),
+        }
+    }
+
+    /// Replaces the fds and inodes of the records by their paths. File
+    /// descriptors are read once per call, so paths are best resolved by
+    /// batch.
+    pub fn resolve(&mut self, records: Vec<Record>) -> Vec<Record> {
+        let mut fds = HashMap::new();
+        let columns = self.columns.clone();
+        records
+            .into_iter()
+            .map(|record| {
+                let mut values = record.to_vec();
+                for (i, k, kind) in &columns {
+                    let (Some(v), Some(key)) = (values[*i].as_i128(), values[*k].as_i128()) else {
+                        continue;
+                    };
+                    let path = match kind {
+                        PathKind::Fd => {
+                            let (pid, fd) = (key as u32, v as i32);
+                            fds.entry((pid, fd))
+                                .or_insert_with(|| fd_path(pid, fd))
+                                .clone()
+                                .unwrap_or_else(|| format!("fd {fd} of {pid}"))
+                        }
+                        PathKind::Inode => {
+                            let (dev, ino) = (key as u32, v as u64);
+                            self.inodes.resolve(dev, ino).unwrap_or_else(|| {
+                                format!("inode {ino} on {}:{}", dev_major(dev), dev_minor(dev))
+                            })
+                        }
+                    };
+                    values[*i] = DataValue::String(path.clone(), path.len());
+                }
+                Record::from(values)
+            })
+            .collect()
+    }
+}
+
+/// Gets the path of a process' file descriptor. Files that aren't on a
+/// filesystem are named by their type, e.g. `socket:[1234]` or `pipe:[5678]`.
+fn fd_path(pid: u32, fd: i32) -> Option<String> {
+    let link = fs::read_link(format!("/proc/{pid}/fd/{fd}")).ok()?;
+    Some(link.to_string_lossy().into_owned())
+}
+
+/// Paths of inodes, by device (in the kernel's encoding, as in `s_dev`). Each
+/// device's filesystem is indexed on its first lookup, and reindexed on the
+/// lookup of an inode it's missing (e.g. of a new file), at most once per
+/// [`REFRESH_INTERVAL`]. Indexing walks the whole filesystem, which takes
+/// seconds on large ones, so it's done on a thread of its own: inodes aren't
+/// resolved until their device's first index is built.
+#[derive(Default)]
+struct InodePaths {
+    /// Mount point of each device, and when they were read
+    mounts: Option<(HashMap<u32, PathBuf>, Instant)>,
+    indices: HashMap<u32, DevIndex>,
+}
+
+/// Index of the inodes of a device.
+struct DevIndex {
+    /// Path of each inode, as of the last completed indexing
+    paths: HashMap<u64, String>,
+    /// Result of the indexing in progress, if any
+    pending: Option<Receiver<HashMap<u64, String>>>,
+    /// When the last indexing started
+    started: Instant,
+}
+
+impl DevIndex {
+    /// Starts indexing the filesystem mounted at the root.
+    fn spawn(&mut self, root: PathBuf) {
+        let (tx, rx) = bounded(1);
+        thread::spawn(move || {
+            let _ = tx.send(index_fs(&root));
+        });
+        self.pending = Some(rx);
+        self.started = Instant::now();
+    }
+
+    /// Replaces the paths by those of the indexing in progress, if it's done.
+    fn poll(&mut self) {
+        let Some(rx) = &self.pending else {
+            return;
+        };
+        match rx.try_recv() {
+            Ok(paths) => {
+                self.paths = paths;
+                self.pending = None;
+            }
+            Err(TryRecvError::Disconnected) => self.pending = None,
+            Err(TryRecvError::Empty) => (),
+        }
+    }
+}
+
+impl InodePaths {
+    fn resolve(&mut self, dev: u32, ino: u64) -> Option<String> {
+        if let Some(index) = self.indices.get_mut(&dev) {
+            index.poll();
+            if let Some(path) = index.paths.get(&ino) {
+                return Some(path.clone());
+            }
+            if index.pending.is_some() || index.started.elapsed() < REFRESH_INTERVAL {
+                return None;
+            }
+        }
+
+        let mounts_fresh = match &self.mounts {
+            Some((mounts, at)) => mounts.contains_key(&dev) || at.elapsed() < REFRESH_INTERVAL,
+            None => false,
+        };
+        if !mounts_fresh {
+            self.mounts = Some((read_mounts(), Instant::now()));
+        }
+        let index = self.indices.entry(dev).or_insert_with(|| {
+            DevIndex {
+                paths: HashMap::new(),
+                pending: None,
+                started: Instant::now(),
+            }
+        });
+        match self
+            .mounts
+            .as_ref()
+            .and_then(|(mounts, _)| mounts.get(&dev))
+        {
+            Some(root) => index.spawn(root.clone()),
+            // Retried once the mounts are read again
+            None => index.started = Instant::now(),
+        }
+        None
+    }
+}
+
+/// Reads the mount point of each device from the mount table.
+fn read_mounts() -> HashMap<u32, PathBuf> {
+    match fs::read_to_string("/proc/self/mountinfo") {
+        Ok(text) => parse_mounts(&text),
+        Err(e) => {
+            log::warn!("Inodes won't be resolved into paths: failed to read mounts: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Parses the mount point of each device from a mount table (in the format of
+/// `/proc/<pid>/mountinfo`). Devices mounted several times (e.g. by bind
+/// mounts) are resolved from the mount of their filesystem's root, if any.
+fn parse_mounts(text: &str) -> HashMap<u32, PathBuf> {
+    let mut mounts = HashMap::new();
+    for line in text.lines() {
+        // Mount id, parent id, major:minor, root, then mount point
+        let cols = line.split(' ').collect::<Vec<_>>();
+        let (Some(dev), Some(root), Some(point)) = (cols.get(2), cols.get(3), cols.get(4)) else {
+            continue;
+        };
+        let Some((major, minor)) = dev.split_once(':') else {
+            continue;
+        };
+        let (Ok(major), Ok(minor)) = (major.parse::<u32>(), minor.parse::<u32>()) else {
+            continue;
+        };
+        let dev = (major << 20) | minor;
+        if *root == "/" || !mounts.contains_key(&dev) {
+            mounts.insert(dev, PathBuf::from(unescape_mount(point)));
+        }
+    }
+    mounts
+}
+
+/// Unescapes the octal escapes of a mount table path (e.g. `\040` for
+/// spaces).
+fn unescape_mount(s: &str) -> String {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            res.push(b);
+            continue;
+        }
+        let digits = bytes.by_ref().take(3).collect::<Vec<_>>();
+        match std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|d| u8::from_str_radix(d, 8).ok())
+        {
+            Some(c) => res.push(c),
+            None => {
+                res.push(b);
+                res.extend(digits);
+            }
+        }
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+/// Indexes the paths of the inodes of the filesystem mounted at the root,
+/// without crossing into other mounts. Inodes with several links are
+/// indexed by the first link found.
+fn index_fs(root: &Path) -> HashMap<u64, String> {
+    let mut index = HashMap::new();
+    let Ok(meta) = fs::metadata(root) else {
+        return index;
+    };
+    let dev = meta.dev();
+    index.insert(meta.ino(), root.to_string_lossy().into_owned());
+
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if index.len() >= MAX_INDEXED_INODES {
+                log::warn!(
+                    "Stopped indexing inodes of {} after {MAX_INDEXED_INODES} inodes",
+                    root.display()
+                );
+                return index;
+            }
+            let path = entry.path();
+            index
+                .entry(entry.ino())
+                .or_insert_with(|| path.to_string_lossy().into_owned());
+            let is_dir = entry.file_type().map_or(false, |t| t.is_dir());
+            if is_dir && fs::metadata(&path).map_or(false, |m| m.dev() == dev) {
+                dirs.push(path);
+            }
+        }
+    }
+    index
+}
+
+/// Gets the major and minor numbers of a device in the kernel's encoding.
+fn dev_major(dev: u32) -> u32 {
+    dev >> 20
+}
+
+fn dev_minor(dev: u32) -> u32 {
+    dev & ((1 << 20) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn unescapes_mount_paths() {
+        assert_eq!(unescape_mount("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape_mount("/mnt/tab\\011"), "/mnt/tab\t");
+        assert_eq!(unescape_mount("/mnt/bad\\09x"), "/mnt/bad\\09x");
+        assert_eq!(unescape_mount("/mnt/end\\"), "/mnt/end\\");
+    }
+
+    #[test]
+    fn parses_mount_points_by_device() {
+        let text = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 22 8:1 /var/lib/docker /mnt/bind rw - ext4 /dev/sda1 rw
+31 22 259:3 /sub /data\\040x rw - xfs /dev/nvme0n1p3 rw
+garbage line
+";
+        let mounts = parse_mounts(text);
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[&((8 << 20) | 1)], PathBuf::from("/"));
+        assert_eq!(mounts[&((259 << 20) | 3)], PathBuf::from("/data x"));
+        assert_eq!(dev_major((259 << 20) | 3), 259);
+        assert_eq!(dev_minor((259 << 20) | 3), 3);
+    }
+
+    #[test]
+    fn indexes_inodes_in_background() {
+        let root = env::temp_dir().join(format!("ebql_paths_test_{}", std::process::id()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file"), "").unwrap();
+        let ino = fs::metadata(root.join("dir/file")).unwrap().ino();
+
+        let index = index_fs(&root);
+        assert_eq!(
+            index.get(&ino).map(String::as_str),
+            root.join("dir/file").to_str()
+        );
+
+        let dev = 1 << 20;
+        let mut paths = InodePaths {
+            mounts: Some((HashMap::from([(dev, root.clone())]), Instant::now())),
+            ..Default::default()
+        };
+        assert_eq!(paths.resolve(dev, ino), None);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut path = None;
+        while path.is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            path = paths.resolve(dev, ino);
+        }
+        // Misses don't reindex until the refresh interval has passed
+        let missing = paths.resolve(dev, u64::MAX);
+        let pending = paths.indices[&dev].pending.is_some();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(path.as_deref(), root.join("dir/file").to_str());
+        assert_eq!(missing, None);
+        assert!(!pending);
+    }
+}
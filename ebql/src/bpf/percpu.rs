@@ -0,0 +1,184 @@
+//! Per-CPU aggregation maps, merged in user space.
+
+use anyhow::{anyhow, Context, Result};
+use libbpf_rs::{MapFlags, MapHandle};
+
+use super::Struct;
+use crate::record::Record;
+
+/// Name of the key field holding the epoch of an entry's window.
+pub const EPOCH_FIELD: &str = "epoch";
+
+/// How the per-CPU partials of an aggregation are merged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeOp {
+    Max,
+    Min,
+    Sum,
+    Count,
+    /// Averages are output in fixed point, with the scale
+    Avg(u64),
+}
+
+/// Aggregate of a per-CPU aggregation map, and the output column of its merged
+/// aggregate.
+#[derive(Clone, Debug)]
+pub struct PercpuAgg {
+    /// Offset of the aggregate's (value, count) pair in the map's values
+    pub offset: usize,
+    pub op: MergeOp,
+    pub column: String,
+}
+
+/// Per-CPU aggregations of a program, kept together in one map. Every record
+/// its ring buffer receives is a marker of a window's end, holding only the
+/// window's bounds; the window's rows are merged from the map's entries of the
+/// window's epoch.
+#[derive(Clone, Debug)]
+pub struct PercpuAggs {
+    pub map: String,
+    /// Layout of the map's keys: the group by fields, then the epoch
+    pub key: Struct,
+    pub aggs: Vec<PercpuAgg>,
+    /// Offset of the (u64) flag marking the "other" group in keys, if any. The
+    /// other group's entries are reserved, so they're reset rather than
+    /// deleted.
+    pub other: Option<usize>,
+}
+
+/// Merges the per-CPU aggregations of a running program into output rows.
+pub struct PercpuMerger {
+    aggs: PercpuAggs,
+    map: MapHandle,
+    /// Layout of output rows
+    out: Struct,
+    /// Epoch of the next window to be merged
+    epoch: u64,
+}
+
+impl PercpuMerger {
+    /// Creates a merger over the aggregation map of a loaded object.
+    pub fn new(aggs: PercpuAggs, out: Struct, obj: &libbpf_rs::Object) -> Result<Self> {
+        let map = obj
+            .map(&aggs.map)
+            .with_context(|| format!("aggregation map {} does not exist", aggs.map))?;
+        let map = MapHandle::try_from(map)?;
+        Ok(Self {
+            aggs,
+            map,
+            out,
+            epoch: 0,
+        })
+    }
+
+    /// Merges the rows of the window ended by the marker, removing its entries
+    /// from the map.
+    pub fn merge(&mut self, marker: &[u8]) -> Result<Vec<Record>> {
+        let key_offs = self.aggs.key.field_offsets();
+        let epoch_off = key_offs
+            .iter()
+            .find(|(f, _)| f._name == EPOCH_FIELD)
+            .map(|(_, off)| *off)
+            .ok_or_else(|| anyhow!("aggregation key {} has no epoch", self.aggs.key.name))?;
+        let out_offs = self.out.field_offsets();
+        let out_off = |name: &str| {
+            out_offs
+                .iter()
+                .find(|(f, _)| f._name == name)
+                .map(|(f, off)| (*off, f.size()))
+                .ok_or_else(|| anyhow!("output {} has no column {name}", self.out.name))
+        };
+
+        let keys = self
+            .map
+            .keys()
+            .filter(|key| read_u64(key, epoch_off) == self.epoch)
+            .collect::<Vec<_>>();
+
+        let mut records = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let mut row = marker.to_vec();
+            let is_other = self.is_other(key);
+            // Group by values (and the other flag, if selected) are copied over
+            // from the key
+            for (f, off) in key_offs.iter().filter(|(f, _)| f._name != EPOCH_FIELD) {
+                let Ok((dst, sz)) = out_off(&f._name) else {
+                    continue;
+                };
+                if Some(*off) == self.aggs.other {
+                    row[dst..dst + sz].fill(0);
+                    row[dst] = is_other as u8;
+                } else {
+                    row[dst..dst + f.size()].copy_from_slice(&key[*off..*off + f.size()]);
+                }
+            }
+            let slots = self
+                .map
+                .lookup_percpu(key, MapFlags::ANY)?
+                .unwrap_or_default();
+            if is_other {
+                let zeros = slots.iter().map(|s| vec![0u8; s.len()]).collect::<Vec<_>>();
+                if let Err(e) = self.map.update_percpu(key, &zeros, MapFlags::EXIST) {
+                    log::warn!("Failed to reset the other group: {e}");
+                }
+            }
+            let mut empty = true;
+            for agg in &self.aggs.aggs {
+                let Some(val) = merge_slots(agg.op, &slots, agg.offset) else {
+                    continue;
+                };
+                empty = false;
+                let (dst, sz) = out_off(&agg.column)?;
+                let sz = sz.min(8);
+                row[dst..dst + sz].copy_from_slice(&val.to_ne_bytes()[..sz]);
+            }
+            if !empty {
+                records.push(self.out.produce_record(&row)?);
+            }
+        }
+
+        for key in keys.iter().filter(|key| !self.is_other(key)) {
+            if let Err(e) = self.map.delete(key) {
+                log::warn!("Failed to delete aggregation entry: {e}");
+            }
+        }
+        self.epoch ^= 1;
+        Ok(records)
+    }
+
+    /// Returns whether the key is of the "other" group.
+    fn is_other(&self, key: &[u8]) -> bool {
+        self.aggs.other.is_some_and(|off| read_u64(key, off) != 0)
+    }
+}
+
+/// Merges the per-CPU partials of the aggregate at the offset of the slots.
+/// Each partial is a value, followed by the number of values it aggregates;
+/// returns None if no CPU aggregated any value.
+fn merge_slots(op: MergeOp, slots: &[Vec<u8>], off: usize) -> Option<u64> {
+    let partials = slots
+        .iter()
+        .map(|slot| (read_u64(slot, off), read_u64(slot, off + 8)))
+        .filter(|(_, count)| *count > 0);
+    let (mut val, mut count) = (None, 0u64);
+    for (v, c) in partials {
+        count += c;
+        val = Some(match (op, val) {
+            (_, None) => v,
+            (MergeOp::Max, Some(acc)) => v.max(acc),
+            (MergeOp::Min, Some(acc)) => v.min(acc),
+            (MergeOp::Sum | MergeOp::Count | MergeOp::Avg(_), Some(acc)) => acc.wrapping_add(v),
+        });
+    }
+    match op {
+        MergeOp::Avg(scale) => val.map(|sum| (sum as u128 * scale as u128 / count as u128) as u64),
+        _ => val,
+    }
+}
+
+/// Reads a u64 at the offset, or 0 if the buffer is too short.
+fn read_u64(buf: &[u8], off: usize) -> u64 {
+    buf.get(off..off + 8)
+        .and_then(|b| b.try_into().ok())
+        .map_or(0, u64::from_ne_bytes)
+}
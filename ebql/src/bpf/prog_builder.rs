@@ -1,23 +1,25 @@
 //! Program builder for eBPF code.
 
 use std::{
-    collections::HashMap,
-    env,
-    ffi::{OsStr, OsString},
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fmt::Display,
     fs::{OpenOptions},
-    io::{self, Write},
+    io::Write,
     marker::PhantomData,
-    path::{PathBuf},
+    path::{Path, PathBuf},
     process::Command,
     str::FromStr,
 };
 
 use anyhow::{bail, Context, Result};
 
-use super::{Field, MapDef, Struct};
+use super::{Field, MapDef, Struct, Type};
 use crate::{
-    events::system::SystemVar,
+    error::EbqlError,
+    events::{perf::PerfEvent, system::SystemVar},
     map::{MapType, RingBuf},
+    program::FlushProgram,
 };
 
 // Common characters
@@ -43,6 +45,13 @@ pub const HASHMAP: &str = "BPF_MAP_TYPE_HASH";
 pub const TYPE: &str = "type";
 pub const MAX_ENTRIES: &str = "max_entries";
 
+// Headers included by generated programs, embedded so that programs build
+// wherever the executable runs from; each build writes them next to its source
+const BPF_HEADERS: [(&str, &str); 2] = [
+    ("common.bpf.h", include_str!("../../../bpf/common.bpf.h")),
+    ("vmlinux.h", include_str!("../../../bpf/vmlinux.h")),
+];
+
 // Default license for most BPF programs
 const DEFAULT_LICENSE: &str = r#"char LICENSE[] SEC("license") = "Dual BSD/GPL";"#;
 
@@ -59,6 +68,10 @@ pub struct BuildResult {
     pub maps: HashMap<String, MapDef>,
     pub globals: HashMap<String, Expr>,
     pub ringbuf: RingBuf,
+    pub flush: Option<FlushProgram>,
+    pub stop: Option<String>,
+    pub fail_on_overflow: bool,
+    pub perf: Option<PerfEvent>,
 }
 
 impl BuildResult {
@@ -69,6 +82,10 @@ impl BuildResult {
         maps: HashMap<String, MapDef>,
         globals: HashMap<String, Expr>,
         ringbuf: RingBuf,
+        flush: Option<FlushProgram>,
+        stop: Option<String>,
+        fail_on_overflow: bool,
+        perf: Option<PerfEvent>,
     ) -> Self {
         Self {
             obj_path,
@@ -77,10 +94,51 @@ impl BuildResult {
             maps,
             globals,
             ringbuf,
+            flush,
+            stop,
+            fail_on_overflow,
+            perf,
         }
     }
 }
 
+/// Generated code of a program, before compilation.
+pub struct GeneratedCode {
+    /// Program name
+    pub name: String,
+    /// Program header (`<name>.bpf.h`)
+    pub header: String,
+    /// Program source (`<name>.bpf.c`)
+    pub source: String,
+    /// Rendered external includes (e.g. window and aggregation templates), by
+    /// file name
+    pub includes: BTreeMap<String, String>,
+}
+
+impl GeneratedCode {
+    pub fn header_name(&self) -> String {
+        format!("{}.bpf.h", self.name)
+    }
+
+    pub fn source_name(&self) -> String {
+        format!("{}.bpf.c", self.name)
+    }
+}
+
+impl Display for GeneratedCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let files = std::iter::once((self.source_name(), &self.source))
+            .chain(std::iter::once((self.header_name(), &self.header)))
+            .chain(self.includes.iter().map(|(name, text)| (name.clone(), text)));
+        for (name, text) in files {
+            writeln!(f, "// ===== {name} ===== //")?;
+            writeln!(f, "{}", text.trim_end())?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
 /// Code builder for BPF programs.
 pub struct BpfCodeBuilder<S = Base> {
     /// Output file name (without extensions; this will add the
@@ -125,6 +183,14 @@ pub struct BpfCodeBuilder<S = Base> {
     /// Store maps defined (name -> map definition)
     /// TODO: migrate to processing generated libbpf obj
     maps: HashMap<String, MapDef>,
+    /// Store the program that flushes windows on schedule, if any
+    flush: Option<FlushProgram>,
+    /// Store the program that flushes the open window when stopping, if any
+    stop: Option<String>,
+    /// Store whether the program's output stops once its groups overflow
+    fail_on_overflow: bool,
+    /// Store the perf event the program samples, if any
+    perf: Option<PerfEvent>,
 
     /// Current prefix while code construction
     prefix: Vec<u8>,
@@ -148,6 +214,10 @@ impl BpfCodeBuilder<Base> {
             maps: HashMap::new(),
             structs: HashMap::new(),
             ring_buffer: None,
+            flush: None,
+            stop: None,
+            fail_on_overflow: false,
+            perf: None,
 
             ext_includes: HashMap::new(),
 
@@ -197,15 +267,22 @@ impl BpfCodeBuilder<Base> {
         let key_type = map_def.key_type.to_string();
         let max_entries = map_def.max_entries.to_string();
         cb.write_attr(__UINT, "type", &map_type);
-        cb.write_attr(__TYPE, "key", &key_type);
-        cb.write_attr(__UINT, "value", &map_def.value_type);
+        // Stack trace maps don't support BTF-typed keys and values
+        if map_def.map_type == MapType::StackTrace {
+            let value_size = format!("sizeof({})", map_def.value_type);
+            cb.write_attr(__UINT, "key_size", &format!("sizeof({key_type})"));
+            cb.write_attr(__UINT, "value_size", &value_size);
+        } else {
+            cb.write_attr(__TYPE, "key", &key_type);
+            cb.write_attr(__TYPE, "value", &map_def.value_type);
+        }
         cb.write_attr(__UINT, "max_entries", &max_entries);
         if map_def.flags.val > 0 {
             let flags = map_def.flags.val.to_string();
             cb.write_attr(__UINT, "map_flags", &flags);
         }
         if let Some(path) = &map_def.pin {
-            cb.write_attr(__UINT, "pinning", path.to_str().unwrap());
+            cb.write_attr(__UINT, "pinning", &path.to_string_lossy());
         }
         let mut cb = cb.close(&map_def.name);
         cb.maps.insert(map_def.name.clone(), map_def.clone());
@@ -253,7 +330,7 @@ impl BpfCodeBuilder<Base> {
     pub fn write_macro<S1: AsRef<str>, S2: AsRef<str>>(&mut self, name: S1, val: S2) -> &mut Self {
         let str = format!("#define {} ({})", name.as_ref(), val.as_ref());
         self.macros_buf.extend(str.as_bytes());
-        self.includes_buf.push(NL);
+        self.macros_buf.push(NL);
         self
     }
 
@@ -272,6 +349,7 @@ impl BpfCodeBuilder<Base> {
         let str = format!("{} {}{};", mods, expr, assignment);
         self.globals_buf.extend(str.as_bytes());
         self.globals_buf.push(NL);
+        self.globals.insert(expr._name.clone(), expr.clone());
         self
     }
 
@@ -306,11 +384,49 @@ impl BpfCodeBuilder<Base> {
 
     /// Starts a BPF program function definition. The user must close the
     /// function definition using self.close().
-    pub fn start_function(mut self, args: &[Expr]) -> BpfCodeBuilder<BodyConstruction> {
+    pub fn start_function(self, args: &[Expr]) -> BpfCodeBuilder<BodyConstruction> {
+        let header = format!("SEC(\"{}\")\nu32 {}", self.section, self.name);
+        self.open_function(header, args)
+    }
+
+    /// Starts the definition of an additional BPF program in the object, in its
+    /// own section (e.g. one that is run from user space rather than
+    /// attached). The user must close the function definition using
+    /// self.close().
+    pub fn start_program(
+        self,
+        section: &str,
+        name: &str,
+        args: &[Expr],
+    ) -> BpfCodeBuilder<BodyConstruction> {
+        self.open_function(format!("SEC(\"{section}\")\nu32 {name}"), args)
+    }
+
+    /// Starts a static (i.e. non-program) function definition, such as a
+    /// helper or callback. The user must close the function definition using
+    /// self.close().
+    pub fn start_static_function(
+        self,
+        mods: Vec<&str>,
+        ret: &Type,
+        name: &str,
+        args: &[Expr],
+    ) -> BpfCodeBuilder<BodyConstruction> {
+        let header = std::iter::once(STATIC)
+            .chain(mods)
+            .map(String::from)
+            .chain(std::iter::once(format!("{ret} {name}")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.open_function(header, args)
+    }
+
+    /// Writes a function header (everything up to the argument list), then its
+    /// arguments, and opens its body.
+    fn open_function(mut self, header: String, args: &[Expr]) -> BpfCodeBuilder<BodyConstruction> {
         // Construct function header
-        let mut str = format!(r#"SEC("{}")"#, self.section);
-        str.push(NL as char);
-        str.push_str(&format!("u32 {}(", self.name));
+        let mut str = header;
+        str.push('(');
         for (i, arg) in args.iter().enumerate() {
             str.push_str(&format!("{}", arg));
             if i != args.len() - 1 {
@@ -332,8 +448,37 @@ impl BpfCodeBuilder<Base> {
         }
     }
 
-    /// Builds the program, returning the path to the output object file.
-    pub fn build(self, out_dir: &PathBuf) -> Result<BuildResult> {
+    /// Registers the program that user space runs to flush the program's
+    /// windows on schedule.
+    pub fn set_flush_program(&mut self, flush: FlushProgram) -> &mut Self {
+        self.flush = Some(flush);
+        self
+    }
+
+    /// Registers the program that user space runs once to flush the open
+    /// window when the program is stopped.
+    pub fn set_stop_program<S: AsRef<str>>(&mut self, name: S) -> &mut Self {
+        self.stop = Some(name.as_ref().to_string());
+        self
+    }
+
+    /// Marks the program as failing once its aggregations drop events on
+    /// overflowing groups.
+    pub fn set_fail_on_overflow(&mut self) -> &mut Self {
+        self.fail_on_overflow = true;
+        self
+    }
+
+    /// Registers the perf event that user space opens (on every CPU) to
+    /// attach the program to.
+    pub fn set_perf_event(&mut self, perf: PerfEvent) -> &mut Self {
+        self.perf = Some(perf);
+        self
+    }
+
+    /// Renders the program's header, source, and external includes, without
+    /// writing or compiling anything.
+    pub fn render(&self) -> GeneratedCode {
         // Build program header
         let hdr_len = self.structs_buf.len() + self.macros_buf.len() + self.globals_buf.len();
         let mut hdr_buf = Vec::with_capacity(hdr_len);
@@ -349,44 +494,22 @@ impl BpfCodeBuilder<Base> {
 
         hdr_buf.extend("// *** MACRO DEFINITIONS *** //".as_bytes());
         hdr_buf.push(NL);
-        hdr_buf.extend(self.macros_buf);
+        hdr_buf.extend(&self.macros_buf);
         hdr_buf.push(NL);
         hdr_buf.push(NL);
 
         hdr_buf.extend("// *** STRUCT DEFINITIONS *** //".as_bytes());
         hdr_buf.push(NL);
-        hdr_buf.extend(self.structs_buf);
+        hdr_buf.extend(&self.structs_buf);
         hdr_buf.push(NL);
         hdr_buf.push(NL);
 
         hdr_buf.extend("// *** GLOBAL DEFINITIONS *** //".as_bytes());
         hdr_buf.push(NL);
-        hdr_buf.extend(self.globals_buf);
+        hdr_buf.extend(&self.globals_buf);
         hdr_buf.push(NL);
         hdr_buf.push(NL);
 
-        let hdr_name = format!("{}.bpf.h", &self.name);
-        let hdr_path = out_dir.join(hdr_name);
-        // Write to output file
-        let mut file = OpenOptions::new()
-            .truncate(true)
-            .write(true)
-            .create(true)
-            .open(&hdr_path)?;
-        file.write_all(&hdr_buf)?;
-
-        // Write all external header files
-        for (ext_hdr_name, ext_hdr_text) in &self.ext_includes {
-            let ext_hdr_path = out_dir.join(ext_hdr_name);
-            // Write to output file
-            let mut file = OpenOptions::new()
-                .truncate(true)
-                .write(true)
-                .create(true)
-                .open(&ext_hdr_path)?;
-            file.write_all(ext_hdr_text.as_bytes())?;
-        }
-
         // Build program source
         let cap = self.includes_buf.len() + self.maps_buf.len() + self.code_buf.len();
         let mut src_buf = Vec::with_capacity(cap);
@@ -397,17 +520,17 @@ impl BpfCodeBuilder<Base> {
 
         src_buf.extend("// *** INCLUDES SECTION *** //".as_bytes());
         src_buf.push(NL);
-        src_buf.extend(self.includes_buf);
+        src_buf.extend(&self.includes_buf);
         src_buf.extend(NLNL);
 
         src_buf.extend("// *** MAPS SECTION *** //".as_bytes());
         src_buf.push(NL);
-        src_buf.extend(self.maps_buf);
+        src_buf.extend(&self.maps_buf);
         src_buf.extend(NLNL);
 
         src_buf.extend("// *** CODE SECTION *** //".as_bytes());
         src_buf.push(NL);
-        src_buf.extend(self.code_buf);
+        src_buf.extend(&self.code_buf);
         src_buf.extend(NLNL);
 
         src_buf.extend("// *** LICENSE *** //".as_bytes());
@@ -415,23 +538,40 @@ impl BpfCodeBuilder<Base> {
         src_buf.extend(DEFAULT_LICENSE.as_bytes());
         src_buf.push(NL);
 
-        let src_name = format!("{}.bpf.c", &self.name);
-        let src_path = out_dir.join(src_name);
-        // Write to output file
-        let mut file = OpenOptions::new()
-            .truncate(true)
-            .write(true)
-            .create(true)
-            .open(&src_path)?;
-        file.write_all(&src_buf)?;
+        GeneratedCode {
+            name: self.name.clone(),
+            header: String::from_utf8_lossy(&hdr_buf).into_owned(),
+            source: String::from_utf8_lossy(&src_buf).into_owned(),
+            includes: self.ext_includes.clone().into_iter().collect(),
+        }
+    }
+
+    /// Builds the program, returning the path to the output object file.
+    pub fn build(self, out_dir: &Path) -> Result<BuildResult> {
+        let code = self.render();
+
+        // Write header, source, and all external and common header files
+        let mut files = vec![
+            (code.header_name(), code.header.as_str()),
+            (code.source_name(), code.source.as_str()),
+        ];
+        files.extend(code.includes.iter().map(|(name, text)| (name.clone(), text.as_str())));
+        files.extend(BPF_HEADERS.map(|(name, text)| (name.to_string(), text)));
+        for (name, text) in files {
+            let mut file = OpenOptions::new()
+                .truncate(true)
+                .write(true)
+                .create(true)
+                .open(out_dir.join(name))?;
+            file.write_all(text.as_bytes())?;
+        }
+        let src_path = out_dir.join(code.source_name());
 
         // Compile program down to object file
-        let root = get_project_root().unwrap();
         let dst_path = src_path.clone().with_extension("o");
-        let vmlinux_dir = root.join("bpf/");
         let mut cmd = Command::new(OsStr::new("clang"));
         // Code yoinked from libbpf-cargo's compilation flags
-        cmd.arg(format!("-I{}", vmlinux_dir.display()))
+        cmd.arg(format!("-I{}", out_dir.display()))
             .arg("-D__TARGET_ARCH_x86_64")
             // Explicitly disable stack protector logic, which doesn't work with
             // BPF. See https://lkml.org/lkml/2020/2/21/1000.
@@ -448,16 +588,24 @@ impl BpfCodeBuilder<Base> {
         let output = cmd.output().context("Failed to execute clang")?;
         if !output.status.success() {
             let err = String::from_utf8_lossy(&output.stderr).to_string();
-            bail!("Compile failed: {err}");
+            bail!(EbqlError::clang(&self.name, err));
         }
 
+        let ring_buffer = match self.ring_buffer {
+            Some(rb) => rb,
+            None => bail!(EbqlError::codegen(&self.name, "program has no output ring buffer")),
+        };
         Ok(BuildResult::new(
             dst_path,
             self.name,
             self.structs,
             self.maps,
             self.globals,
-            self.ring_buffer.unwrap(),
+            ring_buffer,
+            self.flush,
+            self.stop,
+            self.fail_on_overflow,
+            self.perf,
         ))
     }
 }
@@ -604,6 +752,7 @@ impl BpfCodeBuilder<BodyConstruction> {
         let str = format!("}} else if ({}) {{", cond);
         self.code_buf.extend(&self.prefix);
         self.code_buf.extend(str.as_bytes());
+        self.code_buf.push(NL);
 
         self.prefix.push(TAB);
         self
@@ -614,6 +763,7 @@ impl BpfCodeBuilder<BodyConstruction> {
         let str = format!("}} else {{");
         self.code_buf.extend(&self.prefix);
         self.code_buf.extend(str.as_bytes());
+        self.code_buf.push(NL);
 
         self.prefix.push(TAB);
         self
@@ -647,24 +797,6 @@ impl BpfCodeBuilder<BodyConstruction> {
     }
 }
 
-pub(crate) fn get_project_root() -> io::Result<PathBuf> {
-    let path = env::current_dir()?;
-    let mut path_ancestors = path.as_path().ancestors();
-
-    while let Some(p) = path_ancestors.next() {
-        let has_cargo = std::fs::read_dir(p)?
-            .into_iter()
-            .any(|p| p.unwrap().file_name() == OsString::from("Cargo.lock"));
-        if has_cargo {
-            return Ok(PathBuf::from(p));
-        }
-    }
-    Err(io::Error::new(
-        io::ErrorKind::NotFound,
-        "Ran out of places to find Cargo.toml",
-    ))
-}
-
 // State transitions
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Base;
@@ -1,12 +1,45 @@
 //! BPF Program map representation.
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc},
+    thread::JoinHandle,
+    time::Duration,
+};
 
-use crossbeam::channel::Receiver;
-use libbpf_rs::Link;
+use crossbeam::channel::{Receiver, Sender};
+use libbpf_rs::{Link, RingBuffer};
 
 use super::Struct;
-use crate::{map::RingBuf, prog_builder::Expr, record_batch::RecordBatch};
+use crate::{events::perf::PerfEvent, map::RingBuf, prog_builder::Expr, record_batch::RecordBatch};
+
+/// Program that user space runs (rather than attaches) to flush a program's
+/// windows on schedule.
+#[derive(Clone, Debug)]
+pub enum FlushProgram {
+    /// Arms the `bpf_timer` that flushes the windows; run once, after attaching
+    Timer(String),
+    /// Flushes the windows if they are due; run every interval
+    Tick(String, Duration),
+}
+
+impl FlushProgram {
+    pub fn name(&self) -> &str {
+        match self {
+            FlushProgram::Timer(name) | FlushProgram::Tick(name, _) => name,
+        }
+    }
+}
+
+/// Handle over the thread polling a program's ring buffer.
+pub struct Poller {
+    /// Stops polling once set
+    pub stop: Arc<AtomicBool>,
+    /// Marks batches read from the ring buffer as partial once set
+    pub partial: Arc<AtomicBool>,
+    /// Polling thread, which hands back the ring buffer once stopped
+    pub handle: JoinHandle<RingBuffer<'static>>,
+}
 
 /// Handle over an individual BPF program.
 pub struct Program {
@@ -17,10 +50,24 @@ pub struct Program {
     pub globals: HashMap<String, Expr>,
     /// Ring buffer
     pub ring_buffer: RingBuf,
-    /// Program link
-    pub link: Option<Link>,
+    /// Program links (for perf events, one per CPU)
+    pub links: Vec<Link>,
     /// Output receiver channel for events
     pub out_rx: Option<Receiver<RecordBatch>>,
+    /// Program flushing windows on schedule, if any
+    pub flush: Option<FlushProgram>,
+    /// Thread running a tick flush program, which stops once its sender is
+    /// dropped
+    pub ticker: Option<(Sender<()>, JoinHandle<()>)>,
+    /// Program flushing the open window when stopping, if any
+    pub stop: Option<String>,
+    /// Whether the program's output stops (as a failure) once its aggregations
+    /// drop events on overflowing groups
+    pub fail_on_overflow: bool,
+    /// Thread polling the program's ring buffer
+    pub poller: Option<Poller>,
+    /// Perf event the program is attached to, if any
+    pub perf: Option<PerfEvent>,
 }
 
 impl Program {
@@ -29,19 +76,35 @@ impl Program {
         structs: HashMap<String, Struct>,
         globals: HashMap<String, Expr>,
         ring_buffer: RingBuf,
+        flush: Option<FlushProgram>,
+        stop: Option<String>,
+        fail_on_overflow: bool,
+        perf: Option<PerfEvent>,
     ) -> Self {
         Self {
             structs,
             globals,
             ring_buffer,
-            link: None,
+            links: Vec::new(),
             out_rx: None,
+            flush,
+            ticker: None,
+            stop,
+            fail_on_overflow,
+            poller: None,
+            perf,
         }
     }
 
     /// Add attached information to this program
-    pub fn add_attach_info(&mut self, link: Link, out_rx: Receiver<RecordBatch>) {
-        self.link = Some(link);
+    pub fn add_attach_info(
+        &mut self,
+        links: Vec<Link>,
+        out_rx: Receiver<RecordBatch>,
+        poller: Poller,
+    ) {
+        self.links = links;
         self.out_rx = Some(out_rx);
+        self.poller = Some(poller);
     }
 }
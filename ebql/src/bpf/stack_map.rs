@@ -0,0 +1,227 @@
+//! Stack trace maps, from which programs' stack ids are resolved into
+//! symbolized stacks.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use libbpf_rs::{MapFlags, MapHandle};
+use procfs::process::MemoryMap;
+
+use super::{
+    symbols::{process_maps, KernelSymbols, UserSymbols},
+    MapDef, MapDefFlags, MapType, Type,
+};
+use crate::{
+    data_types::DataType,
+    record::{DataValue, Record},
+    schema::schema::Schema,
+    stack::{Stack, StackKind, UNKNOWN_FRAME},
+};
+
+/// Maximum number of frames of a stack (see `MAX_STACK_DEPTH` in
+/// `common.bpf.h`).
+pub const MAX_STACK_DEPTH: usize = 127;
+
+/// Number of distinct stacks a program keeps a window. Windows collect stacks
+/// into two maps in turn, each cleared once its window's stacks are resolved;
+/// once a map is full (or on hash collisions), new stacks are lost, and counted
+/// as dropped events.
+pub const STACK_MAP_ENTRIES: u64 = 1 << 14;
+
+/// Data section holding the epoch of the stack trace map stacks are collected
+/// into (see `STACK_EPOCH`), which is flipped as windows are flushed.
+pub const STACKS_SECTION: &str = ".data.stacks";
+pub const STACK_EPOCH: &str = "stack_epoch";
+
+/// Bit of stack ids holding the epoch of their map.
+const EPOCH_BIT: u32 = 31;
+
+/// Stack id read by programs for stacks that couldn't be collected.
+const NO_STACK: u64 = u64::MAX;
+
+/// Gets the name of a program's stack trace map of the epoch.
+pub fn stack_map_name<S: AsRef<str>>(prog: S, epoch: u64) -> String {
+    format!("stack_traces_{}_{epoch}", prog.as_ref())
+}
+
+/// Gets the definition of a program's stack trace map of the epoch, which holds
+/// the addresses of stacks by their ids.
+pub fn stack_map<S: AsRef<str>>(prog: S, epoch: u64) -> MapDef {
+    MapDef {
+        name: stack_map_name(prog, epoch),
+        map_type: MapType::StackTrace,
+        key_type: Type::U32,
+        value_type: String::from("stack_trace_t"),
+        max_entries: STACK_MAP_ENTRIES,
+        flags: MapDefFlags::new(),
+        pin: None,
+    }
+}
+
+/// Gets the stack columns of a schema, by index.
+pub fn stack_columns(schema: &Schema) -> Vec<(usize, StackKind)> {
+    schema
+        .fields
+        .iter()
+        .enumerate()
+        .filter_map(|(i, f)| {
+            match f.data_type {
+                DataType::Stack(kind) => Some((i, kind)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Gets the map epoch and key of a stack id.
+fn stack_key(id: u64) -> (usize, u32) {
+    let id = id as u32;
+    ((id >> EPOCH_BIT) as usize, id & !(1 << EPOCH_BIT))
+}
+
+/// Resolves the stack ids of a program's output into symbolized stacks.
+pub struct StackResolver {
+    /// Stack trace maps, by epoch
+    maps: [MapHandle; 2],
+    /// Section holding the epoch stacks are collected into
+    epoch: MapHandle,
+    columns: Vec<(usize, StackKind)>,
+    kernel: Arc<KernelSymbols>,
+    user: UserSymbols,
+}
+
+impl StackResolver {
+    /// Creates a resolver of the stack columns of the schema, from the
+    /// program's stack trace maps and their epoch's section.
+    pub fn new(maps: [MapHandle; 2], epoch: MapHandle, schema: &Schema) -> Self {
+        Self {
+            maps,
+            epoch,
+            columns: stack_columns(schema),
+            kernel: KernelSymbols::shared(),
+            user: UserSymbols::new(),
+        }
+    }
+
+    /// Replaces the stack ids of the records by their stacks. Processes' memory
+    /// maps are read once per call, so stacks are best resolved by batch.
+    ///
+    /// Records are expected to be a window's batch: once its stacks are
+    /// resolved, the map of the window (i.e. of the epoch before the current
+    /// one) is cleared for the next window of that epoch.
+    pub fn resolve(&mut self, records: Vec<Record>) -> Vec<Record> {
+        let mut procs = HashMap::new();
+        let columns = self.columns.clone();
+        let records = records
+            .into_iter()
+            .map(|record| {
+                let mut values = record.to_vec();
+                for (i, kind) in &columns {
+                    if let Some(DataValue::UInt64(id)) = values.get(*i) {
+                        values[*i] = DataValue::Stack(self.stack(*kind, *id, &mut procs));
+                    }
+                }
+                Record::from(values)
+            })
+            .collect();
+        if let Err(e) = self.clear_ended() {
+            log::warn!("Failed to clear stack trace map: {e}");
+        }
+        records
+    }
+
+    /// Clears the stack trace map of the ended window.
+    fn clear_ended(&self) -> Result<()> {
+        let buf = self
+            .epoch
+            .lookup(&0u32.to_ne_bytes(), MapFlags::ANY)?
+            .ok_or_else(|| anyhow!("stack epoch section is empty"))?;
+        let epoch = buf
+            .get(..8)
+            .and_then(|b| b.try_into().ok())
+            .map_or(0, u64::from_ne_bytes);
+        let map = &self.maps[((epoch & 1) ^ 1) as usize];
+        // Keys are collected first, since deleting them restarts iteration
+        for key in map.keys().collect::<Vec<_>>() {
+            map.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Symbolizes the stack with the id. User stack ids carry their process in
+    /// their upper 32 bits.
+    fn stack(
+        &mut self,
+        kind: StackKind,
+        id: u64,
+        procs: &mut HashMap<u32, Option<Vec<MemoryMap>>>,
+    ) -> Stack {
+        let addrs = match id {
+            NO_STACK => Vec::new(),
+            _ => self.addresses(id),
+        };
+        // Return addresses point past their calls, which may be the start of
+        // the next function
+        let addrs = addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| if i == 0 { *addr } else { addr - 1 });
+        let frames = match kind {
+            StackKind::Kernel => {
+                addrs
+                    .map(|addr| {
+                        self.kernel
+                            .resolve(addr)
+                            .unwrap_or(UNKNOWN_FRAME)
+                            .to_string()
+                    })
+                    .collect()
+            }
+            StackKind::User => {
+                let pid = (id >> 32) as u32;
+                let maps = procs.entry(pid).or_insert_with(|| process_maps(pid));
+                addrs
+                    .map(|addr| {
+                        maps.as_deref()
+                            .and_then(|maps| self.user.resolve(pid, maps, addr))
+                            .unwrap_or_else(|| UNKNOWN_FRAME.to_string())
+                    })
+                    .collect()
+            }
+        };
+        Stack { kind, frames }
+    }
+
+    /// Reads the addresses of the stack with the id, innermost first.
+    fn addresses(&self, id: u64) -> Vec<u64> {
+        let (epoch, key) = stack_key(id);
+        let value = match self.maps[epoch].lookup(&key.to_ne_bytes(), MapFlags::ANY) {
+            Ok(Some(value)) => value,
+            Ok(None) => return Vec::new(),
+            Err(e) => {
+                log::warn!("Failed to read stack {id}: {e}");
+                return Vec::new();
+            }
+        };
+        value
+            .chunks_exact(8)
+            .take(MAX_STACK_DEPTH)
+            .map(|c| u64::from_ne_bytes(c.try_into().unwrap()))
+            .take_while(|addr| *addr != 0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_stack_ids_into_epoch_and_key() {
+        assert_eq!(stack_key(42), (0, 42));
+        assert_eq!(stack_key(1 << 31 | 42), (1, 42));
+        // User stacks carry their process in their upper bits
+        assert_eq!(stack_key(1234 << 32 | 1 << 31 | 7), (1, 7));
+        assert_eq!(stack_key(1234 << 32 | 7), (0, 7));
+    }
+}
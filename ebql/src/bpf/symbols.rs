@@ -0,0 +1,477 @@
+//! Symbolization of kernel addresses (from `/proc/kallsyms`) and of user
+//! addresses (from the ELF symbol tables of the files mapped by processes),
+//! for stacks and for the `ksym` and `sym` functions.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{Context, Result};
+use procfs::process::{MMapPath, MemoryMap, Process};
+
+use crate::{
+    data_types::DataType,
+    events::system::SystemVar,
+    record::{DataValue, Record},
+    schema::schema::Schema,
+    stack::StackKind,
+};
+
+/// Functions symbolizing an address column in user space: `ksym(addr)` for
+/// kernel addresses, and `sym(addr)` for addresses of the row's process (i.e.
+/// its `pid` column).
+pub const KSYM: &str = "ksym";
+pub const SYM: &str = "sym";
+
+/// ELF section types of symbol tables (SHT_SYMTAB and SHT_DYNSYM).
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+/// ELF segment type of loaded segments (PT_LOAD).
+const PT_LOAD: u32 = 1;
+/// ELF symbol type of functions (STT_FUNC).
+const STT_FUNC: u8 = 2;
+/// Size of an ELF64 symbol table entry.
+const SYM_SIZE: usize = 24;
+
+/// Function symbols, sorted by address.
+#[derive(Default)]
+struct SymbolTable {
+    /// Address, size (0 if unknown) and name of each symbol
+    syms: Vec<(u64, u64, String)>,
+}
+
+impl SymbolTable {
+    fn new(mut syms: Vec<(u64, u64, String)>) -> Self {
+        syms.sort_by_key(|(addr, ..)| *addr);
+        syms.dedup_by_key(|(addr, ..)| *addr);
+        Self { syms }
+    }
+
+    /// Finds the symbol containing the address, i.e. the last one at or below
+    /// it (and, if its size is known, within it), with the address' offset
+    /// into it.
+    fn find(&self, addr: u64) -> Option<(&str, u64)> {
+        let i = self
+            .syms
+            .partition_point(|(a, ..)| *a <= addr)
+            .checked_sub(1)?;
+        let (start, size, name) = &self.syms[i];
+        (*size == 0 || addr < start + size).then_some((name.as_str(), addr - start))
+    }
+}
+
+/// Kernel symbols, as listed by `/proc/kallsyms`. Addresses only show up to
+/// privileged readers; otherwise, no address is symbolized.
+#[derive(Default)]
+pub struct KernelSymbols {
+    table: SymbolTable,
+    /// Module of each module symbol, by address
+    modules: HashMap<u64, Arc<str>>,
+}
+
+impl KernelSymbols {
+    /// Reads the kernel's (and its modules') text symbols.
+    pub fn load() -> Result<Self> {
+        let text = fs::read_to_string("/proc/kallsyms").context("failed to read kallsyms")?;
+        Ok(Self::parse(&text))
+    }
+
+    /// Parses the text symbols of a listing of `/proc/kallsyms`.
+    fn parse(text: &str) -> Self {
+        let mut modules = HashMap::new();
+        let mut module: Option<Arc<str>> = None;
+        let syms = text
+            .lines()
+            .filter_map(|line| {
+                let mut cols = line.split_whitespace();
+                let addr = u64::from_str_radix(cols.next()?, 16).ok()?;
+                let ty = cols.next()?;
+                let name = cols.next()?;
+                if addr == 0 || !matches!(ty, "t" | "T") {
+                    return None;
+                }
+                // Symbols of a module are listed together, so its name is
+                // shared between them
+                if let Some(m) = cols.next().and_then(|m| m.strip_prefix('[')) {
+                    let m = m.trim_end_matches(']');
+                    if module.as_deref() != Some(m) {
+                        module = Some(Arc::from(m));
+                    }
+                    modules.insert(addr, module.clone()?);
+                }
+                Some((addr, 0, name.to_string()))
+            })
+            .collect();
+        Self {
+            table: SymbolTable::new(syms),
+            modules,
+        }
+    }
+
+    /// Gets the kernel's symbols, read once and shared by all queries. Kernel
+    /// addresses aren't symbolized if the symbols can't be read.
+    pub fn shared() -> Arc<KernelSymbols> {
+        static SYMBOLS: OnceLock<Arc<KernelSymbols>> = OnceLock::new();
+        SYMBOLS
+            .get_or_init(|| {
+                Arc::new(KernelSymbols::load().unwrap_or_else(|e| {
+                    log::warn!("Kernel addresses won't be symbolized: {e}");
+                    KernelSymbols::default()
+                }))
+            })
+            .clone()
+    }
+
+    /// Gets the name of the function containing the address.
+    pub fn resolve(&self, addr: u64) -> Option<&str> {
+        self.table.find(addr).map(|(name, _)| name)
+    }
+
+    /// Symbolizes the address as `symbol+offset`, followed by the symbol's
+    /// module if it isn't built into the kernel (e.g. `nf_hook_slow+0x44
+    /// [nf_tables]`).
+    pub fn symbolize(&self, addr: u64) -> Option<String> {
+        let (name, off) = self.table.find(addr)?;
+        let start = addr - off;
+        Some(match self.modules.get(&start) {
+            Some(module) => format!("{name}+{off:#x} [{module}]"),
+            None => format!("{name}+{off:#x}"),
+        })
+    }
+}
+
+/// Function symbols of an ELF file, and the segments it's loaded from.
+struct ElfSymbols {
+    /// File offset, virtual address and file size of each loaded segment
+    loads: Vec<(u64, u64, u64)>,
+    table: SymbolTable,
+}
+
+impl ElfSymbols {
+    /// Reads the symbols of a (64-bit, little-endian) ELF file, from both its
+    /// static and dynamic symbol tables.
+    fn read(path: &Path) -> Option<Self> {
+        let buf = fs::read(path).ok()?;
+        if buf.get(..4)? != b"\x7fELF" || buf[4] != 2 || buf[5] != 1 {
+            return None;
+        }
+        let (phoff, shoff) = (read_u64(&buf, 0x20)?, read_u64(&buf, 0x28)?);
+        let (phentsize, phnum) = (read_u16(&buf, 0x36)?, read_u16(&buf, 0x38)?);
+        let (shentsize, shnum) = (read_u16(&buf, 0x3a)?, read_u16(&buf, 0x3c)?);
+
+        let loads = (0..phnum as u64)
+            .filter_map(|i| {
+                let ph = (phoff + i * phentsize as u64) as usize;
+                (read_u32(&buf, ph)? == PT_LOAD).then_some((
+                    read_u64(&buf, ph + 8)?,
+                    read_u64(&buf, ph + 16)?,
+                    read_u64(&buf, ph + 32)?,
+                ))
+            })
+            .collect();
+
+        // Offset, size and linked section of each section
+        let sections = (0..shnum as u64)
+            .map(|i| {
+                let sh = (shoff + i * shentsize as u64) as usize;
+                Some((
+                    read_u32(&buf, sh + 4)?,
+                    read_u64(&buf, sh + 24)? as usize,
+                    read_u64(&buf, sh + 32)? as usize,
+                    read_u32(&buf, sh + 40)? as usize,
+                ))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let mut syms = Vec::new();
+        for (ty, off, size, link) in &sections {
+            if !matches!(*ty, SHT_SYMTAB | SHT_DYNSYM) {
+                continue;
+            }
+            let Some((_, str_off, str_size, _)) = sections.get(*link) else {
+                continue;
+            };
+            let Some(strtab) = buf.get(*str_off..str_off + str_size) else {
+                continue;
+            };
+            for sym in buf
+                .get(*off..off + size)
+                .into_iter()
+                .flat_map(|s| s.chunks_exact(SYM_SIZE))
+            {
+                let (name, info, shndx) = (read_u32(sym, 0)?, sym[4], read_u16(sym, 6)?);
+                let (value, size) = (read_u64(sym, 8)?, read_u64(sym, 16)?);
+                if info & 0xf != STT_FUNC || shndx == 0 || value == 0 {
+                    continue;
+                }
+                let Some(name) = strtab.get(name as usize..) else {
+                    continue;
+                };
+                let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+                syms.push((
+                    value,
+                    size,
+                    String::from_utf8_lossy(&name[..len]).into_owned(),
+                ));
+            }
+        }
+        Some(Self {
+            loads,
+            table: SymbolTable::new(syms),
+        })
+    }
+
+    /// Gets the name of the function at the offset of the file (and the
+    /// offset's offset into it), by the virtual address the offset is loaded
+    /// at.
+    fn resolve(&self, file_off: u64) -> Option<(&str, u64)> {
+        let (off, vaddr, _) = self
+            .loads
+            .iter()
+            .find(|(off, _, size)| (*off..off + size).contains(&file_off))?;
+        self.table.find(file_off - off + vaddr)
+    }
+}
+
+/// Symbols of the files mapped by processes. Files are read once, and kept by
+/// their device and inode, so that they're shared across processes.
+#[derive(Default)]
+pub struct UserSymbols {
+    files: HashMap<((i32, i32), u64), Option<ElfSymbols>>,
+}
+
+impl UserSymbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the name of the function containing an address of a process,
+    /// given its memory maps (see [`process_maps`]). Addresses without a
+    /// symbol are named after the file they're mapped from, e.g.
+    /// `[libc.so.6]`; those that aren't mapped from a file (e.g. JIT-compiled
+    /// code) aren't symbolized.
+    pub fn resolve(&mut self, pid: u32, maps: &[MemoryMap], addr: u64) -> Option<String> {
+        let (map, file_off) = self.lookup(pid, maps, addr)?;
+        Some(match self.symbol(map, file_off) {
+            Some((name, _)) => name.to_string(),
+            None => format!("[{}]", file_name(map)),
+        })
+    }
+
+    /// Symbolizes an address of a process as `symbol+offset [file]` (e.g.
+    /// `malloc+0x24 [libc.so.6]`), or by its offset into the file it's mapped
+    /// from if it has no symbol.
+    pub fn symbolize(&mut self, pid: u32, maps: &[MemoryMap], addr: u64) -> Option<String> {
+        let (map, file_off) = self.lookup(pid, maps, addr)?;
+        Some(match self.symbol(map, file_off) {
+            Some((name, off)) => format!("{name}+{off:#x} [{}]", file_name(map)),
+            None => format!("{file_off:#x} [{}]", file_name(map)),
+        })
+    }
+
+    /// Finds the file-backed map containing an address of a process, and the
+    /// address' offset into the file. The file's symbols are read on its first
+    /// lookup.
+    fn lookup<'a>(
+        &mut self,
+        pid: u32,
+        maps: &'a [MemoryMap],
+        addr: u64,
+    ) -> Option<(&'a MemoryMap, u64)> {
+        let map = maps
+            .iter()
+            .find(|m| (m.address.0..m.address.1).contains(&addr))?;
+        let MMapPath::Path(path) = &map.pathname else {
+            return None;
+        };
+        self.files.entry((map.dev, map.inode)).or_insert_with(|| {
+            // Files are opened through the process' root, as the process may
+            // live in another mount namespace
+            let root = PathBuf::from(format!("/proc/{pid}/root"));
+            ElfSymbols::read(&root.join(path.strip_prefix("/").unwrap_or(path)))
+        });
+        Some((map, addr - map.address.0 + map.offset))
+    }
+
+    /// Gets the symbol at an offset of a map's file, once looked up.
+    fn symbol(&self, map: &MemoryMap, file_off: u64) -> Option<(&str, u64)> {
+        self.files
+            .get(&(map.dev, map.inode))?
+            .as_ref()?
+            .resolve(file_off)
+    }
+}
+
+/// Gets the name of the file a map is mapped from.
+fn file_name(map: &MemoryMap) -> String {
+    match &map.pathname {
+        MMapPath::Path(path) => {
+            path.file_name()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy()
+                .into_owned()
+        }
+        _ => String::new(),
+    }
+}
+
+/// Resolves the symbolized address columns of a program's output (see
+/// [`DataType::Symbol`]) into their symbols. Addresses that can't be
+/// symbolized are output in hex.
+pub struct SymbolResolver {
+    columns: Vec<(usize, StackKind)>,
+    /// Index of the process column, by which user addresses are symbolized
+    pid: Option<usize>,
+    kernel: Arc<KernelSymbols>,
+    user: UserSymbols,
+}
+
+impl SymbolResolver {
+    pub fn new(schema: &Schema) -> Self {
+        let pid = SystemVar::PID.to_field()._name;
+        Self {
+            columns: symbol_columns(schema),
+            pid: schema.fields.iter().position(|f| f.name == pid),
+            kernel: KernelSymbols::shared(),
+            user: UserSymbols::new(),
+        }
+    }
+
+    /// Replaces the addresses of the records by their symbols. Processes'
+    /// memory maps are read once per call.
+    pub fn resolve(&mut self, records: Vec<Record>) -> Vec<Record> {
+        let mut procs = HashMap::new();
+        let columns = self.columns.clone();
+        records
+            .into_iter()
+            .map(|record| {
+                let mut values = record.to_vec();
+                let pid = self
+                    .pid
+                    .and_then(|i| values.get(i))
+                    .and_then(|v| v.as_i128());
+                for (i, kind) in &columns {
+                    let Some(addr) = values.get(*i).and_then(|v| v.as_i128()) else {
+                        continue;
+                    };
+                    let addr = addr as u64;
+                    let sym = match (kind, pid) {
+                        (StackKind::Kernel, _) => self.kernel.symbolize(addr),
+                        (StackKind::User, Some(pid)) => {
+                            let pid = pid as u32;
+                            let maps = procs.entry(pid).or_insert_with(|| process_maps(pid));
+                            maps.as_deref()
+                                .and_then(|maps| self.user.symbolize(pid, maps, addr))
+                        }
+                        (StackKind::User, None) => None,
+                    };
+                    let sym = sym.unwrap_or_else(|| format!("{addr:#x}"));
+                    values[*i] = DataValue::String(sym.clone(), sym.len());
+                }
+                Record::from(values)
+            })
+            .collect()
+    }
+}
+
+/// Gets the symbolized address columns of a schema, by index.
+pub fn symbol_columns(schema: &Schema) -> Vec<(usize, StackKind)> {
+    schema
+        .fields
+        .iter()
+        .enumerate()
+        .filter_map(|(i, f)| {
+            match f.data_type {
+                DataType::Symbol(kind) => Some((i, kind)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Reads the memory maps of a process, or None if it has exited.
+pub fn process_maps(pid: u32) -> Option<Vec<MemoryMap>> {
+    let maps = Process::new(pid as i32).and_then(|p| p.maps()).ok()?;
+    Some(maps.into_iter().collect())
+}
+
+fn read_u16(buf: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(buf.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn read_u32(buf: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn read_u64(buf: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(off..off + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KALLSYMS: &str = "\
+0000000000000000 T hidden_by_kptr_restrict
+ffffffff81000000 T _stext
+ffffffff81000100 t do_one_initcall
+ffffffff81000200 D some_data
+ffffffff81000300 T vfs_read
+ffffffffc0001000 t nft_do_chain\t[nf_tables]
+ffffffffc0001200 T nf_hook_slow\t[nf_tables]
+ffffffffc0002000 t ext4_read_folio\t[ext4]
+";
+
+    #[test]
+    fn parses_kernel_text_symbols() {
+        let syms = KernelSymbols::parse(KALLSYMS);
+        assert_eq!(syms.resolve(0xffffffff81000104), Some("do_one_initcall"));
+        // Data symbols and hidden addresses are skipped
+        assert_eq!(syms.resolve(0xffffffff81000204), Some("do_one_initcall"));
+        assert_eq!(syms.resolve(0xff), None);
+        assert_eq!(
+            syms.symbolize(0xffffffff81000310).as_deref(),
+            Some("vfs_read+0x10")
+        );
+        assert_eq!(
+            syms.symbolize(0xffffffffc0001244).as_deref(),
+            Some("nf_hook_slow+0x44 [nf_tables]")
+        );
+        assert_eq!(
+            syms.symbolize(0xffffffffc0002000).as_deref(),
+            Some("ext4_read_folio+0x0 [ext4]")
+        );
+    }
+
+    #[test]
+    fn finds_symbols_within_their_size() {
+        let table = SymbolTable::new(vec![
+            (0x300, 0, "unsized".into()),
+            (0x100, 0x10, "sized".into()),
+            (0x100, 0x20, "alias".into()),
+        ]);
+        assert_eq!(table.find(0xff), None);
+        assert_eq!(table.find(0x104), Some(("sized", 4)));
+        assert_eq!(table.find(0x110), None);
+        assert_eq!(table.find(0x1300), Some(("unsized", 0x1000)));
+    }
+
+    #[inline(never)]
+    fn symbolized_marker() -> u64 {
+        symbolized_marker as usize as u64
+    }
+
+    #[test]
+    fn symbolizes_own_functions() {
+        let pid = std::process::id();
+        let maps = process_maps(pid).unwrap();
+        let mut user = UserSymbols::new();
+        let name = user.resolve(pid, &maps, symbolized_marker()).unwrap();
+        assert!(name.contains("symbolized_marker"), "{name}");
+        // Anonymous memory isn't symbolized
+        let local = 0u64;
+        assert_eq!(user.resolve(pid, &maps, &local as *const u64 as u64), None);
+    }
+}
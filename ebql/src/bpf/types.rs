@@ -2,6 +2,10 @@
 
 use std::{cmp::Ordering, fmt::Display};
 
+use anyhow::{bail, Result};
+
+use crate::record::DataValue;
+
 /// Representation of a struct field in BPF (C).
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Field {
@@ -153,6 +157,27 @@ impl Type {
     }
 }
 
+impl Type {
+    /// Encodes a value as this (integer) type, in the (little endian) byte
+    /// order of the target.
+    pub fn encode(&self, v: &DataValue) -> Result<Vec<u8>> {
+        use self::Type::*;
+        let size = self.size();
+        let (min, max) = match self {
+            S8 | S16 | S32 | S64 | SChar => {
+                (-(1i128 << (size * 8 - 1)), (1i128 << (size * 8 - 1)) - 1)
+            }
+            Bool | U8 | U16 | U32 | U64 | UChar => (0, (1i128 << (size * 8)) - 1),
+            _ => bail!("cannot encode values of type {self}"),
+        };
+        match v.as_i128() {
+            Some(v) if v >= min && v <= max => Ok(v.to_le_bytes()[..size].to_vec()),
+            Some(v) => bail!("value {v} out of range for type {self}"),
+            None => bail!("cannot encode {v} as type {self}"),
+        }
+    }
+}
+
 impl Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use self::Type::*;
@@ -0,0 +1,156 @@
+//! Work directories that programs are compiled in: each compilation writes its
+//! sources and object files into a directory of its own, so that concurrent
+//! compilations (in one process or several) never see each other's files.
+//!
+//! Work roots are typically shared (e.g. under `/tmp`), while programs are
+//! compiled and loaded as root, so work directories are private (mode 0700)
+//! and randomly named, and roots are only used if no other user can tamper
+//! with their entries.
+
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use rand::distributions::{Alphanumeric, DistString};
+
+/// Name of the default directory (under the system's temporary directory) that
+/// work directories are created in.
+const WORK_ROOT: &str = "ebql";
+
+/// Permissions of the work directories (and of work roots created for them).
+const PRIVATE_MODE: u32 = 0o700;
+
+/// Gets the default directory that work directories are created in.
+pub fn default_work_root() -> PathBuf {
+    env::temp_dir().join(WORK_ROOT)
+}
+
+/// Directory holding the generated code and object files of one compilation.
+/// It's removed (with its contents) once dropped, i.e. once the compiled
+/// object is.
+#[derive(Debug)]
+pub struct WorkDir {
+    path: PathBuf,
+}
+
+impl WorkDir {
+    /// Creates a private work directory for the named program under `root`,
+    /// which is created (privately) if it's missing. Directories are named
+    /// after the program and a random suffix, and are never reused.
+    pub fn create(root: &Path, name: &str) -> Result<Self> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(PRIVATE_MODE)
+            .create(root)
+            .with_context(|| format!("failed to create work root {}", root.display()))?;
+        check_root(root)?;
+        loop {
+            let suffix = Alphanumeric.sample_string(&mut rand::thread_rng(), 10);
+            let path = root.join(format!("{name}_{suffix}"));
+            // Fails on existing entries (including symlinks), rather than
+            // reusing them
+            match DirBuilder::new().mode(PRIVATE_MODE).create(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("failed to create work directory {}", path.display())
+                    })
+                }
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            log::warn!(
+                "Failed to remove work directory {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Checks that no other user can replace the entries of a work root: it must
+/// be a directory (not a symlink) owned by the current user (or root), and
+/// only writable by others if it's sticky (like `/tmp`).
+fn check_root(root: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(root)
+        .with_context(|| format!("failed to read work root {}", root.display()))?;
+    if !meta.is_dir() {
+        bail!("work root {} is not a directory", root.display());
+    }
+    let euid = unsafe { libc::geteuid() };
+    if meta.uid() != euid && meta.uid() != 0 {
+        bail!(
+            "work root {} is owned by user {}, not by the current user",
+            root.display(),
+            meta.uid()
+        );
+    }
+    if meta.mode() & 0o022 != 0 && meta.mode() & 0o1000 == 0 {
+        bail!(
+            "work root {} is writable by other users (mode {:o})",
+            root.display(),
+            meta.mode() & 0o7777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn creates_private_work_dirs() {
+        let root = env::temp_dir().join(format!("ebql_work_dir_test_{}", std::process::id()));
+        let (a, b) = (
+            WorkDir::create(&root, "query").unwrap(),
+            WorkDir::create(&root, "query").unwrap(),
+        );
+        let root_mode = fs::metadata(&root).unwrap().mode() & 0o777;
+        let mode = fs::metadata(a.path()).unwrap().mode() & 0o777;
+        let (a_path, b_path) = (a.path().to_path_buf(), b.path().to_path_buf());
+        drop((a, b));
+        let removed = !a_path.exists() && !b_path.exists();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(root_mode, PRIVATE_MODE);
+        assert_eq!(mode, PRIVATE_MODE);
+        assert_ne!(a_path, b_path);
+        assert!(a_path.starts_with(&root));
+        assert!(removed);
+    }
+
+    #[test]
+    fn rejects_unsafe_roots() {
+        let base = env::temp_dir().join(format!("ebql_work_root_test_{}", std::process::id()));
+        let (shared, link) = (base.join("shared"), base.join("link"));
+        fs::create_dir_all(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+        symlink(&shared, &link).unwrap();
+        let shared_err = WorkDir::create(&shared, "query").is_err();
+        let link_err = WorkDir::create(&link, "query").is_err();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        let sticky = WorkDir::create(&shared, "query").map(|dir| dir.path().is_dir());
+        fs::remove_dir_all(&base).unwrap();
+
+        assert!(shared_err);
+        assert!(link_err);
+        assert!(sticky.unwrap());
+    }
+}
@@ -0,0 +1,133 @@
+//! Errors raised while planning, compiling, and running queries.
+//!
+//! Library functions return [`anyhow::Result`]; errors that stem from a query
+//! (rather than e.g. I/O) are [`EbqlError`]s, which callers can recover with
+//! [`anyhow::Error::downcast_ref`] to report which part of the query failed.
+
+use std::fmt;
+
+/// Error raised by a query, pointing at the fragment of the query that caused
+/// it. For errors past code generation (i.e. clang, verifier, and attach
+/// errors), the fragment is the name of the query's program.
+#[derive(Clone, Debug)]
+pub enum EbqlError {
+    /// The query is not valid SQL.
+    Parse { fragment: String, msg: String },
+    /// The query refers to an unknown event or field, or to a field of the
+    /// wrong type.
+    Bind { fragment: String, msg: String },
+    /// The query is valid, but uses a feature that is not supported.
+    Unsupported { fragment: String, msg: String },
+    /// The plan could not be translated into a BPF program.
+    Codegen { fragment: String, msg: String },
+    /// Clang failed to compile the generated program.
+    Clang { fragment: String, msg: String },
+    /// The kernel failed to load the compiled program (usually because the
+    /// verifier rejected it).
+    Verifier { fragment: String, msg: String },
+    /// The loaded program could not be attached to its event.
+    Attach { fragment: String, msg: String },
+}
+
+impl EbqlError {
+    pub fn parse(fragment: impl ToString, msg: impl Into<String>) -> Self {
+        Self::Parse {
+            fragment: fragment.to_string(),
+            msg: msg.into(),
+        }
+    }
+
+    pub fn bind(fragment: impl ToString, msg: impl Into<String>) -> Self {
+        Self::Bind {
+            fragment: fragment.to_string(),
+            msg: msg.into(),
+        }
+    }
+
+    pub fn unsupported(fragment: impl ToString, msg: impl Into<String>) -> Self {
+        Self::Unsupported {
+            fragment: fragment.to_string(),
+            msg: msg.into(),
+        }
+    }
+
+    pub fn codegen(fragment: impl ToString, msg: impl Into<String>) -> Self {
+        Self::Codegen {
+            fragment: fragment.to_string(),
+            msg: msg.into(),
+        }
+    }
+
+    pub fn clang(fragment: impl ToString, msg: impl Into<String>) -> Self {
+        Self::Clang {
+            fragment: fragment.to_string(),
+            msg: msg.into(),
+        }
+    }
+
+    pub fn verifier(fragment: impl ToString, msg: impl Into<String>) -> Self {
+        Self::Verifier {
+            fragment: fragment.to_string(),
+            msg: msg.into(),
+        }
+    }
+
+    pub fn attach(fragment: impl ToString, msg: impl Into<String>) -> Self {
+        Self::Attach {
+            fragment: fragment.to_string(),
+            msg: msg.into(),
+        }
+    }
+
+    /// Gets the fragment of the query that caused the error.
+    pub fn fragment(&self) -> &str {
+        match self {
+            Self::Parse { fragment, .. }
+            | Self::Bind { fragment, .. }
+            | Self::Unsupported { fragment, .. }
+            | Self::Codegen { fragment, .. }
+            | Self::Clang { fragment, .. }
+            | Self::Verifier { fragment, .. }
+            | Self::Attach { fragment, .. } => fragment,
+        }
+    }
+
+    /// Gets the error message.
+    pub fn msg(&self) -> &str {
+        match self {
+            Self::Parse { msg, .. }
+            | Self::Bind { msg, .. }
+            | Self::Unsupported { msg, .. }
+            | Self::Codegen { msg, .. }
+            | Self::Clang { msg, .. }
+            | Self::Verifier { msg, .. }
+            | Self::Attach { msg, .. } => msg,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Parse { .. } => "parse error",
+            Self::Bind { .. } => "bind error",
+            Self::Unsupported { .. } => "unsupported",
+            Self::Codegen { .. } => "codegen error",
+            Self::Clang { .. } => "clang error",
+            Self::Verifier { .. } => "verifier error",
+            Self::Attach { .. } => "attach error",
+        }
+    }
+}
+
+impl fmt::Display for EbqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} (at `{}`)",
+            self.kind(),
+            self.msg(),
+            self.fragment()
+        )
+    }
+}
+
+impl std::error::Error for EbqlError {}
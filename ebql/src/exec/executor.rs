@@ -1,73 +1,332 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+};
 
-use anyhow::{Context, Result};
-use crossbeam::channel::Receiver;
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam::channel::{unbounded, Receiver, Sender};
 
 use super::{
     bpf_stats::{get_bpf_stats, BpfProgramStats},
+    query_handle::QueryHandle,
     query_stats::{QueryStats, UserspaceStats},
+    user_ops,
 };
 use crate::{
-    bpf_ops::compiler::QueryCompiler, object::Object, parser, record_batch::RecordBatch,
-    PhysicalPlan, Schema,
+    bpf_ops::{
+        agg::{AggMaps, OverflowPolicy},
+        compiler::QueryCompiler,
+        synopsis::Synopses,
+        window::WindowFlush,
+    },
+    data_types::Clock,
+    error::EbqlError,
+    object::{Object, StoppingProg},
+    parser,
+    projection::Projection,
+    record::DataValue,
+    record_batch::RecordBatch,
+    BpfPlan, PhysicalPlan, Schema, UserPlan,
 };
 
+/// Most batches buffered for a paused query. Once reached, the oldest batches
+/// are dropped (and counted) to make room for new ones, so that a query paused
+/// indefinitely doesn't buffer its program's output indefinitely.
+const MAX_BUFFERED_BATCHES: usize = 1024;
+
+/// A query reading from a (possibly shared) program's output stream.
+struct Subscriber {
+    /// Name of the query
+    query: String,
+    /// Projection from the program's output into the query's schema
+    projection: Projection,
+    /// Sender into the query's output stream
+    tx: Sender<RecordBatch>,
+    /// Whether the query is paused, i.e. its batches are buffered rather than
+    /// sent
+    paused: bool,
+    /// Batches output while the query is paused, sent once it's resumed (up
+    /// to [`MAX_BUFFERED_BATCHES`], the latest)
+    buffered: VecDeque<RecordBatch>,
+    /// Batches dropped from the buffer since the query was paused
+    dropped: u64,
+}
+
+impl Subscriber {
+    /// Buffers a batch output while paused, dropping the oldest batch if the
+    /// buffer is full.
+    fn buffer(&mut self, rb: RecordBatch) {
+        if self.buffered.len() >= MAX_BUFFERED_BATCHES {
+            self.buffered.pop_front();
+            self.dropped += 1;
+        }
+        self.buffered.push_back(rb);
+    }
+
+    /// Sends the batches buffered while paused, then resumes sending batches
+    /// as they're output.
+    fn resume(&mut self) {
+        if self.dropped > 0 {
+            log::warn!(
+                "Dropped {} batches of query {} while it was paused",
+                self.dropped,
+                self.query
+            );
+            self.dropped = 0;
+        }
+        for rb in self.buffered.drain(..) {
+            // Closed streams are dropped on the next batch
+            let _ = self.tx.send(rb);
+        }
+        self.paused = false;
+    }
+}
+
+type Subscribers = Arc<Mutex<Vec<Subscriber>>>;
+
+/// Executes extended-SQL queries, each returning a [`QueryHandle`] to its
+/// output stream and lifecycle. Queries can be submitted from multiple threads:
+/// they're compiled concurrently, and only attached one at a time.
 pub struct Executor {
-    pub prog_streams: HashMap<String, Receiver<RecordBatch>>,
+    /// Compiler for queries submitted to this executor
+    qc: QueryCompiler,
+    state: Arc<Mutex<ExecState>>,
 }
 
-impl Executor {
-    pub fn new(obj: Object) -> Result<Self> {
-        let mut obj = obj;
-        obj.attach_progs()?;
+/// Programs and queries of an executor, shared with the handles of its
+/// queries so that they outlive the executor.
+pub(super) struct ExecState {
+    /// Synopses of the executor's programs, for queries to share
+    synopses: Synopses,
+    /// Loaded objects, kept alive for as long as their programs are attached
+    objs: Vec<Object>,
+    /// Output stream of each attached program
+    prog_streams: HashMap<String, Receiver<RecordBatch>>,
+    /// Queries reading from each program's output
+    subscribers: HashMap<String, Subscribers>,
+    /// Program computing each query, by the query's output schema name
+    query_progs: HashMap<String, String>,
+}
 
-        // Add program streams to hash map
-        let mut prog_streams = HashMap::new();
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        for (prog_name, prog) in &obj.progs {
-            if let Some(out_rx) = &prog.out_rx {
-                prog_streams.insert(prog_name.clone(), out_rx.clone());
-            }
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            qc: QueryCompiler::new(),
+            state: Arc::new(Mutex::new(ExecState {
+                synopses: Synopses::new(),
+                objs: Vec::new(),
+                prog_streams: HashMap::new(),
+                subscribers: HashMap::new(),
+                query_progs: HashMap::new(),
+            })),
         }
+    }
 
-        Ok(Self { prog_streams })
+    /// Sets the clock that queries submitted to this executor read the time of
+    /// events from.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.qc = self.qc.with_clock(clock);
+        self
     }
 
-    pub fn attach(&mut self, obj: Object) -> Result<()> {
-        let mut obj = obj;
-        obj.attach_progs()?;
+    /// Sets how queries submitted to this executor flush their time windows.
+    pub fn with_window_flush(mut self, flush: WindowFlush) -> Self {
+        self.qc = self.qc.with_window_flush(flush);
+        self
+    }
 
-        // Add program streams to hash map
-        let mut prog_streams = HashMap::new();
+    /// Sets whether count queries submitted to this executor emit zero rows
+    /// for groups without events in a window.
+    pub fn with_empty_windows(mut self, emit_empty: bool) -> Self {
+        self.qc = self.qc.with_empty_windows(emit_empty);
+        self
+    }
 
-        for (prog_name, prog) in &obj.progs {
-            if let Some(out_rx) = &prog.out_rx {
-                prog_streams.insert(prog_name.clone(), out_rx.clone());
-            }
-        }
+    /// Sets which maps queries submitted to this executor keep their
+    /// aggregations in.
+    pub fn with_agg_maps(mut self, agg_maps: AggMaps) -> Self {
+        self.qc = self.qc.with_agg_maps(agg_maps);
+        self
+    }
 
-        Ok(())
+    /// Sets what happens to new groups of queries submitted to this executor
+    /// once their aggregations keep `max_groups` groups.
+    pub fn with_overflow(mut self, overflow: OverflowPolicy, max_groups: u64) -> Self {
+        self.qc = self.qc.with_overflow(overflow).with_max_groups(max_groups);
+        self
+    }
+
+    /// Sets the directory under which queries submitted to this executor are
+    /// compiled, each in its own work directory that's removed once the query
+    /// (and any other query sharing its program) stops.
+    pub fn with_work_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.qc = self.qc.with_work_root(root);
+        self
     }
 
     /// Executes an extended-SQL query.
-    pub fn execute_query(
-        &mut self,
-        sql_query: String,
-    ) -> Result<(Arc<Schema>, Receiver<RecordBatch>)> {
-        let s = parser::parse_query(sql_query).context("failed to parse SQL query")?;
-        let physical_plan = PhysicalPlan::from_select(s).unwrap();
-        let bpf_plan = &physical_plan.event_plans[0];
+    pub fn execute_query(&self, sql_query: String) -> Result<QueryHandle> {
+        if parser::parse_explain(&sql_query).0.is_some() {
+            bail!(EbqlError::unsupported(
+                sql_query,
+                "EXPLAIN statements are not executed; see query::explain"
+            ));
+        }
+        let defaults = parser::parse_param_defaults(&sql_query)?;
+        let (s, nested) =
+            parser::parse_nested_query(sql_query).context("failed to parse SQL query")?;
+        let physical_plan = PhysicalPlan::from_nested(s, nested)?.with_param_defaults(defaults)?;
+        let bpf_plan = physical_plan.bpf_plan()?;
+        let query = query_name(&physical_plan)?;
 
         let schema = bpf_plan.schema.clone();
 
-        let mut qc = QueryCompiler {};
-        let obj = qc.compile_bpf_ops(bpf_plan).unwrap();
+        // Read from an existing synopsis, if one already computes this query
+        let shared = self.state.lock().unwrap().share(bpf_plan, &query)?;
+        let (prog, rx) = match shared {
+            Some(shared) => shared,
+            None => {
+                // Compile without holding the lock, so that queries submitted
+                // from other threads compile concurrently
+                let obj = self.qc.compile_bpf_ops(bpf_plan)?;
 
-        self.attach(obj)?;
+                let mut state = self.state.lock().unwrap();
+                // Another thread may have compiled a synopsis answering this
+                // query in the meantime, in which case the object is dropped
+                match state.share(bpf_plan, &query)? {
+                    Some(shared) => shared,
+                    None => {
+                        state.attach(obj)?;
+                        state.synopses.register(bpf_plan, 1);
 
-        let rx = self.prog_streams.get(&bpf_plan.schema.name).unwrap();
+                        let rx = state.subscribe(
+                            &bpf_plan.schema.name,
+                            &query,
+                            Projection::identity(schema.clone()),
+                        )?;
+                        (bpf_plan.schema.name.clone(), rx)
+                    }
+                }
+            }
+        };
 
-        Ok((schema, rx.clone()))
+        // The query is stopped (releasing its share of the program) if its
+        // user-space plan fails to start
+        self.state
+            .lock()
+            .unwrap()
+            .query_progs
+            .insert(query.clone(), prog);
+        match with_user_plan(physical_plan.user_plan, schema, rx) {
+            Ok((schema, rx)) => Ok(QueryHandle::new(schema, rx, self.state.clone())),
+            Err(e) => {
+                self.stop_queries(&[query]);
+                Err(e)
+            }
+        }
+    }
+
+    /// Executes a set of extended-SQL queries together. Queries that aggregate
+    /// over the same state are compiled into one shared program, and each
+    /// query's stream projects out its own columns. Handles are returned in
+    /// the order of the input queries.
+    pub fn execute_queries(&self, sql_queries: Vec<String>) -> Result<Vec<QueryHandle>> {
+        let mut plans = vec![];
+        let mut user_plans = vec![];
+        let mut queries = vec![];
+        for sql_query in sql_queries {
+            let defaults = parser::parse_param_defaults(&sql_query)?;
+            let (s, nested) =
+                parser::parse_nested_query(sql_query).context("failed to parse SQL query")?;
+            let physical_plan =
+                PhysicalPlan::from_nested(s, nested)?.with_param_defaults(defaults)?;
+            queries.push(query_name(&physical_plan)?);
+            plans.push(physical_plan.bpf_plan()?.clone());
+            user_plans.push(physical_plan.user_plan);
+        }
+
+        // Shared plans are compiled concurrently, each on its own thread
+        let shared_plans = self.qc.merge_plans(&plans)?;
+        let objs = thread::scope(|scope| {
+            let compiles = shared_plans
+                .iter()
+                .map(|shared| scope.spawn(|| self.qc.compile_bpf_ops(&shared.plan)))
+                .collect::<Vec<_>>();
+            compiles
+                .into_iter()
+                .zip(&shared_plans)
+                .map(|(compile, shared)| {
+                    compile.join().map_err(|_| {
+                        anyhow!("compilation of {} panicked", shared.plan.schema.name)
+                    })?
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        // Queries are recorded as running as soon as their program is
+        // attached, so that if a later program fails to attach, the programs
+        // attached so far (and their synopses) are stopped along with them
+        let mut state = self.state.lock().unwrap();
+        let mut streams = vec![None; plans.len()];
+        let mut attached = vec![];
+        let attach = (|| {
+            for (shared, obj) in shared_plans.into_iter().zip(objs) {
+                let prog = &shared.plan.schema.name;
+                state.attach(obj)?;
+                state.synopses.register(&shared.plan, shared.members.len());
+                for (i, _) in &shared.members {
+                    state.query_progs.insert(queries[*i].clone(), prog.clone());
+                    attached.push(queries[*i].clone());
+                }
+
+                for (i, projection) in shared.members {
+                    let rx = state.subscribe(prog, &queries[i], projection)?;
+                    streams[i] = Some((plans[i].schema.clone(), rx));
+                }
+            }
+            Ok(())
+        })();
+        drop(state);
+        if let Err(e) = attach {
+            self.stop_queries(&attached);
+            return Err(e);
+        }
+
+        // Queries are stopped (releasing their share of their programs) if
+        // any user-space plan fails to start; those with handles already
+        // stop once their handles are dropped
+        let mut handles = Vec::with_capacity(queries.len());
+        for (i, (stream, user_plan)) in streams.into_iter().zip(user_plans).enumerate() {
+            let started = stream
+                .context("query was not compiled")
+                .and_then(|(schema, rx)| with_user_plan(user_plan, schema, rx));
+            match started {
+                Ok((schema, rx)) => handles.push(QueryHandle::new(schema, rx, self.state.clone())),
+                Err(e) => {
+                    self.stop_queries(&queries[i..]);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    /// Stops queries that have no handles, e.g. those that failed to start.
+    fn stop_queries(&self, queries: &[String]) {
+        for query in queries {
+            let stopped = self.state.lock().unwrap().stop_query(query);
+            if let Err(e) = stopped.and_then(StoppedQuery::finish) {
+                log::warn!("Failed to stop query {query}: {e}");
+            }
+        }
     }
 
     pub fn get_program_stats(&self, prog: String) -> Option<QueryStats> {
@@ -80,6 +339,295 @@ impl Executor {
             _ => progs[0].clone(),
         };
 
-        Some(QueryStats::new(UserspaceStats::new(), bpf_prog))
+        let state = self.state.lock().unwrap();
+        let overflow = state
+            .objs
+            .iter()
+            .find(|obj| obj.progs.contains_key(&prog))
+            .and_then(|obj| {
+                obj.overflow_stats()
+                    .map_err(|e| log::warn!("Failed to read overflow stats of {prog}: {e}"))
+                    .ok()
+                    .flatten()
+            });
+        Some(QueryStats::new(UserspaceStats::new(), bpf_prog).with_overflow(overflow))
+    }
+}
+
+impl ExecState {
+    /// Subscribes a query to an existing synopsis answering its plan, if any,
+    /// returning the synopsis' program and the query's stream.
+    fn share(
+        &mut self,
+        plan: &BpfPlan,
+        query: &str,
+    ) -> Result<Option<(String, Receiver<RecordBatch>)>> {
+        let Some((prog, projection)) = self.synopses.share(plan) else {
+            return Ok(None);
+        };
+        let rx = self.subscribe(&prog, query, projection)?;
+        // The program may have been paused by its other readers
+        self.obj_mut(&prog)?.resume_prog(&prog)?;
+        Ok(Some((prog, rx)))
+    }
+
+    /// Attaches the programs of a loaded object, and keeps the object (and
+    /// so its links) until they are all stopped.
+    fn attach(&mut self, obj: Object) -> Result<()> {
+        let mut obj = obj;
+        obj.attach_progs()?;
+
+        // Add program streams to hash map
+        for (prog_name, prog) in &obj.progs {
+            if let Some(out_rx) = &prog.out_rx {
+                self.prog_streams.insert(prog_name.clone(), out_rx.clone());
+            }
+        }
+        self.objs.push(obj);
+
+        Ok(())
+    }
+
+    /// Subscribes a query to a program's output, projected onto the query's
+    /// schema. The first subscription to a program starts a thread that fans
+    /// out the program's output to every subscriber.
+    fn subscribe(
+        &mut self,
+        prog: &str,
+        query: &str,
+        projection: Projection,
+    ) -> Result<Receiver<RecordBatch>> {
+        let (tx, rx) = unbounded();
+        let sub = Subscriber {
+            query: query.to_string(),
+            projection,
+            tx,
+            paused: false,
+            buffered: VecDeque::new(),
+            dropped: 0,
+        };
+        if let Some(subs) = self.subscribers.get(prog) {
+            subs.lock().unwrap().push(sub);
+            return Ok(rx);
+        }
+
+        let prog_rx = self
+            .prog_streams
+            .get(prog)
+            .with_context(|| format!("program {prog} is not attached"))?
+            .clone();
+        let subs = Arc::new(Mutex::new(vec![sub]));
+        thread::spawn({
+            let subs = subs.clone();
+            move || {
+                while let Ok(rb) = prog_rx.recv() {
+                    // Drop subscribers whose streams have been closed
+                    subs.lock().unwrap().retain_mut(|sub| {
+                        let rb = sub.projection.project(&rb);
+                        match sub.paused {
+                            true => {
+                                sub.buffer(rb);
+                                true
+                            }
+                            false => sub.tx.send(rb).is_ok(),
+                        }
+                    });
+                }
+            }
+        });
+        self.subscribers.insert(prog.to_string(), subs);
+
+        Ok(rx)
+    }
+
+    /// Gets the program computing a running query.
+    fn query_prog(&self, query: &str) -> Result<String> {
+        self.query_progs
+            .get(query)
+            .cloned()
+            .with_context(|| format!("query {query} is not running"))
+    }
+
+    /// Gets the object a program was loaded in.
+    fn obj_mut(&mut self, prog: &str) -> Result<&mut Object> {
+        self.objs
+            .iter_mut()
+            .find(|obj| obj.progs.contains_key(prog))
+            .with_context(|| format!("program {prog} is not loaded"))
+    }
+
+    /// Sets a parameter (`$name`) of a running query. The new value takes
+    /// effect without recompiling or reattaching the query's program.
+    pub(super) fn set_param(&mut self, query: &str, name: &str, value: DataValue) -> Result<()> {
+        let prog = self.query_prog(query)?;
+        self.obj_mut(&prog)?.set_param(name, &value)
+    }
+
+    /// Pauses a running query: its stream stops receiving batches, which are
+    /// buffered until it's resumed (keeping the latest
+    /// [`MAX_BUFFERED_BATCHES`]), and once all readers of its program are
+    /// paused, the program is detached. The program's maps are kept, so that
+    /// it picks up from its state once resumed.
+    pub(super) fn pause_query(&mut self, query: &str) -> Result<()> {
+        let prog = self.query_prog(query)?;
+        if let Some(subs) = self.subscribers.get(&prog) {
+            let mut subs = subs.lock().unwrap();
+            for sub in subs.iter_mut().filter(|sub| sub.query == query) {
+                sub.paused = true;
+            }
+            if !subs.iter().all(|sub| sub.paused) {
+                return Ok(());
+            }
+        }
+        self.obj_mut(&prog)?.pause_prog(&prog)
+    }
+
+    /// Resumes a paused query, sending the batches buffered while it was
+    /// paused, and reattaching its program if it was detached.
+    pub(super) fn resume_query(&mut self, query: &str) -> Result<()> {
+        let prog = self.query_prog(query)?;
+        if let Some(subs) = self.subscribers.get(&prog) {
+            let mut subs = subs.lock().unwrap();
+            for sub in subs.iter_mut().filter(|sub| sub.query == query) {
+                sub.resume();
+            }
+        }
+        self.obj_mut(&prog)?.resume_prog(&prog)
+    }
+
+    /// Stops a running query, closing its stream (after the batches buffered
+    /// while it was paused, if any). If the query is the last reader of its
+    /// program, the program is detached, and once the returned
+    /// [`StoppedQuery`] is finished, its open window is flushed into the
+    /// stream (as a partial batch) before the stream closes; otherwise, the
+    /// program keeps running for its other readers. Objects are dropped once
+    /// all of their programs are stopped.
+    pub(super) fn stop_query(&mut self, query: &str) -> Result<StoppedQuery> {
+        let prog = self
+            .query_progs
+            .remove(query)
+            .with_context(|| format!("query {query} is not running"))?;
+
+        if !self.synopses.release(&prog) {
+            // Dropping the query's subscriber closes its stream
+            let all_paused = match self.subscribers.get(&prog) {
+                Some(subs) => {
+                    let mut subs = subs.lock().unwrap();
+                    subs.retain_mut(|sub| {
+                        if sub.query != query {
+                            return true;
+                        }
+                        sub.resume();
+                        false
+                    });
+                    subs.iter().all(|sub| sub.paused)
+                }
+                None => false,
+            };
+            if all_paused {
+                self.obj_mut(&prog)?.pause_prog(&prog)?;
+            }
+            return Ok(StoppedQuery::default());
+        }
+
+        // The final flush is fanned out to the query, after which the program's
+        // channel (and so the query's stream) closes
+        if let Some(subs) = self.subscribers.get(&prog) {
+            for sub in subs.lock().unwrap().iter_mut() {
+                sub.resume();
+            }
+        }
+        let idx = self
+            .objs
+            .iter()
+            .position(|obj| obj.progs.contains_key(&prog))
+            .with_context(|| format!("program {prog} is not loaded"))?;
+        let stopping = self.objs[idx].stop_prog(&prog);
+        self.prog_streams.remove(&prog);
+        self.subscribers.remove(&prog);
+        let obj = match self.objs[idx].progs.values().all(|p| p.poller.is_none()) {
+            true => Some(self.objs.remove(idx)),
+            false => None,
+        };
+        Ok(StoppedQuery {
+            prog: Some(stopping?),
+            obj,
+        })
+    }
+}
+
+/// Program of a stopped query, whose threads are joined (and open window
+/// flushed) once [finished](StoppedQuery::finish). Finishing waits on the
+/// program's threads, so it's done once the executor's state is unlocked.
+#[derive(Default)]
+pub(super) struct StoppedQuery {
+    /// Program stopped with the query, if it was its last reader
+    prog: Option<StoppingProg>,
+    /// Object of the program, if none of its programs are left running; it's
+    /// dropped once the program is finished
+    obj: Option<Object>,
+}
+
+impl StoppedQuery {
+    /// Flushes the open window of the query's program (if stopped with the
+    /// query), then closes its stream.
+    pub(super) fn finish(self) -> Result<()> {
+        let finished = match self.prog {
+            Some(prog) => prog.finish(),
+            None => Ok(()),
+        };
+        drop(self.obj);
+        finished
+    }
+}
+
+/// Gets the name of a query, i.e. the name of its output schema.
+fn query_name(plan: &PhysicalPlan) -> Result<String> {
+    match &plan.user_plan {
+        Some(user_plan) => Ok(user_plan.schema.name.clone()),
+        None => Ok(plan.bpf_plan()?.schema.name.clone()),
+    }
+}
+
+/// Runs the user-space plan (if any) over a BPF program's output stream, and
+/// returns the resulting schema and stream.
+fn with_user_plan(
+    user_plan: Option<UserPlan>,
+    schema: Arc<Schema>,
+    rx: Receiver<RecordBatch>,
+) -> Result<(Arc<Schema>, Receiver<RecordBatch>)> {
+    Ok(match user_plan {
+        Some(plan) => (plan.schema.clone(), user_ops::execute(plan, rx)?),
+        None => (schema, rx),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_oldest_buffered_batches() {
+        let schema = Arc::new(Schema::default());
+        let (tx, rx) = unbounded();
+        let mut sub = Subscriber {
+            query: "q".into(),
+            projection: Projection::identity(schema.clone()),
+            tx,
+            paused: true,
+            buffered: VecDeque::new(),
+            dropped: 0,
+        };
+        for error in 0..MAX_BUFFERED_BATCHES + 2 {
+            sub.buffer(RecordBatch::new(schema.clone(), vec![]).with_error(error.to_string()));
+        }
+        assert_eq!(sub.buffered.len(), MAX_BUFFERED_BATCHES);
+        assert_eq!(sub.dropped, 2);
+
+        sub.resume();
+        let errors = rx.try_iter().map(|rb| rb.error).collect::<Vec<_>>();
+        assert_eq!(errors.len(), MAX_BUFFERED_BATCHES);
+        assert_eq!(errors[0].as_deref(), Some("2"));
+        assert_eq!(sub.dropped, 0);
     }
 }
@@ -1,3 +1,6 @@
 pub mod bpf_stats;
 pub mod executor;
+pub mod proc_table;
+pub mod query_handle;
 pub mod query_stats;
+pub mod user_ops;
@@ -0,0 +1,262 @@
+//! The `proc` dimension table: metadata of processes, keyed by pid, read from
+//! procfs in user space. Queries label their output with it by joining it
+//! (`JOIN proc USING (pid)`), or by looking up its columns (e.g.
+//! `proc_exe(pid)`).
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use procfs::process::Process;
+use strum::{EnumIter, IntoEnumIterator};
+
+use crate::{
+    cgroups::{container_id, CONTAINER_ID_LEN},
+    data_types::{Clock, DataType, TimeUnit},
+    record::DataValue,
+};
+
+/// Name of the table, as joined.
+pub const PROC_TABLE: &str = "proc";
+/// Column the table is keyed by.
+pub const PROC_KEY: &str = "pid";
+/// Prefix of the functions looking up a column, e.g. `proc_exe(pid)`.
+pub const PROC_FUNC_PREFIX: &str = "proc_";
+
+/// Nominal length of the table's string columns, which (unlike those of BPF
+/// programs) are never laid out in structs.
+const PROC_STR_LEN: usize = 4096;
+/// Interval at which cached processes are checked for having exited.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Columns of the `proc` table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, EnumIter)]
+pub enum ProcColumn {
+    /// Path of the executable
+    Exe,
+    /// Command line, with its arguments separated by spaces
+    Cmdline,
+    /// Parent's pid
+    Ppid,
+    /// Owner of the process
+    Uid,
+    /// Time the process started at
+    StartTime,
+    /// Id of the container the process runs in, or empty outside of
+    /// containers
+    ContainerId,
+    /// Path of the process' cgroup (in the unified hierarchy, if mounted)
+    CgroupPath,
+}
+
+impl ProcColumn {
+    pub fn name(&self) -> &str {
+        match self {
+            ProcColumn::Exe => "exe",
+            ProcColumn::Cmdline => "cmdline",
+            ProcColumn::Ppid => "ppid",
+            ProcColumn::Uid => "uid",
+            ProcColumn::StartTime => "start_time",
+            ProcColumn::ContainerId => "container_id",
+            ProcColumn::CgroupPath => "cgroup_path",
+        }
+    }
+
+    pub fn data_type(&self) -> DataType {
+        match self {
+            ProcColumn::Ppid | ProcColumn::Uid => DataType::UInt32,
+            ProcColumn::StartTime => DataType::Timestamp(TimeUnit::Nanosecond),
+            ProcColumn::ContainerId => DataType::String(CONTAINER_ID_LEN),
+            ProcColumn::Exe | ProcColumn::Cmdline | ProcColumn::CgroupPath => {
+                DataType::String(PROC_STR_LEN)
+            }
+        }
+    }
+
+    /// Gets the column looked up by a function (e.g. `proc_exe`), if any.
+    pub fn from_func(name: &str) -> Option<ProcColumn> {
+        name.strip_prefix(PROC_FUNC_PREFIX)?.parse().ok()
+    }
+}
+
+impl FromStr for ProcColumn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match ProcColumn::iter().find(|c| c.name() == s.to_lowercase()) {
+            Some(c) => Ok(c),
+            None => bail!("{s} is not a column of {PROC_TABLE}"),
+        }
+    }
+}
+
+impl fmt::Display for ProcColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Metadata of a process. Processes that couldn't be read (e.g. that exited
+/// before they were looked up) have empty metadata.
+#[derive(Clone, Default)]
+struct ProcInfo {
+    exe: String,
+    cmdline: String,
+    ppid: u32,
+    uid: u32,
+    /// Start time, in clock ticks since boot
+    start_time: u64,
+    container_id: String,
+    cgroup_path: String,
+}
+
+impl ProcInfo {
+    fn read(pid: u32) -> Option<Self> {
+        let proc = Process::new(pid as i32).ok()?;
+        let stat = proc.stat().ok()?;
+        let cgroup_path = proc
+            .cgroups()
+            .ok()
+            .and_then(|cgroups| {
+                // The unified hierarchy is numbered 0
+                let cgroups = cgroups.0;
+                let unified = cgroups.iter().position(|c| c.hierarchy == 0);
+                cgroups
+                    .into_iter()
+                    .nth(unified.unwrap_or(0))
+                    .map(|c| c.pathname)
+            })
+            .unwrap_or_default();
+        Some(Self {
+            exe: proc
+                .exe()
+                .map(|exe| exe.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            cmdline: proc.cmdline().map(|c| c.join(" ")).unwrap_or_default(),
+            ppid: stat.ppid as u32,
+            uid: proc.uid().unwrap_or_default(),
+            start_time: stat.starttime,
+            container_id: container_id(&cgroup_path).unwrap_or_default(),
+            cgroup_path,
+        })
+    }
+
+    fn value(&self, column: ProcColumn) -> DataValue {
+        let string = |s: &str| DataValue::String(s.to_string(), s.len());
+        match column {
+            ProcColumn::Exe => string(&self.exe),
+            ProcColumn::Cmdline => string(&self.cmdline),
+            ProcColumn::Ppid => DataValue::UInt32(self.ppid),
+            ProcColumn::Uid => DataValue::UInt32(self.uid),
+            ProcColumn::StartTime => {
+                let nanos = self.start_time * 1_000_000_000 / procfs::ticks_per_second();
+                DataValue::Timestamp(Duration::from_nanos(nanos), Clock::Boot)
+            }
+            ProcColumn::ContainerId => string(&self.container_id),
+            ProcColumn::CgroupPath => string(&self.cgroup_path),
+        }
+    }
+}
+
+/// Cache of the `proc` table. Processes are read on their first lookup, and
+/// evicted once they exit (or their pid is reused); rows of processes that
+/// exited before they were first looked up have empty metadata.
+#[derive(Default)]
+pub struct ProcTable {
+    procs: HashMap<u32, ProcInfo>,
+    /// When the cache was last swept of exited processes
+    swept: Option<Instant>,
+}
+
+impl ProcTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Looks up a column of the process with the pid.
+    pub fn get(&mut self, pid: u32, column: ProcColumn) -> DataValue {
+        self.procs
+            .entry(pid)
+            .or_insert_with(|| ProcInfo::read(pid).unwrap_or_default())
+            .value(column)
+    }
+
+    /// Evicts the processes that have exited since the last sweep, at most
+    /// once per [`SWEEP_INTERVAL`]. Processes are identified by their start
+    /// time, since pids are reused.
+    pub fn sweep(&mut self) {
+        if self.swept.map_or(false, |at| at.elapsed() < SWEEP_INTERVAL) {
+            return;
+        }
+        self.swept = Some(Instant::now());
+        self.procs.retain(|pid, info| {
+            let stat = Process::new(*pid as i32).and_then(|p| p.stat());
+            stat.map_or(false, |stat| stat.starttime == info.start_time)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_columns_and_functions() {
+        assert_eq!(
+            "CMDLINE".parse::<ProcColumn>().unwrap(),
+            ProcColumn::Cmdline
+        );
+        assert!("comm".parse::<ProcColumn>().is_err());
+        assert_eq!(ProcColumn::from_func("proc_exe"), Some(ProcColumn::Exe));
+        assert_eq!(
+            ProcColumn::from_func("proc_container_id"),
+            Some(ProcColumn::ContainerId)
+        );
+        assert_eq!(ProcColumn::from_func("exe"), None);
+        assert_eq!(ProcColumn::from_func("proc_comm"), None);
+        for column in ProcColumn::iter() {
+            assert_eq!(column.name().parse::<ProcColumn>().unwrap(), column);
+        }
+    }
+
+    #[test]
+    fn reads_own_process() {
+        let mut table = ProcTable::new();
+        let pid = std::process::id();
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(
+            table.get(pid, ProcColumn::Exe).to_string(),
+            exe.to_string_lossy()
+        );
+        assert_eq!(
+            table.get(pid, ProcColumn::Ppid),
+            DataValue::UInt32(std::os::unix::process::parent_id())
+        );
+        assert_eq!(
+            table.get(pid, ProcColumn::Uid),
+            DataValue::UInt32(unsafe { libc::geteuid() })
+        );
+        assert!(matches!(
+            table.get(pid, ProcColumn::StartTime),
+            DataValue::Timestamp(t, Clock::Boot) if t > Duration::ZERO
+        ));
+        // Still running, so kept by sweeps
+        table.sweep();
+        assert!(table.procs.contains_key(&pid));
+    }
+
+    #[test]
+    fn has_empty_rows_for_missing_processes() {
+        let mut table = ProcTable::new();
+        // Above the maximum pid (4194304)
+        let pid = 1 << 23;
+        assert_eq!(table.get(pid, ProcColumn::Exe).to_string(), "");
+        assert_eq!(table.get(pid, ProcColumn::Ppid), DataValue::UInt32(0));
+        table.sweep();
+        assert!(table.procs.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use crossbeam::channel::Receiver;
+
+use super::executor::ExecState;
+use crate::{record::DataValue, record_batch::RecordBatch, Schema};
+
+/// Lifecycle status of a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryStatus {
+    /// The query's program is attached, and its stream receives batches
+    Running,
+    /// The query's stream receives no batches, which are buffered until it's
+    /// resumed; its program is detached (unless shared with running queries),
+    /// but keeps its state
+    Paused,
+    /// The query's stream is closed (once drained), and its program is
+    /// unloaded (unless shared with other queries)
+    Stopped,
+}
+
+/// Handle to a query submitted to an [`Executor`](super::executor::Executor),
+/// which owns the query's program (along with any other queries sharing it).
+/// Dropping the handle stops the query.
+pub struct QueryHandle {
+    schema: Arc<Schema>,
+    rx: Receiver<RecordBatch>,
+    state: Arc<Mutex<ExecState>>,
+    status: QueryStatus,
+}
+
+impl QueryHandle {
+    pub(super) fn new(
+        schema: Arc<Schema>,
+        rx: Receiver<RecordBatch>,
+        state: Arc<Mutex<ExecState>>,
+    ) -> Self {
+        Self {
+            schema,
+            rx,
+            state,
+            status: QueryStatus::Running,
+        }
+    }
+
+    /// Gets the name of the query, i.e. the name of its output schema.
+    pub fn name(&self) -> &str {
+        &self.schema.name
+    }
+
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.clone()
+    }
+
+    /// Gets the query's output stream. Once the query is stopped, the stream
+    /// closes after its last (possibly partial) batch.
+    pub fn stream(&self) -> &Receiver<RecordBatch> {
+        &self.rx
+    }
+
+    pub fn status(&self) -> QueryStatus {
+        self.status
+    }
+
+    /// Stops the query, detaching and unloading its program unless other
+    /// queries read from it. Stopping a stopped query does nothing.
+    pub fn stop(&mut self) -> Result<()> {
+        if self.status == QueryStatus::Stopped {
+            return Ok(());
+        }
+        // The query is gone from the executor even if stopping its program
+        // fails
+        self.status = QueryStatus::Stopped;
+        // The program's threads are joined once the executor is unlocked
+        let stopped = self.state.lock().unwrap().stop_query(&self.schema.name)?;
+        stopped.finish()
+    }
+
+    /// Pauses the query, detaching its program (once no running queries read
+    /// from it) without clearing its maps. Windows flushed while paused are
+    /// buffered, and output once the query is resumed; only the latest 1024
+    /// batches are kept, and older ones are dropped (with a warning).
+    pub fn pause(&mut self) -> Result<()> {
+        match self.status {
+            QueryStatus::Running => (),
+            QueryStatus::Paused => return Ok(()),
+            QueryStatus::Stopped => bail!("query {} is stopped", self.schema.name),
+        }
+        self.state.lock().unwrap().pause_query(&self.schema.name)?;
+        self.status = QueryStatus::Paused;
+        Ok(())
+    }
+
+    /// Resumes a paused query, reattaching its program.
+    pub fn resume(&mut self) -> Result<()> {
+        match self.status {
+            QueryStatus::Paused => (),
+            QueryStatus::Running => return Ok(()),
+            QueryStatus::Stopped => bail!("query {} is stopped", self.schema.name),
+        }
+        self.state.lock().unwrap().resume_query(&self.schema.name)?;
+        self.status = QueryStatus::Running;
+        Ok(())
+    }
+
+    /// Sets a parameter (`$name`) of the query. The new value takes effect
+    /// without recompiling or reattaching the query's program.
+    pub fn set_param<S: AsRef<str>>(&self, name: S, value: DataValue) -> Result<()> {
+        if self.status == QueryStatus::Stopped {
+            bail!("query {} is stopped", self.schema.name);
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .set_param(&self.schema.name, name.as_ref(), value)
+    }
+}
+
+impl Drop for QueryHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            log::warn!("Failed to stop query {}: {e}", self.schema.name);
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use super::bpf_stats::BpfProgramStats;
+use crate::object::OverflowStats;
 
 pub struct QueryStats {
     pub us_stats: UserspaceStats,
     pub bpf_stats: BpfProgramStats,
+    /// Events and groups dropped by the query's aggregations, if it has any
+    pub overflow: Option<OverflowStats>,
 }
 
 impl QueryStats {
@@ -10,8 +13,14 @@ impl QueryStats {
         Self {
             us_stats,
             bpf_stats,
+            overflow: None,
         }
     }
+
+    pub fn with_overflow(mut self, overflow: Option<OverflowStats>) -> Self {
+        self.overflow = overflow;
+        self
+    }
 }
 
 pub struct UserspaceStats {
@@ -0,0 +1,563 @@
+//! User-space execution of queries over the output stream of BPF programs
+//! (e.g. the outer query of a nested select).
+
+use std::{cmp::Ordering, collections::BTreeMap, thread};
+
+use anyhow::{anyhow, bail, Result};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use nom_sql::{ConditionBase, ConditionExpression, Literal};
+
+use crate::{
+    data_types::DataType,
+    error::EbqlError,
+    exec::proc_table::{ProcColumn, ProcTable},
+    operators::{Operator, WindowType},
+    physical_plan::UserPlan,
+    record::{DataValue, Record},
+    record_batch::RecordBatch,
+    schema::schema::Schema,
+};
+
+/// Executes the plan over an input stream in a separate thread, and returns
+/// the stream of its output.
+pub fn execute(plan: UserPlan, input: Receiver<RecordBatch>) -> Result<Receiver<RecordBatch>> {
+    let mut executor = UserExecutor::new(plan)?;
+    let (tx, rx) = unbounded();
+    thread::spawn(move || {
+        if let Err(err) = executor.run(input, tx) {
+            log::error!(
+                "User-space execution of query {} failed: {err}",
+                executor.plan.schema.name
+            );
+        }
+    });
+    Ok(rx)
+}
+
+/// Where each output column of a plan comes from.
+enum Output {
+    /// Field at the index of the input record
+    Input(usize),
+    /// Group by key at the index
+    Key(usize),
+    /// Aggregation at the index
+    Agg(usize),
+    /// Column of the `proc` table, looked up by the pid output by the source
+    Proc(Box<Output>, ProcColumn),
+}
+
+/// State of an aggregation over one group.
+#[derive(Clone)]
+enum AggState {
+    Max(Option<DataValue>),
+    Min(Option<DataValue>),
+    Sum(i128),
+    FloatSum(f64),
+    Average(f64, u64),
+    Count(u64),
+}
+
+struct UserExecutor {
+    plan: UserPlan,
+    /// Index of each group by field in the input
+    key_indices: Vec<usize>,
+    /// Index of each aggregated field in the input (if any)
+    agg_indices: Vec<Option<usize>>,
+    /// Output type of each aggregation
+    agg_types: Vec<DataType>,
+    /// Source of each output column
+    outputs: Vec<Output>,
+    /// Aggregation state, by group
+    groups: BTreeMap<Record, Vec<AggState>>,
+    /// Number of records in the current count window
+    n_records: usize,
+    /// Index of the start of each input record's window, for time windows
+    start_index: Option<usize>,
+    /// End (in nanoseconds) of the current time window, once it has records
+    window_end: Option<u128>,
+    /// Process metadata, for the `proc` columns
+    procs: ProcTable,
+}
+
+impl UserExecutor {
+    fn new(plan: UserPlan) -> Result<Self> {
+        let index = |name: &str| -> Result<usize> {
+            match plan.input.fields.iter().position(|f| f.name == name) {
+                Some(i) => Ok(i),
+                None => {
+                    bail!(EbqlError::bind(
+                        name,
+                        "nested select does not output column"
+                    ))
+                }
+            }
+        };
+        let key_indices = plan
+            .group_by
+            .iter()
+            .map(|f| index(&f.name))
+            .collect::<Result<Vec<_>>>()?;
+        let agg_indices = plan
+            .aggs
+            .iter()
+            .map(|op| {
+                match op {
+                    Operator::Max(s)
+                    | Operator::Min(s)
+                    | Operator::Sum(s)
+                    | Operator::Average(s)
+                    | Operator::Count(Some(s)) => index(s).map(Some),
+                    _ => Ok(None),
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        // Aggregates are output in the order of the plan's aggregations, and all
+        // other fields are either group by keys or (without aggs) input fields
+        let mut n_aggs = 0;
+        let outputs = plan
+            .schema
+            .fields
+            .iter()
+            .map(|f| {
+                let key = |name: &str| plan.group_by.iter().position(|g| g.name == name);
+                if let Some((_, pid, col)) = plan.procs.iter().find(|(name, ..)| *name == f.name) {
+                    let pid = match key(pid) {
+                        Some(i) => Output::Key(i),
+                        None => Output::Input(index(pid)?),
+                    };
+                    return Ok(Output::Proc(Box::new(pid), *col));
+                }
+                Ok(if let Some(i) = key(&f.name) {
+                    Output::Key(i)
+                } else if plan.aggs.is_empty() {
+                    Output::Input(index(&f.name)?)
+                } else {
+                    n_aggs += 1;
+                    Output::Agg(n_aggs - 1)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let agg_types = plan
+            .schema
+            .fields
+            .iter()
+            .zip(&outputs)
+            .filter(|(_, o)| matches!(o, Output::Agg(_)))
+            .map(|(f, _)| f.data_type.clone())
+            .collect();
+        let start_index = plan.window_start.as_deref().map(index).transpose()?;
+
+        Ok(Self {
+            plan,
+            key_indices,
+            agg_indices,
+            agg_types,
+            outputs,
+            groups: BTreeMap::new(),
+            n_records: 0,
+            start_index,
+            window_end: None,
+            procs: ProcTable::new(),
+        })
+    }
+
+    /// Consumes the input stream until it closes (or the output stream does).
+    fn run(&mut self, input: Receiver<RecordBatch>, tx: Sender<RecordBatch>) -> Result<()> {
+        while let Ok(rb) = input.recv() {
+            // Errors end the input, and are passed on as the output's last
+            // batch
+            if let Some(error) = rb.error {
+                let rb = RecordBatch::new(self.plan.schema.clone(), vec![]).with_error(error);
+                return send(&tx, rb);
+            }
+            self.process(&rb, &tx)?;
+        }
+        // The input only closes once its query is stopped, so the open window
+        // is cut short
+        self.flush(&tx, true)
+    }
+
+    /// Tumbles the current time window if the record's window starts past its
+    /// end. Time windows are aligned to their interval, and made of the input
+    /// windows that start within them, whenever those arrive.
+    fn tumble_time_window(&mut self, r: &Record, tx: &Sender<RecordBatch>) -> Result<()> {
+        let (Some(i), Some(WindowType::Time(ival, _))) = (self.start_index, &self.plan.window)
+        else {
+            return Ok(());
+        };
+        let start = r.get(i);
+        let Some(start) = start.as_i128() else {
+            bail!("expected window start, got {start}");
+        };
+        let (start, ival) = (start.max(0) as u128, ival.as_nanos().max(1));
+        if self.window_end.is_some_and(|end| start >= end) {
+            self.flush(tx, false)?;
+        }
+        if self.window_end.is_none() {
+            self.window_end = Some((start / ival + 1) * ival);
+        }
+        Ok(())
+    }
+
+    /// Processes a batch of input records.
+    fn process(&mut self, rb: &RecordBatch, tx: &Sender<RecordBatch>) -> Result<()> {
+        self.procs.sweep();
+        let mut out = Vec::new();
+        for r in &rb.records {
+            self.tumble_time_window(r, tx)?;
+            if let Some(Operator::Filter(ce)) = &self.plan.filters {
+                if !eval_cond(ce, &self.plan.input, r)? {
+                    continue;
+                }
+            }
+
+            // Without aggregations, project records straight into the output
+            if self.plan.aggs.is_empty() {
+                out.push(self.output_record(r, &Record::empty(), &[]));
+                continue;
+            }
+
+            self.update(r)?;
+            if let Some(WindowType::Count(count, _)) = &self.plan.window {
+                self.n_records += 1;
+                if self.n_records >= *count {
+                    self.flush(tx, false)?;
+                }
+            }
+        }
+
+        if !out.is_empty() {
+            let out = RecordBatch::new(self.plan.schema.clone(), out).with_partial(rb.partial);
+            send(tx, out)?;
+        }
+        // Without a window, each input batch (i.e. one window of the nested
+        // query) is aggregated on its own
+        if self.plan.window.is_none() && !self.plan.aggs.is_empty() {
+            self.flush(tx, rb.partial)?;
+        }
+        Ok(())
+    }
+
+    /// Updates the aggregations of a record's group.
+    fn update(&mut self, r: &Record) -> Result<()> {
+        let key = Record::from(
+            self.key_indices
+                .iter()
+                .map(|i| r.get(*i))
+                .collect::<Vec<_>>(),
+        );
+        let (aggs, agg_types) = (&self.plan.aggs, &self.agg_types);
+        let states = self.groups.entry(key).or_insert_with(|| {
+            aggs.iter()
+                .zip(agg_types)
+                .map(|(op, data_type)| {
+                    match op {
+                        Operator::Max(_) => AggState::Max(None),
+                        Operator::Min(_) => AggState::Min(None),
+                        Operator::Sum(_) if data_type.is_floating() => AggState::FloatSum(0.0),
+                        Operator::Sum(_) => AggState::Sum(0),
+                        Operator::Average(_) => AggState::Average(0.0, 0),
+                        _ => AggState::Count(0),
+                    }
+                })
+                .collect()
+        });
+
+        for (state, idx) in states.iter_mut().zip(&self.agg_indices) {
+            let val = idx.map(|i| r.get(i));
+            match (state, val) {
+                (AggState::Max(max), Some(v)) => {
+                    if max.as_ref().map_or(true, |max| v > *max) {
+                        *max = Some(v);
+                    }
+                }
+                (AggState::Min(min), Some(v)) => {
+                    if min.as_ref().map_or(true, |min| v < *min) {
+                        *min = Some(v);
+                    }
+                }
+                (AggState::Sum(sum), Some(v)) => *sum += to_i128(&v)?,
+                (AggState::FloatSum(sum), Some(v)) => *sum += to_f64(&v)?,
+                (AggState::Average(sum, count), Some(v)) => {
+                    *sum += to_f64(&v)?;
+                    *count += 1;
+                }
+                (AggState::Count(count), _) => *count += 1,
+                (_, None) => bail!("aggregation is missing its input field"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Emits the aggregates of the current window (marked partial if the
+    /// window was cut short), and resets its state.
+    fn flush(&mut self, tx: &Sender<RecordBatch>, partial: bool) -> Result<()> {
+        self.n_records = 0;
+        self.window_end = None;
+        if self.groups.is_empty() {
+            return Ok(());
+        }
+        let groups = std::mem::take(&mut self.groups);
+        let records = groups
+            .iter()
+            .map(|(key, states)| {
+                let aggs = self.agg_values(states);
+                self.output_record(&Record::empty(), key, &aggs)
+            })
+            .collect();
+        send(
+            tx,
+            RecordBatch::new(self.plan.schema.clone(), records).with_partial(partial),
+        )
+    }
+
+    /// Gets the final values of a group's aggregations.
+    fn agg_values(&self, states: &[AggState]) -> Vec<DataValue> {
+        states
+            .iter()
+            .zip(&self.agg_types)
+            .map(|(state, data_type)| {
+                match state {
+                    AggState::Max(v) | AggState::Min(v) => {
+                        v.clone().unwrap_or(DataValue::UInt64(0))
+                    }
+                    AggState::Sum(sum) => from_i128(*sum, data_type),
+                    AggState::FloatSum(sum) => DataValue::Float64(*sum),
+                    AggState::Average(sum, count) => {
+                        DataValue::Float64(sum / (*count).max(1) as f64)
+                    }
+                    AggState::Count(count) => DataValue::UInt64(*count),
+                }
+            })
+            .collect()
+    }
+
+    /// Assembles an output record from an input record, group key, and
+    /// aggregation values.
+    fn output_record(&mut self, r: &Record, key: &Record, aggs: &[DataValue]) -> Record {
+        self.outputs
+            .iter()
+            .map(|o| output_value(o, r, key, aggs, &mut self.procs))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+/// Gets the value of an output column (see [`UserExecutor::output_record`]).
+fn output_value(
+    o: &Output,
+    r: &Record,
+    key: &Record,
+    aggs: &[DataValue],
+    procs: &mut ProcTable,
+) -> DataValue {
+    match o {
+        Output::Input(i) => r.get(*i),
+        Output::Key(i) => key.get(*i),
+        Output::Agg(i) => aggs[*i].clone(),
+        Output::Proc(pid, col) => {
+            let pid = output_value(pid, r, key, aggs, procs);
+            procs.get(pid.as_i128().unwrap_or_default() as u32, *col)
+        }
+    }
+}
+
+fn send(tx: &Sender<RecordBatch>, rb: RecordBatch) -> Result<()> {
+    tx.send(rb)
+        .map_err(|_| anyhow!("output stream has been closed"))
+}
+
+/// Converts an integer value to an i128.
+fn to_i128(v: &DataValue) -> Result<i128> {
+    v.as_i128()
+        .ok_or_else(|| anyhow!("expected numeric value, got {v}"))
+}
+
+/// Converts a numeric value to an f64.
+fn to_f64(v: &DataValue) -> Result<f64> {
+    v.as_f64()
+        .ok_or_else(|| anyhow!("expected numeric value, got {v}"))
+}
+
+/// Converts an accumulated integer into a value of the data type.
+fn from_i128(v: i128, data_type: &DataType) -> DataValue {
+    match data_type {
+        DataType::Int64 => DataValue::Int64(v as i64),
+        _ => DataValue::UInt64(v as u64),
+    }
+}
+
+/// Evaluates a condition over a record.
+fn eval_cond(ce: &ConditionExpression, schema: &Schema, r: &Record) -> Result<bool> {
+    match ce {
+        ConditionExpression::ComparisonOp(ct) if ct.operator == nom_sql::Operator::In => {
+            let (list, negated) = match ct.right.as_ref() {
+                ConditionExpression::Base(ConditionBase::LiteralList(list)) => (list, false),
+                ConditionExpression::NegationOp(ce) => {
+                    match ce.as_ref() {
+                        ConditionExpression::Base(ConditionBase::LiteralList(list)) => (list, true),
+                        _ => bail!("IN over {ce} not supported"),
+                    }
+                }
+                ce => bail!("IN over {ce} not supported"),
+            };
+            let l = eval_value(&ct.left, schema, r)?;
+            let mut found = false;
+            for lit in list {
+                let v = eval_value(
+                    &ConditionExpression::Base(ConditionBase::Literal(lit.clone())),
+                    schema,
+                    r,
+                )?;
+                found = found || compare(&l, &v)? == Ordering::Equal;
+            }
+            Ok(found != negated)
+        }
+        // Values are never null, so IS NULL never holds
+        ConditionExpression::ComparisonOp(ct)
+            if matches!(
+                ct.right.as_ref(),
+                ConditionExpression::Base(ConditionBase::Literal(Literal::Null))
+            ) =>
+        {
+            match ct.operator {
+                nom_sql::Operator::Equal => Ok(false),
+                nom_sql::Operator::NotEqual => Ok(true),
+                _ => bail!("operator {} not supported for NULL", ct.operator),
+            }
+        }
+        ConditionExpression::ComparisonOp(ct) => {
+            let l = eval_value(&ct.left, schema, r)?;
+            let r = eval_value(&ct.right, schema, r)?;
+            let ord = compare(&l, &r)?;
+            use nom_sql::Operator::*;
+            Ok(match ct.operator {
+                Equal => ord == Ordering::Equal,
+                NotEqual => ord != Ordering::Equal,
+                Greater => ord == Ordering::Greater,
+                GreaterOrEqual => ord != Ordering::Less,
+                Less => ord == Ordering::Less,
+                LessOrEqual => ord != Ordering::Greater,
+                _ => bail!("comparison operator {} not supported", ct.operator),
+            })
+        }
+        ConditionExpression::LogicalOp(ct) => {
+            match ct.operator {
+                nom_sql::Operator::And => {
+                    Ok(eval_cond(&ct.left, schema, r)? && eval_cond(&ct.right, schema, r)?)
+                }
+                nom_sql::Operator::Or => {
+                    Ok(eval_cond(&ct.left, schema, r)? || eval_cond(&ct.right, schema, r)?)
+                }
+                _ => bail!("logical operator {} not supported", ct.operator),
+            }
+        }
+        ConditionExpression::NegationOp(ce) => Ok(!eval_cond(ce, schema, r)?),
+        ConditionExpression::Bracketed(ce) => eval_cond(ce, schema, r),
+        _ => bail!("condition {ce} not supported over nested selects"),
+    }
+}
+
+/// Evaluates an operand of a comparison over a record.
+fn eval_value(ce: &ConditionExpression, schema: &Schema, r: &Record) -> Result<DataValue> {
+    match ce {
+        ConditionExpression::Base(ConditionBase::Field(col)) => {
+            let i = schema
+                .fields
+                .iter()
+                .position(|f| f.name == col.name)
+                .ok_or_else(|| anyhow!("unknown column {}", col.name))?;
+            Ok(r.get(i))
+        }
+        ConditionExpression::Base(ConditionBase::Literal(l)) => DataValue::try_from(l.clone()),
+        ConditionExpression::Bracketed(ce) => eval_value(ce, schema, r),
+        _ => bail!("operand {ce} not supported over nested selects"),
+    }
+}
+
+/// Compares two values, converting between numeric types.
+fn compare(l: &DataValue, r: &DataValue) -> Result<Ordering> {
+    match (l, r) {
+        (DataValue::String(l, _), DataValue::String(r, _)) => Ok(l.cmp(r)),
+        (DataValue::String(..), _) | (_, DataValue::String(..)) => {
+            bail!("cannot compare {l} with {r}")
+        }
+        (DataValue::Float32(_) | DataValue::Float64(_), _)
+        | (_, DataValue::Float32(_) | DataValue::Float64(_)) => {
+            Ok(to_f64(l)?.total_cmp(&to_f64(r)?))
+        }
+        _ => Ok(to_i128(l)?.cmp(&to_i128(r)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::{data_types::Clock, field::Field, query::bpf_ops::window::WINDOW_START};
+
+    /// Plan of `SELECT max(cnt) FROM (...) WINDOW TIME(10s)`, over input rows
+    /// of (window_start, cnt).
+    fn max_per_10s() -> UserPlan {
+        let input = Schema::new(
+            Some("inner".into()),
+            vec![
+                Field::new(WINDOW_START, DataType::UInt64),
+                Field::new("cnt", DataType::UInt64),
+            ]
+            .into(),
+        );
+        UserPlan {
+            input: Arc::new(input),
+            schema: Arc::new(Schema::new(
+                Some("outer".into()),
+                vec![Field::new("max_cnt", DataType::UInt64)].into(),
+            )),
+            window: Some(WindowType::Time(
+                Duration::from_secs(10),
+                Duration::from_secs(10),
+            )),
+            window_start: Some(WINDOW_START.into()),
+            filters: None,
+            group_by: Vec::new(),
+            aggs: vec![Operator::Max("cnt".into())],
+            procs: Vec::new(),
+        }
+    }
+
+    /// Batch of an inner window starting at the second, with the counts.
+    fn batch(plan: &UserPlan, start_secs: u64, counts: &[u64]) -> RecordBatch {
+        let start = DataValue::Timestamp(Duration::from_secs(start_secs), Clock::Monotonic);
+        let records = counts
+            .iter()
+            .map(|c| Record::from(vec![start.clone(), DataValue::UInt64(*c)]))
+            .collect();
+        RecordBatch::new(plan.input.clone(), records)
+    }
+
+    #[test]
+    fn assigns_inner_windows_by_their_start() {
+        let plan = max_per_10s();
+        let (in_tx, in_rx) = unbounded();
+        // Inner windows are assigned by their start rather than by when they
+        // arrive (here, all at once): [10s, 20s) ends once the window at 21s
+        // arrives
+        for (start, counts) in [(10, &[3, 7][..]), (15, &[9]), (19, &[4]), (21, &[1])] {
+            in_tx.send(batch(&plan, start, counts)).unwrap();
+        }
+        drop(in_tx);
+        let out = execute(plan, in_rx).unwrap();
+        let batches = out.iter().collect::<Vec<_>>();
+
+        let maxes = batches
+            .iter()
+            .map(|rb| (rb.records[0].get(0), rb.partial))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            maxes,
+            [(DataValue::UInt64(9), false), (DataValue::UInt64(1), true)]
+        );
+    }
+}
@@ -1,28 +1,207 @@
-use std::path::PathBuf;
+use std::{fmt::Display, str::FromStr};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use serde::Serialize;
 
-use super::{HeaderTemplate, BPF_HEADERS_DIR};
-use crate::{query::operators::Operator, types};
+use super::{HeaderTemplate, AGG_TMPL};
+use crate::{
+    query::operators::Operator,
+    schema::{field::MOMENTS_SIZE, histogram::Buckets},
+    types,
+};
 
 #[derive(Serialize, Default)]
 pub struct BpfAggregateTemplate {
     pub query_name: String,
     pub gb_max_entries: u64,
     pub avg_scale: u64,
+    /// Whether rows are filled with the bounds of the window (see
+    /// [`super::window::WINDOW_START`])
+    pub window_start: bool,
+    pub window_end: bool,
+    /// Whether groups that saw no events in a window are still emitted (with
+    /// zero counts); only valid if all aggregations are counts
+    pub emit_empty: bool,
+    /// Whether aggregations are kept in per-CPU maps, which user space merges
+    /// at the end of each window
+    pub percpu: bool,
+    /// Whether full group maps evict their least recently used groups
+    pub lru: bool,
+    /// Whether groups that don't fit are aggregated into an "other" group
+    pub other: bool,
+    /// Whether rows are filled with the overflow columns (see
+    /// [`DROPPED_EVENTS`])
+    pub dropped_events: bool,
+    pub dropped_groups: bool,
+    pub other_col: bool,
     pub group_bys: Vec<GroupBy>,
     pub aggs: Vec<Agg>,
 }
 
-const AVG_SCALE: u64 = 1e6 as u64;
+/// Scale of averages, which are computed and output in fixed point (i.e. with 6
+/// decimal digits).
+pub const AVG_SCALE: u64 = 1e6 as u64;
+
+/// Size of most aggregates (`agg_t` and `avg_t`) in aggregation map values.
+pub const AGG_VALUE_SIZE: usize = 16;
+/// Size of `min_by`/`max_by` aggregates (`by_t`).
+pub const BY_VALUE_SIZE: usize = 24;
+
+/// Functions of the statistical aggregations: `variance(col)`, `stddev(col)`,
+/// `min_by(col, by)`/`max_by(col, by)` (the value of `col` at the
+/// minimum/maximum of `by`) and `first(col)`/`last(col)`.
+pub const VARIANCE: &str = "variance";
+pub const STDDEV: &str = "stddev";
+pub const MIN_BY: &str = "min_by";
+pub const MAX_BY: &str = "max_by";
+pub const FIRST: &str = "first";
+pub const LAST: &str = "last";
+/// Functions of the histogram aggregations: `lhist(col, min, max, step)` and
+/// `log2hist(col)` (see [`Buckets`]).
+pub const LHIST: &str = "lhist";
+pub const LOG2HIST: &str = "log2hist";
+
+/// Gets the function of a histogram with the buckets.
+pub fn hist_agg(buckets: &Buckets) -> &'static str {
+    match buckets {
+        Buckets::Linear { .. } => LHIST,
+        Buckets::Log2 => LOG2HIST,
+    }
+}
+
+/// Gets the size of an aggregation's aggregate in aggregation map values.
+pub fn agg_value_size(op: &Operator) -> usize {
+    match op {
+        Operator::Variance(_) | Operator::Stddev(_) => MOMENTS_SIZE,
+        Operator::MinBy(..) | Operator::MaxBy(..) => BY_VALUE_SIZE,
+        // A count, then the counts of each bucket
+        Operator::Hist(_, buckets) => (buckets.len() + 1) * 8,
+        _ => AGG_VALUE_SIZE,
+    }
+}
+
+/// Implicit column holding the number of events of a window that weren't
+/// aggregated into their own group, since the group map was full, or whose
+/// stack couldn't be collected (see [`crate::stack_map`]).
+pub const DROPPED_EVENTS: &str = "dropped_events";
+/// Implicit column holding the number of groups of a window that were dropped
+/// (or evicted) since the group map was full. Dropped groups are told apart for
+/// up to `OVERFLOW_TRACKED_GROUPS` (see `agg.bpf.h.tmpl`) groups a window.
+pub const DROPPED_GROUPS: &str = "dropped_groups";
+/// Implicit column marking the row of the "other" group, which aggregates the
+/// events of groups that didn't fit (see [`OverflowPolicy::Other`]).
+pub const OTHER_GROUP: &str = "other";
+
+/// Returns whether a column is one of the implicit overflow columns.
+pub fn is_overflow_column<S: AsRef<str>>(name: S) -> bool {
+    matches!(name.as_ref(), DROPPED_EVENTS | DROPPED_GROUPS | OTHER_GROUP)
+}
+
+/// Gets the BPF field of an implicit overflow column.
+pub fn overflow_column_field<S: AsRef<str>>(name: S) -> types::Field {
+    let t = match name.as_ref() {
+        OTHER_GROUP => types::Type::Bool,
+        _ => types::Type::U64,
+    };
+    types::Field::new(name.as_ref().to_string(), t)
+}
+
+/// Default maximum number of groups an aggregation keeps.
+pub const DEFAULT_MAX_GROUPS: u64 = 1 << 14; // 16384
+
+/// What happens to the events of new groups once an aggregation keeps its
+/// maximum number of groups. Whatever the policy, the events and groups that
+/// don't get their own group are counted (see [`DROPPED_EVENTS`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The events are dropped
+    #[default]
+    Drop,
+    /// The least recently updated group is evicted, losing its aggregates
+    Lru,
+    /// The events are aggregated into a single "other" group
+    Other,
+    /// The events are dropped, and the query fails: its stream ends with a
+    /// batch holding the error
+    Error,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "drop" => Self::Drop,
+            "lru" => Self::Lru,
+            "other" => Self::Other,
+            "error" => Self::Error,
+            _ => bail!("unknown overflow policy {s} (expected drop, lru, other, or error)"),
+        })
+    }
+}
+
+impl Display for OverflowPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Drop => write!(f, "drop"),
+            Self::Lru => write!(f, "lru"),
+            Self::Other => write!(f, "other"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Event rate (per second, across all CPUs) from which aggregations are kept in
+/// per-CPU maps when chosen automatically: below it, updates rarely contend on
+/// shared maps, and merging in user space isn't worth its cost.
+pub const PERCPU_MIN_RATE: f64 = 50_000.0;
+
+/// Which maps aggregations are kept in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AggMaps {
+    /// Per-CPU maps if the event fires at least [`PERCPU_MIN_RATE`] times a
+    /// second (sampled when compiling), and shared maps otherwise. EXPLAIN
+    /// CODEGEN doesn't sample events, so shows shared maps
+    #[default]
+    Auto,
+    /// Maps shared by all CPUs, whose aggregations are emitted by the kernel
+    Shared,
+    /// Per-CPU maps, whose partial aggregations are merged by user space
+    PerCpu,
+}
+
+impl FromStr for AggMaps {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "auto" => Self::Auto,
+            "shared" => Self::Shared,
+            "percpu" => Self::PerCpu,
+            _ => bail!("unknown aggregation maps {s} (expected auto, shared, or percpu)"),
+        })
+    }
+}
+
+impl Display for AggMaps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Shared => write!(f, "shared"),
+            Self::PerCpu => write!(f, "percpu"),
+        }
+    }
+}
 
 impl BpfAggregateTemplate {
+    /// Gets the template of aggregations over the group bys, keeping up to
+    /// `max_groups` groups.
     pub fn new(
         query_name: String,
         group_bys: &[types::Field],
+        max_groups: u64,
     ) -> HeaderTemplate<BpfAggregateTemplate> {
-        let gb_max_entries = get_max_entries(&group_bys);
+        let gb_max_entries = get_max_entries(&group_bys, max_groups);
         let group_bys = group_bys
             .iter()
             .map(|f| {
@@ -42,14 +221,21 @@ impl BpfAggregateTemplate {
         // }
         HeaderTemplate {
             name: "agg".into(),
-            tmpl_path: [BPF_HEADERS_DIR, "agg.bpf.h.tmpl"]
-                .iter()
-                .collect::<PathBuf>(),
+            tmpl: AGG_TMPL,
             ctx: BpfAggregateTemplate {
                 query_name,
                 gb_max_entries,
                 group_bys,
                 avg_scale: AVG_SCALE,
+                window_start: false,
+                window_end: false,
+                emit_empty: false,
+                percpu: false,
+                lru: false,
+                other: false,
+                dropped_events: false,
+                dropped_groups: false,
+                other_col: false,
                 aggs: Vec::new(),
             },
         }
@@ -62,57 +248,64 @@ impl BpfAggregateTemplate {
                 "Attempted to call aggregation {op} without group bys"
             ));
         }
-        let agg = match op {
-            Operator::Max(f) => {
-                Agg {
-                    is_avg: false,
-                    agg: "max".into(),
-                    field_name: f.clone(),
-                    query_name: self.query_name.clone(),
-                }
-            }
-            Operator::Min(f) => {
-                Agg {
-                    is_avg: false,
-                    agg: "min".into(),
-                    field_name: f.clone(),
-                    query_name: self.query_name.clone(),
-                }
-            }
-            Operator::Average(f) => {
-                Agg {
-                    is_avg: true,
-                    agg: "avg".into(),
-                    field_name: f.clone(),
-                    query_name: self.query_name.clone(),
-                }
-            }
-            Operator::Sum(f) => {
-                Agg {
-                    is_avg: false,
-                    agg: "sum".into(),
-                    field_name: f.clone(),
-                    query_name: self.query_name.clone(),
-                }
-            }
-            Operator::Count(Some(f)) => {
-                Agg {
-                    is_avg: false,
-                    agg: "count".into(),
-                    field_name: f.clone(),
-                    query_name: self.query_name.clone(),
-                }
-            }
-            Operator::Count(None) => {
-                Agg {
-                    is_avg: false,
-                    agg: "count".into(),
-                    field_name: String::new(),
-                    query_name: self.query_name.clone(),
-                }
-            }
+        let (agg, field_name) = match op {
+            Operator::Max(f) => ("max", f.clone()),
+            Operator::Min(f) => ("min", f.clone()),
+            Operator::Average(f) => ("avg", f.clone()),
+            Operator::Sum(f) => ("sum", f.clone()),
+            Operator::Count(Some(f)) => ("count", f.clone()),
+            Operator::Count(None) => ("count", String::new()),
+            Operator::Variance(f) => (VARIANCE, f.clone()),
+            Operator::Stddev(f) => (STDDEV, f.clone()),
+            Operator::MinBy(f, by) => (MIN_BY, format!("{f}_{by}")),
+            Operator::MaxBy(f, by) => (MAX_BY, format!("{f}_{by}")),
+            Operator::First(f) => (FIRST, f.clone()),
+            Operator::Last(f) => (LAST, f.clone()),
+            Operator::Hist(f, buckets) => (hist_agg(buckets), f.clone()),
             _ => return Err(anyhow!("Got operator non-supported aggregation {op}")),
         };
+        let is_moments = matches!(op, Operator::Variance(_) | Operator::Stddev(_));
+        let is_by = matches!(op, Operator::MinBy(..) | Operator::MaxBy(..));
+        let value_type = match op {
+            Operator::Average(_) => "avg_t".into(),
+            Operator::Variance(_) | Operator::Stddev(_) => "moments_t".into(),
+            Operator::MinBy(..) | Operator::MaxBy(..) => "by_t".into(),
+            // Histograms have as many buckets as they're defined with
+            Operator::Hist(..) => format!("{agg}_{field_name}_{}_t", self.query_name),
+            _ => "agg_t".into(),
+        };
+        let hist = match op {
+            Operator::Hist(_, buckets) => {
+                let (min, max, step) = match *buckets {
+                    Buckets::Linear { min, max, step } => (min, max, step),
+                    Buckets::Log2 => (0, 0, 0),
+                };
+                Some(AggHist {
+                    buckets: buckets.len(),
+                    log2: *buckets == Buckets::Log2,
+                    min,
+                    max,
+                    step,
+                })
+            }
+            _ => None,
+        };
+        // Histograms are aggregated by functions of their own buckets
+        let update_fn = match hist {
+            Some(_) => format!("__{agg}_{field_name}_{}", self.query_name),
+            None => agg.into(),
+        };
+        let agg = Agg {
+            value_type,
+            update_fn,
+            hist,
+            is_avg: matches!(op, Operator::Average(_)),
+            is_moments,
+            is_by,
+            agg: agg.into(),
+            field_name,
+            query_name: self.query_name.clone(),
+        };
         self.aggs.push(agg);
         Ok(())
     }
@@ -126,14 +319,36 @@ pub struct GroupBy {
 
 #[derive(Serialize, Default)]
 pub struct Agg {
+    /// Type of the aggregate in the values of the aggregation map
+    pub value_type: String,
+    /// Function aggregating a value into the aggregate
+    pub update_fn: String,
+    /// Buckets of the histogram, if the aggregation is one
+    pub hist: Option<AggHist>,
     pub is_avg: bool,
+    /// Whether the aggregation accumulates moments (see [`MOMENTS_SIZE`]),
+    /// copied out as is and computed from in user space
+    pub is_moments: bool,
+    /// Whether the aggregation orders values by another column, passed to its
+    /// inserts
+    pub is_by: bool,
     pub agg: String,
     pub field_name: String,
     pub query_name: String,
 }
 
-const GB_MAX_ENTRIES: u64 = 1 << 14; // 16384
-fn get_max_entries(gbs: &[types::Field]) -> u64 {
+#[derive(Serialize, Default)]
+pub struct AggHist {
+    pub buckets: usize,
+    /// Whether buckets are powers of two; otherwise, they're linear from min
+    /// to max, with a bucket below min and one from max
+    pub log2: bool,
+    pub min: u64,
+    pub max: u64,
+    pub step: u64,
+}
+
+fn get_max_entries(gbs: &[types::Field], max_groups: u64) -> u64 {
     if gbs.len() == 1 {
         let field = &gbs[0];
         if field._name == "cpu" {
@@ -141,5 +356,5 @@ fn get_max_entries(gbs: &[types::Field]) -> u64 {
         }
     }
     // TODO: find way to compute
-    GB_MAX_ENTRIES
+    max_groups
 }
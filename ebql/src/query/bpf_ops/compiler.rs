@@ -1,71 +1,334 @@
-use std::{env, ffi::OsString, fs, io, path::PathBuf};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
 use handlebars::Handlebars;
-use nom_sql::{ConditionBase, ConditionExpression, Literal};
 use rand::distributions::{Alphanumeric, DistString};
 
 use super::MAX_MEM_BYTES;
 use crate::{
-    events::Event,
+    bpf_struct::Struct,
+    cgroups::CgroupSet,
+    data_types::Clock,
+    error::EbqlError,
+    events::{perf::PerfEvent, program_types::ProgramType, rate, system::SystemVar},
     map::RingBuf,
-    object::Object,
-    prog_builder::{BpfCodeBuilder, Expr},
+    object::{Object, PARAMS_SECTION, PARAMS_STRUCT, PARAMS_VAR},
+    percpu::{MergeOp, PercpuAgg, PercpuAggs, EPOCH_FIELD},
+    prog_builder::{BodyConstruction, BpfCodeBuilder, Expr, GeneratedCode, ALWAYS_INLINE},
+    program::FlushProgram,
     query::{
-        bpf_ops::{agg::BpfAggregateTemplate, hist::BpfHistogramTemplate, window::BpfWindowType},
+        bpf_ops::{
+            agg::{
+                agg_value_size, hist_agg, is_overflow_column, AggMaps, BpfAggregateTemplate,
+                OverflowPolicy, AGG_VALUE_SIZE, AVG_SCALE, DEFAULT_MAX_GROUPS, DROPPED_EVENTS,
+                DROPPED_GROUPS, FIRST, LAST, MAX_BY, MIN_BY, OTHER_GROUP, PERCPU_MIN_RATE, STDDEV,
+                VARIANCE,
+            },
+            filter::{FilterCompiler, InSet},
+            hist::BpfHistogramTemplate,
+            sketch::{is_sketch, BpfCmsTemplate, BpfHllTemplate, BpfTopkTemplate},
+            synopsis::{self, SharedPlan},
+            window::{
+                is_window_bound, BpfWindowType, WindowFlush, WINDOW_END, WINDOW_START,
+                WINDOW_TIMER_MAP,
+            },
+        },
         operators::{Operator, WindowType},
         physical_plan::BpfPlan,
     },
+    schema::{field, schema::Schema},
+    stack_map::{stack_map, STACKS_SECTION, STACK_EPOCH},
     types::{Field, Type},
+    work_dir::{default_work_root, WorkDir},
 };
 
-/// Query compiler into an actual BPF representation.
+/// Period over which the rate of events is sampled, when choosing which maps to
+/// keep aggregations in.
+const RATE_SAMPLE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Query compiler into an actual BPF representation. Compiling only reads the
+/// compiler's settings, so plans can be compiled concurrently.
+#[derive(Clone, Default)]
 pub struct QueryCompiler {
-    // TODO: add fields. for shared synopses, might want to store compiled query programs internally
-    // to optimize future compilations.
+    /// Directory under which each compilation gets its own work directory
+    /// (see [`WorkDir`]); defaults to [`default_work_root`]
+    work_root: Option<PathBuf>,
+    /// Clock that the time of events is read from
+    clock: Clock,
+    /// How time windows are flushed
+    flush: WindowFlush,
+    /// Whether windows emit zero rows for groups without events (only for
+    /// queries whose aggregations are all counts)
+    emit_empty: bool,
+    /// Which maps aggregations are kept in
+    agg_maps: AggMaps,
+    /// What happens to new groups once aggregations keep `max_groups` groups
+    overflow: OverflowPolicy,
+    max_groups: Option<u64>,
+}
+
+/// Entries that user space reserves in a program's aggregation map once it's
+/// loaded: the "other" groups, which must fit even once the map is full.
+struct ReservedGroups {
+    map: String,
+    value_size: usize,
+    keys: Vec<Vec<u8>>,
+    percpu: bool,
 }
 
 impl QueryCompiler {
-    pub fn compile_bpf_ops(&mut self, plan: &BpfPlan) -> Result<Object> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory under which programs are compiled, each in its own
+    /// work directory.
+    pub fn with_work_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.work_root = Some(root.into());
+        self
+    }
+
+    /// Sets the clock that compiled programs read the time of events from.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets how compiled programs flush their time windows.
+    pub fn with_window_flush(mut self, flush: WindowFlush) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Sets whether windows of count queries emit rows (with zero counts) for
+    /// groups seen in earlier windows, but not the one being emitted.
+    pub fn with_empty_windows(mut self, emit_empty: bool) -> Self {
+        self.emit_empty = emit_empty;
+        self
+    }
+
+    /// Sets which maps compiled programs keep their aggregations in.
+    pub fn with_agg_maps(mut self, agg_maps: AggMaps) -> Self {
+        self.agg_maps = agg_maps;
+        self
+    }
+
+    /// Sets what happens to the events of new groups once aggregations keep
+    /// their maximum number of groups.
+    pub fn with_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Sets the maximum number of groups aggregations keep.
+    pub fn with_max_groups(mut self, max_groups: u64) -> Self {
+        self.max_groups = Some(max_groups);
+        self
+    }
+
+    /// Groups overlapping plans, so that each group can be compiled into one
+    /// shared program. See [`synopsis::merge_plans`].
+    pub fn merge_plans(&self, plans: &[BpfPlan]) -> Result<Vec<SharedPlan>> {
+        synopsis::merge_plans(plans)
+    }
+
+    /// Compiles a BPF plan into a loaded object. The plan is built in its own
+    /// work directory, which is removed once the object is dropped. If the
+    /// program's windows are flushed by a timer, but the kernel (or its
+    /// headers) lack `bpf_timer`, the plan is recompiled to flush its windows
+    /// on ticks instead.
+    pub fn compile_bpf_ops(&self, plan: &BpfPlan) -> Result<Object> {
+        // Which maps to keep aggregations in is decided once, since it may
+        // sample the event
+        let percpu = self.use_percpu(plan, true)?;
+        let timer =
+            self.flush == WindowFlush::Timer && matches!(plan.window, Some(WindowType::Time(..)));
+        match self.load_bpf_ops(plan, percpu) {
+            Err(e) if timer && is_load_error(&e) => {
+                log::warn!(
+                    "Failed to load {} with a window timer, flushing windows on ticks instead: \
+                     {e:#}",
+                    plan.schema.name
+                );
+                self.clone()
+                    .with_window_flush(WindowFlush::Tick)
+                    .load_bpf_ops(plan, percpu)
+            }
+            res => res,
+        }
+    }
+
+    /// Compiles and loads a BPF plan with the compiler's settings.
+    fn load_bpf_ops(&self, plan: &BpfPlan, percpu: bool) -> Result<Object> {
+        let (cb, in_sets, cgroup_sets, reserved) = self.codegen(plan, percpu)?;
+
+        // Build into object
+        let root = self.work_root.clone().unwrap_or_else(default_work_root);
+        let work_dir = WorkDir::create(&root, &plan.schema.name)?;
+        let br = cb.build(work_dir.path())?;
+
+        let mut obj = Object::load(&plan.schema.name, vec![br], None)?.with_work_dir(work_dir);
+
+        // Write parameter defaults before the program is attached
+        for (name, value) in &plan.param_defaults {
+            obj.set_param(name, value)?;
+        }
+
+        // Populate hash sets
+        for set in &in_sets {
+            for key in &set.keys {
+                obj.update_map(&set.map.name, key, &[1])?;
+            }
+        }
+        for set in &cgroup_sets {
+            obj.watch_cgroups(set)?;
+        }
+
+        // Reserve the "other" groups (with no values yet)
+        if let Some(reserved) = reserved {
+            let n_cpus = libbpf_rs::num_possible_cpus()?;
+            let value = vec![0u8; reserved.value_size];
+            for key in &reserved.keys {
+                if reserved.percpu {
+                    obj.update_percpu_map(&reserved.map, key, &vec![value.clone(); n_cpus])?;
+                } else {
+                    obj.update_map(&reserved.map, key, &value)?;
+                }
+            }
+        }
+
+        Ok(obj)
+    }
+
+    /// Generates the code of a BPF plan (i.e. its source, header, and rendered
+    /// templates) without compiling or loading it. Since the event isn't
+    /// sampled, plans whose maps are chosen automatically are shown with
+    /// shared maps.
+    pub fn explain_codegen(&self, plan: &BpfPlan) -> Result<GeneratedCode> {
+        let percpu = self.use_percpu(plan, false)?;
+        Ok(self.codegen(plan, percpu)?.0.render())
+    }
+
+    /// Generates the program of a BPF plan, along with the hash and cgroup sets
+    /// its filter requires and the groups to reserve in its aggregation map.
+    /// Aggregations are kept in per-CPU maps if `percpu` is set (see
+    /// [`Self::use_percpu`]).
+    fn codegen(
+        &self,
+        plan: &BpfPlan,
+        percpu: bool,
+    ) -> Result<(
+        BpfCodeBuilder,
+        Vec<InSet>,
+        Vec<CgroupSet>,
+        Option<ReservedGroups>,
+    )> {
         // Create code builder and template engine
-        let mut cb = BpfCodeBuilder::new(
-            plan.schema.name.clone(),
-            format!(
-                "{}/{}",
-                plan.event.program_type().section_name(),
-                plan.event.name()
-            ),
-        );
+        let mut cb = BpfCodeBuilder::new(plan.schema.name.clone(), plan.event.section());
         let mut handlebars = Handlebars::new();
 
-        // First, generate window definition
-        match &plan.window {
-            Some(wt) => {
-                let wt = BpfWindowType::try_from(wt)?;
-                // For windows, get external header file
-                let tmpl = wt.get_tmpl(plan.schema.name.clone(), !plan.aggs.is_empty());
-                // Render template into actual code
-                handlebars.register_template_file(&tmpl.name, tmpl.tmpl_path)?;
-                let text = handlebars.render(&tmpl.name, &tmpl.ctx)?;
-                // Register rendered template into code builder
-                cb.add_external_includes(&tmpl.name, text);
+        // Read time from the configured clock; UTC is converted from boot time
+        // (whose offset from UTC is unaffected by suspend)
+        let ktime = match self.clock {
+            Clock::Monotonic => "bpf_ktime_get_ns()",
+            Clock::Boot | Clock::Utc => "bpf_ktime_get_boot_ns()",
+        };
+        cb.write_macro("KTIME_NS()", ktime);
+
+        // Stacks are collected into the program's stack trace maps, by epoch
+        if collects_stacks(plan) {
+            for epoch in 0..2 {
+                let map = stack_map(&plan.schema.name, epoch);
+                cb.write_macro(format!("STACK_TRACES_{epoch}"), &map.name);
+                cb = cb.write_map(&map);
             }
+            let section = format!("SEC(\"{STACKS_SECTION}\")");
+            cb.write_global(
+                vec![section.as_str(), "volatile"],
+                &Field::new(STACK_EPOCH.into(), Type::U64),
+                Some("0"),
+            );
+        }
+        // Perf events are opened by user space, which attaches the program
+        if let ProgramType::PerfEvent = plan.event.program_type() {
+            cb.set_perf_event(PerfEvent::from_str(&plan.event.name())?);
+        }
+
+        // First, generate window definition
+        let window = match &plan.window {
+            Some(wt) => wt,
             None => {
-                return Err(anyhow!(
-                    "Did not get window op for query {}",
-                    plan.schema.name
+                bail!(EbqlError::unsupported(
+                    &plan.schema.name,
+                    "queries without a window are not supported"
                 ))
             }
-        }
+        };
+        let wt = BpfWindowType::try_from(window)?;
+        let scheduled = wt.is_scheduled(self.flush);
+        let emit_empty = self.emits_empty(plan);
+        // Window bound columns are filled in by the window and aggregation templates
+        let bounds = plan
+            .schema
+            .fields
+            .iter()
+            .map(|f| f.name.as_str())
+            .filter(|name| is_window_bound(name))
+            .collect::<Vec<_>>();
+        // As are the overflow columns, by the aggregation template
+        let overflow_cols = plan
+            .schema
+            .fields
+            .iter()
+            .map(|f| f.name.as_str())
+            .filter(|name| is_overflow_column(name))
+            .collect::<Vec<_>>();
+        // Only groups overflow (distinct counts aren't grouped)
+        let other = self.overflow == OverflowPolicy::Other && !plan.group_by.is_empty();
+        // For windows, get external header file
+        let tmpl = wt.get_tmpl(
+            plan.schema.name.clone(),
+            !plan.aggs.is_empty(),
+            &bounds,
+            self.flush,
+            percpu,
+        );
+        // Render template into actual code
+        handlebars.register_template_string(&tmpl.name, tmpl.tmpl)?;
+        let text = handlebars.render(&tmpl.name, &tmpl.ctx)?;
+        // Register rendered template into code builder
+        cb.add_external_includes(&tmpl.name, text);
 
         // Then, convert aggregates and joins into headers
-        let mut agg_tmpl = BpfAggregateTemplate::new(plan.schema.name.clone(), &plan.group_by);
+        let mut agg_tmpl = BpfAggregateTemplate::new(
+            plan.schema.name.clone(),
+            &plan.group_by,
+            self.max_groups.unwrap_or(DEFAULT_MAX_GROUPS),
+        );
+        agg_tmpl.ctx.window_start = bounds.contains(&WINDOW_START);
+        agg_tmpl.ctx.window_end = bounds.contains(&WINDOW_END);
+        agg_tmpl.ctx.emit_empty = emit_empty;
+        agg_tmpl.ctx.percpu = percpu;
+        agg_tmpl.ctx.lru = self.overflow == OverflowPolicy::Lru;
+        agg_tmpl.ctx.other = other;
+        agg_tmpl.ctx.dropped_events = overflow_cols.contains(&DROPPED_EVENTS);
+        agg_tmpl.ctx.dropped_groups = overflow_cols.contains(&DROPPED_GROUPS);
+        agg_tmpl.ctx.other_col = overflow_cols.contains(&OTHER_GROUP);
+        // Room for the "other" group (of each epoch, for per-CPU maps)
+        if other {
+            agg_tmpl.ctx.gb_max_entries += if percpu { 2 } else { 1 };
+        }
+        let mut hll_tmpl = BpfHllTemplate::new(plan.schema.name.clone());
+        let mut cms_tmpl = BpfCmsTemplate::new(plan.schema.name.clone());
+        let mut topk_tmpl = BpfTopkTemplate::new(plan.schema.name.clone());
+        topk_tmpl.ctx.window_start = bounds.contains(&WINDOW_START);
+        topk_tmpl.ctx.window_end = bounds.contains(&WINDOW_END);
         for op in &plan.aggs {
             // Get template, then render into code
             match op {
                 Operator::Histogram(buckets) => {
                     let tmpl = BpfHistogramTemplate::get_tmpl(buckets);
-                    handlebars.register_template_file(&tmpl.name, tmpl.tmpl_path)?;
+                    handlebars.register_template_string(&tmpl.name, tmpl.tmpl)?;
                     let text = handlebars.render(&tmpl.name, &tmpl.ctx)?;
                     // Register into code builder
                     cb.add_external_includes(&tmpl.name, text);
@@ -79,21 +342,67 @@ impl QueryCompiler {
                 Operator::Count(_) => {
                     agg_tmpl.ctx.update(op)?;
                 }
+                Operator::Variance(_)
+                | Operator::Stddev(_)
+                | Operator::MinBy(..)
+                | Operator::MaxBy(..)
+                | Operator::First(_)
+                | Operator::Last(_)
+                | Operator::Hist(..) => {
+                    agg_tmpl.ctx.update(op)?;
+                }
                 // Operator::Count(None) => unimplemented!("TODO: implement count star"),
+                Operator::ApproxCountDistinct(..) => {
+                    hll_tmpl.ctx.update(op)?;
+                }
+                // Approximate counts are emitted for the values monitored by a
+                // top-k summary, i.e. the window's candidate heavy hitters
+                Operator::ApproxCount(s, _, _) | Operator::TopK(s, _, _) => {
+                    let Some(f) = plan.projects.iter().find(|f| &f._name == s) else {
+                        bail!(EbqlError::codegen(op, "sketched column is not projected"));
+                    };
+                    if matches!(op, Operator::ApproxCount(..)) {
+                        cms_tmpl.ctx.update(op)?;
+                    }
+                    topk_tmpl.ctx.update(op, f)?;
+                }
                 _ => (),
             };
         }
-        handlebars.register_template_file(&agg_tmpl.name, agg_tmpl.tmpl_path)?;
+        // Sketches are included before the aggregations, which add to them
+        if !hll_tmpl.ctx.sketches.is_empty() {
+            handlebars.register_template_string(&hll_tmpl.name, hll_tmpl.tmpl)?;
+            let text = handlebars.render(&hll_tmpl.name, &hll_tmpl.ctx)?;
+            cb.add_external_includes(&hll_tmpl.name, text);
+        }
+        if !cms_tmpl.ctx.sketches.is_empty() {
+            handlebars.register_template_string(&cms_tmpl.name, cms_tmpl.tmpl)?;
+            let text = handlebars.render(&cms_tmpl.name, &cms_tmpl.ctx)?;
+            cb.add_external_includes(&cms_tmpl.name, text);
+        }
+        if !topk_tmpl.ctx.sketches.is_empty() {
+            handlebars.register_template_string(&topk_tmpl.name, topk_tmpl.tmpl)?;
+            let text = handlebars.render(&topk_tmpl.name, &topk_tmpl.ctx)?;
+            cb.add_external_includes(&topk_tmpl.name, text);
+        }
+        handlebars.register_template_string(&agg_tmpl.name, agg_tmpl.tmpl)?;
         let text = handlebars.render(&agg_tmpl.name, &agg_tmpl.ctx)?;
         cb.add_external_includes(&agg_tmpl.name, text);
 
         // TODO: handle joins
         if let Some(dj) = &plan.distinct_join {
-            unimplemented!("TODO: implement join")
+            bail!(EbqlError::unsupported(
+                Operator::DistinctJoin(dj.fields.iter().map(|f| f.name.clone()).collect()),
+                "joins not yet supported"
+            ))
         }
 
         // Convert schema into bpf struct
-        let bpf_struct = plan.schema.clone().to_bpf_struct(&plan.event)?;
+        let bpf_struct = plan
+            .schema
+            .clone()
+            .to_bpf_struct(&plan.event)?
+            .with_clock(self.clock)?;
         let struct_size = bpf_struct.sz;
 
         // Then, define ring buf from schema:
@@ -103,13 +412,42 @@ impl QueryCompiler {
                 Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
             ),
             s_repr: bpf_struct.clone(),
-            max_entries: get_max_entries(&plan.window.as_ref().unwrap(), struct_size),
+            max_entries: get_max_entries(window, struct_size)?,
+            percpu: percpu.then(|| percpu_aggs(plan, other)).transpose()?,
         };
 
-        let cb = cb.write_ring_buffer(&rb);
+        let mut cb = cb.write_ring_buffer(&rb);
+
+        // Define query parameters in their own data section, so that they can
+        // be updated from user space while the program runs
+        if !plan.params.is_empty() {
+            let params = Struct::new(
+                PARAMS_STRUCT.into(),
+                plan.params.clone(),
+                Arc::new(Schema::new(
+                    Some(PARAMS_STRUCT.into()),
+                    plan.params
+                        .iter()
+                        .map(field::Field::try_from)
+                        .collect::<Result<_>>()?,
+                )),
+                false,
+            );
+            cb = cb.write_struct(&params);
+            let section = format!("SEC(\"{PARAMS_SECTION}\")");
+            cb.write_global(
+                vec![section.as_str(), "volatile"],
+                &Field::new(PARAMS_VAR.into(), Type::Struct(PARAMS_STRUCT.into(), None)),
+                Some("{}"),
+            );
+        }
 
         log::info!("RB schema: {}", rb.s_repr.schema);
 
+        // Flushing (i.e. emitting) the window is shared between the event
+        // handler and, for scheduled windows, the flush program
+        let cb = write_flush(cb, plan, &rb, percpu)?;
+
         // Then, build program from operators (TODO: handle join after i get working)
         let args = vec![Expr::new(
             "ctx".into(),
@@ -126,172 +464,50 @@ impl QueryCompiler {
         cb.write_func_call("DEBUG", &["\"Got event\""]);
 
         // Implement filter
-        if let Some(filter) = &plan.filters {
-            if let Operator::Filter(ce) = filter {
-                let cond = ce_to_cond(&ce);
+        let mut filter = FilterCompiler::new(&plan.projects);
+        if let Some(op) = &plan.filters {
+            if let Operator::Filter(ce) = op {
+                let cond = filter.ce_to_cond(&ce)?;
                 cb.write_if(&cond);
                 let filtered_str = vec!["\"Event did not match filter; dropping...\""];
                 cb.write_func_call("INFO", &filtered_str);
                 cb.write_return("1");
                 cb.close_if();
             } else {
-                return Err(anyhow!("got non-filter op {filter} in filters"));
+                bail!(EbqlError::codegen(op, "got non-filter op in filters"));
             }
         }
 
         // Implement maps
         // TODO: finish this
-        if plan.maps.len() > 0 {
-            unimplemented!("maps not yet supported")
+        if let Some(op) = plan.maps.first() {
+            bail!(EbqlError::unsupported(op, "maps not yet supported"))
         }
 
         // Execute aggs if they exist; otherwise, execute join; otherwise, make struct
         // and add to window
         if plan.aggs.len() > 0 {
-            // First, add to window and see if need to tumble
-            let mut window_args = vec![];
-            if let Some(WindowType::Time(_, _)) = plan.window {
-                window_args.push("time");
-            }
-            cb.write_var_initialization(
-                &Field::new(String::from("tumble"), Type::Bool),
-                &format!("window_add({})", window_args.join(", ")),
-            );
-
-            // Check if we need to tumble
-            cb.write_if("tumble");
-
-            // Within the if, a tumble is required, so allocate space in the ringbuf
-            let (agg_name, field_name) = match &plan.aggs[0] {
-                Operator::Max(s) => ("max", s.as_str()),
-                Operator::Min(s) => ("min", s.as_str()),
-                Operator::Average(s) => ("avg", s.as_str()),
-                Operator::Sum(s) => ("sum", s.as_str()),
-                Operator::Count(Some(s)) => ("count", s.as_str()),
-                Operator::Count(None) => ("count", ""),
-                _ => {
-                    return Err(anyhow!(
-                        "First aggregation {} shuold be one of implemented aggs",
-                        plan.aggs[0]
-                    ))
-                }
-            };
-            // Get the number of unique group bys in all aggs; just one agg should be
-            // sufficient
-            cb.write_var_initialization(
-                &Field::new(String::from("n_results"), Type::U64),
-                &format!("count_{}_{}_{}()", agg_name, field_name, &plan.schema.name),
-            );
-            // Appease verifier
-            cb.write_if(&format!("n_results >= {}", &rb.max_entries));
-            cb.write_func_call(
-                "WARN",
-                &["\"Got too many results; truncating to max rb entries...\""],
-            );
-            cb.write_var_assignment("n_results", &format!("{}", &rb.max_entries));
-            cb.close_if();
-
-            // Only run if we actually got results
-            cb.write_if("n_results > 0");
-
-            // Reserve rb space
-            cb.write_var_initialization(
-                &Field::new(
-                    String::from("buf"),
-                    Type::Pointer(Box::new(Type::Struct(
-                        format!("{}_t", &plan.schema.name),
-                        None,
-                    ))),
-                ),
-                &format!(
-                    "bpf_ringbuf_reserve(&{}, n_results * sizeof({}_t), 0)",
-                    &rb.name, &plan.schema.name
-                ),
-            );
-
-            // Bounds check to appease verifier
-            cb.write_if("!buf");
-            cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
-            cb.write_return("1");
-            cb.close_if();
-
-            // Iterate over aggs and compute result
-            for agg in &plan.aggs {
-                match agg {
-                    Operator::Max(s) => {
-                        let func = format!("get_max_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &["buf", "n_results"]);
-                    }
-                    Operator::Min(s) => {
-                        let func = format!("get_min_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &["buf", "n_results"]);
-                    }
-                    Operator::Average(s) => {
-                        let func = format!("get_avg_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &["buf", "n_results"]);
-                    }
-                    Operator::Sum(s) => {
-                        let func = format!("get_sum_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &["buf", "n_results"]);
-                    }
-                    Operator::Count(Some(s)) => {
-                        let func = format!("get_count_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &["buf", "n_results"]);
-                    }
-                    Operator::Count(None) => {
-                        let func = format!("get_count__{}", &plan.schema.name);
-                        cb.write_func_call(&func, &["buf", "n_results"]);
-                    }
-                    _ => unimplemented!("tumble agg for {agg} not implemented"),
+            // First, add to window and see if need to tumble (scheduled windows
+            // are only tumbled by the flush program)
+            if !scheduled {
+                let mut window_args = vec![];
+                if let Some(WindowType::Time(_, _)) = plan.window {
+                    window_args.push("time");
                 }
-            }
+                cb.write_var_initialization(
+                    &Field::new(String::from("tumble"), Type::Bool),
+                    &format!("window_add({})", window_args.join(", ")),
+                );
 
-            // Submit to ringbuf
-            cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
-
-            cb.close_if();
-
-            // Tumble aggregations
-            for agg in &plan.aggs {
-                match agg {
-                    Operator::Histogram(_) => unimplemented!("need to add histogram to nom-sql"),
-                    Operator::Quantile(_) => unimplemented!("need to add histogram to nom-sql"),
-                    Operator::Max(s) => {
-                        let func = format!("tumble_max_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &[]);
-                    }
-                    Operator::Min(s) => {
-                        let func = format!("tumble_min_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &[]);
-                    }
-                    Operator::Average(s) => {
-                        let func = format!("tumble_avg_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &[]);
-                    }
-                    Operator::Sum(s) => {
-                        let func = format!("tumble_sum_{}_{}", s, &plan.schema.name);
-                        cb.write_func_call(&func, &[]);
-                    }
-                    Operator::Count(s) => {
-                        match s {
-                            Some(s) => {
-                                let func = format!("tumble_count_{}_{}", s, &plan.schema.name);
-                                cb.write_func_call(&func, &[]);
-                            }
-                            None => {
-                                let func = format!("tumble_count__{}", &plan.schema.name);
-                                cb.write_func_call(&func, &[]);
-                            }
-                        }
-                    }
-                    _ => return Err(anyhow!("Operator {agg} not an aggregate!")),
-                }
+                // If a tumble is required, flush the window, then tumble it
+                cb.write_if("tumble");
+                cb.write_if("flush_window() != 0");
+                cb.write_return("1");
+                cb.close_if();
+                cb.write_func_call("window_tumble", &window_args);
+                cb.close_if();
             }
 
-            // Tumble window
-            cb.write_func_call("window_tumble", &window_args);
-            cb.close_if();
-
             // After ifs are closed (i.e. after we potentially tumble), insert into aggs
             let gb = if plan.group_by.len() > 0 {
                 format!(
@@ -303,146 +519,740 @@ impl QueryCompiler {
                         .collect::<Vec<_>>()
                         .join(", "),
                 )
+            } else if plan.aggs.iter().all(|op| {
+                matches!(
+                    op,
+                    Operator::ApproxCountDistinct(..)
+                        | Operator::ApproxCount(..)
+                        | Operator::TopK(..)
+                )
+            }) {
+                // Sketches are kept over the whole window
+                String::new()
             } else {
                 // If no GB, use dummy var
-                unimplemented!("non-grouped aggs not yet supported")
+                bail!(EbqlError::unsupported(
+                    &plan.aggs[0],
+                    "aggregations without a group by not yet supported"
+                ))
             };
+            // All of a group's aggregates are updated by a single insert (see
+            // agg.bpf.h.tmpl), with the values of each in turn
+            let mut args = vec![gb.clone()];
             for agg in &plan.aggs {
                 match agg {
-                    Operator::GroupBy(_) => unimplemented!("shouldn't have any group bys"),
-                    Operator::Histogram(_) => unimplemented!("need to add histogram to nom-sql"),
-                    Operator::Quantile(_) => unimplemented!("need to add histogram to nom-sql"),
-                    Operator::Max(s) => {
-                        let func = format!("insert_max_{}_{}", s, &plan.schema.name);
-                        let args = vec![gb.as_str(), s.as_str()];
-                        cb.write_func_call(&func, &args);
+                    Operator::GroupBy(_) => {
+                        bail!(EbqlError::codegen(agg, "group bys are not aggregates"))
+                    }
+                    Operator::Histogram(_) | Operator::Quantile(_) => {
+                        bail!(EbqlError::unsupported(agg, "histograms not yet supported"))
                     }
-                    Operator::Min(s) => {
-                        let func = format!("insert_min_{}_{}", s, &plan.schema.name);
-                        let args = vec![gb.as_str(), s.as_str()];
-                        cb.write_func_call(&func, &args);
+                    Operator::Max(s)
+                    | Operator::Min(s)
+                    | Operator::Average(s)
+                    | Operator::Sum(s)
+                    | Operator::Variance(s)
+                    | Operator::Stddev(s)
+                    | Operator::First(s)
+                    | Operator::Last(s)
+                    | Operator::Hist(s, _) => args.push(s.clone()),
+                    Operator::Count(_) => args.push("1".into()),
+                    Operator::MinBy(s, by) | Operator::MaxBy(s, by) => {
+                        args.push(s.clone());
+                        args.push(by.clone());
                     }
-                    Operator::Average(s) => {
-                        let func = format!("insert_avg_{}_{}", s, &plan.schema.name);
-                        let args = vec![gb.as_str(), s.as_str()];
-                        cb.write_func_call(&func, &args);
+                    Operator::ApproxCountDistinct(s, _) => {
+                        let func = format!("hll_add_{}_{}", s, &plan.schema.name);
+                        cb.write_func_call(&func, &[&format!("(u64){s}")]);
                     }
-                    Operator::Sum(s) => {
-                        let func = format!("insert_sum_{}_{}", s, &plan.schema.name);
-                        let args = vec![gb.as_str(), s.as_str()];
-                        cb.write_func_call(&func, &args);
+                    Operator::ApproxCount(s, _, _) => {
+                        let val = format!("(u64){s}");
+                        let func = format!("cms_add_{}_{}", s, &plan.schema.name);
+                        cb.write_func_call(&func, &[&val]);
+                        let func = format!("topk_add_{}_{}", s, &plan.schema.name);
+                        cb.write_func_call(&func, &[&val]);
                     }
-                    Operator::Count(s) => {
-                        match s {
-                            Some(s) => {
-                                let func = format!("insert_count_{}_{}", s, &plan.schema.name);
-                                let args = vec![gb.as_str(), "1"];
-                                cb.write_func_call(&func, &args);
-                            }
-                            None => {
-                                let func = format!("insert_count__{}", &plan.schema.name);
-                                let args = vec![gb.as_str(), "1"];
-                                cb.write_func_call(&func, &args);
-                            }
-                        }
+                    Operator::TopK(s, _, _) => {
+                        let func = format!("topk_add_{}_{}", s, &plan.schema.name);
+                        cb.write_func_call(&func, &[&format!("(u64){s}")]);
                     }
-                    _ => return Err(anyhow!("Operator {agg} not an aggregate!")),
+                    _ => bail!(EbqlError::codegen(agg, "operator is not an aggregate")),
                 }
             }
-        } else if let Some(dj) = &plan.distinct_join {
-            unimplemented!("distinct joins not yet supported")
+            if args.len() > 1 {
+                let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+                cb.write_func_call(&format!("insert_{}", &plan.schema.name), &args);
+            }
         } else {
-            // Add to window
+            // Add to window (window bounds are filled in by the window)
             let window_arg = format!(
                 "({}_t){{{}}}",
                 &plan.schema.name,
                 plan.projects
                     .iter()
-                    .map(|f| f._name.clone())
+                    .map(|f| format!(".{0} = {0}", f._name))
                     .collect::<Vec<_>>()
                     .join(", ")
             );
-            cb.write_var_initialization(
-                &Field::new(String::from("tumble"), Type::Bool),
-                &format!("window_add({})", window_arg),
-            );
+            if scheduled {
+                cb.write_func_call("window_add", &[&window_arg]);
+            } else {
+                cb.write_var_initialization(
+                    &Field::new(String::from("tumble"), Type::Bool),
+                    &format!("window_add({})", window_arg),
+                );
 
-            // If tumble, copy over
-            cb.write_if("tumble");
+                // If tumble, copy over, then tumble the window
+                cb.write_if("tumble");
+                cb.write_if("flush_window() != 0");
+                cb.write_return("1");
+                cb.close_if();
+                if matches! {&plan.window, Some(WindowType::Time(_, _))} {
+                    cb.write_func_call("window_tumble", &[&window_arg]);
+                } else {
+                    cb.write_func_call("window_tumble", &[]);
+                }
+                cb.close_if();
+            }
+        }
 
-            cb.write_var_initialization(
-                &Field::new(String::from("n_results"), Type::U64),
-                "get_size()",
-            );
+        cb.write_return("0");
+        let mut cb = cb.close();
 
-            // Only proceed if we actually have results
-            // cb.write_if("n_results > 0");
+        // Scheduled windows are flushed by a separate program
+        let timer = scheduled && self.flush == WindowFlush::Timer;
+        if scheduled {
+            cb = write_schedule(cb, plan, window, self.flush)?;
+        }
+        cb = write_stop(cb, plan, timer);
 
-            // Appease verifier
-            cb.write_if(&format!("n_results >= {}", &rb.max_entries));
-            cb.write_func_call(
-                "WARN",
-                &["\"Got too many results; truncating to max rb entries...\""],
-            );
-            cb.write_var_assignment("n_results", &format!("{}", &rb.max_entries));
-            cb.close_if();
+        // Queries fail on overflow by having user space stop their output
+        if self.overflow == OverflowPolicy::Error && !plan.aggs.is_empty() {
+            cb.set_fail_on_overflow();
+        }
 
-            let n_bytes = format!("n_results * sizeof({}_t)", &plan.schema.name);
-            // Reserve space in ringbuf
-            cb.write_var_initialization(
-                &Field::new(
-                    String::from("buf"),
-                    Type::Pointer(Box::new(Type::Struct(
-                        format!("{}_t", &plan.schema.name),
-                        None,
-                    ))),
-                ),
-                &format!("bpf_ringbuf_reserve(&{}, {}, 0)", &rb.name, n_bytes),
-            );
+        // Define hash sets for the filter's IN lists and cgroups
+        for set in &filter.in_sets {
+            cb = cb.write_map(&set.map);
+        }
+        for set in &filter.cgroup_sets {
+            cb = cb.write_map(&set.map);
+        }
 
-            // Bounds check to appease verifier
-            cb.write_if("!buf");
-            cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
-            cb.write_return("1");
-            cb.close_if();
+        let reserved = other.then(|| reserved_groups(plan, percpu)).transpose()?;
+        Ok((cb, filter.in_sets, filter.cgroup_sets, reserved))
+    }
 
-            // Copy over from window
-            cb.write_func_call("bpf_probe_read_kernel", &["buf", n_bytes.as_str(), "w.buf"]);
+    /// Returns whether the plan's windows emit rows for empty groups, which
+    /// only count queries do.
+    fn emits_empty(&self, plan: &BpfPlan) -> bool {
+        self.emit_empty
+            && !plan.aggs.is_empty()
+            && plan.aggs.iter().all(|op| matches!(op, Operator::Count(_)))
+    }
 
-            // Submit
-            cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
+    /// Decides whether the plan's aggregations are kept in per-CPU maps.
+    /// Groups by CPU alone are never contended, and groups emitted when empty
+    /// must outlive their windows, so both are kept in shared maps. Otherwise,
+    /// [`AggMaps::Auto`] samples the event's rate if `sample` is set, and keeps
+    /// aggregations in shared maps if not.
+    fn use_percpu(&self, plan: &BpfPlan, sample: bool) -> Result<bool> {
+        if plan.aggs.is_empty() {
+            return Ok(false);
+        }
+        let emit_empty = self.emits_empty(plan);
+        let cpu_local = plan.group_by.len() == 1 && plan.group_by[0]._name == "cpu";
+        // User space only merges simple aggregations; sketches are shared array
+        // maps, whose counts can't be merged per group, and the others would
+        // need their order across CPUs
+        let unmergeable = plan.aggs.iter().find(|op| {
+            !matches!(
+                op,
+                Operator::Max(_)
+                    | Operator::Min(_)
+                    | Operator::Average(_)
+                    | Operator::Sum(_)
+                    | Operator::Count(_)
+            )
+        });
+        // Evictions are only counted for shared maps, whose groups are never
+        // deleted
+        let lru = self.overflow == OverflowPolicy::Lru;
+        match self.agg_maps {
+            AggMaps::Shared => Ok(false),
+            AggMaps::PerCpu => {
+                if emit_empty {
+                    bail!(EbqlError::unsupported(
+                        &plan.schema.name,
+                        "empty windows can't be emitted from per-CPU aggregation maps"
+                    ))
+                }
+                if lru {
+                    bail!(EbqlError::unsupported(
+                        &plan.schema.name,
+                        "per-CPU aggregation maps don't support LRU eviction"
+                    ))
+                }
+                if let Some(op) = unmergeable {
+                    let msg = if is_sketch(op) {
+                        "approximate aggregations can't be kept in per-CPU maps"
+                    } else {
+                        "aggregation can't be kept in per-CPU maps"
+                    };
+                    bail!(EbqlError::unsupported(op, msg))
+                }
+                Ok(true)
+            }
+            AggMaps::Auto if emit_empty || cpu_local || lru || unmergeable.is_some() => Ok(false),
+            AggMaps::Auto if !sample => Ok(false),
+            AggMaps::Auto => {
+                match rate::sample_rate(plan.event.as_ref(), RATE_SAMPLE_PERIOD) {
+                    Ok(rate) => {
+                        log::info!("Sampled {} at {rate:.0} events/s", plan.event.name());
+                        Ok(rate >= PERCPU_MIN_RATE)
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to sample {}, keeping aggregations in shared maps: {e}",
+                            plan.event.name()
+                        );
+                        Ok(false)
+                    }
+                }
+            }
+        }
+    }
+}
 
-            // cb.close_if();
+/// Gets the layout of the keys of a plan's aggregation map, as in the
+/// template: the group bys, then whether the group is the "other" group, then
+/// the epoch.
+fn agg_key(plan: &BpfPlan, other: bool, percpu: bool) -> Result<Struct> {
+    let mut key_fields = plan.group_by.clone();
+    if other {
+        key_fields.push(Field::new(OTHER_GROUP.into(), Type::U64));
+    }
+    if percpu {
+        key_fields.push(Field::new(EPOCH_FIELD.into(), Type::U64));
+    }
+    let key_name = format!("group_by_{}_t", &plan.schema.name);
+    Ok(Struct::new(
+        key_name.clone(),
+        key_fields.clone(),
+        Arc::new(Schema::new(
+            Some(key_name),
+            key_fields
+                .iter()
+                .map(field::Field::try_from)
+                .collect::<Result<_>>()?,
+        )),
+        false,
+    ))
+}
 
-            // Afterwards, tumble window
-            if matches! {&plan.window, Some(WindowType::Time(_, _))} {
-                cb.write_func_call("window_tumble", &[&window_arg]);
-            } else {
-                cb.write_func_call("window_tumble", &[]);
+/// Gets the keys of the "other" groups of a plan (one for each epoch, for
+/// per-CPU maps), with the map to reserve them in.
+fn reserved_groups(plan: &BpfPlan, percpu: bool) -> Result<ReservedGroups> {
+    let key = agg_key(plan, true, percpu)?;
+    let offs = key.field_offsets();
+    let off = |name: &str| {
+        offs.iter()
+            .find(|(f, _)| f._name == name)
+            .map(|(_, off)| *off)
+    };
+    let Some(other_off) = off(OTHER_GROUP) else {
+        bail!(EbqlError::codegen(
+            &plan.schema.name,
+            "aggregation key has no other flag"
+        ));
+    };
+    let epochs = if percpu { vec![0u64, 1] } else { vec![0] };
+    let keys = epochs
+        .into_iter()
+        .map(|epoch| {
+            let mut k = vec![0u8; key.sz];
+            k[other_off..other_off + 8].copy_from_slice(&1u64.to_ne_bytes());
+            if let Some(epoch_off) = off(EPOCH_FIELD) {
+                k[epoch_off..epoch_off + 8].copy_from_slice(&epoch.to_ne_bytes());
             }
-            cb.close_if();
+            k
+        })
+        .collect();
+    Ok(ReservedGroups {
+        map: format!("aggs_{}", &plan.schema.name),
+        value_size: plan.aggs.iter().map(agg_value_size).sum(),
+        keys,
+        percpu,
+    })
+}
+
+/// Returns whether a plan collects stacks.
+fn collects_stacks(plan: &BpfPlan) -> bool {
+    plan.projects
+        .iter()
+        .any(|f| SystemVar::from_str(&f._name).is_ok_and(|sv| sv.is_stack()))
+}
+
+/// Gets the output column of an aggregation, which is also the name of its
+/// member in the values of the aggregation map.
+fn agg_column(agg: &Operator) -> Result<String> {
+    let (name, field) = match agg {
+        Operator::Max(s) => ("max", s.clone()),
+        Operator::Min(s) => ("min", s.clone()),
+        Operator::Average(s) => ("avg", s.clone()),
+        Operator::Sum(s) => ("sum", s.clone()),
+        Operator::Count(Some(s)) => ("count", s.clone()),
+        Operator::Count(None) => ("count", String::new()),
+        Operator::Variance(s) => (VARIANCE, s.clone()),
+        Operator::Stddev(s) => (STDDEV, s.clone()),
+        Operator::MinBy(s, by) => (MIN_BY, format!("{s}_{by}")),
+        Operator::MaxBy(s, by) => (MAX_BY, format!("{s}_{by}")),
+        Operator::First(s) => (FIRST, s.clone()),
+        Operator::Last(s) => (LAST, s.clone()),
+        Operator::Hist(s, buckets) => (hist_agg(buckets), s.clone()),
+        agg => bail!(EbqlError::unsupported(agg, "aggregation not yet supported")),
+    };
+    Ok(format!("{name}_{field}"))
+}
+
+/// Gets the per-CPU aggregations of a plan, which user space merges.
+fn percpu_aggs(plan: &BpfPlan, other: bool) -> Result<PercpuAggs> {
+    let key = agg_key(plan, other, true)?;
+    let other = other
+        .then(|| {
+            key.field_offsets()
+                .into_iter()
+                .find(|(f, _)| f._name == OTHER_GROUP)
+                .map(|(_, off)| off)
+        })
+        .flatten();
+
+    // Aggregates are laid out in the map's values in order
+    let mut offset = 0;
+    let aggs = plan
+        .aggs
+        .iter()
+        .map(|agg| {
+            let op = match agg {
+                Operator::Max(_) => MergeOp::Max,
+                Operator::Min(_) => MergeOp::Min,
+                Operator::Average(_) => MergeOp::Avg(AVG_SCALE),
+                Operator::Sum(_) => MergeOp::Sum,
+                Operator::Count(_) => MergeOp::Count,
+                agg => bail!(EbqlError::unsupported(agg, "aggregation not yet supported")),
+            };
+            let column = agg_column(agg)?;
+            let agg = PercpuAgg { offset, op, column };
+            offset += AGG_VALUE_SIZE;
+            Ok(agg)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(PercpuAggs {
+        map: format!("aggs_{}", &plan.schema.name),
+        key,
+        aggs,
+        other,
+    })
+}
+
+/// Writes `flush_window()`, which emits the results of the open window into the
+/// ring buffer and resets its aggregations, returning non-zero if the results
+/// couldn't be emitted. Tumbling the window itself is left to the caller.
+///
+/// Per-CPU aggregations are instead merged by user space: the window is ended
+/// by emitting a marker row, after moving new events to the next epoch.
+fn write_flush(
+    cb: BpfCodeBuilder,
+    plan: &BpfPlan,
+    rb: &RingBuf,
+    percpu: bool,
+) -> Result<BpfCodeBuilder> {
+    let mut cb = cb.start_static_function(vec![ALWAYS_INLINE], &Type::S32, "flush_window", &[]);
+
+    // Stacks of the next window are collected into the other map, while user
+    // space resolves this window's
+    if collects_stacks(plan) {
+        cb.write_func_call("__sync_fetch_and_xor", &[&format!("&{STACK_EPOCH}"), "1"]);
+    }
+
+    if percpu {
+        cb.write_var_initialization(
+            &Field::new(
+                String::from("buf"),
+                Type::Pointer(Box::new(Type::Struct(
+                    format!("{}_t", &plan.schema.name),
+                    None,
+                ))),
+            ),
+            &format!(
+                "bpf_ringbuf_reserve(&{}, sizeof({}_t), 0)",
+                &rb.name, &plan.schema.name
+            ),
+        );
+        cb.write_if("!buf");
+        cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
+        cb.write_return("1");
+        cb.close_if();
+        cb.write_func_call(&format!("mark_window_{}", &plan.schema.name), &["buf"]);
+        cb.write_func_call("window_flip", &[]);
+        cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
+        cb.write_func_call("overflow_window_end", &[]);
+    } else if let [Operator::TopK(s, _, _) | Operator::ApproxCount(s, _, _)] = plan.aggs.as_slice()
+    {
+        // Top-k emits (up to) k rows, in decreasing order of count; approximate
+        // counts are emitted likewise, for all of the summary's candidates
+        cb.write_var_initialization(
+            &Field::new(String::from("n_results"), Type::U64),
+            &format!("topk_count_{}_{}()", s, &plan.schema.name),
+        );
+        // Appease verifier
+        cb.write_if(&format!("n_results >= {}", &rb.max_entries));
+        cb.write_var_assignment("n_results", &format!("{}", &rb.max_entries));
+        cb.close_if();
+        cb.write_if("n_results > 0");
+        cb.write_var_initialization(
+            &Field::new(
+                String::from("buf"),
+                Type::Pointer(Box::new(Type::Struct(
+                    format!("{}_t", &plan.schema.name),
+                    None,
+                ))),
+            ),
+            &format!(
+                "bpf_ringbuf_reserve(&{}, n_results * sizeof({}_t), 0)",
+                &rb.name, &plan.schema.name
+            ),
+        );
+        cb.write_if("!buf");
+        cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
+        cb.write_return("1");
+        cb.close_if();
+        let func = format!("topk_emit_{}_{}", s, &plan.schema.name);
+        cb.write_func_call(&func, &["buf", "n_results"]);
+        cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
+        cb.close_if();
+    } else if plan.aggs.len() > 0 && plan.group_by.is_empty() {
+        // Distinct counts are sketched over the whole window, which is emitted
+        // as a single row (if any events were added). All sketches are reset,
+        // so estimates are computed before the window is skipped.
+        let mut estimates = Vec::new();
+        for agg in &plan.aggs {
+            let Operator::ApproxCountDistinct(s, _) = agg else {
+                bail!(EbqlError::unsupported(
+                    agg,
+                    "aggregations without a group by not yet supported"
+                ))
+            };
+            let col = format!("approx_count_distinct_{s}");
+            cb.write_var_initialization(
+                &Field::new(col.clone(), Type::U64),
+                &format!("hll_estimate_{}_{}()", s, &plan.schema.name),
+            );
+            estimates.push(col);
         }
+        cb.write_if(&format!("{} == 0", estimates[0]));
+        cb.write_return("0");
+        cb.close_if();
+
+        cb.write_var_initialization(
+            &Field::new(
+                String::from("buf"),
+                Type::Pointer(Box::new(Type::Struct(
+                    format!("{}_t", &plan.schema.name),
+                    None,
+                ))),
+            ),
+            &format!(
+                "bpf_ringbuf_reserve(&{}, sizeof({}_t), 0)",
+                &rb.name, &plan.schema.name
+            ),
+        );
+        cb.write_if("!buf");
+        cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
+        cb.write_return("1");
+        cb.close_if();
+        cb.write_func_call("__builtin_memset", &["buf", "0", "sizeof(*buf)"]);
+        for f in plan.schema.fields.iter().map(|f| f.name.as_str()) {
+            if is_window_bound(f) {
+                cb.write_var_assignment(&format!("buf->{f}"), &format!("{f}()"));
+            }
+        }
+        for col in &estimates {
+            cb.write_var_assignment(&format!("buf->{col}"), col);
+        }
+        cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
+    } else if plan.aggs.len() > 0 {
+        if let Some(agg) = plan.aggs.iter().find(|agg| agg_column(agg).is_err()) {
+            bail!(EbqlError::unsupported(agg, "aggregation not yet supported"));
+        }
+        // Allocate space in the ringbuf for all results, a row for each group
+        cb.write_var_initialization(
+            &Field::new(String::from("n_results"), Type::U64),
+            &format!("count_{}()", &plan.schema.name),
+        );
+        // Appease verifier; truncated groups are counted as dropped
+        cb.write_if(&format!("n_results >= {}", &rb.max_entries));
+        cb.write_func_call(
+            "WARN",
+            &["\"Got too many results; truncating to max rb entries...\""],
+        );
+        cb.write_func_call(
+            "overflow_truncate",
+            &[&format!("n_results - {}", &rb.max_entries)],
+        );
+        cb.write_var_assignment("n_results", &format!("{}", &rb.max_entries));
+        cb.close_if();
+
+        // Only run if we actually got results
+        cb.write_if("n_results > 0");
+
+        // Reserve rb space
+        cb.write_var_initialization(
+            &Field::new(
+                String::from("buf"),
+                Type::Pointer(Box::new(Type::Struct(
+                    format!("{}_t", &plan.schema.name),
+                    None,
+                ))),
+            ),
+            &format!(
+                "bpf_ringbuf_reserve(&{}, n_results * sizeof({}_t), 0)",
+                &rb.name, &plan.schema.name
+            ),
+        );
+
+        // Bounds check to appease verifier
+        cb.write_if("!buf");
+        cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
+        cb.write_return("1");
+        cb.close_if();
+
+        // Fill a row for each group, with all of its aggregates
+        let func = format!("get_{}", &plan.schema.name);
+        cb.write_var_initialization(
+            &Field::new(String::from("n_rows"), Type::U64),
+            &format!("{func}(buf, n_results)"),
+        );
+
+        // Groups evicted (from LRU maps) since they were counted leave rows
+        // unfilled, so the rows are reserved again for the remaining groups;
+        // should more be evicted meanwhile, they're all counted as dropped
+        cb.write_if("n_rows < n_results");
+        cb.write_func_call("bpf_ringbuf_discard", &["buf", "0"]);
+        cb.write_var_assignment("n_results", "n_rows");
+        // Appease verifier
+        cb.write_if(&format!("n_results >= {}", &rb.max_entries));
+        cb.write_var_assignment("n_results", &format!("{}", &rb.max_entries));
+        cb.close_if();
+        cb.write_if("n_results > 0");
+        cb.write_var_assignment(
+            "buf",
+            &format!(
+                "bpf_ringbuf_reserve(&{}, n_results * sizeof({}_t), 0)",
+                &rb.name, &plan.schema.name
+            ),
+        );
+        cb.write_if("!buf");
+        cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
+        cb.write_return("1");
+        cb.close_if();
+        cb.write_if(&format!("{func}(buf, n_results) < n_results"));
+        cb.write_func_call("bpf_ringbuf_discard", &["buf", "0"]);
+        cb.write_func_call("overflow_truncate", &["n_results"]);
+        cb.write_else();
+        cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
+        cb.close_if();
+        cb.close_if();
+        cb.write_else();
 
+        // Submit to ringbuf
+        cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
+        cb.close_if();
+        cb.close_if();
+
+        // Tumble aggregations
+        cb.write_func_call(&format!("tumble_{}", &plan.schema.name), &[]);
+        cb.write_func_call("overflow_window_end", &[]);
+    } else {
+        cb.write_var_initialization(
+            &Field::new(String::from("n_results"), Type::U64),
+            "get_size()",
+        );
+
+        // Only proceed if we actually have results; an empty reservation
+        // always fails
+        cb.write_if("n_results == 0");
         cb.write_return("0");
-        let cb = cb.close();
+        cb.close_if();
 
-        // Build into object
-        let root = get_project_root().unwrap();
-        let out_dir = get_out_dir(&root).unwrap();
-        // Clear directory TODO: change this later
-        let _ = fs::remove_dir_all(&out_dir);
-        create_dir_if_not_exists(&out_dir).unwrap();
+        // Appease verifier
+        cb.write_if(&format!("n_results >= {}", &rb.max_entries));
+        cb.write_func_call(
+            "WARN",
+            &["\"Got too many results; truncating to max rb entries...\""],
+        );
+        cb.write_var_assignment("n_results", &format!("{}", &rb.max_entries));
+        cb.close_if();
 
-        let br = cb.build(&out_dir)?;
+        let n_bytes = format!("n_results * sizeof({}_t)", &plan.schema.name);
+        // Reserve space in ringbuf
+        cb.write_var_initialization(
+            &Field::new(
+                String::from("buf"),
+                Type::Pointer(Box::new(Type::Struct(
+                    format!("{}_t", &plan.schema.name),
+                    None,
+                ))),
+            ),
+            &format!("bpf_ringbuf_reserve(&{}, {}, 0)", &rb.name, n_bytes),
+        );
 
-        let obj = Object::load(&plan.schema.name, vec![br], None)?;
+        // Bounds check to appease verifier
+        cb.write_if("!buf");
+        cb.write_func_call("ERROR", &["\"Failed to allocate from ring buffer\""]);
+        cb.write_return("1");
+        cb.close_if();
 
-        Ok(obj)
+        // Copy over from window
+        cb.write_func_call("bpf_probe_read_kernel", &["buf", n_bytes.as_str(), "w.buf"]);
+
+        // Submit
+        cb.write_func_call("bpf_ringbuf_submit", &["buf", "0"]);
+    }
+
+    cb.write_return("0");
+    Ok(cb.close())
+}
+
+/// Returns whether compiling or loading a program failed in clang or the
+/// verifier, as it does on kernels without `bpf_timer`.
+fn is_load_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<EbqlError>(),
+        Some(EbqlError::Clang { .. } | EbqlError::Verifier { .. })
+    )
+}
+
+/// Writes the program that flushes a scheduled window: with a timer, a program
+/// arming a `bpf_timer` whose callback flushes the window; with ticks, a
+/// program flushing the window if it's due, run by user space every interval.
+/// Both are `tc` programs, which are run (not attached) from user space; unlike
+/// tracing programs, they may use timers.
+fn write_schedule(
+    cb: BpfCodeBuilder,
+    plan: &BpfPlan,
+    window: &WindowType,
+    flush: WindowFlush,
+) -> Result<BpfCodeBuilder> {
+    let WindowType::Time(interval, _) = window else {
+        bail!(EbqlError::codegen(
+            window,
+            "only time windows are scheduled"
+        ));
+    };
+    // Flush the window if due; tumbling skips any windows missed meanwhile.
+    // Windows tumble even if their results couldn't be emitted (leaving them
+    // to the next window), so that the open window always ends after now
+    let flush_due = |cb: &mut BpfCodeBuilder<BodyConstruction>| {
+        cb.write_var_initialization(&Field::new(String::from("now"), Type::U64), "KTIME_NS()");
+        cb.write_if("window_expired(now)");
+        cb.write_if("flush_window() != 0");
+        cb.write_func_call("WARN", &["\"Failed to flush window; carrying it over\""]);
+        cb.close_if();
+        cb.write_func_call("window_tumble", &["now"]);
+        cb.close_if();
+    };
+
+    let cb = match flush {
+        WindowFlush::Timer => {
+            // Timer callback, which re-arms the timer for the end of the
+            // (new) open window
+            let timer_cb = "window_timer_cb";
+            let args = vec![
+                void_ptr("map"),
+                Expr::new("key".into(), Type::Pointer(Box::new(Type::U32))),
+                Expr::new(
+                    "timer".into(),
+                    Type::Pointer(Box::new(Type::Struct("struct bpf_timer".into(), None))),
+                ),
+            ];
+            let mut cb = cb.start_static_function(vec![], &Type::S32, timer_cb, &args);
+            flush_due(&mut cb);
+            // The open window ends after now once tumbled; should it not, the
+            // delay would wrap around, so an interval is waited instead
+            cb.write_func_call(
+                "bpf_timer_start",
+                &[
+                    "timer",
+                    "window_end() > now ? window_end() - now : INTERVAL",
+                    "0",
+                ],
+            );
+            cb.write_return("0");
+            let cb = cb.close();
+
+            // Program arming the timer, which also opens the first window
+            let name = format!("arm_{}", &plan.schema.name);
+            let mut cb = cb.start_program("tc", &name, &[void_ptr("ctx")]);
+            cb.write_func_call("GLOBAL_GET", &["struct window_timer", "window_timer", "t"]);
+            cb.write_func_call("window_expired", &["KTIME_NS()"]);
+            cb.write_if(&format!(
+                "bpf_timer_init(&t->timer, &{WINDOW_TIMER_MAP}, CLOCK_MONOTONIC) != 0"
+            ));
+            cb.write_func_call("ERROR", &["\"Failed to initialize window timer\""]);
+            cb.write_return("1");
+            cb.close_if();
+            cb.write_func_call("bpf_timer_set_callback", &["&t->timer", timer_cb]);
+            cb.write_return("bpf_timer_start(&t->timer, INTERVAL, 0) != 0");
+            let mut cb = cb.close();
+            cb.set_flush_program(FlushProgram::Timer(name));
+            cb
+        }
+        WindowFlush::Tick => {
+            let name = format!("flush_{}", &plan.schema.name);
+            let mut cb = cb.start_program("tc", &name, &[void_ptr("ctx")]);
+            flush_due(&mut cb);
+            cb.write_return("0");
+            let mut cb = cb.close();
+            cb.set_flush_program(FlushProgram::Tick(name, *interval));
+            cb
+        }
+        WindowFlush::OnEvent => {
+            bail!(EbqlError::codegen(
+                window,
+                "window is not flushed on schedule"
+            ))
+        }
+    };
+    Ok(cb)
+}
+
+/// Writes the program that flushes the open window without waiting for it to
+/// end, which user space runs when stopping the query. The window timer (if
+/// any) is cancelled first, so that it can't flush concurrently.
+fn write_stop(cb: BpfCodeBuilder, plan: &BpfPlan, timer: bool) -> BpfCodeBuilder {
+    let name = format!("stop_{}", &plan.schema.name);
+    let mut cb = cb.start_program("tc", &name, &[void_ptr("ctx")]);
+    if timer {
+        cb.write_func_call("GLOBAL_GET", &["struct window_timer", "window_timer", "t"]);
+        cb.write_func_call("bpf_timer_cancel", &["&t->timer"]);
     }
+    cb.write_return("flush_window()");
+    let mut cb = cb.close();
+    cb.set_stop_program(name);
+    cb
+}
+
+/// Argument of type `void *`.
+fn void_ptr(name: &str) -> Expr {
+    Expr::new(
+        name.into(),
+        Type::Pointer(Box::new(Type::Struct("void".into(), None))),
+    )
 }
 
-fn get_max_entries(wt: &WindowType, s_size: usize) -> u64 {
-    match wt {
+fn get_max_entries(wt: &WindowType, s_size: usize) -> Result<u64> {
+    Ok(match wt {
         WindowType::Time(_, _) => MAX_MEM_BYTES / (s_size as u64),
         WindowType::Count(count, _) => {
             if (count * s_size) as u64 > MAX_MEM_BYTES {
@@ -451,83 +1261,13 @@ fn get_max_entries(wt: &WindowType, s_size: usize) -> u64 {
                 *count as u64
             }
         }
-        WindowType::Session(_) => todo!(),
-    }
-}
-
-/// Convert conditional expression into if cond statement. Here, we do the
-/// opposite, since if we want this filter to be satisfied, we should filter out
-/// all things that don't satisfy the filter.
-fn ce_to_cond(ce: &ConditionExpression) -> String {
-    let str = match ce {
-        ConditionExpression::Base(cb) => {
-            match cb {
-                ConditionBase::Field(col) => col.name.clone(),
-                ConditionBase::Literal(l) => {
-                    match l {
-                        Literal::Integer(_) | Literal::UnsignedInteger(_) => l.to_string(),
-                        _ => unimplemented!("Literal {} not supported", l.to_string()),
-                    }
-                }
-                _ => unimplemented!("condition base {cb} not supported"),
-            }
+        WindowType::Session(_) => {
+            bail!(EbqlError::unsupported(
+                wt,
+                "session windows not yet supported"
+            ))
         }
-        ConditionExpression::ComparisonOp(ct) => {
-            match ct.operator {
-                nom_sql::Operator::Equal => {
-                    format!("({}) != ({})", ce_to_cond(&ct.left), ce_to_cond(&ct.right))
-                }
-                nom_sql::Operator::NotEqual => {
-                    format!("({}) == ({})", ce_to_cond(&ct.left), ce_to_cond(&ct.right))
-                }
-                nom_sql::Operator::Greater => {
-                    format!("({}) <= ({})", ce_to_cond(&ct.left), ce_to_cond(&ct.right))
-                }
-                nom_sql::Operator::GreaterOrEqual => {
-                    format!("({}) < ({})", ce_to_cond(&ct.left), ce_to_cond(&ct.right))
-                }
-                nom_sql::Operator::Less => {
-                    format!("({}) >= ({})", ce_to_cond(&ct.left), ce_to_cond(&ct.right))
-                }
-                nom_sql::Operator::LessOrEqual => {
-                    format!("({}) > ({})", ce_to_cond(&ct.left), ce_to_cond(&ct.right))
-                }
-                _ => {
-                    unimplemented!(
-                        "conditional operator {} not supported for comparisons",
-                        ct.operator
-                    )
-                }
-            }
-        }
-        ConditionExpression::LogicalOp(ct) => {
-            match ct.operator {
-                nom_sql::Operator::And => {
-                    format!(
-                        "!({}) || !({})",
-                        ce_to_cond(&ct.left),
-                        ce_to_cond(&ct.right)
-                    )
-                }
-                nom_sql::Operator::Or => {
-                    format!(
-                        "!({}) && !({})",
-                        ce_to_cond(&ct.left),
-                        ce_to_cond(&ct.right)
-                    )
-                }
-                _ => {
-                    unimplemented!(
-                        "conditional operator {} not supported for logical operators",
-                        ct.operator
-                    )
-                }
-            }
-        }
-        _ => unimplemented!("condition expression {ce} not supported"),
-    };
-
-    str
+    })
 }
 
 /*
@@ -539,7 +1279,7 @@ for op in plan {
             // For windows, get external header file
             let tmpl = wt.get_tmpl(name.clone());
             // Render template into actual code
-            handlebars.register_template_file(&tmpl.name, tmpl.tmpl_path)?;
+            handlebars.register_template_string(&tmpl.name, tmpl.tmpl)?;
             let text = handlebars.render(&tmpl.name, &tmpl.ctx)?;
             // Register rendered template into code builder
             cb.add_external_includes(&tmpl.name, text);
@@ -547,7 +1287,7 @@ for op in plan {
         Operator::Histogram(buckets) => {
             // Get template, then render into code
             let tmpl = BpfHistogramTemplate::get_tmpl(buckets);
-            handlebars.register_template_file(&tmpl.name, tmpl.tmpl_path)?;
+            handlebars.register_template_string(&tmpl.name, tmpl.tmpl)?;
             let text = handlebars.render(&tmpl.name, &tmpl.ctx)?;
             // Register into code builder
             cb.add_external_includes(&tmpl.name, text);
@@ -608,56 +1348,3 @@ for op in ops {
 let cb = cb.close();
 todo!()
 */
-
-fn get_project_root() -> io::Result<PathBuf> {
-    let path = env::current_dir()?;
-    let mut path_ancestors = path.as_path().ancestors();
-
-    while let Some(p) = path_ancestors.next() {
-        let has_cargo = std::fs::read_dir(p)?
-            .into_iter()
-            .any(|p| p.unwrap().file_name() == OsString::from("Cargo.lock"));
-        if has_cargo {
-            return Ok(PathBuf::from(p));
-        }
-    }
-    Err(io::Error::new(
-        io::ErrorKind::NotFound,
-        "Ran out of places to find Cargo.toml",
-    ))
-}
-
-fn get_out_dir(base: &PathBuf) -> Option<PathBuf> {
-    // First we get the arguments for the rustc invocation
-    let mut args = std::env::args();
-
-    // Then we loop through them all, and find the value of "out-dir"
-    while let Some(arg) = args.next() {
-        if arg.contains("target") {
-            let mut path = base.clone();
-            path.push(PathBuf::from(arg));
-            path.pop();
-            path.push("tmp_build");
-            return Some(path);
-        }
-    }
-    None
-}
-
-// Creates directory if not exists
-fn create_dir_if_not_exists(dir: &PathBuf) -> io::Result<()> {
-    if let Ok(metadata) = fs::metadata(dir) {
-        if !metadata.is_dir() {
-            return Err(io::Error::new(
-                io::ErrorKind::AlreadyExists,
-                format!(
-                    "Directory {} already exists, but is not a directory",
-                    dir.display()
-                ),
-            ));
-        }
-        Ok(())
-    } else {
-        fs::create_dir(dir)
-    }
-}
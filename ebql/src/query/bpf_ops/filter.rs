@@ -0,0 +1,649 @@
+//! Compilation of WHERE clauses into BPF filter conditions.
+
+use anyhow::{bail, Result};
+use nom_sql::{Column, ConditionBase, ConditionExpression, ConditionTree, Literal};
+use rand::distributions::{Alphanumeric, DistString};
+
+use crate::{
+    cgroups::{escape_like, CgroupSet, CGROUP_SET_ENTRIES},
+    data_types::CgroupKind,
+    error::EbqlError,
+    map::{MapDef, MapDefFlags, MapType},
+    object::PARAMS_VAR,
+    parser::PARAM_PREFIX,
+    physical_plan::cgroup_column,
+    record::DataValue,
+    types::{Field, Type},
+};
+
+/// Largest IN list compiled into a chain of comparisons; larger lists are
+/// looked up in a hash set instead, to stay within the verifier's instruction
+/// limit.
+pub const MAX_IN_CHAIN: usize = 8;
+
+/// Hash set backing a large IN list. The set must be populated with its keys
+/// after the program is loaded.
+#[derive(Clone, Debug)]
+pub struct InSet {
+    pub map: MapDef,
+    /// Keys to insert, encoded as the field's type
+    pub keys: Vec<Vec<u8>>,
+}
+
+/// State for compiling one filter.
+pub struct FilterCompiler<'a> {
+    /// Fields in scope in the program
+    fields: &'a [Field],
+    /// Hash sets required by the filter
+    pub in_sets: Vec<InSet>,
+    /// Sets of the cgroups matched by the filter
+    pub cgroup_sets: Vec<CgroupSet>,
+}
+
+impl<'a> FilterCompiler<'a> {
+    pub fn new(fields: &'a [Field]) -> Self {
+        Self {
+            fields,
+            in_sets: Vec::new(),
+            cgroup_sets: Vec::new(),
+        }
+    }
+
+    /// Convert conditional expression into if cond statement. Here, we do the
+    /// opposite, since if we want this filter to be satisfied, we should filter
+    /// out all things that don't satisfy the filter.
+    pub fn ce_to_cond(&mut self, ce: &ConditionExpression) -> Result<String> {
+        Ok(format!("!({})", self.ce_to_pred(ce)?))
+    }
+
+    /// Converts a conditional expression into a C expression that holds when
+    /// the condition is satisfied.
+    fn ce_to_pred(&mut self, ce: &ConditionExpression) -> Result<String> {
+        let str = match ce {
+            ConditionExpression::Base(cb) => {
+                match cb {
+                    ConditionBase::Field(col) => {
+                        match col.name.strip_prefix(PARAM_PREFIX) {
+                            Some(param) => format!("{PARAMS_VAR}.{param}"),
+                            None => col.name.clone(),
+                        }
+                    }
+                    ConditionBase::Literal(l) => int_literal(l)?,
+                    _ => bail!(EbqlError::unsupported(cb, "condition not supported")),
+                }
+            }
+            ConditionExpression::ComparisonOp(ct) => self.comparison_to_pred(ct)?,
+            ConditionExpression::LogicalOp(ct) => {
+                let (l, r) = (self.ce_to_pred(&ct.left)?, self.ce_to_pred(&ct.right)?);
+                match ct.operator {
+                    nom_sql::Operator::And => format!("({l}) && ({r})"),
+                    nom_sql::Operator::Or => format!("({l}) || ({r})"),
+                    _ => {
+                        bail!(EbqlError::unsupported(
+                            ct,
+                            format!(
+                                "operator {} not supported for logical operators",
+                                ct.operator
+                            )
+                        ))
+                    }
+                }
+            }
+            ConditionExpression::NegationOp(ce) => format!("!({})", self.ce_to_pred(ce)?),
+            ConditionExpression::Bracketed(ce) => format!("({})", self.ce_to_pred(ce)?),
+            _ => bail!(EbqlError::unsupported(ce, "condition not supported")),
+        };
+
+        Ok(str)
+    }
+
+    fn comparison_to_pred(&mut self, ct: &ConditionTree) -> Result<String> {
+        use nom_sql::Operator::*;
+
+        // IN lists (and NOT IN, which is parsed as a negated list)
+        if let (In, ConditionExpression::Base(ConditionBase::Field(col))) =
+            (&ct.operator, ct.left.as_ref())
+        {
+            return match ct.right.as_ref() {
+                ConditionExpression::Base(ConditionBase::LiteralList(list)) => {
+                    self.in_to_pred(col, list)
+                }
+                ConditionExpression::NegationOp(ce) => {
+                    match ce.as_ref() {
+                        ConditionExpression::Base(ConditionBase::LiteralList(list)) => {
+                            Ok(format!("!({})", self.in_to_pred(col, list)?))
+                        }
+                        _ => {
+                            bail!(EbqlError::unsupported(
+                                ct,
+                                "IN is only supported over lists"
+                            ))
+                        }
+                    }
+                }
+                _ => {
+                    bail!(EbqlError::unsupported(
+                        ct,
+                        "IN is only supported over lists"
+                    ))
+                }
+            };
+        }
+
+        // Get the field and literal being compared, if any. Comparisons are
+        // normalized so that the field is on the left.
+        let (col, lit, op) = match (ct.left.as_ref(), ct.right.as_ref()) {
+            (
+                ConditionExpression::Base(ConditionBase::Field(col)),
+                ConditionExpression::Base(ConditionBase::Literal(lit)),
+            ) => (Some(col), Some(lit), ct.operator.clone()),
+            (
+                ConditionExpression::Base(ConditionBase::Literal(lit)),
+                ConditionExpression::Base(ConditionBase::Field(col)),
+            ) => {
+                let op = match ct.operator {
+                    Greater => Less,
+                    GreaterOrEqual => LessOrEqual,
+                    Less => Greater,
+                    LessOrEqual => GreaterOrEqual,
+                    Like | NotLike => {
+                        bail!(EbqlError::unsupported(
+                            ct,
+                            "LIKE patterns must be on the right"
+                        ))
+                    }
+                    ref op => op.clone(),
+                };
+                (Some(col), Some(lit), op)
+            }
+            _ => (None, None, ct.operator.clone()),
+        };
+
+        if let (Some(col), Some(lit)) = (col, lit) {
+            if let Some((id, kind)) = cgroup_column(col)? {
+                let (pattern, negated) = match (lit, &op) {
+                    (Literal::String(s), Like | NotLike) => (s.clone(), op == NotLike),
+                    (Literal::String(s), Equal | NotEqual) => (escape_like(s), op == NotEqual),
+                    (Literal::String(_), _) => {
+                        bail!(EbqlError::unsupported(
+                            ct,
+                            "operator not supported for cgroups"
+                        ))
+                    }
+                    _ => {
+                        bail!(EbqlError::bind(
+                            ct,
+                            "cgroups can only be compared to strings"
+                        ))
+                    }
+                };
+                let pred = self.cgroup_to_pred(&id, kind, vec![pattern])?;
+                return Ok(if negated { format!("!({pred})") } else { pred });
+            }
+            let f = self.field(col)?;
+            match (lit, &f._type) {
+                // IS NULL is parsed as a comparison to NULL. Only pointers can be
+                // null; other fields always have a value.
+                (Literal::Null, Type::Pointer(_)) => {
+                    return match op {
+                        Equal => Ok(format!("({}) == NULL", col.name)),
+                        NotEqual => Ok(format!("({}) != NULL", col.name)),
+                        _ => {
+                            bail!(EbqlError::unsupported(
+                                ct,
+                                "operator not supported for NULL"
+                            ))
+                        }
+                    };
+                }
+                (Literal::Null, _) => {
+                    return match op {
+                        Equal => Ok(String::from("0")),
+                        NotEqual => Ok(String::from("1")),
+                        _ => {
+                            bail!(EbqlError::unsupported(
+                                ct,
+                                "operator not supported for NULL"
+                            ))
+                        }
+                    };
+                }
+                // String fields are compared byte by byte
+                (Literal::String(s), Type::String(size)) => {
+                    return str_to_pred(col, s, &op, *size);
+                }
+                (_, Type::String(_)) => {
+                    bail!(EbqlError::bind(
+                        ct,
+                        "string fields can only be compared to strings"
+                    ))
+                }
+                _ => (),
+            }
+        }
+
+        let (l, r) = (self.ce_to_pred(&ct.left)?, self.ce_to_pred(&ct.right)?);
+        Ok(match ct.operator {
+            Equal => format!("({l}) == ({r})"),
+            NotEqual => format!("({l}) != ({r})"),
+            Greater => format!("({l}) > ({r})"),
+            GreaterOrEqual => format!("({l}) >= ({r})"),
+            Less => format!("({l}) < ({r})"),
+            LessOrEqual => format!("({l}) <= ({r})"),
+            _ => {
+                bail!(EbqlError::unsupported(
+                    ct,
+                    format!("operator {} not supported for comparisons", ct.operator)
+                ))
+            }
+        })
+    }
+
+    /// Converts membership in a literal list into a predicate: a chain of
+    /// comparisons for short lists, and a hash set lookup otherwise.
+    fn in_to_pred(&mut self, col: &Column, list: &[Literal]) -> Result<String> {
+        if let Some((id, kind)) = cgroup_column(col)? {
+            let patterns = list
+                .iter()
+                .map(|l| {
+                    match l {
+                        Literal::String(s) => Ok(escape_like(s)),
+                        _ => {
+                            bail!(EbqlError::bind(
+                                l.to_string(),
+                                "cgroups can only be compared to strings"
+                            ))
+                        }
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            return self.cgroup_to_pred(&id, kind, patterns);
+        }
+        let f = self.field(col)?.clone();
+        if list.is_empty() {
+            return Ok(String::from("0"));
+        }
+
+        // Strings are always compared one by one, since they are not guaranteed
+        // to be zero-padded (which hash keys would require)
+        if let Type::String(size) = f._type {
+            let preds = list
+                .iter()
+                .map(|l| {
+                    match l {
+                        Literal::String(s) => str_to_pred(col, s, &nom_sql::Operator::Equal, size),
+                        _ => {
+                            bail!(EbqlError::bind(
+                                l.to_string(),
+                                format!(
+                                    "string field {} can only be compared to strings",
+                                    col.name
+                                )
+                            ))
+                        }
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(preds.join(" || "));
+        }
+
+        if list.len() <= MAX_IN_CHAIN {
+            let preds = list
+                .iter()
+                .map(|l| Ok(format!("({}) == ({})", col.name, int_literal(l)?)))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(preds.join(" || "));
+        }
+
+        let keys = list
+            .iter()
+            .map(|l| {
+                int_literal(l)?;
+                f._type.encode(&DataValue::try_from(l.clone())?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let map = MapDef {
+            name: format!(
+                "in_set_{}",
+                Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
+            ),
+            map_type: MapType::Hash,
+            key_type: f._type.clone(),
+            value_type: String::from("u8"),
+            max_entries: keys.len() as u64,
+            flags: MapDefFlags::new(),
+            pin: None,
+        };
+        let pred = format!("bpf_map_lookup_elem(&{}, &{}) != NULL", map.name, col.name);
+        self.in_sets.push(InSet { map, keys });
+
+        Ok(pred)
+    }
+
+    /// Converts a match of cgroups against LIKE patterns into a lookup of
+    /// their ids in the set of matching cgroups, which user space keeps in
+    /// sync with the cgroup hierarchy.
+    fn cgroup_to_pred(
+        &mut self,
+        id: &str,
+        kind: CgroupKind,
+        patterns: Vec<String>,
+    ) -> Result<String> {
+        let Some(f) = self.fields.iter().find(|f| f._name == id) else {
+            bail!(EbqlError::bind(id, "unknown field in filter"));
+        };
+        let map = MapDef {
+            name: format!(
+                "cgroup_set_{}",
+                Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
+            ),
+            map_type: MapType::Hash,
+            key_type: f._type.clone(),
+            value_type: String::from("u8"),
+            max_entries: CGROUP_SET_ENTRIES,
+            flags: MapDefFlags::new(),
+            pin: None,
+        };
+        let pred = format!("bpf_map_lookup_elem(&{}, &{id}) != NULL", map.name);
+        self.cgroup_sets.push(CgroupSet {
+            map,
+            kind,
+            patterns,
+        });
+
+        Ok(pred)
+    }
+
+    fn field(&self, col: &Column) -> Result<&Field> {
+        self.fields
+            .iter()
+            .find(|f| f._name == col.name)
+            .ok_or_else(|| EbqlError::bind(col, "unknown field in filter").into())
+    }
+}
+
+/// Converts an integer literal into its C representation.
+fn int_literal(l: &Literal) -> Result<String> {
+    match l {
+        Literal::Integer(_) | Literal::UnsignedInteger(_) => Ok(l.to_string()),
+        _ => {
+            bail!(EbqlError::unsupported(
+                l.to_string(),
+                "literal not supported"
+            ))
+        }
+    }
+}
+
+/// Converts a comparison between a string field and a string literal into a
+/// predicate, using the bounded comparison helpers in common.bpf.h.
+fn str_to_pred(col: &Column, lit: &str, op: &nom_sql::Operator, size: usize) -> Result<String> {
+    use nom_sql::Operator::*;
+
+    // Only prefix patterns (i.e. a single trailing %) are supported
+    let (pattern, is_prefix) = match op {
+        Like | NotLike => {
+            let pattern = lit.strip_suffix('%').unwrap_or(lit);
+            if pattern.contains(['%', '_']) {
+                bail!(EbqlError::unsupported(
+                    format!("'{lit}'"),
+                    "only prefix LIKE patterns ('prefix%') are supported"
+                ));
+            }
+            (pattern, pattern.len() != lit.len())
+        }
+        Equal | NotEqual => (lit, false),
+        _ => {
+            bail!(EbqlError::unsupported(
+                op,
+                "operator not supported for strings"
+            ))
+        }
+    };
+    // Strings are null-terminated within the field
+    if pattern.len() >= size {
+        bail!(EbqlError::bind(
+            format!("'{pattern}'"),
+            format!(
+                "string is longer than field {} (at most {} bytes)",
+                col.name,
+                size - 1
+            )
+        ));
+    }
+
+    let helper = if is_prefix { "STR_PREFIX" } else { "STR_EQ" };
+    let pred = format!("{helper}({}, {})", col.name, c_str_literal(pattern));
+    Ok(match op {
+        Equal | Like => pred,
+        _ => format!("!{pred}"),
+    })
+}
+
+/// Escapes a string into a C string literal.
+fn c_str_literal(s: &str) -> String {
+    let mut lit = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' => {
+                lit.push('\\');
+                lit.push(b as char);
+            }
+            0x20..=0x7e => lit.push(b as char),
+            // Octal escapes have a fixed width, unlike hex escapes
+            _ => lit.push_str(&format!("\\{b:03o}")),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+#[cfg(test)]
+mod tests {
+    use nom_sql::Operator;
+
+    use super::*;
+
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::new("pid".into(), Type::U32),
+            Field::new("comm".into(), Type::String(16)),
+            Field::new("buf".into(), Type::Pointer(Box::new(Type::U8))),
+            Field::new("cgroup".into(), Type::U64),
+        ]
+    }
+
+    fn col(name: &str) -> ConditionExpression {
+        ConditionExpression::Base(ConditionBase::Field(Column {
+            name: name.into(),
+            ..Default::default()
+        }))
+    }
+
+    fn lit(l: Literal) -> ConditionExpression {
+        ConditionExpression::Base(ConditionBase::Literal(l))
+    }
+
+    fn cmp(
+        operator: Operator,
+        left: ConditionExpression,
+        right: ConditionExpression,
+    ) -> ConditionExpression {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        })
+    }
+
+    fn in_list(name: &str, list: Vec<Literal>) -> ConditionExpression {
+        cmp(
+            Operator::In,
+            col(name),
+            ConditionExpression::Base(ConditionBase::LiteralList(list)),
+        )
+    }
+
+    fn pred(ce: &ConditionExpression) -> Result<String> {
+        let fields = fields();
+        FilterCompiler::new(&fields).ce_to_pred(ce)
+    }
+
+    fn ints(n: u64) -> Vec<Literal> {
+        (1..=n).map(Literal::UnsignedInteger).collect()
+    }
+
+    #[test]
+    fn compiles_comparisons() {
+        let ce = cmp(
+            Operator::Greater,
+            col("pid"),
+            lit(Literal::UnsignedInteger(10)),
+        );
+        assert_eq!(pred(&ce).unwrap(), "(pid) > (10)");
+        // Literals on the left are kept on the left, with the operator as is
+        let ce = cmp(Operator::Less, lit(Literal::Integer(-1)), col("pid"));
+        assert_eq!(pred(&ce).unwrap(), "(-1) < (pid)");
+        let ce = ConditionExpression::NegationOp(Box::new(cmp(
+            Operator::Equal,
+            col("pid"),
+            lit(Literal::UnsignedInteger(1)),
+        )));
+        assert_eq!(pred(&ce).unwrap(), "!((pid) == (1))");
+        let fields = fields();
+        assert_eq!(
+            FilterCompiler::new(&fields).ce_to_cond(&ce).unwrap(),
+            "!(!((pid) == (1)))"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields_and_literals() {
+        let ce = cmp(
+            Operator::Equal,
+            col("tid"),
+            lit(Literal::UnsignedInteger(1)),
+        );
+        assert!(pred(&ce).is_err());
+        let ce = cmp(
+            Operator::Equal,
+            col("pid"),
+            lit(Literal::String("1".into())),
+        );
+        assert!(pred(&ce).is_err());
+        let ce = cmp(
+            Operator::Equal,
+            col("comm"),
+            lit(Literal::UnsignedInteger(1)),
+        );
+        assert!(pred(&ce).is_err());
+    }
+
+    #[test]
+    fn compiles_in_lists() {
+        assert_eq!(
+            pred(&in_list("pid", ints(2))).unwrap(),
+            "(pid) == (1) || (pid) == (2)"
+        );
+        // An empty list matches nothing
+        assert_eq!(pred(&in_list("pid", vec![])).unwrap(), "0");
+        // NOT IN is parsed as IN a negated list
+        let ce = cmp(
+            Operator::In,
+            col("pid"),
+            ConditionExpression::NegationOp(Box::new(ConditionExpression::Base(
+                ConditionBase::LiteralList(ints(1)),
+            ))),
+        );
+        assert_eq!(pred(&ce).unwrap(), "!((pid) == (1))");
+        assert_eq!(
+            pred(&in_list("comm", vec![Literal::String("sh".into())])).unwrap(),
+            "STR_EQ(comm, \"sh\")"
+        );
+        assert!(pred(&in_list("comm", ints(1))).is_err());
+    }
+
+    #[test]
+    fn looks_long_in_lists_up_in_hash_sets() {
+        let fields = fields();
+        let mut fc = FilterCompiler::new(&fields);
+        let n = MAX_IN_CHAIN as u64 + 1;
+        let p = fc.ce_to_pred(&in_list("pid", ints(n))).unwrap();
+        assert_eq!(fc.in_sets.len(), 1);
+        let set = &fc.in_sets[0];
+        assert_eq!(
+            p,
+            format!("bpf_map_lookup_elem(&{}, &pid) != NULL", set.map.name)
+        );
+        assert_eq!(set.map.max_entries, n);
+        assert_eq!(set.keys[0], 1u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn compiles_is_null() {
+        let ce = cmp(Operator::Equal, col("buf"), lit(Literal::Null));
+        assert_eq!(pred(&ce).unwrap(), "(buf) == NULL");
+        let ce = cmp(Operator::NotEqual, col("buf"), lit(Literal::Null));
+        assert_eq!(pred(&ce).unwrap(), "(buf) != NULL");
+        // Only pointers can be null
+        let ce = cmp(Operator::Equal, col("pid"), lit(Literal::Null));
+        assert_eq!(pred(&ce).unwrap(), "0");
+        let ce = cmp(Operator::Greater, col("buf"), lit(Literal::Null));
+        assert!(pred(&ce).is_err());
+    }
+
+    #[test]
+    fn compiles_string_predicates() {
+        let ce = cmp(
+            Operator::NotEqual,
+            col("comm"),
+            lit(Literal::String("a\"b".into())),
+        );
+        assert_eq!(pred(&ce).unwrap(), "!STR_EQ(comm, \"a\\\"b\")");
+        let ce = cmp(
+            Operator::Like,
+            col("comm"),
+            lit(Literal::String("kworker%".into())),
+        );
+        assert_eq!(pred(&ce).unwrap(), "STR_PREFIX(comm, \"kworker\")");
+        let ce = cmp(
+            Operator::Like,
+            col("comm"),
+            lit(Literal::String("%worker".into())),
+        );
+        assert!(pred(&ce).is_err());
+        // At most 15 bytes fit in comm, with its null terminator
+        let ce = cmp(
+            Operator::Equal,
+            col("comm"),
+            lit(Literal::String("a".repeat(16))),
+        );
+        assert!(pred(&ce).is_err());
+    }
+
+    #[test]
+    fn escapes_c_string_literals() {
+        assert_eq!(c_str_literal("a\\b"), "\"a\\\\b\"");
+        assert_eq!(c_str_literal("\n\u{e9}"), "\"\\012\\303\\251\"");
+    }
+
+    #[test]
+    fn compiles_cgroup_filters_into_sets() {
+        let fields = fields();
+        let mut fc = FilterCompiler::new(&fields);
+        let ce = cmp(
+            Operator::Equal,
+            col("container_id"),
+            lit(Literal::String("a_b".into())),
+        );
+        let p = fc.ce_to_pred(&ce).unwrap();
+        let set = &fc.cgroup_sets[0];
+        assert_eq!(
+            p,
+            format!("bpf_map_lookup_elem(&{}, &cgroup) != NULL", set.map.name)
+        );
+        assert_eq!(set.kind, CgroupKind::ContainerId);
+        // Exact values have their wildcards escaped
+        assert_eq!(set.patterns, ["a\\_b"]);
+    }
+}
@@ -1,11 +1,15 @@
-use std::path::PathBuf;
-
 use serde::Serialize;
 
-use super::{HeaderTemplate, BPF_HEADERS_DIR};
+use super::{HeaderTemplate, HIST_TMPL};
+
+/// Value to scale quantile computations by. Quantiles are output in fixed
+/// point, scaled by this value.
+pub const FP_SCALE: u64 = 1e6 as u64;
 
-/// Value to scale quantile computations by
-const FP_SCALE: usize = 1e6 as usize;
+/// Gets the name of the column holding the q quantile of a histogram.
+pub fn quantile_column(q: usize) -> String {
+    format!("quantile_{q}")
+}
 
 /// BPF Histogram implementation.
 #[derive(Serialize)]
@@ -13,7 +17,7 @@ pub struct BpfHistogramTemplate {
     n_buckets: usize,
     buckets: String,
     is_log: bool,
-    fp_scale: usize,
+    fp_scale: u64,
 }
 
 impl BpfHistogramTemplate {
@@ -32,17 +36,13 @@ impl BpfHistogramTemplate {
             .iter()
             .map(|(lb, ub)| format!("{{{lb}, {ub}, 0}}"))
             .collect::<Vec<_>>();
-        buckets_str.push(format!(
-            "{{{}, {}, 0}}",
-            buckets.last().unwrap().1,
-            u64::MAX
-        ));
+        if let Some((_, ub)) = buckets.last() {
+            buckets_str.push(format!("{{{ub}, {}, 0}}", u64::MAX));
+        }
         let buckets = format!("{{{}}}", buckets_str.join(", "));
         HeaderTemplate {
             name: "hist".into(),
-            tmpl_path: [BPF_HEADERS_DIR, "hist.bpf.h.tmpl"]
-                .iter()
-                .collect::<PathBuf>(),
+            tmpl: HIST_TMPL,
             ctx: BpfHistogramTemplate {
                 n_buckets: buckets.len(),
                 buckets,
@@ -1,13 +1,22 @@
 pub mod agg;
 pub mod compiler;
+pub mod filter;
 pub mod hist;
+pub mod sketch;
+pub mod synopsis;
 pub mod window;
 
-use std::path::PathBuf;
-
 use serde::Serialize;
 
-pub const BPF_HEADERS_DIR: &str = "./bpf/";
+/// Templates of the headers of BPF representations, embedded so that queries
+/// compile wherever the executable runs from.
+pub const AGG_TMPL: &str = include_str!("../../../../bpf/agg.bpf.h.tmpl");
+pub const CMS_TMPL: &str = include_str!("../../../../bpf/cms.bpf.h.tmpl");
+pub const HIST_TMPL: &str = include_str!("../../../../bpf/hist.bpf.h.tmpl");
+pub const HLL_TMPL: &str = include_str!("../../../../bpf/hll.bpf.h.tmpl");
+pub const STATEFUL_WINDOW_TMPL: &str = include_str!("../../../../bpf/stateful_window.bpf.h.tmpl");
+pub const TOPK_TMPL: &str = include_str!("../../../../bpf/topk.bpf.h.tmpl");
+pub const TUMBLING_WINDOW_TMPL: &str = include_str!("../../../../bpf/tumbling_window.bpf.h.tmpl");
 pub const MAX_MEM_BYTES: u64 = 2 << 21;
 
 /// For BPF representations that require an external header, return a header
@@ -17,6 +26,7 @@ where
     C: Serialize,
 {
     pub name: String,
-    pub tmpl_path: PathBuf,
+    /// Source of the template
+    pub tmpl: &'static str,
     pub ctx: C,
 }
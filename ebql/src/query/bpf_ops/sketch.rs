@@ -0,0 +1,227 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use super::{HeaderTemplate, CMS_TMPL, HLL_TMPL, TOPK_TMPL};
+use crate::{query::operators::Operator, types};
+
+/// Functions of the approximate aggregations, which take the column and
+/// optionally the sketch's parameters:
+/// `approx_count_distinct(col[, registers])` and
+/// `approx_count(col[, width[, depth]])`. The latter outputs the column and
+/// its estimated count, for the values monitored by a top-k summary (see
+/// [`APPROX_COUNT_CANDIDATES`]).
+pub const APPROX_COUNT_DISTINCT: &str = "approx_count_distinct";
+pub const APPROX_COUNT: &str = "approx_count";
+/// Function of the heavy hitters aggregation, `topk(col, k[, counters])`,
+/// which outputs the column, and its estimated count and error bound (see
+/// [`topk_count_column`]).
+pub const TOPK: &str = "topk";
+
+/// Number of HyperLogLog registers, a power of two. Estimates have a standard
+/// error of about 1.04/sqrt(registers) (1.6% by default).
+pub const DEFAULT_HLL_REGISTERS: usize = 1 << 12;
+pub const MIN_HLL_REGISTERS: usize = 1 << 4;
+/// Past it, the scaled numerator of estimates (see `hll.bpf.h.tmpl`) overflows
+pub const MAX_HLL_REGISTERS: usize = 1 << 14;
+
+/// Width and depth of count-min sketches. Estimates overcount by at most
+/// e/width of a window's events, with probability 1 - e^-depth.
+pub const DEFAULT_CMS_WIDTH: usize = 1 << 11;
+pub const DEFAULT_CMS_DEPTH: usize = 4;
+pub const MAX_CMS_DEPTH: usize = 8;
+/// Maximum number of counters (width * depth) of a count-min sketch.
+pub const MAX_CMS_COUNTERS: usize = 1 << 20;
+
+/// Number of values whose counts top-k summaries monitor, by default per value
+/// emitted (i.e. k); the count of any value occurring more often than a
+/// `1/counters` fraction of the window's events is within `1/counters` of its
+/// true count. Every event scans the counters, so they're kept few.
+pub const DEFAULT_TOPK_COUNTERS_PER_KEY: usize = 8;
+pub const MAX_TOPK_COUNTERS: usize = 1 << 10;
+pub const MAX_TOPK: usize = 1 << 7;
+/// Number of candidate values approximate counts are emitted for each window,
+/// i.e. the values monitored by a top-k summary of the column; their counts are
+/// estimated by its count-min sketch.
+pub const APPROX_COUNT_CANDIDATES: usize = MAX_TOPK;
+
+/// Fixed point scale of the register sums of HyperLogLog sketches.
+const HLL_SUM_SHIFT: u32 = 32;
+
+/// BPF HyperLogLog sketches of a query, one per column.
+#[derive(Serialize, Default)]
+pub struct BpfHllTemplate {
+    pub query_name: String,
+    pub sketches: Vec<Hll>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Hll {
+    pub field_name: String,
+    pub registers: usize,
+    /// Bits of hashes indexing registers (i.e. log2 of registers)
+    pub precision: u32,
+    /// Numerator of the raw estimate, in fixed point
+    pub alpha_mm: u64,
+    /// Array initializer of the linear counting estimates, by the number of
+    /// empty registers
+    pub linear_counting: String,
+}
+
+impl BpfHllTemplate {
+    pub fn new(query_name: String) -> HeaderTemplate<BpfHllTemplate> {
+        HeaderTemplate {
+            name: "hll".into(),
+            tmpl: HLL_TMPL,
+            ctx: BpfHllTemplate {
+                query_name,
+                sketches: Vec::new(),
+            },
+        }
+    }
+
+    pub fn update(&mut self, op: &Operator) -> Result<()> {
+        let Operator::ApproxCountDistinct(f, registers) = op else {
+            return Err(anyhow!("Got operator {op} that isn't a HyperLogLog sketch"));
+        };
+        let m = *registers as f64;
+        // Bias correction of the raw estimate
+        let alpha = match registers {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let linear_counting = (0..=*registers)
+            .map(|zeros| {
+                match zeros {
+                    0 => 0,
+                    _ => (m * (m / zeros as f64).ln()).round() as u64,
+                }
+                .to_string()
+            })
+            .collect::<Vec<_>>();
+        self.sketches.push(Hll {
+            field_name: f.clone(),
+            registers: *registers,
+            precision: registers.trailing_zeros(),
+            alpha_mm: (alpha * m * m * (1u64 << HLL_SUM_SHIFT) as f64) as u64,
+            linear_counting: format!("{{{}}}", linear_counting.join(", ")),
+        });
+        Ok(())
+    }
+}
+
+/// BPF count-min sketches of a query, one per approximately counted column.
+#[derive(Serialize, Default)]
+pub struct BpfCmsTemplate {
+    pub query_name: String,
+    pub sketches: Vec<Cms>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Cms {
+    pub field_name: String,
+    pub width: usize,
+    pub depth: usize,
+}
+
+impl BpfCmsTemplate {
+    pub fn new(query_name: String) -> HeaderTemplate<BpfCmsTemplate> {
+        HeaderTemplate {
+            name: "cms".into(),
+            tmpl: CMS_TMPL,
+            ctx: BpfCmsTemplate {
+                query_name,
+                sketches: Vec::new(),
+            },
+        }
+    }
+
+    pub fn update(&mut self, op: &Operator) -> Result<()> {
+        let Operator::ApproxCount(f, width, depth) = op else {
+            return Err(anyhow!("Got operator {op} that isn't a count-min sketch"));
+        };
+        self.sketches.push(Cms {
+            field_name: f.clone(),
+            width: *width,
+            depth: *depth,
+        });
+        Ok(())
+    }
+}
+
+/// BPF space-saving summaries of a query's top-k values.
+#[derive(Serialize, Default)]
+pub struct BpfTopkTemplate {
+    pub query_name: String,
+    /// Whether rows are filled with the bounds of the window
+    pub window_start: bool,
+    pub window_end: bool,
+    pub sketches: Vec<Topk>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Topk {
+    pub field_name: String,
+    pub field_type: String,
+    pub k: usize,
+    pub counters: usize,
+    /// Whether counts are estimated by the column's count-min sketch (for
+    /// approximate counts) rather than by the summary
+    pub cms: bool,
+}
+
+impl BpfTopkTemplate {
+    pub fn new(query_name: String) -> HeaderTemplate<BpfTopkTemplate> {
+        HeaderTemplate {
+            name: "topk".into(),
+            tmpl: TOPK_TMPL,
+            ctx: BpfTopkTemplate {
+                query_name,
+                window_start: false,
+                window_end: false,
+                sketches: Vec::new(),
+            },
+        }
+    }
+
+    /// Adds the summary of a top-k, or of the candidates of approximate
+    /// counts, over the field.
+    pub fn update(&mut self, op: &Operator, field: &types::Field) -> Result<()> {
+        let (f, k, counters, cms) = match op {
+            Operator::TopK(f, k, counters) => (f, *k, *counters, false),
+            Operator::ApproxCount(f, _, _) => {
+                (f, APPROX_COUNT_CANDIDATES, APPROX_COUNT_CANDIDATES, true)
+            }
+            _ => return Err(anyhow!("Got operator {op} that isn't a top-k summary")),
+        };
+        self.sketches.push(Topk {
+            field_name: f.clone(),
+            field_type: field._type.to_string(),
+            k,
+            counters,
+            cms,
+        });
+        Ok(())
+    }
+}
+
+/// Gets the names of the estimated count and error bound columns of a top-k
+/// over the column.
+pub fn topk_count_column(col: &str) -> (String, String) {
+    (format!("{TOPK}_{col}"), format!("{TOPK}_{col}_error"))
+}
+
+/// Gets the name of the estimated count column of approximate counts of the
+/// column.
+pub fn approx_count_column(col: &str) -> String {
+    format!("{APPROX_COUNT}_{col}")
+}
+
+/// Returns whether an operator is an aggregation approximated by a sketch.
+pub fn is_sketch(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::ApproxCountDistinct(..) | Operator::ApproxCount(..) | Operator::TopK(..)
+    )
+}
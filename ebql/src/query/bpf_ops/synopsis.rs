@@ -0,0 +1,449 @@
+//! Shared synopses: aggregation state shared across concurrent queries.
+//!
+//! Two queries that aggregate over the same event, window, filter, and group
+//! by keys can be answered from the same BPF program and aggregation map; the
+//! program computes the union of both queries' aggregates, and each query
+//! projects out the columns it asked for.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use rand::distributions::{Alphanumeric, DistString};
+
+use crate::{
+    field::Field,
+    query::{
+        operators::{Operator, WindowType},
+        physical_plan::BpfPlan,
+        projection::Projection,
+    },
+    schema::schema::Schema,
+};
+
+/// Identifies the state an aggregation query computes over. Queries with equal
+/// keys can share one synopsis.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SynopsisKey {
+    /// Event the synopsis is computed over
+    pub event: String,
+    /// Window over the event stream
+    pub window: Option<WindowType>,
+    /// Filters applied before aggregation
+    pub filter: Option<String>,
+    /// Group by keys (in order, since they determine the key layout)
+    pub group_by: Vec<String>,
+}
+
+impl SynopsisKey {
+    /// Gets the synopsis key of a plan, if its state can be shared with other
+    /// queries. For now, only aggregation plans without joins, maps,
+    /// parameters, distinct values, or symbolized, path or cgroup columns are
+    /// shareable.
+    pub fn from_plan(plan: &BpfPlan) -> Option<Self> {
+        if plan.aggs.is_empty()
+            || !plan.aggs.iter().all(is_shareable_agg)
+            || plan.distinct
+            || plan.distinct_join.is_some()
+            || !plan.maps.is_empty()
+            || !plan.params.is_empty()
+            || !plan.symbols.is_empty()
+            || !plan.paths.is_empty()
+            || !plan.cgroups.is_empty()
+        {
+            return None;
+        }
+        Some(Self {
+            event: plan.event.name(),
+            window: plan.window.clone(),
+            filter: plan.filters.as_ref().map(|f| f.to_string()),
+            group_by: plan.group_by.iter().map(|f| f._name.clone()).collect(),
+        })
+    }
+}
+
+/// A compiled synopsis that queries can attach to.
+#[derive(Clone, Debug)]
+pub struct Synopsis {
+    /// Name of the program (and output schema) computing this synopsis
+    pub name: String,
+    /// Key of the synopsis' state, if it can be shared
+    pub key: Option<SynopsisKey>,
+    /// Aggregates computed by the program
+    pub aggs: Vec<String>,
+    /// Key of each output field of the program (see [`field_key`])
+    pub fields: Vec<String>,
+    /// Number of active queries reading from this synopsis
+    pub refs: usize,
+}
+
+impl Synopsis {
+    /// Constructs a synopsis from a compiled plan.
+    pub fn new(plan: &BpfPlan) -> Self {
+        Self {
+            name: plan.schema.name.clone(),
+            key: SynopsisKey::from_plan(plan),
+            aggs: plan.aggs.iter().map(|op| op.to_string()).collect(),
+            fields: field_keys(plan),
+            refs: 0,
+        }
+    }
+
+    /// Gets the projection from this synopsis onto the plan's schema, if the
+    /// synopsis computes every aggregate in the plan. Fields are matched by
+    /// key, as for the members of merged plans.
+    pub fn covers(&self, plan: &BpfPlan) -> Option<Projection> {
+        if !plan
+            .aggs
+            .iter()
+            .all(|op| self.aggs.contains(&op.to_string()))
+        {
+            return None;
+        }
+        project_by_key(&self.fields, plan)
+    }
+}
+
+/// Catalog of the synopses of running programs, so that overlapping queries can
+/// share one BPF program and its aggregation map. Synopses are kept by program
+/// name, since several programs may compute synopses with the same key (e.g.
+/// if one doesn't compute all of a later query's aggregates).
+#[derive(Debug, Default)]
+pub struct Synopses {
+    synopses: HashMap<String, Synopsis>,
+}
+
+impl Synopses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Finds an active synopsis that can answer the plan. If one exists,
+    /// registers the plan as a reader of the synopsis, and returns the
+    /// synopsis' program name with the projection of its output into the
+    /// plan's schema.
+    pub fn share(&mut self, plan: &BpfPlan) -> Option<(String, Projection)> {
+        let key = SynopsisKey::from_plan(plan)?;
+        let (syn, proj) = self
+            .synopses
+            .values_mut()
+            .filter(|syn| syn.key.as_ref() == Some(&key))
+            .find_map(|syn| syn.covers(plan).map(|proj| (syn, proj)))?;
+        syn.refs += 1;
+        log::info!("Query {} shares synopsis {}", plan.schema.name, syn.name);
+        Some((syn.name.clone(), proj))
+    }
+
+    /// Records the synopsis of an attached program, starting with `readers`
+    /// readers. Programs whose state isn't shareable are recorded too, so that
+    /// their readers are counted alike.
+    pub fn register(&mut self, plan: &BpfPlan, readers: usize) {
+        let mut syn = Synopsis::new(plan);
+        syn.refs = readers;
+        self.synopses.insert(syn.name.clone(), syn);
+    }
+
+    /// Releases one reader of the synopsis computed by the named program.
+    /// Returns true if this was its last reader (i.e. the program can be
+    /// detached), and false if readers remain or the program is unknown.
+    pub fn release<S: AsRef<str>>(&mut self, name: S) -> bool {
+        let Some(syn) = self.synopses.get_mut(name.as_ref()) else {
+            return false;
+        };
+        syn.refs = syn.refs.saturating_sub(1);
+        if syn.refs > 0 {
+            return false;
+        }
+        self.synopses.remove(name.as_ref());
+        true
+    }
+}
+
+/// A plan that computes the shared state of one or more queries.
+pub struct SharedPlan {
+    /// Merged BPF plan to compile
+    pub plan: BpfPlan,
+    /// Index of each member query in the original plan list, and the projection
+    /// from the merged plan's output into that query's schema
+    pub members: Vec<(usize, Projection)>,
+}
+
+/// Groups plans with equal synopsis keys, and merges each group into one plan
+/// that computes the union of the group's aggregates.
+pub fn merge_plans(plans: &[BpfPlan]) -> Result<Vec<SharedPlan>> {
+    let mut shared: Vec<SharedPlan> = Vec::new();
+    let mut by_key: HashMap<SynopsisKey, usize> = HashMap::new();
+
+    for (i, plan) in plans.iter().enumerate() {
+        let key = SynopsisKey::from_plan(plan);
+        // Merge into an existing plan with the same key, if one exists
+        if let Some(j) = key.as_ref().and_then(|k| by_key.get(k)) {
+            merge_into(&mut shared[*j].plan, plan);
+            shared[*j]
+                .members
+                .push((i, Projection::identity(plan.schema.clone())));
+            continue;
+        }
+        if let Some(key) = key {
+            by_key.insert(key, shared.len());
+        }
+        shared.push(SharedPlan {
+            plan: plan.clone(),
+            members: vec![(i, Projection::identity(plan.schema.clone()))],
+        });
+    }
+
+    // Now that the merged schemas are final, compute each member's projection
+    for sp in &mut shared {
+        if sp.members.len() == 1 {
+            continue;
+        }
+        let name = format!(
+            "shared_{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
+        );
+        sp.plan.schema = Arc::new(Schema::new(Some(name), sp.plan.schema.fields.clone()));
+        for (i, proj) in &mut sp.members {
+            // Every member's fields are in the merged schema by construction
+            *proj = member_projection(&sp.plan, &plans[*i])
+                .context("merged plan does not output all fields of its members")?;
+        }
+    }
+
+    Ok(shared)
+}
+
+/// Merges the aggregates, projections, and output fields of `plan` into `dst`.
+/// Aggregates and their output fields are deduplicated by the same key (see
+/// [`field_key`]), so that the merged schema matches the merged aggregates.
+fn merge_into(dst: &mut BpfPlan, plan: &BpfPlan) {
+    let mut fields = dst
+        .schema
+        .fields
+        .iter()
+        .map(|f| f.as_ref().clone())
+        .collect::<Vec<Field>>();
+    let mut keys = fields
+        .iter()
+        .map(|f| field_key(dst, &f.name))
+        .collect::<Vec<_>>();
+    for op in &plan.aggs {
+        let op_str = op.to_string();
+        if !dst.aggs.iter().any(|a| a.to_string() == op_str) {
+            dst.aggs.push(op.clone());
+        }
+    }
+    for f in &plan.projects {
+        if !dst.projects.contains(f) {
+            dst.projects.push(f.clone());
+        }
+    }
+    for f in plan.schema.fields.iter() {
+        let key = field_key(plan, &f.name);
+        if !keys.contains(&key) {
+            keys.push(key);
+            fields.push(f.as_ref().clone());
+        }
+    }
+    dst.schema = Arc::new(Schema::new(Some(dst.schema.name.clone()), fields.into()));
+}
+
+/// Gets the projection from a merged plan's output into a member's schema,
+/// matching fields by key, so that each member keeps its own field names.
+fn member_projection(merged: &BpfPlan, plan: &BpfPlan) -> Option<Projection> {
+    project_by_key(&field_keys(merged), plan)
+}
+
+/// Gets the projection from an output with the fields of the keys into the
+/// plan's schema, matching the plan's fields by key.
+fn project_by_key(keys: &[String], plan: &BpfPlan) -> Option<Projection> {
+    let indices = field_keys(plan)
+        .iter()
+        .map(|key| keys.iter().position(|k| k == key))
+        .collect::<Option<Vec<_>>>()?;
+    Some(Projection {
+        schema: plan.schema.clone(),
+        indices,
+    })
+}
+
+/// Gets the keys of a plan's output fields, in order.
+fn field_keys(plan: &BpfPlan) -> Vec<String> {
+    plan.schema
+        .fields
+        .iter()
+        .map(|f| field_key(plan, &f.name))
+        .collect()
+}
+
+/// Gets the key identifying an output field of a plan across plans: the
+/// aggregate computing it (i.e. its operator and column), or, for other fields
+/// (e.g. group by keys), its name.
+fn field_key(plan: &BpfPlan, name: &str) -> String {
+    plan.aggs
+        .iter()
+        .find(|op| agg_field_name(op).as_deref() == Some(name))
+        .map_or_else(|| name.to_string(), |op| op.to_string())
+}
+
+/// Gets the name of the output field of a shareable aggregate.
+fn agg_field_name(op: &Operator) -> Option<String> {
+    Some(match op {
+        Operator::Max(col) => format!("max_{col}"),
+        Operator::Min(col) => format!("min_{col}"),
+        Operator::Average(col) => format!("avg_{col}"),
+        Operator::Sum(col) => format!("sum_{col}"),
+        Operator::Count(col) => format!("count_{}", col.as_deref().unwrap_or_default()),
+        _ => return None,
+    })
+}
+
+/// Returns whether an operator is an aggregate that synopses can share.
+pub fn is_shareable_agg(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Max(_)
+            | Operator::Min(_)
+            | Operator::Average(_)
+            | Operator::Sum(_)
+            | Operator::Count(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::DataType,
+        events::get_event,
+        record::{DataValue, Record},
+        types,
+    };
+
+    /// Plan over `sys_enter_pread64`, grouped by cpu.
+    fn plan(name: &str, aggs: Vec<Operator>, fields: &[&str]) -> BpfPlan {
+        let e = get_event("syscalls/sys_enter_pread64").unwrap();
+        let mut plan = BpfPlan::new(&e);
+        plan.group_by = vec![types::Field::new("cpu".into(), types::Type::U64)];
+        plan.aggs = aggs;
+        plan.schema = Arc::new(Schema::new(
+            Some(name.into()),
+            fields
+                .iter()
+                .map(|f| Field::new(*f, DataType::UInt64))
+                .collect::<Vec<_>>()
+                .into(),
+        ));
+        plan
+    }
+
+    fn names(schema: &Schema) -> Vec<&str> {
+        schema.fields.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn merges_aggregates_once() {
+        let a = plan(
+            "a",
+            vec![Operator::Count(None), Operator::Average("pid".into())],
+            &["cpu", "count_", "avg_pid"],
+        );
+        let b = plan(
+            "b",
+            vec![Operator::Count(None), Operator::Sum("pid".into())],
+            &["cpu", "sum_pid", "count_"],
+        );
+        let shared = merge_plans(&[a, b]).unwrap();
+        assert_eq!(shared.len(), 1);
+
+        let merged = &shared[0].plan;
+        let aggs = merged
+            .aggs
+            .iter()
+            .map(|op| op.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(aggs, ["Count(*)", "Average(pid)", "Sum(pid)"]);
+        assert_eq!(
+            names(&merged.schema),
+            ["cpu", "count_", "avg_pid", "sum_pid"]
+        );
+
+        let (i, proj) = &shared[0].members[1];
+        assert_eq!(*i, 1);
+        assert_eq!(proj.indices, [0, 3, 1]);
+        assert_eq!(names(&proj.schema), ["cpu", "sum_pid", "count_"]);
+    }
+
+    #[test]
+    fn keeps_plans_with_different_keys_apart() {
+        let a = plan("a", vec![Operator::Count(None)], &["cpu", "count_"]);
+        let mut b = plan("b", vec![Operator::Count(None)], &["pid", "count_"]);
+        b.group_by = vec![types::Field::new("pid".into(), types::Type::U64)];
+        let shared = merge_plans(&[a, b]).unwrap();
+        assert_eq!(shared.len(), 2);
+        for sp in &shared {
+            assert_eq!(sp.members.len(), 1);
+            assert!(sp.members[0].1.is_identity(&sp.plan.schema));
+        }
+    }
+
+    #[test]
+    fn covers_plans_by_field_key() {
+        let merged = plan(
+            "shared",
+            vec![Operator::Max("pid".into()), Operator::Min("pid".into())],
+            &["cpu", "max_pid", "min_pid"],
+        );
+        let syn = Synopsis::new(&merged);
+        let b = plan(
+            "b",
+            vec![Operator::Min("pid".into()), Operator::Max("pid".into())],
+            &["min_pid", "cpu", "max_pid"],
+        );
+        let proj = syn.covers(&b).unwrap();
+        assert_eq!(proj.indices, [2, 0, 1]);
+
+        // Fields named after an aggregate the plan doesn't compute aren't
+        // matched to it
+        let c = plan("c", vec![Operator::Max("pid".into())], &["cpu", "min_pid"]);
+        assert!(syn.covers(&c).is_none());
+    }
+
+    #[test]
+    fn counts_readers_by_program() {
+        let a = plan("a", vec![Operator::Count(None)], &["cpu", "count_"]);
+        let b = plan("b", vec![Operator::Sum("pid".into())], &["cpu", "sum_pid"]);
+        let mut synopses = Synopses::new();
+        synopses.register(&a, 1);
+        // A program with the same key doesn't replace a's readers
+        synopses.register(&b, 1);
+        let (prog, _) = synopses.share(&a).unwrap();
+        assert_eq!(prog, "a");
+
+        assert!(!synopses.release("unknown"));
+        assert!(!synopses.release("a"));
+        assert!(synopses.release("b"));
+        assert!(synopses.release("a"));
+        assert!(!synopses.release("a"));
+        assert!(synopses.share(&a).is_none());
+    }
+
+    #[test]
+    fn projects_fields_by_name() {
+        let input = plan("a", vec![], &["cpu", "count_", "avg_pid"]).schema;
+        let output = plan("b", vec![], &["avg_pid", "cpu"]).schema;
+        let proj = Projection::new(&input, output).unwrap();
+        assert_eq!(proj.indices, [2, 0]);
+
+        let record = Record::from(vec![
+            DataValue::UInt64(1),
+            DataValue::UInt64(2),
+            DataValue::UInt64(3),
+        ]);
+        let projected = proj.project_record(&record);
+        assert_eq!(projected.get(0), DataValue::UInt64(3));
+        assert_eq!(projected.get(1), DataValue::UInt64(1));
+
+        let missing = plan("c", vec![], &["max_pid"]).schema;
+        assert!(Projection::new(&input, missing).is_none());
+    }
+}
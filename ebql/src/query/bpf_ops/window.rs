@@ -1,10 +1,69 @@
-use std::{path::PathBuf, time::Duration};
+use std::{fmt::Display, str::FromStr, time::Duration};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
 use serde::Serialize;
 
-use super::{HeaderTemplate, BPF_HEADERS_DIR};
-use crate::query::operators::WindowType;
+use super::{HeaderTemplate, STATEFUL_WINDOW_TMPL, TUMBLING_WINDOW_TMPL};
+use crate::{error::EbqlError, query::operators::WindowType, types};
+
+/// Implicit column holding the start of the window a row was emitted from: its
+/// start time for time windows, or the sequence number of its first event for
+/// count windows.
+pub const WINDOW_START: &str = "window_start";
+/// Implicit column holding the (exclusive) end of the window a row was emitted
+/// from.
+pub const WINDOW_END: &str = "window_end";
+
+/// Returns whether a column is one of the implicit window bound columns.
+pub fn is_window_bound<S: AsRef<str>>(name: S) -> bool {
+    matches!(name.as_ref(), WINDOW_START | WINDOW_END)
+}
+
+/// Gets the BPF field of an implicit window bound column.
+pub fn window_bound_field<S: AsRef<str>>(name: S) -> types::Field {
+    types::Field::new(name.as_ref().to_string(), types::Type::U64)
+}
+
+/// Map holding the timer that flushes scheduled windows (see `common.bpf.h`).
+pub const WINDOW_TIMER_MAP: &str = "window_timer_var";
+
+/// How time windows are flushed. Count windows are always flushed by the event
+/// that fills them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WindowFlush {
+    /// Windows are flushed when the first event past their end arrives, so
+    /// the last window before a quiet period is only emitted once events resume
+    OnEvent,
+    /// Windows are flushed on schedule by a `bpf_timer` (Linux 5.15+)
+    #[default]
+    Timer,
+    /// Windows are flushed on schedule by user space, which runs a flush
+    /// program every interval (for kernels without `bpf_timer`)
+    Tick,
+}
+
+impl FromStr for WindowFlush {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "event" => Self::OnEvent,
+            "timer" => Self::Timer,
+            "tick" => Self::Tick,
+            _ => bail!("unknown window flush {s} (expected event, timer, or tick)"),
+        })
+    }
+}
+
+impl Display for WindowFlush {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OnEvent => write!(f, "event"),
+            Self::Timer => write!(f, "timer"),
+            Self::Tick => write!(f, "tick"),
+        }
+    }
+}
 
 /// BPF window implementations.
 pub enum BpfWindowType {
@@ -18,33 +77,61 @@ pub struct BpfWindowTemplate {
     is_count: bool,
     count: usize,
     interval: u64,
+    window_start: bool,
+    window_end: bool,
+    /// Whether the window is flushed on schedule rather than by events
+    scheduled: bool,
+    /// Whether the schedule is kept by a `bpf_timer`
+    timer: bool,
+    /// Whether the window keeps an epoch for per-CPU aggregations
+    percpu: bool,
 }
 
 impl BpfWindowType {
-    pub fn get_tmpl(&self, name: String, has_aggs: bool) -> HeaderTemplate<BpfWindowTemplate> {
+    /// Gets the window's template. `bounds` are the window bound columns that
+    /// the template should fill into each row of the window, and `percpu`
+    /// whether the aggregations are kept in per-CPU maps.
+    pub fn get_tmpl(
+        &self,
+        name: String,
+        has_aggs: bool,
+        bounds: &[&str],
+        flush: WindowFlush,
+        percpu: bool,
+    ) -> HeaderTemplate<BpfWindowTemplate> {
+        let scheduled = self.is_scheduled(flush);
         let (is_count, count, interval_ns) = match self {
             BpfWindowType::TumblingCountWindow(n) => (true, *n, 0),
             BpfWindowType::TumblingTimeWindow(dur) => (false, 1 << 15, dur.as_nanos() as u64),
         };
 
-        let window_type = if has_aggs {
-            "tumbling_window"
+        let (window_type, tmpl) = if has_aggs {
+            ("tumbling_window", TUMBLING_WINDOW_TMPL)
         } else {
-            "stateful_window"
+            ("stateful_window", STATEFUL_WINDOW_TMPL)
         };
         HeaderTemplate {
             name: window_type.to_string(),
-            tmpl_path: [BPF_HEADERS_DIR, &format!("{window_type}.bpf.h.tmpl")]
-                .iter()
-                .collect::<PathBuf>(),
+            tmpl,
             ctx: BpfWindowTemplate {
                 query_name: name,
                 is_count,
                 count,
                 interval: interval_ns,
+                window_start: bounds.contains(&WINDOW_START),
+                window_end: bounds.contains(&WINDOW_END),
+                scheduled,
+                timer: scheduled && flush == WindowFlush::Timer,
+                percpu: has_aggs && percpu,
             },
         }
     }
+
+    /// Returns whether the window is flushed on schedule (rather than by
+    /// events) under the flush setting.
+    pub fn is_scheduled(&self, flush: WindowFlush) -> bool {
+        matches!(self, BpfWindowType::TumblingTimeWindow(_)) && flush != WindowFlush::OnEvent
+    }
 }
 
 impl TryFrom<&WindowType> for BpfWindowType {
@@ -56,21 +143,27 @@ impl TryFrom<&WindowType> for BpfWindowType {
                 if *iv == *step {
                     Ok(Self::TumblingTimeWindow(*iv))
                 } else {
-                    Err(anyhow!(
-                        "Non-tumbling step windows not supported in BPF yet"
-                    ))
+                    Err(EbqlError::unsupported(
+                        wt,
+                        "non-tumbling step windows not supported in BPF yet",
+                    )
+                    .into())
                 }
             }
             WindowType::Count(n, step) => {
                 if *n == *step {
                     Ok(Self::TumblingCountWindow(*n))
                 } else {
-                    Err(anyhow!(
-                        "Non-tumbling step windows not supported in BPF yet"
-                    ))
+                    Err(EbqlError::unsupported(
+                        wt,
+                        "non-tumbling step windows not supported in BPF yet",
+                    )
+                    .into())
                 }
             }
-            WindowType::Session(_) => Err(anyhow!("Session windows not supported in BPF yet")),
+            WindowType::Session(_) => {
+                Err(EbqlError::unsupported(wt, "session windows not supported in BPF yet").into())
+            }
         }
     }
 }
@@ -0,0 +1,432 @@
+//! EXPLAIN statements, which describe how a query would run without loading
+//! anything into the kernel.
+
+use anyhow::Result;
+
+use super::{
+    bpf_ops::compiler::QueryCompiler,
+    operators::Operator,
+    parser::{self, ExplainMode, PARAM_PREFIX},
+    physical_plan::{BpfPlan, PhysicalPlan, UserPlan},
+};
+use crate::{exec::proc_table::PROC_TABLE, schema::schema::Schema};
+
+/// Explains an `EXPLAIN [CODEGEN] SELECT ...` statement. Returns None if the
+/// statement is not an EXPLAIN statement.
+pub fn explain<S: AsRef<str>>(q: S) -> Result<Option<String>> {
+    let (mode, q) = match parser::parse_explain(q.as_ref()) {
+        (Some(mode), q) => (mode, q),
+        (None, _) => return Ok(None),
+    };
+    let defaults = parser::parse_param_defaults(q)?;
+    let (s, nested) = parser::parse_nested_query(q.to_string())?;
+    let plan = PhysicalPlan::from_nested(s, nested)?.with_param_defaults(defaults)?;
+
+    Ok(Some(match mode {
+        ExplainMode::Plan => explain_plan(&plan),
+        ExplainMode::Codegen => explain_codegen(&plan)?,
+    }))
+}
+
+/// Describes the logical operator DAG of a plan, and the physical plans
+/// executed in the kernel and in user space.
+pub fn explain_plan(plan: &PhysicalPlan) -> String {
+    let mut out = String::from("Logical plan:\n");
+    logical_tree(plan).write(&mut out, "  ", None);
+
+    out.push_str("\nPhysical plan:\n");
+    for bpf_plan in &plan.event_plans {
+        write_bpf_plan(&mut out, bpf_plan);
+    }
+    if let Some(user_plan) = &plan.user_plan {
+        write_user_plan(&mut out, user_plan);
+    }
+    out
+}
+
+/// Generates the code of each BPF program in a plan, without compiling or
+/// loading anything.
+pub fn explain_codegen(plan: &PhysicalPlan) -> Result<String> {
+    let qc = QueryCompiler::new();
+    let mut out = String::new();
+    for bpf_plan in &plan.event_plans {
+        out.push_str(&qc.explain_codegen(bpf_plan)?.to_string());
+    }
+    if let Some(user_plan) = &plan.user_plan {
+        out.push_str(&format!(
+            "// {} runs in user space; see EXPLAIN for its plan\n",
+            user_plan.schema.name
+        ));
+    }
+    Ok(out)
+}
+
+/// Node of the logical operator DAG. Nodes are drawn from the output down to
+/// the events.
+struct Node {
+    op: String,
+    children: Vec<Node>,
+}
+
+impl Node {
+    /// Puts an operator on top of the node.
+    fn wrap(self, op: impl ToString) -> Node {
+        Node {
+            op: op.to_string(),
+            children: vec![self],
+        }
+    }
+
+    /// Writes the tree rooted at this node. `last` is whether the node is the
+    /// last of its siblings, or None for the root.
+    fn write(&self, out: &mut String, prefix: &str, last: Option<bool>) {
+        let (branch, indent) = match last {
+            None => ("", ""),
+            Some(false) => ("├── ", "│   "),
+            Some(true) => ("└── ", "    "),
+        };
+        out.push_str(&format!("{prefix}{branch}{}\n", self.op));
+        let prefix = format!("{prefix}{indent}");
+        for (i, child) in self.children.iter().enumerate() {
+            child.write(out, &prefix, Some(i == self.children.len() - 1));
+        }
+    }
+}
+
+/// Gets the logical operator DAG of a plan.
+fn logical_tree(plan: &PhysicalPlan) -> Node {
+    let mut kernel = plan.event_plans.iter().map(bpf_tree).collect::<Vec<_>>();
+    let root = match plan
+        .event_plans
+        .last()
+        .and_then(|p| p.distinct_join.as_ref())
+    {
+        Some(join) => {
+            let fields = join.fields.iter().map(|f| f.name.clone()).collect();
+            Node {
+                op: format!("{} [kernel]", Operator::DistinctJoin(fields)),
+                children: kernel,
+            }
+        }
+        None => kernel.remove(0),
+    };
+    match &plan.user_plan {
+        Some(user_plan) => user_tree(user_plan, root),
+        None => root,
+    }
+}
+
+/// Gets the operators of a BPF program, from its output down to its event.
+fn bpf_tree(plan: &BpfPlan) -> Node {
+    let tag = |op: Operator| format!("{} [kernel]", show(op));
+    let mut node = Node {
+        op: tag(Operator::Select(plan.event.clone())),
+        children: vec![],
+    };
+    node = node.wrap(tag(Operator::Project(
+        plan.projects.iter().map(|f| f._name.clone()).collect(),
+    )));
+    if let Some(filter) = &plan.filters {
+        node = node.wrap(tag(filter.clone()));
+    }
+    if let Some(window) = &plan.window {
+        node = node.wrap(tag(Operator::Window(window.clone())));
+    }
+    if !plan.group_by.is_empty() {
+        node = node.wrap(tag(Operator::GroupBy(
+            plan.group_by.iter().map(|f| f._name.clone()).collect(),
+        )));
+    }
+    if !plan.aggs.is_empty() {
+        node = node.wrap(format!("{} [kernel]", show_aggs(&plan.aggs)));
+    }
+    if plan.distinct {
+        node = node.wrap("Distinct [kernel]");
+    }
+    node
+}
+
+/// Puts the operators executed in user space on top of the kernel's.
+fn user_tree(plan: &UserPlan, input: Node) -> Node {
+    let tag = |op: Operator| format!("{} [user]", show(op));
+    let mut node = input;
+    if let Some(filter) = &plan.filters {
+        node = node.wrap(tag(filter.clone()));
+    }
+    if let Some(window) = &plan.window {
+        node = node.wrap(tag(Operator::Window(window.clone())));
+    }
+    if !plan.group_by.is_empty() {
+        node = node.wrap(tag(Operator::GroupBy(
+            plan.group_by.iter().map(|f| f.name.clone()).collect(),
+        )));
+    }
+    // Process metadata is looked up for each output record
+    if plan.aggs.is_empty() {
+        node = join_procs(plan, node);
+        node = node.wrap(tag(Operator::Project(field_names(&plan.schema))));
+    } else {
+        node = node.wrap(format!("{} [user]", show_aggs(&plan.aggs)));
+        node = join_procs(plan, node);
+    }
+    node
+}
+
+/// Joins the `proc` table by the pids of a user-space plan's lookups, if any.
+fn join_procs(plan: &UserPlan, input: Node) -> Node {
+    if plan.procs.is_empty() {
+        return input;
+    }
+    let mut pids = plan
+        .procs
+        .iter()
+        .map(|(_, pid, _)| pid.clone())
+        .collect::<Vec<_>>();
+    pids.dedup();
+    Node {
+        op: format!("{} [user]", show(Operator::Join(pids))),
+        children: vec![
+            input,
+            Node {
+                op: format!("{PROC_TABLE} [user]"),
+                children: vec![],
+            },
+        ],
+    }
+}
+
+/// Describes a plan executed by a BPF program.
+fn write_bpf_plan(out: &mut String, plan: &BpfPlan) {
+    out.push_str(&format!(
+        "  [kernel] {} on {}\n",
+        plan.schema.name,
+        plan.event.section()
+    ));
+    let mut entry = |key: &str, val: String| out.push_str(&format!("    {key:<12}{val}\n"));
+    if let Some(window) = &plan.window {
+        entry("window:", window.to_string());
+    }
+    entry("projects:", join(plan.projects.iter()));
+    if let Some(Operator::Filter(ce)) = &plan.filters {
+        entry("filter:", ce.to_string().replace(PARAM_PREFIX, "$"));
+    }
+    if !plan.group_by.is_empty() {
+        entry("group by:", join(plan.group_by.iter().map(|f| &f._name)));
+    }
+    if !plan.aggs.is_empty() {
+        entry("aggregates:", join(plan.aggs.iter()));
+    }
+    if !plan.params.is_empty() {
+        entry(
+            "params:",
+            join(plan.params.iter().map(|f| {
+                match plan.param_defaults.iter().find(|(n, _)| *n == f._name) {
+                    Some((_, v)) => format!("{} ${} = {v}", f._type, f._name),
+                    None => format!("{} ${}", f._type, f._name),
+                }
+            })),
+        );
+    }
+    if plan.distinct {
+        entry("distinct:", String::from("true"));
+    }
+    if let Some(join_spec) = &plan.distinct_join {
+        entry(
+            "join:",
+            format!(
+                "{} with {} using ({})",
+                join_spec.l.name,
+                join_spec.r.name,
+                join(join_spec.fields.iter().map(|f| &f.name))
+            ),
+        );
+    }
+    entry("output:", join(plan.schema.fields.iter()));
+}
+
+/// Describes a plan executed in user space.
+fn write_user_plan(out: &mut String, plan: &UserPlan) {
+    out.push_str(&format!(
+        "  [user] {} over {}\n",
+        plan.schema.name, plan.input.name
+    ));
+    let mut entry = |key: &str, val: String| out.push_str(&format!("    {key:<12}{val}\n"));
+    entry("input:", join(plan.input.fields.iter()));
+    if let Some(window) = &plan.window {
+        entry("window:", window.to_string());
+    }
+    if let Some(Operator::Filter(ce)) = &plan.filters {
+        entry("filter:", ce.to_string());
+    }
+    if !plan.group_by.is_empty() {
+        entry("group by:", join(plan.group_by.iter().map(|f| &f.name)));
+    }
+    if !plan.aggs.is_empty() {
+        entry("aggregates:", join(plan.aggs.iter()));
+    }
+    if !plan.procs.is_empty() {
+        entry(
+            "proc:",
+            join(
+                plan.procs
+                    .iter()
+                    .map(|(name, pid, col)| format!("{name} = {col} of {pid}")),
+            ),
+        );
+    }
+    entry("output:", join(plan.schema.fields.iter()));
+}
+
+/// Displays an operator, with parameters shown as they were written.
+fn show(op: Operator) -> String {
+    op.to_string().replace(PARAM_PREFIX, "$")
+}
+
+/// Displays the aggregations computed over each group.
+fn show_aggs(aggs: &[Operator]) -> String {
+    format!("Aggregate({})", join(aggs.iter()))
+}
+
+fn field_names(schema: &Schema) -> Vec<String> {
+    schema.fields.iter().map(|f| f.name.clone()).collect()
+}
+
+fn join<T: ToString>(items: impl Iterator<Item = T>) -> String {
+    items.map(|i| i.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use nom_sql::{Column, ConditionBase, ConditionExpression, ConditionTree, Literal};
+
+    use super::*;
+    use crate::{
+        data_types::DataType, events::get_event, field::Field, query::operators::WindowType,
+    };
+
+    /// Gets the filter on a column being greater than a value.
+    fn greater(name: &str, value: u64) -> ConditionExpression {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            operator: nom_sql::Operator::Greater,
+            left: Box::new(ConditionExpression::Base(ConditionBase::Field(Column {
+                name: name.into(),
+                ..Default::default()
+            }))),
+            right: Box::new(ConditionExpression::Base(ConditionBase::Literal(
+                Literal::UnsignedInteger(value),
+            ))),
+        })
+    }
+
+    fn schema(name: &str, fields: &[&str]) -> Arc<Schema> {
+        Arc::new(Schema::new(
+            Some(name.into()),
+            fields
+                .iter()
+                .map(|f| Field::new(*f, DataType::UInt64))
+                .collect::<Vec<_>>()
+                .into(),
+        ))
+    }
+
+    /// Gets the plan of `SELECT fd, count(*), sum(count) FROM
+    /// syscalls/sys_enter_pread64 WHERE count > 4096 GROUP BY fd WINDOW
+    /// time(1s)`.
+    fn read_plan() -> BpfPlan {
+        let e = get_event("syscalls/sys_enter_pread64").unwrap();
+        let mut plan = BpfPlan::new(&e);
+        plan.projects = vec![e.get_arg("fd").unwrap(), e.get_arg("count").unwrap()];
+        plan.filters = Some(Operator::Filter(greater("count", 4096)));
+        plan.window = Some(WindowType::Time(
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ));
+        plan.group_by = vec![e.get_arg("fd").unwrap()];
+        plan.aggs = vec![Operator::Count(None), Operator::Sum("count".into())];
+        plan.schema = schema("reads", &["fd", "count", "sum_count"]);
+        plan
+    }
+
+    #[test]
+    fn explains_aggregations() {
+        let plan = PhysicalPlan {
+            event_plans: vec![read_plan()],
+            user_plan: None,
+        };
+        let filter = greater("count", 4096);
+        assert_eq!(
+            explain_plan(&plan),
+            format!(
+                "Logical plan:
+  Aggregate(Count(*), Sum(count)) [kernel]
+  └── GroupBy(fd) [kernel]
+      └── Window(Time(1s)) [kernel]
+          └── Filter({filter}) [kernel]
+              └── Project(fd, count) [kernel]
+                  └── Select(syscalls/sys_enter_pread64) [kernel]
+
+Physical plan:
+  [kernel] reads on tp/syscalls/sys_enter_pread64
+    window:     Time(1s)
+    projects:   u64 fd, u64 count
+    filter:     {filter}
+    group by:   fd
+    aggregates: Count(*), Sum(count)
+    output:     fd (UInt64), count (UInt64), sum_count (UInt64)
+"
+            )
+        );
+    }
+
+    /// Explains `SELECT max(cnt) FROM (<read_plan>) AS reads(fd, cnt,
+    /// sum_count) WHERE cnt > 1`.
+    #[test]
+    fn explains_nested_selects() {
+        let input = schema("reads", &["fd", "cnt", "sum_count"]);
+        let plan = PhysicalPlan {
+            event_plans: vec![read_plan()],
+            user_plan: Some(UserPlan {
+                input,
+                schema: schema("busiest", &["max_cnt"]),
+                window: None,
+                window_start: None,
+                filters: Some(Operator::Filter(greater("cnt", 1))),
+                group_by: vec![],
+                aggs: vec![Operator::Max("cnt".into())],
+                procs: vec![],
+            }),
+        };
+        let (inner, outer) = (greater("count", 4096), greater("cnt", 1));
+        assert_eq!(
+            explain_plan(&plan),
+            format!(
+                "Logical plan:
+  Aggregate(Max(cnt)) [user]
+  └── Filter({outer}) [user]
+      └── Aggregate(Count(*), Sum(count)) [kernel]
+          └── GroupBy(fd) [kernel]
+              └── Window(Time(1s)) [kernel]
+                  └── Filter({inner}) [kernel]
+                      └── Project(fd, count) [kernel]
+                          └── Select(syscalls/sys_enter_pread64) [kernel]
+
+Physical plan:
+  [kernel] reads on tp/syscalls/sys_enter_pread64
+    window:     Time(1s)
+    projects:   u64 fd, u64 count
+    filter:     {inner}
+    group by:   fd
+    aggregates: Count(*), Sum(count)
+    output:     fd (UInt64), count (UInt64), sum_count (UInt64)
+  [user] busiest over reads
+    input:      fd (UInt64), cnt (UInt64), sum_count (UInt64)
+    filter:     {outer}
+    aggregates: Max(cnt)
+    output:     max_cnt (UInt64)
+"
+            )
+        );
+    }
+}
@@ -1,9 +1,10 @@
 use std::{collections::HashMap, marker::PhantomData, sync::Arc};
 
+use anyhow::{bail, Result};
 use daggy::{Dag, NodeIndex};
 
 use super::operators::Operator;
-use crate::{events::Event, schema::schema::Schema};
+use crate::{error::EbqlError, events::Event, schema::schema::Schema};
 
 /// Logical plan representation.
 pub struct LogicalPlan<S = Base> {
@@ -28,13 +29,19 @@ impl LogicalPlan<Base> {
     // TODO:
     // pub fn select(&self, )
 
-    pub fn join(&mut self, e1: &Box<dyn Event>, e2: &Box<dyn Event>) -> &mut Self {
-        todo!()
+    pub fn join(&mut self, e1: &Box<dyn Event>, e2: &Box<dyn Event>) -> Result<&mut Self> {
+        bail!(EbqlError::unsupported(
+            format!("{} JOIN {}", e1.name(), e2.name()),
+            "joins not yet supported"
+        ))
     }
 
     /// Verify data types are coherent.
-    pub fn verify(&self) -> bool {
-        todo!()
+    pub fn verify(&self) -> Result<bool> {
+        bail!(EbqlError::unsupported(
+            "logical plan",
+            "verification not yet supported"
+        ))
     }
 }
 
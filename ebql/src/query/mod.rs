@@ -1,7 +1,9 @@
 pub mod bpf_ops;
 // pub mod compiler;
+pub mod explain;
 pub mod logical_plan;
 pub mod operators;
 pub mod physical_plan;
+pub mod projection;
 
 pub mod parser;
@@ -2,7 +2,7 @@ use std::{fmt::Display, sync::Arc, time::Duration};
 
 use nom_sql::{ArithmeticExpression, ConditionExpression};
 
-use crate::{events::Event, field::Field, record::DataValue};
+use crate::{events::Event, field::Field, histogram::Buckets, record::DataValue};
 
 #[derive(Clone)]
 pub enum Operator {
@@ -32,6 +32,26 @@ pub enum Operator {
     Sum(String),
     /// Count either all values, or grouped on a value
     Count(Option<String>),
+    /// Sample variance/standard deviation of a field
+    Variance(String),
+    Stddev(String),
+    /// Value of a field at the minimum/maximum of another field
+    MinBy(String, String),
+    MaxBy(String, String),
+    /// First/last value of a field in a window
+    First(String),
+    Last(String),
+    /// Histogram of a field's values in each group
+    Hist(String, Buckets),
+    /// Approximate number of distinct values of a field in a window, with the
+    /// number of HyperLogLog registers
+    ApproxCountDistinct(String, usize),
+    /// Approximate count of each of a field's (most frequent) values in a
+    /// window, with the width and depth of its count-min sketch
+    ApproxCount(String, usize, usize),
+    /// The k most frequent values of a field in a window, with the number of
+    /// values the space-saving summary monitors
+    TopK(String, usize, usize),
     /// Join by keys
     Join(Vec<String>),
     DistinctJoin(Vec<String>),
@@ -45,10 +65,20 @@ impl Display for Operator {
             Operator::Project(fs) => write!(f, "Project({})", fs.join(", ")),
             Operator::Filter(ce) => write!(f, "Filter({ce})"),
             Operator::Map(me) => write!(f, "Map({me})"),
-            Operator::MapInPlace(_, _) => todo!(),
-            Operator::GroupBy(_) => todo!(),
-            Operator::Histogram(_) => todo!(),
-            Operator::Quantile(_) => todo!(),
+            Operator::MapInPlace(field, me) => write!(f, "MapInPlace({field}, {me})"),
+            Operator::GroupBy(fs) => write!(f, "GroupBy({})", fs.join(", ")),
+            Operator::Histogram(buckets) => {
+                write!(
+                    f,
+                    "Histogram({})",
+                    buckets
+                        .iter()
+                        .map(|(lo, hi)| format!("[{lo}, {hi})"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+            Operator::Quantile(q) => write!(f, "Quantile({q})"),
             Operator::Max(s) => write!(f, "Max({s})"),
             Operator::Min(s) => write!(f, "Min({s})"),
             Operator::Average(s) => write!(f, "Average({s})"),
@@ -63,7 +93,21 @@ impl Display for Operator {
                     }
                 )
             }
-            Operator::Join(_) => todo!(),
+            Operator::Variance(s) => write!(f, "Variance({s})"),
+            Operator::Stddev(s) => write!(f, "Stddev({s})"),
+            Operator::MinBy(s, by) => write!(f, "MinBy({s}, {by})"),
+            Operator::MaxBy(s, by) => write!(f, "MaxBy({s}, {by})"),
+            Operator::First(s) => write!(f, "First({s})"),
+            Operator::Last(s) => write!(f, "Last({s})"),
+            Operator::Hist(s, buckets) => write!(f, "Hist({s}, {buckets})"),
+            Operator::ApproxCountDistinct(s, registers) => {
+                write!(f, "ApproxCountDistinct({s}, {registers})")
+            }
+            Operator::ApproxCount(s, width, depth) => {
+                write!(f, "ApproxCount({s}, {width}, {depth})")
+            }
+            Operator::TopK(s, k, counters) => write!(f, "TopK({s}, {k}, {counters})"),
+            Operator::Join(args) => write!(f, "Join({})", args.join(", ")),
             Operator::DistinctJoin(args) => write!(f, "DistinctJoin({})", args.join(", ")),
         }
     }
@@ -128,7 +172,7 @@ Map
 /// - Session-based windows (inactivity threshold)
 ///
 /// Currently, only tumbling time/count windows are supported in eBPF.
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum WindowType {
     Time(Duration, Duration),
     Count(usize, usize),
@@ -160,7 +204,7 @@ impl Display for WindowType {
                     }
                 )
             }
-            WindowType::Session(_) => todo!(),
+            WindowType::Session(gap) => write!(f, "Session({:?})", gap),
         }
     }
 }
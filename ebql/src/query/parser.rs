@@ -1,14 +1,551 @@
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
 use nom_sql::{SelectStatement, SqlQuery};
 
+use crate::{error::EbqlError, events::EVENT_ARG_SEP, record::DataValue};
+
 pub fn parse_query(q: String) -> Result<SelectStatement> {
+    let (q, _) = strip_param_defaults(&q)?;
+    let q = rewrite_params(&rewrite_event_args(&rewrite_between(&q)?));
     match nom_sql::parse_query(&q) {
         Ok(q) => {
             match q {
                 SqlQuery::Select(s) => Ok(s),
-                _ => Err(anyhow!("Query {q} not supported")),
+                _ => bail!(EbqlError::unsupported(q, "only selects are supported")),
+            }
+        }
+        Err(e) => {
+            bail!(EbqlError::parse(
+                failing_clause(&q).replace(PARAM_PREFIX, "$"),
+                e
+            ))
+        }
+    }
+}
+
+/// Keywords that start the top-level clauses of a select after its FROM
+/// clause.
+const CLAUSES: [&str; 6] = ["where", "group", "having", "order", "limit", "window"];
+
+/// Gets the offsets at which a query's top-level clauses (after its select
+/// list and FROM clause) start.
+fn clause_starts(q: &str) -> Vec<usize> {
+    let mut depth = 0;
+    let mut starts = Vec::new();
+    for (start, end) in tokenize(q) {
+        match &q[start..end] {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            t if depth == 0 && CLAUSES.iter().any(|kw| t.eq_ignore_ascii_case(kw)) => {
+                starts.push(start)
+            }
+            _ => (),
+        }
+    }
+    starts
+}
+
+/// Finds the clause of a query that fails to parse, by parsing longer and
+/// longer prefixes of the query (each ending before a top-level clause). If
+/// even the select list and FROM clause fail to parse, those are returned.
+fn failing_clause(q: &str) -> &str {
+    let mut starts = clause_starts(q);
+    starts.push(q.len());
+    let mut prev = 0;
+    for end in starts {
+        if nom_sql::parse_query(&q[..end]).is_err() {
+            return q[prev..end].trim();
+        }
+        prev = end;
+    }
+    q
+}
+
+/// Kind of output requested by an EXPLAIN statement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExplainMode {
+    /// `EXPLAIN SELECT ...`: the query's logical and physical plans
+    Plan,
+    /// `EXPLAIN CODEGEN SELECT ...`: the query's generated BPF code
+    Codegen,
+}
+
+/// Splits the EXPLAIN prefix (if any) off a statement, returning the kind of
+/// explanation requested and the query to explain.
+pub fn parse_explain(q: &str) -> (Option<ExplainMode>, &str) {
+    match strip_keyword(q, "explain") {
+        Some(rest) => {
+            match strip_keyword(rest, "codegen") {
+                Some(rest) => (Some(ExplainMode::Codegen), rest),
+                None => (Some(ExplainMode::Plan), rest),
+            }
+        }
+        None => (None, q),
+    }
+}
+
+/// Strips the keyword off the start of the query, if the query starts with it.
+fn strip_keyword<'a>(q: &'a str, kw: &str) -> Option<&'a str> {
+    let q = q.trim_start();
+    let (word, rest) = q.split_at(q.find(char::is_whitespace).unwrap_or(q.len()));
+    word.eq_ignore_ascii_case(kw).then_some(rest)
+}
+
+/// Prefix of the columns that stand in for query parameters (`$name`).
+pub const PARAM_PREFIX: &str = "__param_";
+
+/// Name of the table that stands in for a select nested in a FROM clause.
+pub const NESTED_TABLE: &str = "__nested";
+
+/// Parses a query whose FROM clause may be a nested select, e.g.
+/// `SELECT max(cnt) FROM (SELECT pid, count(*) AS cnt FROM ...) WINDOW ...`.
+/// Returns the outer statement, which selects from [`NESTED_TABLE`], along
+/// with the nested statement. Queries without a nested select are returned
+/// as-is.
+pub fn parse_nested_query(q: String) -> Result<(SelectStatement, Option<SelectStatement>)> {
+    let (start, end) = match find_nested_from(&q)? {
+        Some(span) => span,
+        None => return Ok((parse_query(q)?, None)),
+    };
+    let inner = q[start + 1..end].to_string();
+    if find_nested_from(&inner)?.is_some() {
+        bail!(EbqlError::unsupported(
+            inner,
+            "only one level of nested selects is supported"
+        ));
+    }
+    let outer = format!("{}{}{}", &q[..start], NESTED_TABLE, &q[end + 1..]);
+
+    Ok((parse_query(outer)?, Some(parse_query(inner)?)))
+}
+
+/// Finds the (inclusive) span of the parentheses around a select nested in
+/// the top-level FROM clause, if one exists.
+fn find_nested_from(q: &str) -> Result<Option<(usize, usize)>> {
+    let bytes = q.as_bytes();
+    let mut depth = 0;
+    let mut in_str = None;
+    for (i, &c) in bytes.iter().enumerate() {
+        match in_str {
+            // Skip over string literals
+            Some(quote) => {
+                if c == quote {
+                    in_str = None;
+                }
+            }
+            None if c == b'\'' || c == b'"' => in_str = Some(c),
+            None if c == b'(' => depth += 1,
+            None if c == b')' => depth -= 1,
+            None if depth == 0 && is_keyword_at(bytes, i, b"from") => {
+                // Check whether the FROM clause opens with a parenthesis
+                let mut j = i + 4;
+                while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+                    j += 1;
+                }
+                if j == bytes.len() || bytes[j] != b'(' {
+                    return Ok(None);
+                }
+                // Find matching close parenthesis, skipping over string
+                // literals (e.g. `comm = ')'`)
+                let mut depth = 0;
+                for (start, end) in tokenize(&q[j..]) {
+                    match bytes[j + start] {
+                        b'(' => depth += 1,
+                        b')' => depth -= 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        return Ok(Some((j, j + end - 1)));
+                    }
+                }
+                bail!(EbqlError::parse(&q[j..], "unbalanced parentheses"));
+            }
+            None => (),
+        }
+    }
+    Ok(None)
+}
+
+/// Returns whether the keyword (in lowercase) starts at index i of the query,
+/// as a standalone word.
+fn is_keyword_at(q: &[u8], i: usize, kw: &[u8]) -> bool {
+    let is_ident = |c: &u8| c.is_ascii_alphanumeric() || *c == b'_';
+    q.len() >= i + kw.len()
+        && q[i..i + kw.len()].eq_ignore_ascii_case(kw)
+        && (i == 0 || !is_ident(&q[i - 1]))
+        && q.get(i + kw.len()).map_or(true, |c| !is_ident(c))
+}
+
+/// Rewrites `x [NOT] BETWEEN lo AND hi` (which the SQL parser does not
+/// support) into the equivalent comparisons, i.e. `(x >= lo AND x <= hi)` and
+/// `(x < lo OR x > hi)`. Operands must be columns or literals.
+fn rewrite_between(q: &str) -> Result<String> {
+    let tokens = tokenize(q);
+    let text = |i: usize| &q[tokens[i].0..tokens[i].1];
+    let is_kw = |i: usize, kw: &str| i < tokens.len() && text(i).eq_ignore_ascii_case(kw);
+    let is_operand = |i: usize| {
+        i < tokens.len() && {
+            let c = q.as_bytes()[tokens[i].0];
+            c.is_ascii_alphanumeric() || matches!(c, b'_' | b'-' | b'\'' | b'"')
+        }
+    };
+
+    let mut res = String::new();
+    // End of the text copied into the result
+    let mut copied = 0;
+    for i in 0..tokens.len() {
+        if !is_kw(i, "between") {
+            continue;
+        }
+        let negated = i >= 2 && is_kw(i - 1, "not");
+        let col = match i.checked_sub(1 + negated as usize) {
+            Some(col)
+                if is_operand(col)
+                    && is_operand(i + 1)
+                    && is_kw(i + 2, "and")
+                    && is_operand(i + 3) =>
+            {
+                col
+            }
+            _ => {
+                let (start, end) = (i.saturating_sub(2), (i + 3).min(tokens.len() - 1));
+                bail!(EbqlError::parse(
+                    &q[tokens[start].0..tokens[end].1],
+                    "malformed BETWEEN expression"
+                ))
+            }
+        };
+        let (lo, hi) = (text(i + 1), text(i + 3));
+        res.push_str(&q[copied..tokens[col].0]);
+        let col = text(col);
+        if negated {
+            res.push_str(&format!("({col} < {lo} OR {col} > {hi})"));
+        } else {
+            res.push_str(&format!("({col} >= {lo} AND {col} <= {hi})"));
+        }
+        copied = tokens[i + 3].1;
+    }
+    res.push_str(&q[copied..]);
+
+    Ok(res)
+}
+
+/// Rewrites the arguments of events named in table names (e.g.
+/// `perf/cpu_clock(99)`), which the SQL parser does not accept, into their
+/// names after [`EVENT_ARG_SEP`] (e.g. `perf/cpu_clock__99`). Only numeric
+/// arguments directly following an event's name are rewritten, so function
+/// calls are left as-is.
+fn rewrite_event_args(q: &str) -> String {
+    let tokens = tokenize(q);
+    let text = |i: usize| &q[tokens[i].0..tokens[i].1];
+    let adjacent = |i: usize| tokens[i].1 == tokens[i + 1].0;
+
+    let mut res = String::with_capacity(q.len());
+    // End of the text copied into the result
+    let mut copied = 0;
+    for i in 1..tokens.len().saturating_sub(3) {
+        let is_event_arg = text(i - 1) == "/"
+            && text(i).starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && adjacent(i - 1)
+            && adjacent(i)
+            && text(i + 1) == "("
+            && text(i + 2).bytes().all(|c| c.is_ascii_digit())
+            && text(i + 3) == ")";
+        if is_event_arg {
+            res.push_str(&q[copied..tokens[i].1]);
+            res.push_str(EVENT_ARG_SEP);
+            res.push_str(text(i + 2));
+            copied = tokens[i + 3].1;
+        }
+    }
+    res.push_str(&q[copied..]);
+    res
+}
+
+/// Splits a query into the spans of its tokens: string literals, words
+/// (identifiers, keywords, and numbers, including negative numbers), and
+/// single punctuation characters.
+fn tokenize(q: &str) -> Vec<(usize, usize)> {
+    let bytes = q.as_bytes();
+    let is_word = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'.';
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        } else if c == b'\'' || c == b'"' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != c {
+                i += 1;
+            }
+            i = (i + 1).min(bytes.len());
+        } else if is_word(c) || (c == b'-' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit)) {
+            i += 1;
+            while i < bytes.len() && is_word(bytes[i]) {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+        tokens.push((start, i));
+    }
+    tokens
+}
+
+/// Keyword of the clause that sets the defaults of a query's parameters.
+const DEFAULT_CLAUSE: &str = "default";
+
+/// Gets the default values of a query's parameters, set by a trailing
+/// `DEFAULT $name = value, ...` clause (e.g. `WHERE latency > $threshold
+/// DEFAULT $threshold = 100`). Parameters without defaults start at zero.
+pub fn parse_param_defaults(q: &str) -> Result<Vec<(String, DataValue)>> {
+    Ok(strip_param_defaults(q)?.1)
+}
+
+/// Strips the DEFAULT clause off a query, returning the stripped query along
+/// with the default of each parameter it sets. The clause must end the query
+/// (outside of any nested select), defaults must be integers, and each
+/// parameter may only be given one default.
+fn strip_param_defaults(q: &str) -> Result<(String, Vec<(String, DataValue)>)> {
+    let tokens = tokenize(q);
+    let text = |i: usize| &q[tokens[i].0..tokens[i].1];
+    let mut depth = 0;
+    let clause = tokens.iter().enumerate().position(|(i, _)| {
+        match text(i) {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            t => return depth == 0 && t.eq_ignore_ascii_case(DEFAULT_CLAUSE),
+        }
+        false
+    });
+    let Some(clause) = clause else {
+        return Ok((q.to_string(), vec![]));
+    };
+
+    // Defaults are comma-separated `$name = value` assignments
+    let mut defaults: Vec<(String, DataValue)> = Vec::new();
+    let mut i = clause + 1;
+    loop {
+        let is_default = i + 3 < tokens.len()
+            && text(i) == "$"
+            && tokens[i].1 == tokens[i + 1].0
+            && text(i + 2) == "=";
+        if !is_default {
+            bail!(EbqlError::parse(
+                &q[tokens[clause].0..],
+                "expected parameter defaults ($name = value)"
+            ));
+        }
+        let (name, value) = (text(i + 1), text(i + 3));
+        let fragment = &q[tokens[i].0..tokens[i + 3].1];
+        let value = match (value.parse::<i64>(), value.parse::<u64>()) {
+            (Ok(v), _) => DataValue::Int64(v),
+            (_, Ok(v)) => DataValue::UInt64(v),
+            _ => {
+                bail!(EbqlError::parse(
+                    fragment,
+                    "parameter defaults must be integers"
+                ))
             }
+        };
+        if defaults.iter().any(|(n, _)| n == name) {
+            bail!(EbqlError::parse(
+                fragment,
+                format!("parameter ${name} is given several defaults")
+            ));
         }
-        Err(e) => Err(anyhow!("Failed to parse query {q}: {e}")),
+        defaults.push((name.to_string(), value));
+        match tokens.get(i + 4).map(|_| text(i + 4)) {
+            Some(",") => i += 5,
+            None => break,
+            Some(_) => {
+                bail!(EbqlError::parse(
+                    &q[tokens[i + 4].0..],
+                    "the DEFAULT clause must end the query"
+                ))
+            }
+        }
+    }
+    Ok((q[..tokens[clause].0].trim_end().to_string(), defaults))
+}
+
+/// Rewrites query parameters (`$name`) into columns prefixed by
+/// [`PARAM_PREFIX`], since the SQL parser only supports positional
+/// placeholders.
+fn rewrite_params(q: &str) -> String {
+    let mut res = String::with_capacity(q.len());
+    let mut in_str = None;
+    for c in q.chars() {
+        match in_str {
+            Some(quote) if c == quote => in_str = None,
+            Some(_) => (),
+            None if c == '\'' || c == '"' => in_str = Some(c),
+            None if c == '$' => {
+                res.push_str(PARAM_PREFIX);
+                continue;
+            }
+            None => (),
+        }
+        res.push(c);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gets the text of the nested select in a query's FROM clause.
+    fn nested(q: &str) -> Option<&str> {
+        find_nested_from(q)
+            .unwrap()
+            .map(|(start, end)| &q[start + 1..end])
+    }
+
+    #[test]
+    fn finds_nested_from() {
+        let q = "SELECT max(cnt) FROM (SELECT pid, count(*) AS cnt FROM e GROUP BY pid) WINDOW \
+                 tumbling(1s)";
+        assert_eq!(
+            nested(q),
+            Some("SELECT pid, count(*) AS cnt FROM e GROUP BY pid")
+        );
+        assert_eq!(nested("SELECT pid FROM e WHERE pid IN (1, 2)"), None);
+        assert_eq!(nested("SELECT count(*) AS from_x FROM e"), None);
+    }
+
+    #[test]
+    fn finds_nested_from_around_string_literals() {
+        let q = "SELECT c FROM (SELECT comm AS c FROM e WHERE comm = ')') WHERE c != '('";
+        assert_eq!(nested(q), Some("SELECT comm AS c FROM e WHERE comm = ')'"));
+        // Keywords in literals don't start FROM clauses
+        let q = "SELECT pid FROM e WHERE comm = 'from (x'";
+        assert_eq!(nested(q), None);
+    }
+
+    #[test]
+    fn rejects_unbalanced_nested_from() {
+        assert!(find_nested_from("SELECT c FROM (SELECT comm AS c FROM e").is_err());
+        assert!(
+            find_nested_from("SELECT c FROM (SELECT comm AS c FROM e WHERE comm = ')'").is_err()
+        );
+    }
+
+    #[test]
+    fn rewrites_between() {
+        assert_eq!(
+            rewrite_between("SELECT pid FROM e WHERE pid BETWEEN 1 AND 10").unwrap(),
+            "SELECT pid FROM e WHERE (pid >= 1 AND pid <= 10)"
+        );
+        assert_eq!(
+            rewrite_between("SELECT pid FROM e WHERE pid NOT BETWEEN -5 AND 5 AND cpu = 0")
+                .unwrap(),
+            "SELECT pid FROM e WHERE (pid < -5 OR pid > 5) AND cpu = 0"
+        );
+        assert_eq!(
+            rewrite_between("SELECT c FROM e WHERE c between 'a' and 'm'").unwrap(),
+            "SELECT c FROM e WHERE (c >= 'a' AND c <= 'm')"
+        );
+        // BETWEEN in string literals is left as-is
+        let q = "SELECT pid FROM e WHERE comm = 'x BETWEEN y'";
+        assert_eq!(rewrite_between(q).unwrap(), q);
+    }
+
+    #[test]
+    fn rejects_malformed_between() {
+        assert!(rewrite_between("SELECT pid FROM e WHERE pid BETWEEN 1").is_err());
+        assert!(rewrite_between("SELECT pid FROM e WHERE pid BETWEEN 1 OR 2").is_err());
+        assert!(rewrite_between("BETWEEN 1 AND 2").is_err());
+    }
+
+    #[test]
+    fn rewrites_params() {
+        assert_eq!(
+            rewrite_params("SELECT pid FROM e WHERE pid = $target AND comm != '$x'"),
+            format!("SELECT pid FROM e WHERE pid = {PARAM_PREFIX}target AND comm != '$x'")
+        );
+        assert_eq!(rewrite_params("SELECT pid FROM e"), "SELECT pid FROM e");
+    }
+
+    #[test]
+    fn rewrites_event_args() {
+        assert_eq!(
+            rewrite_event_args("SELECT ustack FROM perf/cpu_clock(99) WINDOW tumbling(1s)"),
+            format!("SELECT ustack FROM perf/cpu_clock{EVENT_ARG_SEP}99 WINDOW tumbling(1s)")
+        );
+        // Function calls and non-numeric arguments are left as-is
+        let q = "SELECT count(pid) FROM syscalls/sys_enter_pread64 WHERE comm = 'a/b(1)'";
+        assert_eq!(rewrite_event_args(q), q);
+        assert_eq!(
+            rewrite_event_args("SELECT x FROM a/b(c)"),
+            "SELECT x FROM a/b(c)"
+        );
+    }
+
+    #[test]
+    fn strips_param_defaults() {
+        let (q, defaults) = strip_param_defaults(
+            "SELECT pid FROM e WHERE ret > $min AND pid = $target DEFAULT $min = -1, $target=42",
+        )
+        .unwrap();
+        assert_eq!(q, "SELECT pid FROM e WHERE ret > $min AND pid = $target");
+        assert_eq!(
+            defaults,
+            vec![
+                ("min".to_string(), DataValue::Int64(-1)),
+                ("target".to_string(), DataValue::Int64(42)),
+            ]
+        );
+
+        // Parameters compared to literals are predicates, and are left as-is,
+        // as are string literals and nested selects
+        let q = "SELECT pid FROM e WHERE $target = 5 AND comm != 'default $x = 1'";
+        assert_eq!(strip_param_defaults(q).unwrap(), (q.to_string(), vec![]));
+        let (q, defaults) = strip_param_defaults(
+            "SELECT max(cnt) FROM (SELECT count(*) AS cnt FROM e WHERE pid = $p) default $p = 1",
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "SELECT max(cnt) FROM (SELECT count(*) AS cnt FROM e WHERE pid = $p)"
+        );
+        assert_eq!(defaults, vec![("p".to_string(), DataValue::Int64(1))]);
+    }
+
+    #[test]
+    fn rejects_bad_param_defaults() {
+        for q in [
+            "SELECT pid FROM e WHERE ret > $min DEFAULT $min = 1.5",
+            "SELECT pid FROM e WHERE ret > $min DEFAULT $min = 1, $min = 1",
+            "SELECT pid FROM e WHERE ret > $min DEFAULT min = 1",
+            "SELECT pid FROM e WHERE ret > $min DEFAULT",
+            "SELECT pid FROM e DEFAULT $min = 1 WHERE ret > $min",
+        ] {
+            assert!(parse_param_defaults(q).is_err(), "{q}");
+        }
+    }
+
+    #[test]
+    fn finds_clause_starts() {
+        let q = "SELECT pid FROM (SELECT pid FROM e WHERE pid > 1) WHERE comm = 'group' GROUP BY \
+                 pid WINDOW tumbling(1s)";
+        let clauses = clause_starts(q)
+            .into_iter()
+            .map(|i| &q[i..i + 5])
+            .collect::<Vec<_>>();
+        assert_eq!(clauses, vec!["WHERE", "GROUP", "WINDO"]);
+        assert!(clause_starts("SELECT pid FROM e").is_empty());
+    }
+
+    #[test]
+    fn tokenizes_literals_and_negative_numbers() {
+        let q = "a.b >= -12 'x y'";
+        let tokens = tokenize(q)
+            .into_iter()
+            .map(|(start, end)| &q[start..end])
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, ["a.b", ">", "=", "-12", "'x y'"]);
     }
 }
@@ -1,6 +1,6 @@
-use std::{collections::HashMap, fmt, sync::Arc};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Result};
 use daggy::Walker;
 use nom_sql::{
     Column, ConditionBase, ConditionExpression, FieldDefinitionExpression, FunctionArgument,
@@ -8,11 +8,36 @@ use nom_sql::{
 };
 use rand::distributions::{Alphanumeric, DistString};
 
-use super::operators::{Operator, WindowType};
+use super::{
+    bpf_ops::{
+        agg::{
+            hist_agg, is_overflow_column, overflow_column_field, AVG_SCALE, FIRST, LAST, LHIST,
+            LOG2HIST, MAX_BY, MIN_BY, STDDEV, VARIANCE,
+        },
+        hist::{quantile_column, FP_SCALE},
+        sketch::{
+            approx_count_column, topk_count_column, APPROX_COUNT, APPROX_COUNT_DISTINCT,
+            DEFAULT_CMS_DEPTH, DEFAULT_CMS_WIDTH, DEFAULT_HLL_REGISTERS,
+            DEFAULT_TOPK_COUNTERS_PER_KEY, MAX_CMS_COUNTERS, MAX_CMS_DEPTH, MAX_HLL_REGISTERS,
+            MAX_TOPK, MAX_TOPK_COUNTERS, MIN_HLL_REGISTERS, TOPK,
+        },
+        window::{is_window_bound, window_bound_field, WINDOW_START},
+    },
+    operators::{Operator, WindowType},
+    parser::{NESTED_TABLE, PARAM_PREFIX},
+};
 use crate::{
-    events::{get_event, Event},
-    field::Field,
+    data_types::{CgroupKind, DataType, PathKind, TimeUnit},
+    error::EbqlError,
+    events::{get_event, system::SystemVar, Event},
+    exec::proc_table::{ProcColumn, PROC_FUNC_PREFIX, PROC_KEY, PROC_TABLE},
+    field::{Field, Statistic},
+    histogram::{Buckets, MAX_LINEAR_BUCKETS},
+    paths::{D_PATH, FD_PATH, INODE_PATH},
+    record::DataValue,
     schema::schema::Schema,
+    stack::StackKind,
+    symbols::{KSYM, SYM},
     types::{self, Type},
 };
 
@@ -50,6 +75,21 @@ pub struct BpfPlan {
     /// Aggregations to execute.
     pub aggs: Vec<Operator>,
 
+    /// Query parameters, typed by the fields they are compared to
+    pub params: Vec<types::Field>,
+    /// Initial values of parameters, written before the program is attached.
+    /// Parameters without one start at zero
+    pub param_defaults: Vec<(String, DataValue)>,
+    /// Columns whose addresses are symbolized in user space, by the address
+    /// space of their symbols
+    pub symbols: Vec<(String, StackKind)>,
+    /// Columns resolved into file paths in user space, with the column each is
+    /// resolved within (i.e. the process of an fd, or the device of an inode)
+    pub paths: Vec<(String, String, PathKind)>,
+    /// Columns of cgroup ids resolved in user space, by what they're resolved
+    /// into
+    pub cgroups: Vec<(String, CgroupKind)>,
+
     // Whether is distinct
     pub distinct: bool,
     // If a distinct join occurs, get the two input schemas and fields on which to join
@@ -89,6 +129,11 @@ impl fmt::Debug for BpfPlan {
                     .collect::<Vec<_>>()
                     .join(", "),
             )
+            .field("params", &self.params)
+            .field("param_defaults", &self.param_defaults)
+            .field("symbols", &self.symbols)
+            .field("paths", &self.paths)
+            .field("cgroups", &self.cgroups)
             .field("distinct", &self.distinct)
             .field("distinct_join", &self.distinct_join)
             .finish()
@@ -106,6 +151,11 @@ impl BpfPlan {
             maps: Vec::new(),
             group_by: Vec::new(),
             aggs: Vec::new(),
+            params: Vec::new(),
+            param_defaults: Vec::new(),
+            symbols: Vec::new(),
+            paths: Vec::new(),
+            cgroups: Vec::new(),
             distinct: false,
             distinct_join: None,
         }
@@ -119,12 +169,417 @@ pub struct BpfJoin {
     pub fields: Vec<Field>,
 }
 
+/// Plan executed in user space over the output stream of a BPF plan (e.g. the
+/// outer query of a nested select).
+#[derive(Clone)]
+pub struct UserPlan {
+    /// Schema of the input stream, i.e. the nested query's output with its
+    /// aliases applied
+    pub input: Arc<Schema>,
+    /// Output schema
+    pub schema: Arc<Schema>,
+
+    /// Window expression. Time windows are made of the windows of the input,
+    /// each assigned by its start (see [`WINDOW_START`]); without a window,
+    /// aggregations are computed over each input batch.
+    pub window: Option<WindowType>,
+    /// Input column holding the start of each record's window, for time
+    /// windows
+    pub window_start: Option<String>,
+    /// Filters applied to input records
+    pub filters: Option<Operator>,
+    /// Fields on which to group by
+    pub group_by: Vec<Field>,
+    /// Aggregations to execute, in the order of their output fields
+    pub aggs: Vec<Operator>,
+    /// Output fields looked up from the `proc` table, by name, with the
+    /// column holding their pid
+    pub procs: Vec<(String, String, ProcColumn)>,
+}
+
+impl UserPlan {
+    /// Constructs a user-space plan labeling the records of the input schema
+    /// with the `proc` columns.
+    fn with_procs(input: Arc<Schema>, procs: Vec<(Field, String, ProcColumn)>) -> UserPlan {
+        let fields = input
+            .fields
+            .iter()
+            .map(|f| f.as_ref().clone())
+            .chain(procs.iter().map(|(f, ..)| f.clone()))
+            .collect::<Vec<_>>();
+        UserPlan {
+            input,
+            schema: Arc::new(Schema::new(
+                Some(format!(
+                    "select_{}",
+                    Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
+                )),
+                fields.into(),
+            )),
+            window: None,
+            window_start: None,
+            filters: None,
+            group_by: Vec::new(),
+            aggs: Vec::new(),
+            procs: procs
+                .into_iter()
+                .map(|(f, pid, col)| (f.name, pid, col))
+                .collect(),
+        }
+    }
+
+    /// Constructs a user-space plan from a select over [`NESTED_TABLE`], whose
+    /// records are in the input schema.
+    pub fn from_select(s: SelectStatement, input: Arc<Schema>) -> Result<UserPlan> {
+        if s.tables.len() != 1 || s.tables[0].name != NESTED_TABLE {
+            bail!(EbqlError::unsupported(
+                tables_fragment(&s),
+                "nested selects can only be combined with other tables through joins"
+            ));
+        }
+        let joined_proc = joins_proc(&s)?;
+        if let (false, Some(join)) = (joined_proc, s.join.first()) {
+            bail!(EbqlError::unsupported(
+                join,
+                "joins over nested selects are not supported"
+            ));
+        }
+        let get_field = |c: &Column| -> Result<Field> {
+            match input.fields.iter().find(|f| f.name == c.name) {
+                Some(f) => Ok(f.as_ref().clone()),
+                None => bail!(EbqlError::bind(c, "nested select does not output column")),
+            }
+        };
+
+        let mut plan = UserPlan {
+            input: input.clone(),
+            schema: Arc::new(Schema::default()),
+            window: None,
+            window_start: None,
+            filters: None,
+            group_by: Vec::new(),
+            aggs: Vec::new(),
+            procs: Vec::new(),
+        };
+        let mut output_fields = Vec::new();
+
+        // Parse window
+        if let Some(window) = s.window {
+            plan.window = Some(get_window(window.wt)?);
+        }
+
+        // Parse group by clause; group by fields are output first
+        if let Some(gb) = s.group_by {
+            for c in &gb.columns {
+                let f = get_field(c)?;
+                output_fields.push(f.clone());
+                plan.group_by.push(f);
+            }
+        }
+
+        // Parse field selections and their aggregates
+        let selected = fields_fragment(&s.fields);
+        for f_def in s.fields {
+            match f_def {
+                FieldDefinitionExpression::All => {
+                    input.fields.iter().for_each(|f| {
+                        if !output_fields.contains(f.as_ref()) {
+                            output_fields.push(f.as_ref().clone());
+                        }
+                    });
+                }
+                FieldDefinitionExpression::Col(c) => {
+                    if let Some((col, pid)) = proc_column(&c, joined_proc)? {
+                        let Some(pid_f) = input.fields.iter().find(|f| f.name == pid) else {
+                            bail!(EbqlError::bind(c, "nested select does not output column"));
+                        };
+                        if !plan.group_by.is_empty() && !plan.group_by.contains(pid_f) {
+                            bail!(EbqlError::bind(
+                                c,
+                                "process metadata must be looked up by a group by key"
+                            ));
+                        }
+                        let f =
+                            Field::new(c.alias.as_deref().unwrap_or(col.name()), col.data_type());
+                        plan.procs.push((f.name.clone(), pid, col));
+                        if !output_fields.contains(&f) {
+                            output_fields.push(f);
+                        }
+                        continue;
+                    }
+                    let (f, op) = match &c.function {
+                        Some(func) => get_user_agg(func, &get_field)?,
+                        None => (get_field(&c)?, None),
+                    };
+                    let f = match &c.alias {
+                        Some(alias) => Field::new(alias, f.data_type),
+                        None => f,
+                    };
+                    if let Some(op) = op {
+                        plan.aggs.push(op);
+                    } else if !plan.group_by.is_empty() && !plan.group_by.contains(&f) {
+                        bail!(EbqlError::bind(
+                            c,
+                            "column must appear in the group by clause"
+                        ));
+                    }
+                    if !output_fields.contains(&f) {
+                        output_fields.push(f);
+                    }
+                }
+                _ => {
+                    bail!(EbqlError::unsupported(
+                        f_def,
+                        "field definition not supported over nested selects"
+                    ))
+                }
+            }
+        }
+        if !plan.aggs.is_empty()
+            && output_fields.len() != plan.group_by.len() + plan.aggs.len() + plan.procs.len()
+        {
+            bail!(EbqlError::unsupported(
+                &selected,
+                "only group by keys and distinct aggregations can be selected together"
+            ));
+        }
+        if plan.aggs.is_empty() && !plan.group_by.is_empty() {
+            bail!(EbqlError::unsupported(
+                &selected,
+                "group by over a nested select requires an aggregation"
+            ));
+        }
+        if let (true, Some(window)) = (plan.aggs.is_empty(), &plan.window) {
+            bail!(EbqlError::unsupported(
+                window,
+                "windows over a nested select require an aggregation"
+            ));
+        }
+
+        // Parse where clause
+        if let Some(ce) = s.where_clause {
+            for col in condition_columns(&ce) {
+                get_field(col)?;
+            }
+            plan.filters = Some(Operator::Filter(ce));
+        }
+
+        plan.schema = Arc::new(Schema::new(
+            Some(format!(
+                "select_{}",
+                Alphanumeric.sample_string(&mut rand::thread_rng(), 5)
+            )),
+            output_fields.into(),
+        ));
+
+        Ok(plan)
+    }
+}
+
+/// Gets the output field of an aggregation over a nested select, and the
+/// aggregation operator.
+fn get_user_agg(
+    func: &FunctionExpression,
+    get_field: &impl Fn(&Column) -> Result<Field>,
+) -> Result<(Field, Option<Operator>)> {
+    let col = match func {
+        FunctionExpression::CountStar => {
+            return Ok((
+                Field::new("count_", DataType::UInt64),
+                Some(Operator::Count(None)),
+            ));
+        }
+        FunctionExpression::Avg(FunctionArgument::Column(col), false)
+        | FunctionExpression::Count(FunctionArgument::Column(col), false)
+        | FunctionExpression::Sum(FunctionArgument::Column(col), false)
+        | FunctionExpression::Max(FunctionArgument::Column(col))
+        | FunctionExpression::Min(FunctionArgument::Column(col)) => col,
+        _ => {
+            bail!(EbqlError::unsupported(
+                func,
+                "aggregation not supported over nested selects"
+            ))
+        }
+    };
+    if col.function.is_some() {
+        bail!(EbqlError::unsupported(func, "nested aggs not supported"));
+    }
+    let f = get_field(col)?;
+    // Sums keep the signedness (or floatness) of their input
+    let acc_type = if f.data_type.is_floating() {
+        DataType::Float64
+    } else if f.data_type.is_signed_integer() {
+        DataType::Int64
+    } else {
+        DataType::UInt64
+    };
+    let (prefix, data_type, op) = match func {
+        FunctionExpression::Avg(..) => {
+            ("avg", DataType::Float64, Operator::Average(f.name.clone()))
+        }
+        FunctionExpression::Count(..) => {
+            (
+                "count",
+                DataType::UInt64,
+                Operator::Count(Some(f.name.clone())),
+            )
+        }
+        FunctionExpression::Sum(..) => ("sum", acc_type, Operator::Sum(f.name.clone())),
+        FunctionExpression::Max(..) => ("max", f.data_type.clone(), Operator::Max(f.name.clone())),
+        _ => ("min", f.data_type.clone(), Operator::Min(f.name.clone())),
+    };
+    if !data_type.is_numeric() && !matches!(op, Operator::Count(_)) {
+        bail!(EbqlError::bind(
+            func,
+            "aggregation requires a numeric column"
+        ));
+    }
+
+    Ok((
+        Field::new(format!("{prefix}_{}", f.name), data_type),
+        Some(op),
+    ))
+}
+
 pub struct PhysicalPlan {
     /// Physical plans for each event.
     pub event_plans: Vec<BpfPlan>,
+    /// Plan to execute in user space over the output of the event plans, if
+    /// the query selects from a nested select (or looks up process metadata).
+    pub user_plan: Option<UserPlan>,
 }
 
 impl PhysicalPlan {
+    /// Gets the plan of the program the query runs in the kernel (i.e. of its
+    /// first event).
+    pub fn bpf_plan(&self) -> Result<&BpfPlan> {
+        self.event_plans.first().ok_or_else(|| {
+            anyhow!(EbqlError::codegen(
+                self.user_plan
+                    .as_ref()
+                    .map_or("", |p| p.schema.name.as_str()),
+                "query has no event plan"
+            ))
+        })
+    }
+
+    /// Constructs a physical plan from a select, and the select nested in its
+    /// FROM clause (if any). The nested select runs in BPF, while the outer
+    /// select runs in user space over the nested select's output.
+    pub fn from_nested(
+        s: SelectStatement,
+        nested: Option<SelectStatement>,
+    ) -> Result<PhysicalPlan> {
+        let nested = match nested {
+            Some(nested) => nested,
+            None => return Self::from_select(s),
+        };
+        let aliases = get_output_aliases(&nested)?;
+        let join = nested.join.first().cloned();
+        let mut plan = Self::from_select(nested)?;
+        if plan.user_plan.is_some() {
+            bail!(EbqlError::unsupported(
+                PROC_TABLE,
+                "process metadata can only be looked up by the outer select"
+            ));
+        }
+        if let Some(join) = join {
+            bail!(EbqlError::unsupported(
+                join,
+                "joins within nested selects are not supported"
+            ));
+        }
+
+        // Outer time windows are made of the nested select's windows, assigned
+        // by their start, which the nested select outputs even if unselected
+        if let Some(window) = &s.window {
+            let window = get_window(window.wt.clone())?;
+            if let WindowType::Time(..) = window {
+                let Some(bpf_plan) = plan.event_plans.first_mut() else {
+                    bail!(EbqlError::codegen(NESTED_TABLE, "query has no event plan"));
+                };
+                if !matches!(bpf_plan.window, Some(WindowType::Time(..))) {
+                    bail!(EbqlError::unsupported(
+                        window,
+                        "time windows over nested selects require a time windowed nested select"
+                    ));
+                }
+                if !bpf_plan
+                    .schema
+                    .fields
+                    .iter()
+                    .any(|f| f.name == WINDOW_START)
+                {
+                    let start = output_field(&window_bound_field(WINDOW_START), bpf_plan)?;
+                    let fields = bpf_plan
+                        .schema
+                        .fields
+                        .iter()
+                        .map(|f| f.as_ref().clone())
+                        .chain([start])
+                        .collect::<Vec<_>>();
+                    bpf_plan.schema = Arc::new(Schema::new(
+                        Some(bpf_plan.schema.name.clone()),
+                        fields.into(),
+                    ));
+                }
+            }
+        }
+
+        // The outer query refers to the nested query's output by its aliases
+        let schema = &plan.bpf_plan()?.schema;
+        let input = Schema::new(
+            Some(schema.name.clone()),
+            schema
+                .fields
+                .iter()
+                .map(|f| {
+                    match aliases.get(&f.name) {
+                        Some(alias) => Field::new(alias, f.data_type.clone()),
+                        None => f.as_ref().clone(),
+                    }
+                })
+                .collect(),
+        );
+        let mut user_plan = UserPlan::from_select(s, Arc::new(input))?;
+        if let Some(WindowType::Time(..)) = user_plan.window {
+            user_plan.window_start = Some(
+                aliases
+                    .get(WINDOW_START)
+                    .cloned()
+                    .unwrap_or_else(|| WINDOW_START.into()),
+            );
+        }
+        plan.user_plan = Some(user_plan);
+
+        Ok(plan)
+    }
+
+    /// Sets the default values of the plan's parameters (see
+    /// [`parse_param_defaults`](super::parser::parse_param_defaults)), checking
+    /// that each names a parameter of the plan and fits its type.
+    pub fn with_param_defaults(mut self, defaults: Vec<(String, DataValue)>) -> Result<Self> {
+        for (name, value) in defaults {
+            let mut found = false;
+            for plan in &mut self.event_plans {
+                if let Some(param) = plan.params.iter().find(|p| p._name == name) {
+                    if let Err(e) = param._type.encode(&value) {
+                        bail!(EbqlError::bind(format!("${name} = {value}"), e.to_string()));
+                    }
+                    plan.param_defaults.push((name.clone(), value.clone()));
+                    found = true;
+                }
+            }
+            if !found {
+                bail!(EbqlError::bind(
+                    format!("${name}"),
+                    "parameter is not compared to any field"
+                ));
+            }
+        }
+        Ok(self)
+    }
+
     pub fn from_select(s: SelectStatement) -> Result<PhysicalPlan> {
         // Generate query name
         let query_name = format!(
@@ -133,13 +588,19 @@ impl PhysicalPlan {
         );
         // Attempts to get event name from table
         if s.tables.len() != 1 {
-            unimplemented!("Only one table selects are supported")
+            bail!(EbqlError::unsupported(
+                tables_fragment(&s),
+                "only single-table selects are supported"
+            ));
         }
-        let e =
-            get_event(&s.tables[0].name).context("Select table does not correspond to an event")?;
+        let e = match get_event(&s.tables[0].name) {
+            Some(e) => e,
+            None => bail!(EbqlError::bind(&s.tables[0], "table is not a known event")),
+        };
 
         // Construct BPF plan
         let mut bpf_plan = BpfPlan::new(&e);
+        let joined_proc = joins_proc(&s)?;
 
         // Mark whether distinct
         bpf_plan.distinct = s.distinct;
@@ -147,34 +608,32 @@ impl PhysicalPlan {
         // Parse fields to project, and see which aggregations to use
         let mut project_fields = Vec::new();
         let mut output_fields = Vec::new();
+        // Window bound columns, which are filled in by the window rather than
+        // projected from the event
+        let mut bound_fields = Vec::new();
+        // Overflow columns, which are filled in by the aggregations
+        let mut overflow_fields = Vec::new();
+        // Process metadata, which is looked up in user space by the pid
+        let mut proc_fields = Vec::new();
 
         // Parse window
         if let Some(window) = s.window {
-            match window.wt {
-                nom_sql::WindowType::Time(ival, step) => {
-                    if ival != step {
-                        unimplemented!("non-tumbling windows not yet supported")
-                    }
-                    bpf_plan.window = Some(WindowType::Time(ival, step));
-                    project_fields.push(e.get_arg("time").unwrap());
-                }
-                nom_sql::WindowType::Count(count, step) => {
-                    if count != step {
-                        unimplemented!("non-tumbling windows not yet supported")
-                    }
-                    bpf_plan.window = Some(WindowType::Count(count as usize, step as usize));
-                }
+            let window = get_window(window.wt)?;
+            if let WindowType::Time(_, _) = window {
+                project_fields.push(e.get_arg("time")?);
             }
+            bpf_plan.window = Some(window);
         }
 
         // Parse group by clause
         if let Some(gb) = s.group_by {
-            // Get column names
+            // Get column names; cgroups are grouped by their ids
             let cols = gb
                 .columns
                 .iter()
-                .map(|c| c.name.as_str())
-                .collect::<Vec<_>>();
+                .map(|c| Ok(cgroup_column(c)?.map_or(c.name.clone(), |(col, _)| col)))
+                .collect::<Result<Vec<_>>>()?;
+            let cols = cols.iter().map(String::as_str).collect::<Vec<_>>();
             // Get fields associated with columns
             let fields = e.get_args(&cols)?;
             // Add them to be projected *and* outputted
@@ -204,9 +663,71 @@ impl PhysicalPlan {
                     });
                 }
                 FieldDefinitionExpression::AllInTable(_) => {
-                    unimplemented!("cannot select from tables other than own event")
+                    bail!(EbqlError::unsupported(
+                        f_def,
+                        "cannot select from tables other than own event"
+                    ))
+                }
+                FieldDefinitionExpression::Col(c)
+                    if c.function.is_none() && is_window_bound(&c.name) =>
+                {
+                    if bpf_plan.window.is_none() {
+                        bail!(EbqlError::bind(
+                            &c,
+                            "window bounds can only be selected with a window clause"
+                        ));
+                    }
+                    let f = window_bound_field(&c.name);
+                    if !bound_fields.contains(&f) {
+                        bound_fields.push(f);
+                    }
+                }
+                FieldDefinitionExpression::Col(c)
+                    if c.function.is_none() && is_overflow_column(&c.name) =>
+                {
+                    let f = overflow_column_field(&c.name);
+                    if !overflow_fields.contains(&f) {
+                        overflow_fields.push(f);
+                    }
                 }
                 FieldDefinitionExpression::Col(c) => {
+                    if let Some((col, pid)) = proc_column(&c, joined_proc)? {
+                        let pid = e.get_arg(&pid)?;
+                        if !project_fields.contains(&pid) {
+                            project_fields.push(pid.clone());
+                        }
+                        let f =
+                            Field::new(c.alias.as_deref().unwrap_or(col.name()), col.data_type());
+                        proc_fields.push((f, pid._name, col));
+                        continue;
+                    }
+                    if let Some((col, kind)) = cgroup_column(&c)? {
+                        let f = get_cgroup_id(&c, &col, &e)?;
+                        match bpf_plan.cgroups.iter().find(|(c, _)| *c == col) {
+                            Some((_, k)) if *k != kind => {
+                                bail!(EbqlError::unsupported(
+                                    &c,
+                                    format!("{col} is already resolved by {}", k.func())
+                                ))
+                            }
+                            Some(_) => (),
+                            None => bpf_plan.cgroups.push((col, kind)),
+                        }
+                        if !project_fields.contains(&f) {
+                            project_fields.push(f);
+                        }
+                        continue;
+                    }
+                    if let Some(symbol) = symbol_column(&c, &e)? {
+                        if !bpf_plan.symbols.contains(&symbol) {
+                            bpf_plan.symbols.push(symbol);
+                        }
+                    }
+                    if let Some(path) = path_column(&c, &e)? {
+                        if !bpf_plan.paths.contains(&path) {
+                            bpf_plan.paths.push(path);
+                        }
+                    }
                     let (proj_f, out_f, op, d) = get_column(c, &e)?;
                     bpf_plan.distinct = bpf_plan.distinct || d;
                     if let Some(op) = op {
@@ -223,7 +744,6 @@ impl PhysicalPlan {
                         }
                     });
                 }
-                _ => unimplemented!("field definition expressions not supported"),
                 // FieldDefinitionExpression::Value(fve) => {
                 //     match fve {
                 //         Arithmetic(ae) => {
@@ -253,8 +773,63 @@ impl PhysicalPlan {
                 //         Literal(_) => unimplemented!("literal selections not supported"),
                 //     }
                 // }
+                _ => {
+                    bail!(EbqlError::unsupported(
+                        f_def,
+                        "field definition expressions not supported"
+                    ))
+                }
             };
         }
+        if let (Some(f), true) = (overflow_fields.first(), bpf_plan.aggs.is_empty()) {
+            bail!(EbqlError::bind(
+                &f._name,
+                "overflow columns can only be selected with an aggregation"
+            ));
+        }
+        // Sketches are kept over whole windows; approximate counts group by
+        // their column's values already, so may only be grouped by it
+        let mut grouped_by_sketch = false;
+        for op in &bpf_plan.aggs {
+            match op {
+                Operator::ApproxCountDistinct(..) if !bpf_plan.group_by.is_empty() => {
+                    bail!(EbqlError::unsupported(
+                        op,
+                        "approximate distinct counts can't be grouped"
+                    ))
+                }
+                Operator::ApproxCount(..) if bpf_plan.aggs.len() > 1 => {
+                    bail!(EbqlError::unsupported(
+                        op,
+                        "approximate counts can't be combined with other aggregations"
+                    ))
+                }
+                Operator::ApproxCount(col, ..) => {
+                    match bpf_plan.group_by.as_slice() {
+                        [] => (),
+                        [f] if &f._name == col => grouped_by_sketch = true,
+                        _ => {
+                            bail!(EbqlError::bind(
+                                op,
+                                "approximate counts can only be grouped by their column"
+                            ))
+                        }
+                    }
+                }
+                // Top-k emits its own rows
+                Operator::TopK(..) if !bpf_plan.group_by.is_empty() || bpf_plan.aggs.len() > 1 => {
+                    bail!(EbqlError::unsupported(
+                        op,
+                        "top-k can't be grouped, or combined with other aggregations"
+                    ))
+                }
+                _ => (),
+            }
+        }
+        if grouped_by_sketch {
+            bpf_plan.group_by.clear();
+        }
+
         // Parse where clause (i.e. filters)
         if let Some(ce) = s.where_clause {
             // Get all columns and associated fields
@@ -263,37 +838,50 @@ impl PhysicalPlan {
                     project_fields.push(f.clone());
                 }
             });
+            bpf_plan.params = get_params(&ce, &e)?;
             // Create filter op out of it
             bpf_plan.filters = Some(Operator::Filter(ce));
         }
 
         let mut plan = PhysicalPlan {
             event_plans: Vec::new(),
+            user_plan: None,
         };
 
         // Parse join clause if it exists
         match s.join.len() {
-            2.. => unimplemented!("only one join allowed"),
+            2.. => bail!(EbqlError::unsupported(&s.join[1], "only one join allowed")),
+            // The proc table is joined in user space
+            1 if joined_proc => (),
             1 => {
                 let join = &s.join[0];
                 // Only support joins (i.e. left joins)
                 if !matches! { join.operator, JoinOperator::Join } {
-                    unimplemented!("Join {} not supported", join.operator)
+                    bail!(EbqlError::unsupported(
+                        join,
+                        "only left joins are supported"
+                    ))
                 }
                 // Only support joins on another select statement for now
                 if !matches! { join.right, JoinRightSide::NestedSelect(_, _)} {
-                    unimplemented!("Join on {} not supported", join.right)
+                    bail!(EbqlError::unsupported(
+                        join,
+                        "joins are only supported on nested selects"
+                    ))
                 }
                 if let JoinRightSide::NestedSelect(select, _) = &join.right {
                     // Parse inside nested select
                     let join_bpf_plan = Self::from_select(*select.clone())?;
                     // TODO: migrate this out so it's not this garbage
-                    let mut join_bpf_plan = join_bpf_plan.event_plans[0].clone();
+                    let mut join_bpf_plan = join_bpf_plan.bpf_plan()?.clone();
 
                     // Get join condition
                     let join_fields = match &join.constraint {
                         JoinConstraint::On(_) => {
-                            unimplemented!("Join filter {} not yet supported", &join.constraint)
+                            bail!(EbqlError::unsupported(
+                                &join.constraint,
+                                "join filters are not supported"
+                            ))
                         }
                         JoinConstraint::Using(cols) => {
                             // Get column names
@@ -301,8 +889,8 @@ impl PhysicalPlan {
                             // Get fields associated with columns
                             e.get_args(&cols)?
                                 .iter()
-                                .map(|f| Field::from(f))
-                                .collect::<Vec<_>>()
+                                .map(schema_field)
+                                .collect::<Result<Vec<_>>>()?
                         }
                     };
                     bpf_plan.distinct_join = Some(BpfJoin {
@@ -329,23 +917,83 @@ impl PhysicalPlan {
             bpf_plan.schema = Arc::new(Schema::new(
                 Some(query_name),
                 output_fields
-                    .clone()
-                    .into_iter()
-                    .map(|f| Field::from(f))
-                    .collect(),
+                    .iter()
+                    .chain(&bound_fields)
+                    .chain(&overflow_fields)
+                    .map(|f| output_field(f, &bpf_plan))
+                    .collect::<Result<_>>()?,
             ));
         } else {
             bpf_plan.schema = Arc::new(Schema::new(
                 Some(query_name),
                 bpf_plan
                     .projects
-                    .clone()
                     .iter()
-                    .map(|f| Field::from(f))
-                    .collect(),
+                    .chain(&bound_fields)
+                    .map(|f| output_field(f, &bpf_plan))
+                    .collect::<Result<_>>()?,
             ));
         }
 
+        // Symbols are resolved from the output, so their addresses (and, for
+        // user addresses, the process) must be output
+        let out = |name: &str| bpf_plan.schema.fields.iter().any(|f| f.name == name);
+        for (col, kind) in &bpf_plan.symbols {
+            let func = match kind {
+                StackKind::Kernel => format!("{KSYM}({col})"),
+                StackKind::User => format!("{SYM}({col})"),
+            };
+            if !out(col) {
+                bail!(EbqlError::bind(
+                    func,
+                    "symbolized columns must be grouped by when aggregating"
+                ));
+            }
+            if *kind == StackKind::User && !out(&SystemVar::PID.to_field()._name) {
+                bail!(EbqlError::bind(
+                    func,
+                    "user addresses are symbolized by their process, so pid must be grouped by \
+                     when aggregating"
+                ));
+            }
+        }
+        // As are paths, and the columns they're resolved within
+        for (col, key, kind) in &bpf_plan.paths {
+            if !out(col) || !out(key) {
+                let func = match kind {
+                    PathKind::Fd => format!("{FD_PATH}({key}, {col})"),
+                    PathKind::Inode => format!("{INODE_PATH}({key}, {col})"),
+                };
+                bail!(EbqlError::bind(
+                    func,
+                    "path columns must be grouped by, with the columns they're resolved within, \
+                     when aggregating"
+                ));
+            }
+        }
+        // As are cgroup ids
+        for (col, kind) in &bpf_plan.cgroups {
+            if !out(col) {
+                bail!(EbqlError::bind(
+                    format!("{}({col})", kind.func()),
+                    "cgroups must be grouped by when aggregating"
+                ));
+            }
+        }
+        // As are the pids of process metadata
+        for (_, pid, col) in &proc_fields {
+            if !out(pid) {
+                bail!(EbqlError::bind(
+                    format!("{PROC_FUNC_PREFIX}{col}({pid})"),
+                    "process metadata is looked up by pid, which must be grouped by when \
+                     aggregating"
+                ));
+            }
+        }
+        if !proc_fields.is_empty() {
+            plan.user_plan = Some(UserPlan::with_procs(bpf_plan.schema.clone(), proc_fields));
+        }
+
         plan.event_plans.push(bpf_plan);
 
         Ok(plan)
@@ -416,7 +1064,7 @@ fn get_column(
                 if let FunctionArgument::Column(col) = col {
                     // Assert that no nested function computations
                     if let Some(_) = col.function {
-                        unimplemented!("nested aggs not supported")
+                        bail!(EbqlError::unsupported(&func, "nested aggs not supported"))
                     }
                     // Add two fields: avg_{field}, and avg_{field}_count
                     let f_avg = types::Field::new(format!("avg_{}", &col.name), Type::U64);
@@ -429,14 +1077,14 @@ fn get_column(
                         d,
                     ));
                 } else {
-                    unimplemented!("case when not supported")
+                    bail!(EbqlError::unsupported(&func, "case when not supported"))
                 }
             }
             FunctionExpression::Count(ref col, d) => {
                 if let FunctionArgument::Column(col) = col {
                     // Assert that no nested function computations
                     if let Some(_) = col.function {
-                        unimplemented!("nested aggs not supported")
+                        bail!(EbqlError::unsupported(&func, "nested aggs not supported"))
                     }
                     // Create new field
                     return Ok((
@@ -446,7 +1094,7 @@ fn get_column(
                         d,
                     ));
                 } else {
-                    unimplemented!("case when not supported")
+                    bail!(EbqlError::unsupported(&func, "case when not supported"))
                 }
             }
             FunctionExpression::CountStar => {
@@ -462,7 +1110,7 @@ fn get_column(
                 if let FunctionArgument::Column(col) = col {
                     // Assert that no nested function computations
                     if let Some(_) = col.function {
-                        unimplemented!("nested aggs not supported")
+                        bail!(EbqlError::unsupported(&func, "nested aggs not supported"))
                     }
                     // Add new field
                     return Ok((
@@ -472,14 +1120,14 @@ fn get_column(
                         d,
                     ));
                 } else {
-                    unimplemented!("case when not supported")
+                    bail!(EbqlError::unsupported(&func, "case when not supported"))
                 }
             }
             FunctionExpression::Max(ref col) => {
                 if let FunctionArgument::Column(col) = col {
                     // Assert that no nested function computations
                     if let Some(_) = col.function {
-                        unimplemented!("nested aggs not supported")
+                        bail!(EbqlError::unsupported(&func, "nested aggs not supported"))
                     }
                     // Update distinct value
                     return Ok((
@@ -489,14 +1137,14 @@ fn get_column(
                         false,
                     ));
                 } else {
-                    unimplemented!("case when not supported")
+                    bail!(EbqlError::unsupported(&func, "case when not supported"))
                 }
             }
             FunctionExpression::Min(ref col) => {
                 if let FunctionArgument::Column(col) = col {
                     // Assert that no nested function computations
                     if let Some(_) = col.function {
-                        unimplemented!("nested aggs not supported")
+                        bail!(EbqlError::unsupported(&func, "nested aggs not supported"))
                     }
                     // Update distinct value
                     return Ok((
@@ -506,44 +1154,879 @@ fn get_column(
                         false,
                     ));
                 } else {
-                    unimplemented!("case when not supported")
+                    bail!(EbqlError::unsupported(&func, "case when not supported"))
                 }
             }
             // TODO: add histogram to grammar
-            FunctionExpression::GroupConcat(_, _) => unimplemented!("group concat not supported"),
-            FunctionExpression::Generic(_, _) => unimplemented!("support generic function calls"),
+            FunctionExpression::GroupConcat(_, _) => {
+                bail!(EbqlError::unsupported(&func, "group concat not supported"))
+            }
+            FunctionExpression::Generic(ref name, ref args) => {
+                match name.to_lowercase().as_str() {
+                    name @ (VARIANCE | STDDEV | MIN_BY | MAX_BY | FIRST | LAST) => {
+                        let (proj_f, out_f, op) = get_stat(&func, name, &args.arguments, e)?;
+                        return Ok((proj_f, vec![out_f], Some(op), false));
+                    }
+                    name @ (LHIST | LOG2HIST) => {
+                        let (proj_f, out_f, op) = get_hist(&func, name, &args.arguments, e)?;
+                        return Ok((vec![proj_f], vec![out_f], Some(op), false));
+                    }
+                    // Symbolized and path columns are output under their own names
+                    name @ (SYM | KSYM) => {
+                        let (proj_f, ..) = get_symbol(&func, name, &args.arguments, e)?;
+                        return Ok((proj_f, vec![], None, false));
+                    }
+                    name @ (FD_PATH | INODE_PATH) => {
+                        let (proj_f, ..) = get_path(&func, name, &args.arguments, e)?;
+                        return Ok((proj_f, vec![], None, false));
+                    }
+                    D_PATH => {
+                        bail!(EbqlError::unsupported(
+                            &func,
+                            format!(
+                                "{} programs can't resolve paths in the kernel; use {FD_PATH} \
+                                 or {INODE_PATH}",
+                                e.program_type().section_name()
+                            )
+                        ))
+                    }
+                    _ => {
+                        let (proj_f, out_f, op) = get_sketch(&func, name, &args.arguments, e)?;
+                        return Ok((vec![proj_f], out_f, Some(op), false));
+                    }
+                }
+            }
         }
     } else {
         Ok((vec![e.get_arg(&c.name)?], vec![], None, false))
     }
 }
 
-/// Gets the filters from a condition expression.
-/// TODO: parse deeper trees if possible
+/// Gets the projected fields, symbolized column and address space of a
+/// symbolization, i.e. `ksym(col)` or `sym(col)`. User addresses are
+/// symbolized within the row's process, so `sym` also projects its pid.
+fn get_symbol(
+    func: &FunctionExpression,
+    name: &str,
+    args: &[FunctionArgument],
+    e: &Arc<dyn Event>,
+) -> Result<(Vec<types::Field>, String, StackKind)> {
+    let col = match args {
+        [FunctionArgument::Column(col)] if col.function.is_none() => col,
+        [FunctionArgument::Column(_)] => {
+            bail!(EbqlError::unsupported(
+                func,
+                "nested functions not supported"
+            ))
+        }
+        _ => bail!(EbqlError::bind(func, "function requires a single column")),
+    };
+    let f = e.get_arg(&col.name)?;
+    if !matches!(f._type, Type::Pointer(_) | Type::U64 | Type::S64) {
+        bail!(EbqlError::bind(
+            func,
+            "only pointer and 64-bit integer columns can be symbolized"
+        ));
+    }
+    let mut proj_f = vec![f];
+    let kind = match name {
+        KSYM => StackKind::Kernel,
+        _ => {
+            proj_f.push(e.get_arg(&SystemVar::PID.to_field()._name)?);
+            StackKind::User
+        }
+    };
+    Ok((proj_f, col.name.clone(), kind))
+}
+
+/// Gets the symbolized column and address space of a column, if it's a
+/// symbolization.
+fn symbol_column(c: &Column, e: &Arc<dyn Event>) -> Result<Option<(String, StackKind)>> {
+    let Some(func) = &c.function else {
+        return Ok(None);
+    };
+    let FunctionExpression::Generic(name, args) = func.as_ref() else {
+        return Ok(None);
+    };
+    match name.to_lowercase().as_str() {
+        name @ (SYM | KSYM) => {
+            let (_, col, kind) = get_symbol(func, name, &args.arguments, e)?;
+            Ok(Some((col, kind)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Gets the projected fields, path column, key column and path kind of a path
+/// resolution, i.e. `fd_path(pid, fd)` or `inode_path(s_dev, i_ino)`.
+fn get_path(
+    func: &FunctionExpression,
+    name: &str,
+    args: &[FunctionArgument],
+    e: &Arc<dyn Event>,
+) -> Result<(Vec<types::Field>, String, String, PathKind)> {
+    let (key, col) = match args {
+        [FunctionArgument::Column(key), FunctionArgument::Column(col)]
+            if key.function.is_none() && col.function.is_none() =>
+        {
+            (key, col)
+        }
+        [FunctionArgument::Column(_), FunctionArgument::Column(_)] => {
+            bail!(EbqlError::unsupported(
+                func,
+                "nested functions not supported"
+            ))
+        }
+        _ => {
+            bail!(EbqlError::bind(
+                func,
+                "function requires the column to resolve within, and the column to resolve"
+            ))
+        }
+    };
+    let proj_f = e.get_args(&[&key.name, &col.name])?;
+    if proj_f.iter().any(|f| {
+        matches!(
+            f._type,
+            Type::String(_) | Type::Pointer(_) | Type::Struct(_, _)
+        )
+    }) {
+        bail!(EbqlError::bind(
+            func,
+            "paths can only be resolved from integer columns"
+        ));
+    }
+    let kind = match name {
+        FD_PATH => PathKind::Fd,
+        _ => PathKind::Inode,
+    };
+    Ok((proj_f, col.name.clone(), key.name.clone(), kind))
+}
+
+/// Gets the path column, key column and path kind of a column, if it's a path
+/// resolution.
+fn path_column(c: &Column, e: &Arc<dyn Event>) -> Result<Option<(String, String, PathKind)>> {
+    let Some(func) = &c.function else {
+        return Ok(None);
+    };
+    let FunctionExpression::Generic(name, args) = func.as_ref() else {
+        return Ok(None);
+    };
+    match name.to_lowercase().as_str() {
+        name @ (FD_PATH | INODE_PATH) => {
+            let (_, col, key, kind) = get_path(func, name, &args.arguments, e)?;
+            Ok(Some((col, key, kind)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Gets the cgroup id column and kind of a cgroup resolution, if the column is
+/// one: `cgroup_path(col)`, `container_id(col)` or `pod_uid(col)`, or the bare
+/// function name for the cgroup of the current task (e.g. `cgroup_path`).
+pub fn cgroup_column(c: &Column) -> Result<Option<(String, CgroupKind)>> {
+    let (name, args) = match c.function.as_deref() {
+        Some(FunctionExpression::Generic(name, args)) => {
+            (name.to_lowercase(), args.arguments.as_slice())
+        }
+        Some(_) => return Ok(None),
+        None => (c.name.to_lowercase(), [].as_slice()),
+    };
+    let Some(kind) = CgroupKind::from_func(&name) else {
+        return Ok(None);
+    };
+    match args {
+        [] => Ok(Some((SystemVar::CGROUP.to_string(), kind))),
+        [FunctionArgument::Column(col)] if col.function.is_none() => {
+            Ok(Some((col.name.clone(), kind)))
+        }
+        _ => {
+            bail!(EbqlError::bind(
+                c,
+                "function requires a single cgroup id column"
+            ))
+        }
+    }
+}
+
+/// Gets the field of the cgroup ids a column resolves.
+fn get_cgroup_id(c: &Column, col: &str, e: &Arc<dyn Event>) -> Result<types::Field> {
+    let f = e.get_arg(col)?;
+    if !matches!(f._type, Type::U64 | Type::S64) {
+        bail!(EbqlError::bind(
+            c,
+            "cgroups can only be resolved from 64-bit integer columns"
+        ));
+    }
+    Ok(f)
+}
+
+/// Gets the projected fields, output field and operator of a statistical
+/// aggregation, i.e. `variance(col)`, `stddev(col)`, `min_by(col, by)`,
+/// `max_by(col, by)`, `first(col)` or `last(col)`.
+fn get_stat(
+    func: &FunctionExpression,
+    name: &str,
+    args: &[FunctionArgument],
+    e: &Arc<dyn Event>,
+) -> Result<(Vec<types::Field>, types::Field, Operator)> {
+    let cols = args
+        .iter()
+        .map(|arg| {
+            match arg {
+                FunctionArgument::Column(col) if col.function.is_none() => Ok(col.name.clone()),
+                FunctionArgument::Column(_) => {
+                    Err(EbqlError::unsupported(func, "nested aggs not supported").into())
+                }
+                _ => Err(EbqlError::unsupported(func, "case when not supported").into()),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (op, out_name) = match (name, cols.as_slice()) {
+        (VARIANCE, [col]) => (Operator::Variance(col.clone()), format!("{name}_{col}")),
+        (STDDEV, [col]) => (Operator::Stddev(col.clone()), format!("{name}_{col}")),
+        (FIRST, [col]) => (Operator::First(col.clone()), format!("{name}_{col}")),
+        (LAST, [col]) => (Operator::Last(col.clone()), format!("{name}_{col}")),
+        (MIN_BY, [col, by]) => {
+            (
+                Operator::MinBy(col.clone(), by.clone()),
+                format!("{name}_{col}_{by}"),
+            )
+        }
+        (MAX_BY, [col, by]) => {
+            (
+                Operator::MaxBy(col.clone(), by.clone()),
+                format!("{name}_{col}_{by}"),
+            )
+        }
+        (MIN_BY | MAX_BY, _) => {
+            bail!(EbqlError::bind(
+                func,
+                "function requires a column and a column to order by"
+            ))
+        }
+        _ => bail!(EbqlError::bind(func, "function requires a single column")),
+    };
+    let proj_f = cols
+        .iter()
+        .map(|col| e.get_arg(col))
+        .collect::<Result<Vec<_>>>()?;
+    // Values are aggregated as 64-bit integers
+    if proj_f
+        .iter()
+        .any(|f| matches!(f._type, Type::String(_) | Type::Struct(_, _)))
+    {
+        bail!(EbqlError::unsupported(
+            func,
+            "values of the column can't be aggregated"
+        ));
+    }
+    Ok((proj_f, types::Field::new(out_name, Type::U64), op))
+}
+
+/// Gets the projected field, output field and operator of a histogram, i.e.
+/// `lhist(col, min, max, step)` or `log2hist(col)`.
+fn get_hist(
+    func: &FunctionExpression,
+    name: &str,
+    args: &[FunctionArgument],
+    e: &Arc<dyn Event>,
+) -> Result<(types::Field, types::Field, Operator)> {
+    let (col, params) = match args.split_first() {
+        Some((FunctionArgument::Column(col), params)) if col.function.is_none() => (col, params),
+        Some((FunctionArgument::Column(_), _)) => {
+            bail!(EbqlError::unsupported(func, "nested aggs not supported"))
+        }
+        Some(_) => bail!(EbqlError::unsupported(func, "case when not supported")),
+        None => bail!(EbqlError::bind(func, "function requires a column")),
+    };
+    let buckets = match (name, int_params(func, params)?.as_slice()) {
+        (LHIST, [min, max, step]) => {
+            if *step == 0 || max <= min {
+                bail!(EbqlError::bind(
+                    func,
+                    "step must be positive, and max greater than min"
+                ));
+            }
+            Buckets::Linear {
+                min: *min as u64,
+                max: *max as u64,
+                step: *step as u64,
+            }
+        }
+        (LHIST, _) => bail!(EbqlError::bind(func, "lhist requires a min, max and step")),
+        (_, []) => Buckets::Log2,
+        _ => bail!(EbqlError::bind(func, "log2hist takes no parameters")),
+    };
+    if buckets.len() > MAX_LINEAR_BUCKETS {
+        bail!(EbqlError::bind(
+            func,
+            format!("histogram must have at most {MAX_LINEAR_BUCKETS} buckets")
+        ));
+    }
+    let proj_f = e.get_arg(&col.name)?;
+    if let Type::String(_) | Type::Struct(_, _) = proj_f._type {
+        bail!(EbqlError::unsupported(
+            func,
+            "values of the column can't be counted into buckets"
+        ));
+    }
+    let out_f = types::Field::new(
+        format!("{name}_{}", col.name),
+        Type::String(buckets.len() * 8),
+    );
+    Ok((proj_f, out_f, Operator::Hist(col.name.clone(), buckets)))
+}
+
+/// Parses the parameters of a function, which are integer literals (parsed as
+/// column names).
+fn int_params(func: &FunctionExpression, params: &[FunctionArgument]) -> Result<Vec<usize>> {
+    params
+        .iter()
+        .map(|p| {
+            match p {
+                FunctionArgument::Column(c) if c.function.is_none() => c.name.parse::<usize>().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| EbqlError::bind(func, "function parameters must be integers").into())
+        })
+        .collect()
+}
+
+/// Gets the projected field, output fields and operator of an approximate
+/// aggregation, i.e. `approx_count_distinct(col[, registers])`,
+/// `approx_count(col[, width[, depth]])` or `topk(col, k[, counters])`.
+fn get_sketch(
+    func: &FunctionExpression,
+    name: &str,
+    args: &[FunctionArgument],
+    e: &Arc<dyn Event>,
+) -> Result<(types::Field, Vec<types::Field>, Operator)> {
+    let name = name.to_lowercase();
+    let (col, params) = match args.split_first() {
+        Some((FunctionArgument::Column(col), params)) if col.function.is_none() => (col, params),
+        Some((FunctionArgument::Column(_), _)) => {
+            bail!(EbqlError::unsupported(func, "nested aggs not supported"))
+        }
+        Some(_) => bail!(EbqlError::unsupported(func, "case when not supported")),
+        None => bail!(EbqlError::bind(func, "function requires a column")),
+    };
+    let params = int_params(func, params)?;
+    let proj_f = e.get_arg(&col.name)?;
+    // Values are hashed as integers (pointers by address); top-k and
+    // approximate counts also output them, so can't sketch pointers
+    match (name.as_str(), &proj_f._type) {
+        (APPROX_COUNT_DISTINCT | APPROX_COUNT | TOPK, Type::String(_) | Type::Struct(_, _))
+        | (APPROX_COUNT | TOPK, Type::Pointer(_)) => {
+            bail!(EbqlError::unsupported(
+                func,
+                "values of the column can't be sketched"
+            ))
+        }
+        _ => (),
+    }
+    let op = match (name.as_str(), params.as_slice()) {
+        (APPROX_COUNT_DISTINCT, [] | [_]) => {
+            let registers = params.first().copied().unwrap_or(DEFAULT_HLL_REGISTERS);
+            if !registers.is_power_of_two()
+                || !(MIN_HLL_REGISTERS..=MAX_HLL_REGISTERS).contains(&registers)
+            {
+                bail!(EbqlError::bind(
+                    func,
+                    format!(
+                        "registers must be a power of two between {MIN_HLL_REGISTERS} and \
+                         {MAX_HLL_REGISTERS}"
+                    )
+                ));
+            }
+            Operator::ApproxCountDistinct(col.name.clone(), registers)
+        }
+        (APPROX_COUNT, [] | [_] | [_, _]) => {
+            let width = params.first().copied().unwrap_or(DEFAULT_CMS_WIDTH);
+            let depth = params.get(1).copied().unwrap_or(DEFAULT_CMS_DEPTH);
+            if width == 0 || !(1..=MAX_CMS_DEPTH).contains(&depth) {
+                bail!(EbqlError::bind(
+                    func,
+                    format!("width must be positive, and depth between 1 and {MAX_CMS_DEPTH}")
+                ));
+            }
+            if width * depth > MAX_CMS_COUNTERS {
+                bail!(EbqlError::bind(
+                    func,
+                    format!("sketch must have at most {MAX_CMS_COUNTERS} counters")
+                ));
+            }
+            Operator::ApproxCount(col.name.clone(), width, depth)
+        }
+        (TOPK, [k] | [k, _]) => {
+            let counters = params
+                .get(1)
+                .copied()
+                .unwrap_or_else(|| (k * DEFAULT_TOPK_COUNTERS_PER_KEY).min(MAX_TOPK_COUNTERS));
+            if !(1..=MAX_TOPK).contains(k) || counters < *k {
+                bail!(EbqlError::bind(
+                    func,
+                    format!("k must be between 1 and {MAX_TOPK}, and at most the counters")
+                ));
+            }
+            if counters > MAX_TOPK_COUNTERS {
+                bail!(EbqlError::bind(
+                    func,
+                    format!("summary must have at most {MAX_TOPK_COUNTERS} counters")
+                ));
+            }
+            Operator::TopK(col.name.clone(), *k, counters)
+        }
+        (TOPK, []) => bail!(EbqlError::bind(func, "top-k requires k")),
+        (APPROX_COUNT_DISTINCT | APPROX_COUNT | TOPK, _) => {
+            bail!(EbqlError::bind(func, "too many sketch parameters"))
+        }
+        _ => bail!(EbqlError::unsupported(func, "unknown function")),
+    };
+    // Top-k outputs its values, each with its count and error bound, and
+    // approximate counts each with its estimated count
+    let out_f = match op {
+        Operator::TopK(..) => {
+            let (count, error) = topk_count_column(&col.name);
+            vec![
+                proj_f.clone(),
+                types::Field::new(count, Type::U64),
+                types::Field::new(error, Type::U64),
+            ]
+        }
+        Operator::ApproxCount(..) => {
+            vec![
+                proj_f.clone(),
+                types::Field::new(approx_count_column(&col.name), Type::U64),
+            ]
+        }
+        _ => {
+            vec![types::Field::new(format!("{name}_{}", col.name), Type::U64)]
+        }
+    };
+    Ok((proj_f, out_f, op))
+}
+
+/// Converts a window clause into a window. Only tumbling windows are supported.
+fn get_window(wt: nom_sql::WindowType) -> Result<WindowType> {
+    let window = match wt {
+        nom_sql::WindowType::Time(ival, step) => WindowType::Time(ival, step),
+        nom_sql::WindowType::Count(count, step) => WindowType::Count(count as usize, step as usize),
+    };
+    match window {
+        WindowType::Time(ival, step) if ival == step => Ok(window),
+        WindowType::Count(count, step) if count == step => Ok(window),
+        _ => {
+            bail!(EbqlError::unsupported(
+                window,
+                "non-tumbling windows not yet supported"
+            ))
+        }
+    }
+}
+
+/// Converts an event field into an output field. Structs have no user space
+/// representation, so they cannot be output, while pointers are output as
+/// their addresses; the time system variable is output as a timestamp.
+fn schema_field(f: &types::Field) -> Result<Field> {
+    if let Type::Struct(_, _) = f._type {
+        bail!(EbqlError::unsupported(
+            &f._name,
+            "struct fields cannot be selected"
+        ));
+    }
+    let field = Field::try_from(f)?;
+    Ok(match SystemVar::from_str(&f._name) {
+        Ok(SystemVar::TIME) => field.with_data_type(DataType::Timestamp(TimeUnit::Nanosecond)),
+        Ok(SystemVar::KSTACK) => field.with_data_type(DataType::Stack(StackKind::Kernel)),
+        Ok(SystemVar::USTACK) => field.with_data_type(DataType::Stack(StackKind::User)),
+        _ => field,
+    })
+}
+
+/// Converts a field of a BPF program's output struct into an output field.
+/// Averages and quantiles are computed in fixed point, and are output as
+/// floats, as are variances and standard deviations, computed from moments;
+/// histograms are output from the counts of their buckets, the bounds of time
+/// windows as timestamps, symbolized addresses as their symbols, path columns
+/// as their paths, and cgroup ids as what they're resolved into.
+fn output_field(f: &types::Field, plan: &BpfPlan) -> Result<Field> {
+    let field = schema_field(f)?;
+    let symbol = plan
+        .symbols
+        .iter()
+        .find_map(|(col, kind)| (*col == f._name).then_some(*kind));
+    let path = plan
+        .paths
+        .iter()
+        .find(|(col, ..)| *col == f._name)
+        .map(|(_, key, kind)| (key.clone(), *kind));
+    let cgroup = plan
+        .cgroups
+        .iter()
+        .find_map(|(col, kind)| (*col == f._name).then_some(*kind));
+    let is_avg = plan
+        .aggs
+        .iter()
+        .any(|op| matches!(op, Operator::Average(col) if f._name == format!("avg_{col}")));
+    let is_quantile = plan
+        .aggs
+        .iter()
+        .any(|op| matches!(op, Operator::Quantile(q) if f._name == quantile_column(*q)));
+    let buckets = plan.aggs.iter().find_map(|op| {
+        match op {
+            Operator::Hist(col, buckets) if f._name == format!("{}_{col}", hist_agg(buckets)) => {
+                Some(*buckets)
+            }
+            _ => None,
+        }
+    });
+    let moments = plan.aggs.iter().find_map(|op| {
+        match op {
+            Operator::Variance(col) if f._name == format!("{VARIANCE}_{col}") => {
+                Some(Statistic::Variance)
+            }
+            Operator::Stddev(col) if f._name == format!("{STDDEV}_{col}") => {
+                Some(Statistic::Stddev)
+            }
+            _ => None,
+        }
+    });
+    let is_time_bound =
+        is_window_bound(&f._name) && matches!(plan.window, Some(WindowType::Time(..)));
+    Ok(if is_avg {
+        field
+            .with_data_type(DataType::Float64)
+            .with_fixed_point_scale(AVG_SCALE)
+    } else if is_quantile {
+        field
+            .with_data_type(DataType::Float64)
+            .with_fixed_point_scale(FP_SCALE)
+    } else if let Some(buckets) = buckets {
+        field.with_data_type(DataType::Histogram(buckets))
+    } else if let Some(stat) = moments {
+        field.with_data_type(DataType::Float64).with_moments(stat)
+    } else if is_time_bound {
+        field.with_data_type(DataType::Timestamp(TimeUnit::Nanosecond))
+    } else if let Some(kind) = symbol {
+        field.with_data_type(DataType::Symbol(kind))
+    } else if let Some((key, kind)) = path {
+        field
+            .with_data_type(DataType::Path(kind))
+            .with_path_key(key)
+    } else if let Some(kind) = cgroup {
+        field.with_data_type(DataType::Cgroup(kind))
+    } else {
+        field
+    })
+}
+
+/// Gets whether a select joins the `proc` table, i.e. `JOIN proc USING (pid)`,
+/// which must then be its only join.
+fn joins_proc(s: &SelectStatement) -> Result<bool> {
+    let Some(join) = s
+        .join
+        .iter()
+        .find(|j| matches!(&j.right, JoinRightSide::Table(t) if t.name == PROC_TABLE))
+    else {
+        return Ok(false);
+    };
+    if s.join.len() > 1 {
+        bail!(EbqlError::unsupported(&s.join[1], "only one join allowed"));
+    }
+    match &join.constraint {
+        JoinConstraint::Using(cols) if cols.len() == 1 && cols[0].name == PROC_KEY => Ok(true),
+        _ => {
+            bail!(EbqlError::unsupported(
+                join,
+                format!("{PROC_TABLE} can only be joined using ({PROC_KEY})")
+            ))
+        }
+    }
+}
+
+/// Gets the `proc` column a selected column looks up, with the column holding
+/// its pid: either a column of the joined `proc` table, or a lookup function
+/// (e.g. `proc_exe(pid)`).
+fn proc_column(c: &Column, joined: bool) -> Result<Option<(ProcColumn, String)>> {
+    let func = match &c.function {
+        Some(func) => func,
+        None if joined && c.name != PROC_KEY => {
+            return Ok(ProcColumn::from_str(&c.name)
+                .ok()
+                .map(|col| (col, PROC_KEY.to_string())));
+        }
+        None => return Ok(None),
+    };
+    let FunctionExpression::Generic(name, args) = func.as_ref() else {
+        return Ok(None);
+    };
+    let Some(col) = ProcColumn::from_func(&name.to_lowercase()) else {
+        return Ok(None);
+    };
+    match args.arguments.as_slice() {
+        [FunctionArgument::Column(pid)] if pid.function.is_none() => {
+            Ok(Some((col, pid.name.clone())))
+        }
+        _ => bail!(EbqlError::bind(func, "function requires a pid column")),
+    }
+}
+
+/// Gets the FROM clause of a select, for error messages.
+fn tables_fragment(s: &SelectStatement) -> String {
+    s.tables
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Gets the selected fields of a select, for error messages.
+fn fields_fragment(fields: &[FieldDefinitionExpression]) -> String {
+    fields
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Gets the aliases of a select's output fields, keyed by output field name.
+fn get_output_aliases(s: &SelectStatement) -> Result<HashMap<String, String>> {
+    let e = match s.tables.first().and_then(|t| get_event(&t.name)) {
+        Some(e) => e,
+        None => {
+            bail!(EbqlError::bind(
+                tables_fragment(s),
+                "table is not a known event"
+            ))
+        }
+    };
+    let mut aliases = HashMap::new();
+    let joined_proc = joins_proc(s)?;
+    for f_def in &s.fields {
+        if let FieldDefinitionExpression::Col(c) = f_def {
+            // Process metadata isn't output by the select's program
+            if proc_column(c, joined_proc)?.is_some() {
+                continue;
+            }
+            // Cgroups are output under their ids' names
+            if let Some((col, _)) = cgroup_column(c)? {
+                if let Some(alias) = &c.alias {
+                    aliases.insert(col, alias.clone());
+                }
+                continue;
+            }
+            if let Some(alias) = &c.alias {
+                let (_, out_f, _, _) = get_column(c.clone(), &e)?;
+                let name = match (out_f.first(), symbol_column(c, &e)?, path_column(c, &e)?) {
+                    (Some(f), ..) => f._name.clone(),
+                    (None, Some((col, _)), _) | (None, None, Some((col, ..))) => col,
+                    (None, None, None) => c.name.clone(),
+                };
+                aliases.insert(name, alias.clone());
+            }
+        }
+    }
+    Ok(aliases)
+}
+
+/// Gets all columns referenced in a condition expression.
+fn condition_columns(ce: &ConditionExpression) -> Vec<&Column> {
+    match ce {
+        ConditionExpression::ComparisonOp(ct) | ConditionExpression::LogicalOp(ct) => {
+            let mut cols = condition_columns(&ct.left);
+            cols.extend(condition_columns(&ct.right));
+            cols
+        }
+        ConditionExpression::NegationOp(ce) | ConditionExpression::Bracketed(ce) => {
+            condition_columns(ce)
+        }
+        ConditionExpression::Base(ConditionBase::Field(col)) => vec![col],
+        _ => vec![],
+    }
+}
+
+/// Gets the fields of all columns in a condition expression.
 fn get_contained_columns(
     ce: &ConditionExpression,
     e: &Arc<dyn Event>,
 ) -> Result<Vec<types::Field>> {
     let mut res = Vec::new();
+    for col in condition_columns(ce) {
+        if col.name.starts_with(PARAM_PREFIX) {
+            continue;
+        }
+        // Cgroups are filtered by their ids (see `CgroupSet`)
+        if let Some((id, _)) = cgroup_column(col)? {
+            let f = get_cgroup_id(col, &id, e)?;
+            if !res.contains(&f) {
+                res.push(f);
+            }
+            continue;
+        }
+        // Assert that no nested function computations
+        if let Some(_) = col.function {
+            bail!(EbqlError::unsupported(
+                col,
+                "nested aggs within filters not supported"
+            ))
+        }
+        let f = e.get_arg(&col.name)?;
+        if !res.contains(&f) {
+            res.push(f);
+        }
+    }
+    Ok(res)
+}
+
+/// Gets the parameters in a condition expression. Each parameter takes the
+/// type of the field it is compared to.
+fn get_params(ce: &ConditionExpression, e: &Arc<dyn Event>) -> Result<Vec<types::Field>> {
+    let mut res: Vec<types::Field> = Vec::new();
     match ce {
-        ConditionExpression::ComparisonOp(ct) | ConditionExpression::LogicalOp(ct) => {
-            let cols = ct.contained_columns();
-            for col in cols {
-                // Assert that no nested function computations
-                if let Some(_) = col.function {
-                    unimplemented!("nested aggs within filters not supported")
+        ConditionExpression::ComparisonOp(ct) => {
+            let (param, col) = match (ct.left.as_ref(), ct.right.as_ref()) {
+                (
+                    ConditionExpression::Base(ConditionBase::Field(l)),
+                    ConditionExpression::Base(ConditionBase::Field(r)),
+                ) if l.name.starts_with(PARAM_PREFIX) => (l, r),
+                (
+                    ConditionExpression::Base(ConditionBase::Field(l)),
+                    ConditionExpression::Base(ConditionBase::Field(r)),
+                ) if r.name.starts_with(PARAM_PREFIX) => (r, l),
+                (l, r) => {
+                    if condition_columns(l)
+                        .iter()
+                        .chain(condition_columns(r).iter())
+                        .any(|c| c.name.starts_with(PARAM_PREFIX))
+                    {
+                        bail!(EbqlError::unsupported(
+                            ce,
+                            "parameters can only be compared directly to fields"
+                        ));
+                    }
+                    return Ok(res);
                 }
-                res.push(e.get_arg(&col.name)?);
+            };
+            let name = &param.name[PARAM_PREFIX.len()..];
+            if cgroup_column(col)?.is_some() {
+                bail!(EbqlError::unsupported(
+                    ce,
+                    "cgroups can't be compared to parameters"
+                ));
             }
+            if col.name.starts_with(PARAM_PREFIX) {
+                bail!(EbqlError::unsupported(
+                    ce,
+                    "parameters can only be compared to fields"
+                ));
+            }
+            let f = e.get_arg(&col.name)?;
+            if !matches!(
+                f._type,
+                Type::Bool
+                    | Type::U8
+                    | Type::U16
+                    | Type::U32
+                    | Type::U64
+                    | Type::S8
+                    | Type::S16
+                    | Type::S32
+                    | Type::S64
+                    | Type::UChar
+                    | Type::SChar
+            ) {
+                bail!(EbqlError::bind(
+                    ce,
+                    format!("parameter ${name} must be compared to an integer field")
+                ));
+            }
+            res.push(types::Field::new(name.to_string(), f._type));
         }
-        ConditionExpression::Base(ConditionBase::Field(ref col)) => {
-            // Assert that no nested function computations
-            if let Some(_) = col.function {
-                unimplemented!("nested aggs within filters not supported")
+        ConditionExpression::LogicalOp(ct) => {
+            res = get_params(&ct.left, e)?;
+            for f in get_params(&ct.right, e)? {
+                match res.iter().find(|p| p._name == f._name) {
+                    Some(p) if p._type != f._type => {
+                        bail!(EbqlError::bind(
+                            ce,
+                            format!(
+                                "parameter ${} is compared to fields of different types",
+                                f._name
+                            )
+                        ))
+                    }
+                    Some(_) => (),
+                    None => res.push(f),
+                }
             }
-            res.push(e.get_arg(&col.name)?);
         }
-        _ => unimplemented!("conditional expression {ce} not supported"),
+        ConditionExpression::NegationOp(ce) | ConditionExpression::Bracketed(ce) => {
+            res = get_params(ce, e)?;
+        }
+        _ => (),
     }
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gets the output field of a u64 column of a plan with the aggregates.
+    fn output(name: &str, aggs: Vec<Operator>) -> Field {
+        let mut plan = BpfPlan::new(&get_event("syscalls/sys_enter_pread64").unwrap());
+        plan.aggs = aggs;
+        output_field(&types::Field::new(name.into(), Type::U64), &plan).unwrap()
+    }
+
+    #[test]
+    fn marks_fixed_point_outputs() {
+        let avg = output("avg_count", vec![Operator::Average("count".into())]);
+        assert_eq!(avg.data_type, DataType::Float64);
+        assert_eq!(avg.fixed_point_scale(), Some(AVG_SCALE));
+
+        let quantile = output(&quantile_column(99), vec![Operator::Quantile(99)]);
+        assert_eq!(quantile.data_type, DataType::Float64);
+        assert_eq!(quantile.fixed_point_scale(), Some(FP_SCALE));
+
+        let other = output(&quantile_column(50), vec![Operator::Quantile(99)]);
+        assert_eq!(other.data_type, DataType::UInt64);
+        assert_eq!(other.fixed_point_scale(), None);
+    }
+
+    /// Gets the sketch of a function over the column of pread64 events.
+    fn sketch(name: &str, col: &str) -> Result<(types::Field, Vec<types::Field>, Operator)> {
+        let arg = FunctionArgument::Column(Column {
+            name: col.into(),
+            alias: None,
+            table: None,
+            function: None,
+        });
+        let func = FunctionExpression::Generic(
+            name.into(),
+            nom_sql::FunctionArguments {
+                arguments: vec![arg.clone()],
+            },
+        );
+        let e = get_event("syscalls/sys_enter_pread64").unwrap();
+        get_sketch(&func, name, &[arg], &e)
+    }
+
+    #[test]
+    fn approx_counts_output_their_column() {
+        let (proj_f, out_f, op) = sketch(APPROX_COUNT, "fd").unwrap();
+        assert_eq!(proj_f._name, "fd");
+        assert_eq!(
+            out_f.iter().map(|f| f._name.as_str()).collect::<Vec<_>>(),
+            ["fd", "approx_count_fd"]
+        );
+        assert!(matches!(
+            op,
+            Operator::ApproxCount(col, DEFAULT_CMS_WIDTH, DEFAULT_CMS_DEPTH) if col == "fd"
+        ));
+        // Pointers would be output as values
+        assert!(sketch(APPROX_COUNT, "buf").is_err());
+        assert!(sketch(APPROX_COUNT_DISTINCT, "buf").is_ok());
+    }
+}
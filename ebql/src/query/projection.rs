@@ -0,0 +1,64 @@
+//! Projections of a program's output onto an individual query's schema.
+
+use std::sync::Arc;
+
+use crate::{record::Record, record_batch::RecordBatch, schema::schema::Schema};
+
+/// Projection from the output schema of a (possibly shared) BPF program into
+/// the schema of a single query.
+#[derive(Clone, Debug)]
+pub struct Projection {
+    /// Schema of the projected output
+    pub schema: Arc<Schema>,
+    /// For each field in the projected schema, the index of the field in the
+    /// input schema
+    pub indices: Vec<usize>,
+}
+
+impl Projection {
+    /// Attempts to construct a projection from the input schema to the output
+    /// schema. Returns None if any output field does not exist in the input.
+    pub fn new(input: &Schema, output: Arc<Schema>) -> Option<Self> {
+        let indices = output
+            .fields
+            .iter()
+            .map(|f| input.fields.iter().position(|inf| inf.name == f.name))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            schema: output,
+            indices,
+        })
+    }
+
+    /// Constructs the projection of a schema onto itself.
+    pub fn identity(schema: Arc<Schema>) -> Self {
+        Self {
+            indices: (0..schema.fields.len()).collect(),
+            schema,
+        }
+    }
+
+    /// Returns whether this projection leaves its input unchanged.
+    pub fn is_identity(&self, input: &Schema) -> bool {
+        self.indices.len() == input.fields.len()
+            && self.indices.iter().enumerate().all(|(i, idx)| i == *idx)
+    }
+
+    /// Projects a record.
+    pub fn project_record(&self, r: &Record) -> Record {
+        Record::from(self.indices.iter().map(|i| r.get(*i)).collect::<Vec<_>>())
+    }
+
+    /// Projects a record batch into the projection's schema.
+    pub fn project(&self, rb: &RecordBatch) -> RecordBatch {
+        let projected = RecordBatch::new(
+            self.schema.clone(),
+            rb.records.iter().map(|r| self.project_record(r)).collect(),
+        )
+        .with_partial(rb.partial);
+        match &rb.error {
+            Some(error) => projected.with_error(error),
+            None => projected,
+        }
+    }
+}
@@ -1,9 +1,14 @@
 //! Data type definitions.
 
-use std::{fmt};
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
 
 use crate::{
+    error::EbqlError,
     field::{Fields},
+    histogram::Buckets,
+    stack::StackKind,
     types::Type,
 };
 
@@ -25,6 +30,17 @@ pub enum DataType {
     String(usize),
     Timestamp(TimeUnit),
     Struct(String, Fields),
+    /// Counts of values in each of the buckets
+    Histogram(Buckets),
+    /// Symbolized stack trace
+    Stack(StackKind),
+    /// Address, symbolized in user space into a string (e.g. `vfs_read+0x4`)
+    Symbol(StackKind),
+    /// File descriptor or inode, resolved in user space into a file path
+    Path(PathKind),
+    /// Cgroup id, resolved in user space into the cgroup's path (or the
+    /// container or pod it belongs to)
+    Cgroup(CgroupKind),
 }
 
 impl DataType {
@@ -99,10 +115,21 @@ impl DataType {
             DataType::String(len) => *len,
             DataType::Timestamp(_) => 8,
             DataType::Struct(_, fields) => fields.size(),
+            DataType::Histogram(buckets) => buckets.len() * 8,
+            DataType::Stack(_)
+            | DataType::Symbol(_)
+            | DataType::Path(_)
+            | DataType::Cgroup(_) => 8,
         }
     }
 }
 
+/// BPF programs cannot use floating point, so floats are carried as
+/// fixed-point integers (see [`crate::field::Field::fixed_point_scale`]).
+/// Histograms are carried as the raw bytes of their (u64) counts, stacks as
+/// their ids in the program's stack trace maps (see [`crate::stack_map`]),
+/// symbols as their addresses, paths as their fds or inodes, and cgroups as
+/// their ids.
 impl Into<Type> for DataType {
     fn into(self) -> Type {
         match self {
@@ -115,8 +142,8 @@ impl Into<Type> for DataType {
             DataType::Int16 => Type::S16,
             DataType::Int32 => Type::S32,
             DataType::Int64 => Type::S64,
-            DataType::Float32 => unimplemented!("see if bpf supports floats"),
-            DataType::Float64 => unimplemented!("see if bpf supports floats"),
+            DataType::Float32 => Type::U32,
+            DataType::Float64 => Type::U64,
             DataType::String(l) => Type::String(l),
             DataType::Timestamp(_) => Type::U64,
             DataType::Struct(name, fields) => {
@@ -130,36 +157,30 @@ impl Into<Type> for DataType {
                     ),
                 )
             }
+            DataType::Histogram(buckets) => Type::String(buckets.len() * 8),
+            DataType::Stack(_)
+            | DataType::Symbol(_)
+            | DataType::Path(_)
+            | DataType::Cgroup(_) => Type::U64,
         }
     }
 }
 
-impl From<Type> for DataType {
-    fn from(t: Type) -> Self {
-        use DataType::*;
-        match t {
-            Type::Bool => Boolean,
-            Type::U8 => UInt8,
-            Type::U16 => UInt16,
-            Type::U32 => UInt32,
-            Type::U64 => UInt64,
-            Type::S8 => Int8,
-            Type::S16 => Int16,
-            Type::S32 => Int32,
-            Type::S64 => Int64,
-            Type::UChar => UInt8,
-            Type::SChar => Int8,
-            Type::String(l) => String(l),
-            Type::Pointer(_) => unimplemented!("TODO: figure out what to do with pointers"),
-            Type::Struct(_, _) => unimplemented!("TODO: figure out what to do with structs"),
-        }
+impl TryFrom<Type> for DataType {
+    type Error = anyhow::Error;
+
+    fn try_from(t: Type) -> Result<Self> {
+        DataType::try_from(&t)
     }
 }
 
-impl From<&Type> for DataType {
-    fn from(t: &Type) -> Self {
+/// Pointers are represented by their addresses.
+impl TryFrom<&Type> for DataType {
+    type Error = anyhow::Error;
+
+    fn try_from(t: &Type) -> Result<Self> {
         use DataType::*;
-        match t {
+        Ok(match t {
             Type::Bool => Boolean,
             Type::U8 => UInt8,
             Type::U16 => UInt16,
@@ -172,9 +193,11 @@ impl From<&Type> for DataType {
             Type::UChar => UInt8,
             Type::SChar => Int8,
             Type::String(l) => String(*l),
-            Type::Pointer(_) => unimplemented!("TODO: figure out what to do with pointers"),
-            Type::Struct(_, _) => unimplemented!("TODO: figure out what to do with structs"),
-        }
+            Type::Pointer(_) => UInt64,
+            Type::Struct(name, _) => {
+                bail!(EbqlError::unsupported(name, "struct fields not supported"))
+            }
+        })
     }
 }
 
@@ -184,6 +207,26 @@ impl fmt::Display for DataType {
     }
 }
 
+/// What a file path is resolved from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PathKind {
+    /// File descriptor of a process
+    Fd,
+    /// Inode of a device
+    Inode,
+}
+
+/// What a cgroup id is resolved into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CgroupKind {
+    /// Path of the cgroup, relative to the root of the cgroup v2 hierarchy
+    Path,
+    /// Id of the container the cgroup belongs to
+    ContainerId,
+    /// Uid of the Kubernetes pod the cgroup belongs to
+    PodUid,
+}
+
 /// Unit of time in a timestamp.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum TimeUnit {
@@ -196,3 +239,39 @@ pub enum TimeUnit {
     /// Time in nanoseconds.
     Nanosecond,
 }
+
+/// Clock a timestamp is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Clock {
+    /// Time since boot, excluding suspend (`CLOCK_MONOTONIC`).
+    #[default]
+    Monotonic,
+    /// Time since boot, including suspend (`CLOCK_BOOTTIME`).
+    Boot,
+    /// Wall-clock time since the Unix epoch. Read from the boot clock, and
+    /// converted with an offset calibrated when the query is compiled.
+    Utc,
+}
+
+impl FromStr for Clock {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "monotonic" => Ok(Clock::Monotonic),
+            "boot" => Ok(Clock::Boot),
+            "utc" => Ok(Clock::Utc),
+            _ => bail!("unknown clock {s} (expected monotonic, boot, or utc)"),
+        }
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Clock::Monotonic => write!(f, "monotonic"),
+            Clock::Boot => write!(f, "boot"),
+            Clock::Utc => write!(f, "utc"),
+        }
+    }
+}
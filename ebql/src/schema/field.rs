@@ -1,7 +1,8 @@
 //! Field (a single "column" in the schema) representation.
 
-use std::{fmt, ops::Deref, sync::Arc};
+use std::{collections::BTreeMap, fmt, ops::Deref, str::FromStr, sync::Arc};
 
+use anyhow::{bail, Result};
 
 use crate::{
     data_types::DataType,
@@ -9,6 +10,55 @@ use crate::{
     types,
 };
 
+/// Metadata key marking a fixed-point column, whose value is the scale the
+/// column's integers are multiplied by (e.g. `1000000` for 6 decimal digits).
+pub const FIXED_POINT_SCALE_KEY: &str = "ebql.fixed_point_scale";
+
+/// Metadata key marking a column computed in user space from moments (i.e.
+/// the count, sum and sum of squares of its values), whose value is the
+/// statistic computed.
+pub const MOMENTS_KEY: &str = "ebql.moments";
+
+/// Metadata key marking a column resolved into file paths in user space, whose
+/// value is the column it's resolved within (i.e. the process of a file
+/// descriptor, or the device of an inode).
+pub const PATH_KEY_KEY: &str = "ebql.path_key";
+
+/// Size of the moments of a column in BPF structs (`moments_t` in
+/// `agg.bpf.h.tmpl`): the count, the first value (which the others are shifted
+/// by), then the 128-bit sum and sum of squares of the shifted values.
+pub const MOMENTS_SIZE: usize = 48;
+
+/// Statistic computed from the moments of a column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Statistic {
+    /// Sample variance
+    Variance,
+    /// Sample standard deviation
+    Stddev,
+}
+
+impl FromStr for Statistic {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "variance" => Self::Variance,
+            "stddev" => Self::Stddev,
+            _ => bail!("unknown statistic {s} (expected variance or stddev)"),
+        })
+    }
+}
+
+impl fmt::Display for Statistic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Variance => write!(f, "variance"),
+            Self::Stddev => write!(f, "stddev"),
+        }
+    }
+}
+
 /// Reference to a Field
 /// TODO: Arc or just Rc?
 pub type FieldRef = Arc<Field>;
@@ -32,12 +82,16 @@ impl Fields {
     }
 
     /// Converts a collection of fields into a list of BPF fields at an event.
-    pub fn to_bpf_fields(&self, e: &Arc<dyn Event>) -> Vec<types::Field> {
+    pub fn to_bpf_fields(&self, e: &Arc<dyn Event>) -> Result<Vec<types::Field>> {
         self.0
             .iter()
             .map(|f| {
-                match get_event_field(e, &f.name) {
+                Ok(match get_event_field(e, &f.name)? {
                     Some(f) => f,
+                    // Moments are carried as raw bytes
+                    None if f.moments().is_some() => {
+                        types::Field::new(f.name.clone(), types::Type::String(MOMENTS_SIZE))
+                    }
                     None => {
                         types::Field {
                             _name: f.name.clone(),
@@ -46,7 +100,7 @@ impl Fields {
                             _off: None,
                         }
                     }
-                }
+                })
             })
             .collect()
     }
@@ -136,9 +190,20 @@ impl fmt::Display for Fields {
 pub struct Field {
     pub name: String,
     pub data_type: DataType,
+    /// Key-value metadata of the field
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl Field {
+    /// Creates a new field.
+    pub fn new(name: impl Into<String>, data_type: DataType) -> Self {
+        Self {
+            name: name.into(),
+            data_type,
+            metadata: BTreeMap::new(),
+        }
+    }
+
     /// Returns an immutable reference to the `Field`'s name.
     #[inline]
     pub fn name(&self) -> &String {
@@ -162,23 +227,75 @@ impl Field {
         self.data_type = data_type;
         self
     }
+
+    /// Returns an immutable reference to the [`Field`]'s metadata.
+    #[inline]
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// Marks the field as a fixed-point column, whose values are produced as
+    /// integers scaled by `scale`, and returns self.
+    pub fn with_fixed_point_scale(mut self, scale: u64) -> Self {
+        self.metadata
+            .insert(FIXED_POINT_SCALE_KEY.into(), scale.to_string());
+        self
+    }
+
+    /// Gets the scale of a fixed-point column, or None if the field is not
+    /// fixed-point.
+    pub fn fixed_point_scale(&self) -> Option<u64> {
+        self.metadata
+            .get(FIXED_POINT_SCALE_KEY)
+            .and_then(|scale| scale.parse().ok())
+    }
+
+    /// Marks the field as a statistic of a column, computed from the column's
+    /// moments, and returns self.
+    pub fn with_moments(mut self, stat: Statistic) -> Self {
+        self.metadata.insert(MOMENTS_KEY.into(), stat.to_string());
+        self
+    }
+
+    /// Gets the statistic the field computes from moments, or None if it's
+    /// produced as is.
+    pub fn moments(&self) -> Option<Statistic> {
+        self.metadata
+            .get(MOMENTS_KEY)
+            .and_then(|stat| stat.parse().ok())
+    }
+
+    /// Marks the field as a path column, resolved within the key column, and
+    /// returns self.
+    pub fn with_path_key(mut self, key: impl Into<String>) -> Self {
+        self.metadata.insert(PATH_KEY_KEY.into(), key.into());
+        self
+    }
+
+    /// Gets the column a path column is resolved within, or None if the field
+    /// isn't a path column.
+    pub fn path_key(&self) -> Option<&str> {
+        self.metadata.get(PATH_KEY_KEY).map(String::as_str)
+    }
 }
 
-impl From<types::Field> for Field {
-    fn from(value: types::Field) -> Self {
-        Self {
-            name: value._name,
-            data_type: DataType::from(value._type),
-        }
+impl TryFrom<types::Field> for Field {
+    type Error = anyhow::Error;
+
+    fn try_from(value: types::Field) -> Result<Self> {
+        let data_type = DataType::try_from(&value._type)?;
+        Ok(Self::new(value._name, data_type))
     }
 }
 
-impl From<&types::Field> for Field {
-    fn from(value: &types::Field) -> Self {
-        Self {
-            name: value._name.clone(),
-            data_type: DataType::from(&value._type),
-        }
+impl TryFrom<&types::Field> for Field {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &types::Field) -> Result<Self> {
+        Ok(Self::new(
+            value._name.clone(),
+            DataType::try_from(&value._type)?,
+        ))
     }
 }
 
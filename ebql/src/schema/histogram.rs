@@ -0,0 +1,234 @@
+//! Histogram values, as aggregated by `lhist` and `log2hist`.
+
+use std::fmt;
+
+/// Maximum number of buckets of a linear histogram (including the buckets
+/// below its minimum and from its maximum). Each group keeps a counter per
+/// bucket, and each row carries them all.
+pub const MAX_LINEAR_BUCKETS: usize = 256;
+
+/// Number of buckets of a log2 histogram: one for 0, then one for each bit
+/// length of 64-bit values.
+pub const LOG2_BUCKETS: usize = 65;
+
+/// Width of the bars of rendered histograms, in characters.
+const BAR_WIDTH: usize = 52;
+
+/// Buckets values are counted into. Values are compared as unsigned integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Buckets {
+    /// Buckets of width `step` from `min` to `max` (the last one cut short at
+    /// `max`), plus a bucket for values below `min` and one for values from
+    /// `max`
+    Linear { min: u64, max: u64, step: u64 },
+    /// A bucket for 0, then `[2^(i-1), 2^i)` for each bit length i
+    Log2,
+}
+
+impl Buckets {
+    /// Gets the number of buckets.
+    pub fn len(&self) -> usize {
+        match self {
+            Buckets::Linear { min, max, step } => (max - min).div_ceil(*step) as usize + 2,
+            Buckets::Log2 => LOG2_BUCKETS,
+        }
+    }
+
+    /// Gets the label of the i-th bucket, like bpftrace's (e.g. `[4K, 8K)`).
+    pub fn label(&self, i: usize) -> String {
+        match *self {
+            Buckets::Linear { min, max, step } => {
+                let n = self.len();
+                if i == 0 {
+                    format!("(..., {})", si(min))
+                } else if i == n - 1 {
+                    format!("[{}, ...)", si(max))
+                } else {
+                    let lo = min + (i as u64 - 1) * step;
+                    format!("[{}, {})", si(lo), si((lo + step).min(max)))
+                }
+            }
+            Buckets::Log2 => {
+                match i {
+                    0 => "[0]".into(),
+                    1 => "[1]".into(),
+                    64 => format!("[{}, ...)", si(1 << 63)),
+                    _ => format!("[{}, {})", si(1 << (i - 1)), si(1 << i)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Buckets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Buckets::Linear { min, max, step } => write!(f, "lhist({min}, {max}, {step})"),
+            Buckets::Log2 => write!(f, "log2hist"),
+        }
+    }
+}
+
+/// Formats a bucket bound with a binary suffix, if it's a multiple of one.
+fn si(v: u64) -> String {
+    const SUFFIXES: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+    let mut suffix = None;
+    let mut scaled = v;
+    for s in SUFFIXES {
+        if scaled == 0 || scaled % 1024 != 0 {
+            break;
+        }
+        scaled /= 1024;
+        suffix = Some(s);
+    }
+    match suffix {
+        Some(s) => format!("{scaled}{s}"),
+        None => v.to_string(),
+    }
+}
+
+/// Counts of values in each bucket.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Histogram {
+    pub buckets: Buckets,
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Renders the histogram as an ASCII bar chart, like bpftrace's `hist()`,
+    /// from its first to its last non-empty bucket. Empty histograms render as
+    /// nothing.
+    pub fn render(&self) -> String {
+        let Some(first) = self.counts.iter().position(|c| *c > 0) else {
+            return String::new();
+        };
+        let last = self.counts.iter().rposition(|c| *c > 0).unwrap_or(first);
+        let max = self.counts.iter().max().copied().unwrap_or(1);
+        (first..=last)
+            .map(|i| {
+                let count = self.counts[i];
+                let bar = "@".repeat((count as u128 * BAR_WIDTH as u128 / max as u128) as usize);
+                format!(
+                    "{:<16} {count:>8} |{bar:<BAR_WIDTH$}|\n",
+                    self.buckets.label(i)
+                )
+            })
+            .collect()
+    }
+}
+
+/// Lists the non-empty buckets, e.g. `{[2, 4): 5, [4, 8): 1}`.
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(i, c)| format!("{}: {c}", self.buckets.label(i)))
+            .collect::<Vec<_>>();
+        write!(f, "{{{}}}", buckets.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINEAR: Buckets = Buckets::Linear {
+        min: 10,
+        max: 100,
+        step: 30,
+    };
+
+    fn labels(buckets: Buckets) -> Vec<String> {
+        (0..buckets.len()).map(|i| buckets.label(i)).collect()
+    }
+
+    #[test]
+    fn labels_linear_buckets() {
+        assert_eq!(
+            labels(LINEAR),
+            [
+                "(..., 10)",
+                "[10, 40)",
+                "[40, 70)",
+                "[70, 100)",
+                "[100, ...)"
+            ]
+        );
+        // The last bucket is cut short at the maximum
+        let buckets = Buckets::Linear {
+            min: 0,
+            max: 100,
+            step: 30,
+        };
+        assert_eq!(buckets.len(), 6);
+        assert_eq!(buckets.label(4), "[90, 100)");
+    }
+
+    #[test]
+    fn labels_log2_buckets() {
+        let buckets = Buckets::Log2;
+        assert_eq!(buckets.len(), LOG2_BUCKETS);
+        assert_eq!(buckets.label(0), "[0]");
+        assert_eq!(buckets.label(1), "[1]");
+        assert_eq!(buckets.label(2), "[2, 4)");
+        assert_eq!(buckets.label(13), "[4K, 8K)");
+        assert_eq!(buckets.label(21), "[1M, 2M)");
+        assert_eq!(buckets.label(64), "[8E, ...)");
+    }
+
+    #[test]
+    fn suffixes_multiples_of_powers_of_1024() {
+        assert_eq!(si(0), "0");
+        assert_eq!(si(1000), "1000");
+        assert_eq!(si(1536), "1536");
+        assert_eq!(si(3 << 10), "3K");
+        assert_eq!(si(5 << 30), "5G");
+    }
+
+    #[test]
+    fn renders_from_first_to_last_counted_bucket() {
+        let hist = Histogram {
+            buckets: LINEAR,
+            counts: vec![0, 4, 0, 2, 0],
+        };
+        let lines = hist.render().lines().map(String::from).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        let full = format!("|{}|", "@".repeat(BAR_WIDTH));
+        let half = format!("|{:<BAR_WIDTH$}|", "@".repeat(BAR_WIDTH / 2));
+        assert_eq!(lines[0], format!("{:<16} {:>8} {full}", "[10, 40)", 4));
+        assert_eq!(
+            lines[1],
+            format!("{:<16} {:>8} |{:BAR_WIDTH$}|", "[40, 70)", 0, "")
+        );
+        assert_eq!(lines[2], format!("{:<16} {:>8} {half}", "[70, 100)", 2));
+    }
+
+    #[test]
+    fn renders_out_of_range_buckets() {
+        // Values are compared unsigned, so negative values count from the
+        // maximum
+        let hist = Histogram {
+            buckets: LINEAR,
+            counts: vec![1, 0, 0, 0, 1],
+        };
+        let rendered = hist.render();
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("(..., 10) "));
+        assert!(lines[4].starts_with("[100, ...) "));
+        assert_eq!(hist.to_string(), "{(..., 10): 1, [100, ...): 1}");
+    }
+
+    #[test]
+    fn renders_empty_histograms_as_nothing() {
+        let hist = Histogram {
+            buckets: Buckets::Log2,
+            counts: vec![0; LOG2_BUCKETS],
+        };
+        assert_eq!(hist.render(), "");
+        assert_eq!(hist.to_string(), "{}");
+    }
+}
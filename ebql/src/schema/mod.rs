@@ -2,6 +2,8 @@
 
 pub mod data_types;
 pub mod field;
+pub mod histogram;
 pub mod record;
 pub mod record_batch;
 pub mod schema;
+pub mod stack;
@@ -1,10 +1,22 @@
 //
 
-use std::{fmt::Display, ops::Deref, time::Duration};
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    time::Duration,
+};
 
+use anyhow::Result;
 use nom_sql::Literal;
 
-use crate::data_types::{DataType, TimeUnit};
+use crate::{
+    data_types::{Clock, DataType, TimeUnit},
+    error::EbqlError,
+    histogram::Histogram,
+    stack::Stack,
+};
 
 /// Record representation.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -87,7 +99,9 @@ impl Display for Record {
     }
 }
 
-#[derive(Debug, Clone, PartialOrd, Ord, Hash)]
+/// Floats are ordered and hashed by their total order (see [`f64::total_cmp`]),
+/// so that records with float values can be grouped by.
+#[derive(Debug, Clone)]
 pub enum DataValue {
     Boolean(bool),
     UInt8(u8),
@@ -98,13 +112,15 @@ pub enum DataValue {
     Int16(i16),
     Int32(i32),
     Int64(i64),
+    Float32(f32),
+    Float64(f64),
     String(String, usize),
-    Timestamp(Duration),
+    /// Time since the start of the clock (i.e. boot, or the Unix epoch for UTC)
+    Timestamp(Duration, Clock),
+    Histogram(Histogram),
+    Stack(Stack),
     // TODO: implement nested data values later
     // Struct(Fields),
-    // TODO: implement
-    // Float32(f32),
-    // Float64(f64),
 }
 
 impl DataValue {
@@ -121,8 +137,44 @@ impl DataValue {
             DataValue::Int16(_) => 2,
             DataValue::Int32(_) => 4,
             DataValue::Int64(_) => 8,
+            DataValue::Float32(_) => 4,
+            DataValue::Float64(_) => 8,
             DataValue::String(_, l) => *l,
-            DataValue::Timestamp(_) => 8,
+            DataValue::Timestamp(..) => 8,
+            DataValue::Histogram(h) => h.counts.len() * 8,
+            DataValue::Stack(_) => 8,
+        }
+    }
+
+    /// Gets the integer value of the [`DataValue`] (timestamps in nanoseconds),
+    /// or None for floats, strings, histograms and stacks.
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            DataValue::Boolean(b) => Some(*b as i128),
+            DataValue::UInt8(u) => Some(*u as i128),
+            DataValue::UInt16(u) => Some(*u as i128),
+            DataValue::UInt32(u) => Some(*u as i128),
+            DataValue::UInt64(u) => Some(*u as i128),
+            DataValue::Int8(i) => Some(*i as i128),
+            DataValue::Int16(i) => Some(*i as i128),
+            DataValue::Int32(i) => Some(*i as i128),
+            DataValue::Int64(i) => Some(*i as i128),
+            DataValue::Timestamp(d, _) => Some(d.as_nanos() as i128),
+            DataValue::Float32(_)
+            | DataValue::Float64(_)
+            | DataValue::String(..)
+            | DataValue::Histogram(_)
+            | DataValue::Stack(_) => None,
+        }
+    }
+
+    /// Gets the floating-point value of the [`DataValue`] (timestamps in
+    /// nanoseconds), or None for strings, histograms and stacks.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            DataValue::Float32(f) => Some(*f as f64),
+            DataValue::Float64(f) => Some(*f),
+            _ => self.as_i128().map(|i| i as f64),
         }
     }
 
@@ -140,19 +192,46 @@ impl DataValue {
             Int16(_) => DataType::Int16,
             Int32(_) => DataType::Int32,
             Int64(_) => DataType::Int64,
+            Float32(_) => DataType::Float32,
+            Float64(_) => DataType::Float64,
             String(_, l) => DataType::String(*l),
-            Timestamp(_) => DataType::Timestamp(TimeUnit::Nanosecond),
+            Timestamp(..) => DataType::Timestamp(TimeUnit::Nanosecond),
+            Histogram(h) => DataType::Histogram(h.buckets),
+            Stack(s) => DataType::Stack(s.kind),
+        }
+    }
+
+    /// Gets the index of the value's variant, for ordering and hashing.
+    fn variant(&self) -> u8 {
+        match self {
+            DataValue::Boolean(_) => 0,
+            DataValue::UInt8(_) => 1,
+            DataValue::UInt16(_) => 2,
+            DataValue::UInt32(_) => 3,
+            DataValue::UInt64(_) => 4,
+            DataValue::Int8(_) => 5,
+            DataValue::Int16(_) => 6,
+            DataValue::Int32(_) => 7,
+            DataValue::Int64(_) => 8,
+            DataValue::Float32(_) => 9,
+            DataValue::Float64(_) => 10,
+            DataValue::String(..) => 11,
+            DataValue::Timestamp(..) => 12,
+            DataValue::Histogram(_) => 13,
+            DataValue::Stack(_) => 14,
         }
     }
 }
 
-impl From<Literal> for DataValue {
-    fn from(l: Literal) -> Self {
+impl TryFrom<Literal> for DataValue {
+    type Error = anyhow::Error;
+
+    fn try_from(l: Literal) -> Result<Self> {
         match l {
-            Literal::Integer(i) => DataValue::Int64(i),
-            Literal::UnsignedInteger(u) => DataValue::UInt64(u),
-            Literal::String(s) => DataValue::String(s.clone(), s.len()),
-            _ => unimplemented!("literal {} not supported", l.to_string()),
+            Literal::Integer(i) => Ok(DataValue::Int64(i)),
+            Literal::UnsignedInteger(u) => Ok(DataValue::UInt64(u)),
+            Literal::String(s) => Ok(DataValue::String(s.clone(), s.len())),
+            _ => Err(EbqlError::unsupported(l.to_string(), "literal not supported").into()),
         }
     }
 }
@@ -169,8 +248,12 @@ impl PartialEq for DataValue {
             (Self::Int16(l0), Self::Int16(r0)) => l0 == r0,
             (Self::Int32(l0), Self::Int32(r0)) => l0 == r0,
             (Self::Int64(l0), Self::Int64(r0)) => l0 == r0,
+            (Self::Float32(l0), Self::Float32(r0)) => l0.total_cmp(r0).is_eq(),
+            (Self::Float64(l0), Self::Float64(r0)) => l0.total_cmp(r0).is_eq(),
             (Self::String(l0, _), Self::String(r0, _)) => l0 == r0,
-            (Self::Timestamp(l0), Self::Timestamp(r0)) => l0 == r0,
+            (Self::Timestamp(l0, l1), Self::Timestamp(r0, r1)) => l0 == r0 && l1 == r1,
+            (Self::Histogram(l0), Self::Histogram(r0)) => l0 == r0,
+            (Self::Stack(l0), Self::Stack(r0)) => l0 == r0,
             _ => false,
         }
     }
@@ -178,6 +261,60 @@ impl PartialEq for DataValue {
 
 impl Eq for DataValue {}
 
+impl PartialOrd for DataValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Values of the same variant are compared by value, and values of different
+/// variants by the order of their variants.
+impl Ord for DataValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Boolean(l0), Self::Boolean(r0)) => l0.cmp(r0),
+            (Self::UInt8(l0), Self::UInt8(r0)) => l0.cmp(r0),
+            (Self::UInt16(l0), Self::UInt16(r0)) => l0.cmp(r0),
+            (Self::UInt32(l0), Self::UInt32(r0)) => l0.cmp(r0),
+            (Self::UInt64(l0), Self::UInt64(r0)) => l0.cmp(r0),
+            (Self::Int8(l0), Self::Int8(r0)) => l0.cmp(r0),
+            (Self::Int16(l0), Self::Int16(r0)) => l0.cmp(r0),
+            (Self::Int32(l0), Self::Int32(r0)) => l0.cmp(r0),
+            (Self::Int64(l0), Self::Int64(r0)) => l0.cmp(r0),
+            (Self::Float32(l0), Self::Float32(r0)) => l0.total_cmp(r0),
+            (Self::Float64(l0), Self::Float64(r0)) => l0.total_cmp(r0),
+            (Self::String(l0, _), Self::String(r0, _)) => l0.cmp(r0),
+            (Self::Timestamp(l0, l1), Self::Timestamp(r0, r1)) => (l1, l0).cmp(&(r1, r0)),
+            (Self::Histogram(l0), Self::Histogram(r0)) => l0.cmp(r0),
+            (Self::Stack(l0), Self::Stack(r0)) => l0.cmp(r0),
+            _ => self.variant().cmp(&other.variant()),
+        }
+    }
+}
+
+impl Hash for DataValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.variant().hash(state);
+        match self {
+            DataValue::Boolean(b) => b.hash(state),
+            DataValue::UInt8(u) => u.hash(state),
+            DataValue::UInt16(u) => u.hash(state),
+            DataValue::UInt32(u) => u.hash(state),
+            DataValue::UInt64(u) => u.hash(state),
+            DataValue::Int8(i) => i.hash(state),
+            DataValue::Int16(i) => i.hash(state),
+            DataValue::Int32(i) => i.hash(state),
+            DataValue::Int64(i) => i.hash(state),
+            DataValue::Float32(f) => f.to_bits().hash(state),
+            DataValue::Float64(f) => f.to_bits().hash(state),
+            DataValue::String(s, _) => s.hash(state),
+            DataValue::Timestamp(d, clock) => (d, clock).hash(state),
+            DataValue::Histogram(h) => h.hash(state),
+            DataValue::Stack(s) => s.hash(state),
+        }
+    }
+}
+
 impl std::fmt::Display for DataValue {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -190,8 +327,92 @@ impl std::fmt::Display for DataValue {
             DataValue::Int16(i) => write!(f, "{i}"),
             DataValue::Int32(i) => write!(f, "{i}"),
             DataValue::Int64(i) => write!(f, "{i}"),
+            DataValue::Float32(x) => write!(f, "{x}"),
+            DataValue::Float64(x) => write!(f, "{x}"),
             DataValue::String(s, _) => write!(f, "{s}"),
-            DataValue::Timestamp(d) => write!(f, "{:?}", d),
+            DataValue::Timestamp(d, Clock::Utc) => write_rfc3339(f, d),
+            DataValue::Timestamp(d, _) => write!(f, "{:?}", d),
+            DataValue::Histogram(h) => write!(f, "{h}"),
+            DataValue::Stack(s) => write!(f, "{s}"),
         }
     }
 }
+
+/// Writes a time since the Unix epoch as an RFC 3339 date in UTC, e.g.
+/// `2024-04-01T12:34:56.789012345Z`.
+fn write_rfc3339(f: &mut std::fmt::Formatter, d: &Duration) -> std::fmt::Result {
+    let secs = d.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch into a civil date (see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    write!(
+        f,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:09}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        d.subsec_nanos()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(secs: u64, nanos: u32) -> String {
+        DataValue::Timestamp(Duration::new(secs, nanos), Clock::Utc).to_string()
+    }
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(utc(0, 0), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(utc(951782400, 0), "2000-02-29T00:00:00.000000000Z");
+        assert_eq!(utc(1709251200, 0), "2024-03-01T00:00:00.000000000Z");
+        assert_eq!(utc(1711974896, 789012345), "2024-04-01T12:34:56.789012345Z");
+        assert_eq!(utc(4102444799, 1), "2099-12-31T23:59:59.000000001Z");
+    }
+
+    #[test]
+    fn formats_other_timestamps_as_durations() {
+        let d = Duration::from_millis(1500);
+        assert_eq!(
+            DataValue::Timestamp(d, Clock::Monotonic).to_string(),
+            "1.5s"
+        );
+    }
+
+    #[test]
+    fn orders_values_within_variants() {
+        assert!(DataValue::UInt64(2) > DataValue::UInt64(1));
+        assert!(DataValue::Int64(-2) < DataValue::Int64(1));
+        assert!(DataValue::Float64(-0.5) < DataValue::Float64(0.25));
+        assert!(DataValue::Float64(f64::NAN) > DataValue::Float64(f64::INFINITY));
+        assert_eq!(DataValue::Float64(f64::NAN), DataValue::Float64(f64::NAN));
+        assert!(DataValue::String("a".into(), 1) < DataValue::String("b".into(), 1));
+    }
+
+    #[test]
+    fn orders_values_across_variants_by_variant() {
+        // Values of different variants never compare equal, regardless of
+        // their values
+        assert!(DataValue::Boolean(true) < DataValue::UInt8(0));
+        assert!(DataValue::UInt64(u64::MAX) < DataValue::Int8(i8::MIN));
+        assert_ne!(DataValue::UInt64(1), DataValue::Int64(1));
+        // Timestamps of different clocks are ordered by their clocks first
+        let (early, late) = (Duration::from_secs(1), Duration::from_secs(2));
+        assert!(
+            DataValue::Timestamp(late, Clock::Monotonic) < DataValue::Timestamp(early, Clock::Utc)
+        );
+        assert!(DataValue::Timestamp(early, Clock::Utc) < DataValue::Timestamp(late, Clock::Utc));
+    }
+}
@@ -7,13 +7,36 @@ use crate::record::Record;
 pub struct RecordBatch {
     pub schema: Arc<Schema>,
     pub records: Vec<Record>,
+    /// Whether the batch holds a window that was cut short (i.e. flushed
+    /// before it ended, because its query was stopped)
+    pub partial: bool,
+    /// Error that ended the batch's stream, if any. Such a batch holds no
+    /// records, and is the last of its stream.
+    pub error: Option<String>,
 }
 
 impl RecordBatch {
     /// Creates a new [`RecordBatch`] from the schema definition and list of
     /// records.
     pub fn new(schema: Arc<Schema>, records: Vec<Record>) -> Self {
-        Self { schema, records }
+        Self {
+            schema,
+            records,
+            partial: false,
+            error: None,
+        }
+    }
+
+    /// Marks whether the batch holds a partial window.
+    pub fn with_partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// Marks the batch as ending its stream with the error.
+    pub fn with_error<S: Into<String>>(mut self, error: S) -> Self {
+        self.error = Some(error.into());
+        self
     }
 
     /// Gets the length of this record (i.e. # records)
@@ -44,7 +67,11 @@ impl Display for RecordBatch {
             records.push(record_str);
         }
 
-        write!(f, "RecordBatch(\n\t{}\n)", records.join("\n\t"))
+        if let Some(error) = &self.error {
+            return write!(f, "RecordBatch [error: {error}]");
+        }
+        let partial = if self.partial { " [partial]" } else { "" };
+        write!(f, "RecordBatch{partial}(\n\t{}\n)", records.join("\n\t"))
     }
 }
 
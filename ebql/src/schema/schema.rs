@@ -36,7 +36,7 @@ impl Schema {
 
     /// Tries to convert schema into a BpfStruct representation.
     pub fn to_bpf_struct(self: Arc<Self>, e: &Arc<dyn Event>) -> Result<Struct> {
-        let fields = self.fields.to_bpf_fields(e);
+        let fields = self.fields.to_bpf_fields(e)?;
 
         Ok(Struct::new(
             format!("{}_t", self.name.clone()),
@@ -0,0 +1,46 @@
+//! Stack traces, as sampled by the `kstack` and `ustack` system variables.
+
+use std::fmt;
+
+/// Frame standing in for addresses that can't be symbolized.
+pub const UNKNOWN_FRAME: &str = "[unknown]";
+
+/// Address space of a stack's frames, or of a symbolized address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StackKind {
+    Kernel,
+    User,
+}
+
+impl fmt::Display for StackKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackKind::Kernel => write!(f, "kstack"),
+            StackKind::User => write!(f, "ustack"),
+        }
+    }
+}
+
+/// Symbolized frames of a stack, from its innermost (i.e. the sampled
+/// function) to its outermost frame. Stacks that couldn't be read (e.g. the
+/// kernel stack of a sample taken in user space) have no frames.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stack {
+    pub kind: StackKind,
+    pub frames: Vec<String>,
+}
+
+impl Stack {
+    /// Gets the frames from the outermost one, as flame graphs draw them.
+    pub fn root_first(&self) -> impl Iterator<Item = &str> {
+        self.frames.iter().rev().map(String::as_str)
+    }
+}
+
+/// Writes the frames in folded form (i.e. from the outermost frame, separated
+/// by semicolons), e.g. `start_kernel;cpu_idle;default_idle`.
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root_first().collect::<Vec<_>>().join(";"))
+    }
+}